@@ -0,0 +1,71 @@
+//! Cancellation of long-running statements.
+//!
+//! Statements that may run for long, e.g. building an index over a large
+//! table, mark themselves cancellable while running and poll for cancellation
+//! requests between pages. Interrupting the process while no such statement
+//! is running terminates it as usual.
+
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::error::{Error, Result};
+
+/// Exit code of a process terminated by an interrupt.
+const INTERRUPTED: i32 = 130;
+
+/// Shared state of cancellation requests.
+#[derive(Debug, Default)]
+pub struct CancelToken {
+    /// Whether a cancellable statement is running.
+    active: AtomicBool,
+    /// Whether the running statement is requested to stop.
+    requested: AtomicBool,
+}
+
+impl CancelToken {
+    /// Request the running statement to stop.
+    ///
+    /// Returns whether a cancellable statement is running.
+    pub fn cancel(&self) -> bool {
+        self.requested.store(true, Ordering::SeqCst);
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Mark a cancellable statement as running until the guard is dropped.
+    pub fn begin(&self) -> Guard<'_> {
+        self.active.store(true, Ordering::SeqCst);
+        Guard(self)
+    }
+
+    /// Fail with `Error::Cancelled` if the running statement is requested to stop.
+    pub fn check(&self) -> Result<()> {
+        if self.requested.load(Ordering::SeqCst) {
+            Err(Error::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Guard of a running cancellable statement, clearing pending requests on drop.
+pub struct Guard<'a>(&'a CancelToken);
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        self.0.active.store(false, Ordering::SeqCst);
+        self.0.requested.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Cancel the running statement on interrupt, or exit if there is none.
+pub fn install(token: Arc<CancelToken>) -> Result<()> {
+    ctrlc::set_handler(move || {
+        if token.cancel() {
+            eprintln!("Cancelling...");
+        } else {
+            process::exit(INTERRUPTED);
+        }
+    })?;
+    Ok(())
+}
@@ -1,17 +1,95 @@
-//! Configuration constants and command line arguments.
+//! Configuration constants, command line arguments and the config file.
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
 
-pub const PAGE_SIZE: usize = 8192;
+/// Page size of newly created databases, unless configured.
+pub const DEFAULT_PAGE_SIZE: usize = 8192;
+
+/// Smallest page size allowed.
+pub const MIN_PAGE_SIZE: usize = 1024;
+
+/// Largest page size allowed.
+pub const MAX_PAGE_SIZE: usize = 65536;
+
+/// Size of the checksum stored after each page on disk.
+pub const CHECKSUM_SIZE: usize = 4;
+/// Size of the length of compressed contents stored before each compressed page.
+pub const COMPRESSED_LENGTH_SIZE: usize = 4;
 pub const CACHE_SIZE: usize = 16384;
 
+/// Count of shards of the page cache, each with its own lock and LRU list.
+pub const CACHE_SHARDS: usize = 16;
+
+/// Count of buffers in the ring reused by a large sequential scan.
+pub const SCAN_RING_SIZE: usize = 32;
+
+/// Sequential scans over more pages than this fraction of the page cache
+/// load their pages into a ring of buffers.
+pub const SCAN_RING_RATIO: usize = 4;
+
+/// Most pages read at once by a sequential scan missing the cache.
+pub const READ_AHEAD_PAGES: usize = 8;
+
+/// Most tables kept open, beyond which the least recently used ones are closed
+/// before the next statement.
+pub const TABLE_CACHE_SIZE: usize = 64;
+
+/// Count of pages scanned after each statement for an index built online.
+pub const INDEX_BUILD_BATCH: usize = 64;
+
+/// Least count of records per distinct leading value of a composite index
+/// for the index to be scanned by skipping, when its leading column is unbounded.
+pub const SKIP_SCAN_RATIO: usize = 16;
+
+/// Most duplicate keys listed in the error of adding a primary key,
+/// beyond which only their count is reported.
+pub const MAX_REPORTED_DUPLICATES: usize = 16;
+
+/// Least interval between flushes of the page cache in periodic durability mode.
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Size of a link in a linked list.
 pub const LINK_SIZE: usize = 4;
 
+/// Size of the deletion time stored after each record in a table page.
+pub const DELETED_TIME_SIZE: usize = 8;
+
+/// Size of the length prefix of a varchar.
+pub const VARCHAR_PREFIX_SIZE: usize = 2;
+
+/// Version of the layout of files in a database, saved in its metadata.
+///
+/// Bumped whenever pages or records are laid out differently, e.g. the length
+/// prefix of varchars and the deletion time after each record.
+pub const FORMAT_VERSION: u32 = 1;
+
 pub const SHELL_HISTORY: &str = ".yoursql_history";
 
+/// Default path to data directory.
+pub const DATA_PATH: &str = "data";
+
+/// Default path to config file, which is optional.
+pub const CONFIG_FILE: &str = "yoursql.toml";
+
+/// File name of query plan history in a database.
+pub const PLAN_HISTORY: &str = "plans.json";
+
+/// File name of metadata of a database, e.g. its page size.
+pub const DB_META: &str = "database.json";
+
+/// File name of the journal of DDL operations in progress in a database.
+pub const DDL_JOURNAL: &str = "ddl.json";
+
+/// File name of pages cached when a database was last used.
+pub const HOT_PAGES: &str = "hot_pages.json";
+
+/// Suffix appended to the path of a file for its double-write journal.
+pub const DOUBLE_WRITE_SUFFIX: &str = ".dw";
+
 /// Command line arguments.
 #[derive(Parser, Debug)]
 #[clap(
@@ -23,6 +101,18 @@ pub struct Config {
     #[clap(short, long)]
     pub batch: bool,
 
+    /// Print only result rows in batch mode or with --execute.
+    #[clap(short, long)]
+    pub quiet: bool,
+
+    /// Specify format of results [default: table in the shell, csv otherwise].
+    #[clap(long, value_enum)]
+    pub format: Option<OutputFormat>,
+
+    /// Execute statements, print results as in batch mode and exit.
+    #[clap(short, long, conflicts_with("batch"))]
+    pub execute: Option<String>,
+
     /// Specify database.
     #[clap(short, long)]
     pub database: Option<String>,
@@ -31,9 +121,9 @@ pub struct Config {
     #[clap(short, long)]
     pub init: bool,
 
-    /// Specify path to data directory.
-    #[clap(short, long, default_value = "data")]
-    pub path: PathBuf,
+    /// Specify path to data directory [default: data].
+    #[clap(short, long)]
+    pub path: Option<PathBuf>,
 
     /// Specify table to load data into.
     #[clap(short, long, requires("database"))]
@@ -42,4 +132,167 @@ pub struct Config {
     /// Specify path to data file to load.
     #[clap(short, long, requires("table"))]
     pub file: Option<PathBuf>,
+
+    /// Specify path to query log to record statements into.
+    #[clap(short = 'l', long)]
+    pub query_log: Option<PathBuf>,
+
+    /// Specify path to config file [default: yoursql.toml].
+    #[clap(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Specify number of pages in the page cache.
+    #[clap(long)]
+    pub cache_size: Option<usize>,
+
+    /// Specify page size in bytes of newly created databases [default: 8192].
+    #[clap(long)]
+    pub page_size: Option<usize>,
+
+    /// Load pages cached when a database was last used on switching to it.
+    #[clap(long)]
+    pub warm_cache: bool,
+
+    /// Write pages through a synced journal first, protecting them from torn writes.
+    #[clap(long)]
+    pub double_write: bool,
+
+    /// Specify when written pages are synced to disk [default: off].
+    #[clap(long, value_enum)]
+    pub durability: Option<Durability>,
+
+    /// Specify how data files are accessed [default: buffered].
+    #[clap(long, value_enum)]
+    pub io_backend: Option<IoBackend>,
+
+    /// Specify SQL script to run at startup.
+    #[clap(long)]
+    pub init_script: Option<PathBuf>,
+
+    /// Specify log level, used when RUST_LOG is not set [default: info].
+    #[clap(long)]
+    pub log_level: Option<String>,
+
+    /// Specify format of log records [default: text].
+    #[clap(long, value_enum)]
+    pub log_format: Option<LogFormat>,
+}
+
+impl Config {
+    /// Fill in settings not given in command line arguments from the config file.
+    pub fn merge(&mut self, file: FileConfig) {
+        self.path = self.path.take().or(file.path);
+        self.database = self.database.take().or(file.database);
+        self.cache_size = self.cache_size.or(file.cache_size);
+        self.page_size = self.page_size.or(file.page_size);
+        self.io_backend = self.io_backend.or(file.io_backend);
+        self.warm_cache |= file.warm_cache.unwrap_or_default();
+        self.double_write |= file.double_write.unwrap_or_default();
+        self.durability = self.durability.or(file.durability);
+        self.init_script = self.init_script.take().or(file.init_script);
+        self.log_level = self.log_level.take().or(file.log_level);
+        self.log_format = self.log_format.or(file.log_format);
+        self.format = self.format.or(file.format);
+    }
+
+    /// Get path to data directory.
+    pub fn get_path(&self) -> &Path {
+        self.path.as_deref().unwrap_or(Path::new(DATA_PATH))
+    }
+}
+
+/// Settings in the config file, all optional.
+///
+/// # Example
+///
+/// ```toml
+/// path = "data"
+/// cache_size = 16384
+/// page_size = 8192
+/// io_backend = "mmap"
+/// warm_cache = true
+/// double_write = true
+/// durability = "commit"
+/// database = "db"
+/// init_script = "init.sql"
+/// log_level = "warn"
+/// log_format = "json"
+/// format = "json"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    /// Path to data directory.
+    pub path: Option<PathBuf>,
+    /// Number of pages in the page cache.
+    pub cache_size: Option<usize>,
+    /// Page size in bytes of newly created databases.
+    pub page_size: Option<usize>,
+    /// How data files are accessed.
+    pub io_backend: Option<IoBackend>,
+    /// Whether to load pages cached when a database was last used.
+    pub warm_cache: Option<bool>,
+    /// Whether to write pages through a double-write journal.
+    pub double_write: Option<bool>,
+    /// When written pages are synced to disk.
+    pub durability: Option<Durability>,
+    /// Database to use at startup.
+    pub database: Option<String>,
+    /// SQL script to run at startup.
+    pub init_script: Option<PathBuf>,
+    /// Log level, used when RUST_LOG is not set.
+    pub log_level: Option<String>,
+    /// Format of log records.
+    pub log_format: Option<LogFormat>,
+    /// Format of results.
+    pub format: Option<OutputFormat>,
+}
+
+/// How data files are accessed by the page cache.
+#[derive(Clone, Copy, Debug, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum IoBackend {
+    /// Read and write calls on each page missed in the cache.
+    #[default]
+    Buffered,
+    /// Copy pages from and into a memory map of the whole file.
+    Mmap,
+}
+
+/// When pages written back by the page cache are synced to disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// Left to the operating system, only written back on eviction and exit.
+    #[default]
+    Off,
+    /// Synced after every write.
+    Always,
+    /// Flushed and synced after every statement.
+    Commit,
+    /// Flushed and synced after a statement, at most once per interval.
+    Periodic,
+}
+
+/// Format of log records.
+#[derive(Clone, Copy, Debug, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines, with tracing fields before the message.
+    #[default]
+    Text,
+    /// One JSON object per line, with tracing fields as keys.
+    Json,
+}
+
+/// Format of results of statements.
+#[derive(Clone, Copy, Debug, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Tables drawn with borders, followed by a summary and the time taken.
+    Table,
+    /// CSV rows, each result followed by its statement after `@`.
+    Csv,
+    /// One JSON object per line for each statement.
+    Json,
 }
@@ -2,18 +2,19 @@
 
 use std::io::Error as IOError;
 use std::num::{ParseFloatError, ParseIntError};
+use std::ops::Range;
 use std::result;
-use std::sync::{MutexGuard, PoisonError};
 
 use chrono::format::ParseError as ChronoParseError;
 use csv::Error as CsvError;
+use ctrlc::Error as CtrlcError;
 use pest::error::Error as PestError;
-use regex::Error as RegexError;
+use regex::{Error as RegexError, Regex};
 use rustyline::error::ReadlineError;
 use serde_json::error::Error as SerdeError;
 use thiserror::Error;
+use toml::de::Error as TomlError;
 
-use crate::file::PageCache;
 use crate::parser::Rule;
 use crate::schema::{Type, Value};
 
@@ -41,6 +42,10 @@ pub enum Error {
     InexactColumn(String),
     #[error("Index `{0}` on table `{1}` not found")]
     IndexNotFound(String, String),
+    #[error("Index `{0}` on table `{1}` is unavailable for its files missing, rebuild it first")]
+    IndexUnavailable(String, String),
+    #[error("Failed to build index `{0}` online: {1}")]
+    IndexBuildFailed(String, String),
 
     #[error("Duplicate column name `{0}`")]
     DuplicateColumn(String),
@@ -53,6 +58,8 @@ pub enum Error {
 
     #[error("Field count mismatch: {0} provided but {1} expected")]
     FieldCountMismatch(usize, usize),
+    #[error("Field count mismatch in row {0}: {1} provided but {2} expected, for columns ({3})")]
+    RowFieldCountMismatch(usize, usize, usize, String),
     #[error("Value `{0}` does not match type `{1}`")]
     TypeMismatch(Value, Type),
     #[error("Field `{0}` must not be null")]
@@ -60,10 +67,18 @@ pub enum Error {
 
     #[error("Constraint failed: types of foreign keys mismatch")]
     ForeignKeyTypeMismatch,
-    #[error("Constraint failed: referenced fields not primary key")]
+    #[error("Constraint failed: referenced fields not primary key or unique")]
     ForeignKeyNotPrimaryKey,
     #[error("Constraint failed: duplicate value for constraint `{0}`")]
     DuplicateValue(String),
+    #[error(
+        "Constraint failed: duplicate values for constraint `{0}`: {}{}",
+        .1.join(", "),
+        if *.2 > 0 { format!(" ... and {} more", .2) } else { String::new() }
+    )]
+    DuplicateValues(String, Vec<String>, usize),
+    #[error("Duplicate value for unique index `{0}`")]
+    DuplicateKey(String),
     #[error("Constraint failed: columns referenced by foreign key must be primary key")]
     ReferencedColumnsNotPrimaryKey,
     #[error("Constraint failed: fields referenced by foreign key `{0}` not exist")]
@@ -72,6 +87,16 @@ pub enum Error {
     RowReferencedByForeignKey(String),
     #[error("Constraint failed: cannot drop table due to foreign key `{0}`")]
     TableReferencedByForeignKey(String),
+    #[error("Constraint failed: cannot drop index due to foreign key `{0}`")]
+    IndexReferencedByForeignKey(String),
+    #[error("Column `{0}` of type TEXT cannot be indexed")]
+    TextIndex(String),
+    #[error("String column `{0}` cannot be unique under the NOCASE collation")]
+    CaseInsensitiveKey(String),
+    #[error("Column `{0}` of type `{1}` cannot hold the time of records")]
+    TtlColumnType(String, Type),
+    #[error("No TTL on table `{0}`")]
+    NoTtl(String),
 
     #[error("There should be exactly one join condition")]
     JoinConditionCount,
@@ -79,19 +104,52 @@ pub enum Error {
     JoinOperation,
     #[error("Aggregation query mixed with non-aggregation query")]
     MixedAggregate,
+    #[error("Column `{0}` is neither grouped nor aggregated")]
+    NotGrouped(String),
+    #[error("Sum out of range of BIGINT")]
+    SumOverflow,
+    #[error("Cannot add `{0}` and `{1}`")]
+    SumTypeMismatch(Value, Value),
+
+    #[error("Unknown variable `{0}`")]
+    UnknownVariable(String),
+    #[error("Unknown collation `{0}`")]
+    UnknownCollation(String),
+    #[error("Quota of database `{0}` exceeded: {1} bytes needed, {2} bytes allowed")]
+    QuotaExceeded(String, u64, u64),
+    #[error("Invalid time zone `{0}`: expected an offset like `+08:00`, or `SYSTEM`")]
+    InvalidTimeZone(String),
+    #[error("Table `{0}` is modified by another process")]
+    TableConflict(String),
+    #[error("Database `{0}` is a read-only snapshot")]
+    ReadOnlyDatabase(String),
+    #[error("Replica `{0}` would be inside the data directory")]
+    ReplicaInsideBase(String),
+
+    #[error("Statement cancelled")]
+    Cancelled,
+
+    #[error("Page {1} of file `{0}` is corrupted")]
+    PageCorrupted(String, usize),
+    #[error("Invalid page size {0}: must be a power of two between 1024 and 65536")]
+    InvalidPageSize(usize),
+    #[error("Database `{0}` is in format {1}, while only format {2} is supported")]
+    UnsupportedFormat(String, u32, u32),
 
     #[error("Date parse error: {0}")]
     ChronoParse(#[from] ChronoParseError),
+    #[error("Config error: {0}")]
+    Config(#[from] TomlError),
     #[error("CSV error: {0}")]
     Csv(#[from] CsvError),
+    #[error("Signal handler error: {0}")]
+    Ctrlc(#[from] CtrlcError),
     #[error("IO error: {0}")]
     IO(#[from] IOError),
     #[error("Float parse error: {0}")]
     ParseFloat(#[from] ParseFloatError),
     #[error("Int parse error: {0}")]
     ParseInt(#[from] ParseIntError),
-    #[error("Poison error: {0}")]
-    Poison(#[from] PoisonError<MutexGuard<'static, PageCache>>),
     #[error("Readline error: {0}")]
     Readline(#[from] ReadlineError),
     #[error("Regex error: {0}")]
@@ -102,4 +160,32 @@ pub enum Error {
     Syntax(#[from] Box<PestError<Rule>>),
 }
 
+impl Error {
+    /// Locate the name an error is about in the statement causing it.
+    ///
+    /// Returns the byte range of the first occurrence of the name as a whole
+    /// word, or `None` if the error is not about a name or it is not found.
+    pub fn span(&self, statement: &str) -> Option<Range<usize>> {
+        let name = match self {
+            Error::DatabaseExists(name)
+            | Error::DatabaseNotFound(name)
+            | Error::TableExists(name)
+            | Error::TableNotFound(name)
+            | Error::ColumnNotFound(name)
+            | Error::ConstraintNotFound(name)
+            | Error::InexactColumn(name)
+            | Error::IndexNotFound(name, _)
+            | Error::IndexUnavailable(name, _)
+            | Error::DuplicateColumn(name)
+            | Error::NotGrouped(name)
+            | Error::UnknownVariable(name)
+            | Error::UnknownCollation(name)
+            | Error::InvalidTimeZone(name) => name,
+            _ => return None,
+        };
+        let re = Regex::new(&format!(r"\b{}\b", regex::escape(name))).ok()?;
+        re.find(statement).map(|m| m.range())
+    }
+}
+
 pub type Result<T> = result::Result<T, Error>;
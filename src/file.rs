@@ -1,44 +1,330 @@
-//! Paged file system, with LRU cache.
+//! Paged file system, with LRU-2 cache.
+//!
+//! Each page is followed on disk by a CRC-32 checksum of its contents,
+//! computed when the page is written and verified when it is read.
+//! A page whose contents and checksum are all zeros has never been
+//! written, and reads as an empty page.
+//!
+//! The page size is set per database, and kept by the page cache, which
+//! opens files with it until it is changed.
+//!
+//! There is no global page cache: each system owns one, so that several
+//! systems may be used in the same process.
+//!
+//! Data files of tables may store each page compressed with LZ4, in a slot
+//! of fixed size whose unused tail takes no disk space.
+//!
+//! With double write enabled, pages are written back in batches, each first
+//! written into a journal beside the file and synced, so that a page torn by
+//! a crash while written in place is restored when the file is opened again.
+//!
+//! The cache evicts first the pages referenced only once, then those whose
+//! second last reference is the oldest, so that pages read once by a scan
+//! do not flush the working set. Large sequential scans further load their
+//! pages into a small ring of buffers, reused as the scan goes on.
+//! Pages missed by a scan are read ahead in batches of consecutive pages.
 
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::{self, OpenOptions};
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::num::NonZeroUsize;
-use std::path::Path;
-use std::sync::Mutex;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
 
 use lru::LruCache;
-use once_cell::sync::Lazy;
+use memmap2::MmapMut;
+use parking_lot::Mutex;
 use uuid::Uuid;
 
-use crate::config::{CACHE_SIZE, PAGE_SIZE};
+use crate::config::{
+    Durability, IoBackend, CACHE_SHARDS, CACHE_SIZE, CHECKSUM_SIZE, COMPRESSED_LENGTH_SIZE,
+    DEFAULT_PAGE_SIZE, DOUBLE_WRITE_SUFFIX, READ_AHEAD_PAGES, SCAN_RING_RATIO, SCAN_RING_SIZE,
+    SYNC_INTERVAL,
+};
+use crate::error::{Error, Result};
+use crate::trace;
 
-pub static FS: Lazy<Mutex<PageCache>> = Lazy::new(|| Mutex::new(PageCache::new()));
+/// Page cache owned by a system, shared with callers configuring it.
+///
+/// Operations lock it with `lock_arc`, so that the guard does not borrow
+/// the owner while its tables and indexes are accessed.
+pub type SharedCache = Arc<Mutex<PageCache>>;
 
-/// File wrapper providing a uuid for hashing.
+/// Lookup table of CRC-32 (IEEE) remainders of each byte.
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Compute the CRC-32 checksum of a buffer.
+fn crc32(buf: &[u8]) -> u32 {
+    !buf.iter().fold(!0, |crc, &byte| {
+        CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Report a page failing its checksum.
+fn corrupted(path: &Path, page: usize) -> Error {
+    let path = path.display().to_string();
+    log::error!("Checksum mismatch on page {page} of file {path}");
+    Error::PageCorrupted(path, page)
+}
+
+/// Check the checksum of a page read from disk, and copy its contents.
+///
+/// A page whose contents and checksum are all zeros has never been written.
+fn load_page(disk: &[u8], buf: &mut [u8], path: &Path, page: usize) -> Result<()> {
+    let (contents, checksum) = disk.split_at(buf.len());
+    let checksum = u32::from_le_bytes(checksum.try_into().unwrap());
+    let written = checksum != 0 || contents.iter().any(|&byte| byte != 0);
+    if written && checksum != crc32(contents) {
+        return Err(corrupted(path, page));
+    }
+
+    buf.copy_from_slice(contents);
+    Ok(())
+}
+
+/// Read into a buffer until it is full or the end of file is reached.
+///
+/// Return the number of bytes read.
+fn read_full(file: &mut fs::File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut bytes_read = 0;
+    while bytes_read < buf.len() {
+        match file.read(&mut buf[bytes_read..])? {
+            0 => break,
+            n => bytes_read += n,
+        }
+    }
+    Ok(bytes_read)
+}
+
+/// Release the disk space of a range in a file, which then reads as zeros.
+///
+/// Zeros are written instead where the file system cannot punch holes.
+fn punch_hole(file: &mut fs::File, offset: usize, len: usize) -> io::Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        // SAFETY: fallocate is called on the descriptor of a file open for write.
+        let ret = unsafe {
+            libc::fallocate(
+                file.as_raw_fd(),
+                mode,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if ret == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+            return Err(err);
+        }
+    }
+
+    file.seek(SeekFrom::Start(offset as u64))?;
+    file.write_all(&vec![0u8; len])
+}
+
+/// Open a file for read and write. If not exists, create it.
+fn open_file(name: &Path) -> io::Result<fs::File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(name)
+}
+
+/// Size of the page number of a record in a double-write journal.
+const JOURNAL_PAGE_SIZE: usize = 8;
+
+/// Get the path to the double-write journal of a file.
+fn journal_path(path: &Path) -> PathBuf {
+    let mut journal = path.as_os_str().to_owned();
+    journal.push(DOUBLE_WRITE_SUFFIX);
+    PathBuf::from(journal)
+}
+
+/// Write pages into a file.
+///
+/// With double write, the pages are first written into the journal of the
+/// file, each record followed by its checksum, and synced. The pages are then
+/// written in place and synced, after which the journal is removed.
+fn write_pages(
+    file: &mut dyn PageStore,
+    pages: &[(usize, &[u8])],
+    double_write: bool,
+) -> io::Result<()> {
+    if !double_write {
+        for &(page, buf) in pages {
+            file.write_page(page, buf)?;
+        }
+        return Ok(());
+    }
+
+    let record_size = JOURNAL_PAGE_SIZE + file.page_size() + CHECKSUM_SIZE;
+    let mut records = Vec::with_capacity(pages.len() * record_size);
+    for &(page, buf) in pages {
+        let start = records.len();
+        records.extend_from_slice(&(page as u64).to_le_bytes());
+        records.extend_from_slice(buf);
+        let checksum = crc32(&records[start..]);
+        records.extend_from_slice(&checksum.to_le_bytes());
+    }
+
+    let path = journal_path(file.path());
+    let mut journal = fs::File::create(&path)?;
+    journal.write_all(&records)?;
+    journal.sync_data()?;
+    log::debug!("Journaled {} pages of file {}", pages.len(), file.id());
+
+    for &(page, buf) in pages {
+        file.write_page(page, buf)?;
+    }
+    file.sync()?;
+    fs::remove_file(path)
+}
+
+/// Restore pages of a file from its double-write journal, if any.
+///
+/// Pages are written in place only after the whole journal is synced, so
+/// records after an incomplete one are never needed.
+fn recover(file: &mut dyn PageStore) -> io::Result<()> {
+    let path = journal_path(file.path());
+    let records = match fs::read(&path) {
+        Ok(records) => records,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    let record_size = JOURNAL_PAGE_SIZE + file.page_size() + CHECKSUM_SIZE;
+    let mut restored = 0;
+    for record in records.chunks_exact(record_size) {
+        let (contents, checksum) = record.split_at(record_size - CHECKSUM_SIZE);
+        if crc32(contents) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+            break;
+        }
+        let (page, buf) = contents.split_at(JOURNAL_PAGE_SIZE);
+        let page = u64::from_le_bytes(page.try_into().unwrap()) as usize;
+        file.write_page(page, buf)?;
+        restored += 1;
+    }
+    log::warn!(
+        "Restored {restored} pages of file {} from its double-write journal",
+        file.path().display()
+    );
+
+    file.sync()?;
+    fs::remove_file(path)
+}
+
+/// Storage of the pages of a file, each followed by its checksum.
+pub trait PageStore: Send {
+    /// Get the uuid of the file.
+    fn id(&self) -> Uuid;
+
+    /// Get the path to the file.
+    fn path(&self) -> &Path;
+
+    /// Get the size of pages in the file.
+    fn page_size(&self) -> usize;
+
+    /// Read a given page on the file, verifying its checksum.
+    ///
+    /// Pages beyond the end of the file read as zeros.
+    fn read_page(&mut self, page: usize, buf: &mut [u8]) -> Result<()>;
+
+    /// Read consecutive pages from a given one, a buffer for each.
+    ///
+    /// By default the pages are read one by one.
+    fn read_pages(&mut self, first: usize, bufs: &mut [Vec<u8>]) -> Result<()> {
+        for (page, buf) in (first..).zip(bufs) {
+            self.read_page(page, buf)?;
+        }
+        Ok(())
+    }
+
+    /// Write to a given page on the file, along with its checksum.
+    fn write_page(&mut self, page: usize, buf: &[u8]) -> io::Result<()>;
+
+    /// Cut the file down to a given count of pages.
+    fn truncate(&mut self, pages: usize) -> io::Result<()>;
+
+    /// Make written pages durable on disk.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+/// File wrapper providing a uuid for hashing, accessed with read and write calls.
 pub struct File {
     id: Uuid,
     file: fs::File,
+    /// Path to the file, for error messages.
+    path: PathBuf,
+    /// Size of pages in the file.
+    page_size: usize,
 }
 
 impl File {
-    /// Open a file for read and write. If not exists, create it.
-    pub fn open(name: &Path) -> io::Result<Self> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(name)?;
+    /// Open a file of pages in some size for read and write. If not exists, create it.
+    pub fn open(name: &Path, page_size: usize) -> io::Result<Self> {
+        let file = open_file(name)?;
         let id = Uuid::new_v4();
-        Ok(Self { id, file })
+        let path = name.to_owned();
+        Ok(Self {
+            id,
+            file,
+            path,
+            page_size,
+        })
+    }
+}
+
+impl PageStore for File {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
     }
 
-    /// Read a given page on the file.
-    pub fn read_page(&mut self, page: usize, buf: &mut [u8]) -> io::Result<()> {
-        let offset = page * PAGE_SIZE;
+    fn read_page(&mut self, page: usize, buf: &mut [u8]) -> Result<()> {
+        let offset = page * (self.page_size + CHECKSUM_SIZE);
         self.file.seek(SeekFrom::Start(offset as u64))?;
 
-        let bytes_read = self.file.read(buf)?;
+        let mut disk = vec![0u8; self.page_size + CHECKSUM_SIZE];
+        let bytes_read = read_full(&mut self.file, &mut disk)?;
         log::debug!(
             "Read {} bytes from page {} on file {}",
             bytes_read,
@@ -46,31 +332,325 @@ impl File {
             self.id
         );
 
+        load_page(&disk, buf, &self.path, page)
+    }
+
+    fn read_pages(&mut self, first: usize, bufs: &mut [Vec<u8>]) -> Result<()> {
+        let stride = self.page_size + CHECKSUM_SIZE;
+        self.file.seek(SeekFrom::Start((first * stride) as u64))?;
+
+        let mut disk = vec![0u8; bufs.len() * stride];
+        let bytes_read = read_full(&mut self.file, &mut disk)?;
+        log::debug!(
+            "Read {} bytes from {} pages from page {} on file {}",
+            bytes_read,
+            bufs.len(),
+            first,
+            self.id
+        );
+
+        for ((page, disk), buf) in (first..).zip(disk.chunks(stride)).zip(bufs) {
+            load_page(disk, buf, &self.path, page)?;
+        }
         Ok(())
     }
 
-    /// Write to a given page on the file.
-    pub fn write_page(&mut self, page: usize, buf: &[u8]) -> io::Result<()> {
-        let offset = page * PAGE_SIZE;
+    fn write_page(&mut self, page: usize, buf: &[u8]) -> io::Result<()> {
+        let offset = page * (self.page_size + CHECKSUM_SIZE);
         self.file.seek(SeekFrom::Start(offset as u64))?;
         self.file.write_all(buf)?;
+        self.file.write_all(&crc32(buf).to_le_bytes())?;
         log::debug!("Write to page {} on file {}", page, self.id);
         Ok(())
     }
+
+    fn truncate(&mut self, pages: usize) -> io::Result<()> {
+        log::debug!("Truncating file {} to {} pages", self.id, pages);
+        let len = pages * (self.page_size + CHECKSUM_SIZE);
+        self.file.set_len(len as u64)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// File wrapper providing a uuid for hashing, accessed through a memory map.
+///
+/// The whole file is mapped, and the mapping is replaced whenever the file
+/// grows or shrinks, so that pages are read and written without syscalls.
+pub struct MmapFile {
+    id: Uuid,
+    file: fs::File,
+    /// Mapping of the whole file, none if the file is empty.
+    map: Option<MmapMut>,
+    /// Path to the file, for error messages.
+    path: PathBuf,
+    /// Size of pages in the file.
+    page_size: usize,
+}
+
+impl MmapFile {
+    /// Open a file of pages in some size for read and write. If not exists, create it.
+    pub fn open(name: &Path, page_size: usize) -> io::Result<Self> {
+        let mut file = Self {
+            id: Uuid::new_v4(),
+            file: open_file(name)?,
+            map: None,
+            path: name.to_owned(),
+            page_size,
+        };
+        file.remap()?;
+        Ok(file)
+    }
+
+    /// Map the file again after its length changed.
+    fn remap(&mut self) -> io::Result<()> {
+        self.map = None;
+        if self.file.metadata()?.len() > 0 {
+            // SAFETY: the file is only accessed through this mapping while
+            // it is open, since each file is opened once by the page cache.
+            self.map = Some(unsafe { MmapMut::map_mut(&self.file)? });
+        }
+        Ok(())
+    }
+
+    /// Get the length of the mapped file.
+    fn len(&self) -> usize {
+        self.map.as_ref().map_or(0, |map| map.len())
+    }
+}
+
+impl PageStore for MmapFile {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn read_page(&mut self, page: usize, buf: &mut [u8]) -> Result<()> {
+        let stride = self.page_size + CHECKSUM_SIZE;
+        let offset = page * stride;
+        log::debug!("Read page {} on mapped file {}", page, self.id);
+
+        match &self.map {
+            Some(map) if offset + stride <= map.len() => {
+                load_page(&map[offset..offset + stride], buf, &self.path, page)
+            }
+            // Pages partly beyond the end, e.g. torn writes, are padded with zeros
+            Some(map) if offset < map.len() => {
+                let mut disk = vec![0u8; stride];
+                disk[..map.len() - offset].copy_from_slice(&map[offset..]);
+                load_page(&disk, buf, &self.path, page)
+            }
+            _ => {
+                buf.fill(0);
+                Ok(())
+            }
+        }
+    }
+
+    fn write_page(&mut self, page: usize, buf: &[u8]) -> io::Result<()> {
+        let stride = self.page_size + CHECKSUM_SIZE;
+        let offset = page * stride;
+        if offset + stride > self.len() {
+            self.file.set_len((offset + stride) as u64)?;
+            self.remap()?;
+        }
+
+        let map = self.map.as_mut().expect("File mapped after growing");
+        map[offset..offset + self.page_size].copy_from_slice(buf);
+        map[offset + self.page_size..offset + stride].copy_from_slice(&crc32(buf).to_le_bytes());
+        log::debug!("Write to page {} on mapped file {}", page, self.id);
+        Ok(())
+    }
+
+    fn truncate(&mut self, pages: usize) -> io::Result<()> {
+        log::debug!("Truncating mapped file {} to {} pages", self.id, pages);
+        let len = pages * (self.page_size + CHECKSUM_SIZE);
+        // Unmap first, since the mapping must not outlive the file contents
+        self.map = None;
+        self.file.set_len(len as u64)?;
+        self.remap()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        match &self.map {
+            Some(map) => map.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// File wrapper providing a uuid for hashing, storing each page compressed.
+///
+/// Each page takes a slot of fixed size, starting with the checksum of its
+/// contents and the length of the compressed contents, followed by the
+/// compressed contents. Pages not shrinking on compression are stored as is.
+/// The unused tail of each slot is punched out of the file.
+pub struct CompressedFile {
+    id: Uuid,
+    file: fs::File,
+    /// Path to the file, for error messages.
+    path: PathBuf,
+    /// Size of pages in the file.
+    page_size: usize,
+}
+
+impl CompressedFile {
+    /// Size of the header of a slot.
+    const HEADER_SIZE: usize = CHECKSUM_SIZE + COMPRESSED_LENGTH_SIZE;
+
+    /// Open a file of pages in some size for read and write. If not exists, create it.
+    pub fn open(name: &Path, page_size: usize) -> io::Result<Self> {
+        Ok(Self {
+            id: Uuid::new_v4(),
+            file: open_file(name)?,
+            path: name.to_owned(),
+            page_size,
+        })
+    }
+
+    /// Get the size of the slot of a page on disk.
+    fn stride(&self) -> usize {
+        Self::HEADER_SIZE + self.page_size
+    }
+}
+
+impl PageStore for CompressedFile {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn read_page(&mut self, page: usize, buf: &mut [u8]) -> Result<()> {
+        let offset = page * self.stride();
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+
+        let mut disk = vec![0u8; self.stride()];
+        read_full(&mut self.file, &mut disk)?;
+        log::debug!("Read page {} on compressed file {}", page, self.id);
+
+        let (header, contents) = disk.split_at(Self::HEADER_SIZE);
+        let (checksum, len) = header.split_at(CHECKSUM_SIZE);
+        let checksum = u32::from_le_bytes(checksum.try_into().unwrap());
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+
+        // Compressed contents are never empty, so the page has never been written
+        if len == 0 && checksum == 0 {
+            buf.fill(0);
+            return Ok(());
+        }
+
+        let loaded = match len {
+            len if len == self.page_size => {
+                buf.copy_from_slice(contents);
+                true
+            }
+            len if len < self.page_size => matches!(
+                lz4_flex::decompress_into(&contents[..len], buf),
+                Ok(size) if size == self.page_size
+            ),
+            _ => false,
+        };
+        if !loaded || checksum != crc32(buf) {
+            return Err(corrupted(&self.path, page));
+        }
+        Ok(())
+    }
+
+    fn write_page(&mut self, page: usize, buf: &[u8]) -> io::Result<()> {
+        let mut contents = lz4_flex::compress(buf);
+        if contents.len() >= self.page_size {
+            contents = buf.to_vec();
+        }
+
+        let offset = page * self.stride();
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        let len = contents.len() as u32;
+        self.file.write_all(&crc32(buf).to_le_bytes())?;
+        self.file.write_all(&len.to_le_bytes())?;
+        self.file.write_all(&contents)?;
+        log::debug!(
+            "Write {} bytes to page {} on compressed file {}",
+            contents.len(),
+            page,
+            self.id
+        );
+
+        // Release the tail of the slot, which may still hold a longer page
+        let used = offset + Self::HEADER_SIZE + contents.len();
+        let end = offset + self.stride();
+        if self.file.metadata()?.len() < end as u64 {
+            self.file.set_len(end as u64)
+        } else {
+            punch_hole(&mut self.file, used, end - used)
+        }
+    }
+
+    fn truncate(&mut self, pages: usize) -> io::Result<()> {
+        log::debug!("Truncating compressed file {} to {} pages", self.id, pages);
+        let len = pages * self.stride();
+        self.file.set_len(len as u64)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
 }
 
 /// A page in the cache.
 pub struct Page {
     dirty: bool,
-    buf: [u8; PAGE_SIZE],
+    /// Shared with the guards pinning the page, and copied on write while shared.
+    buf: Arc<Vec<u8>>,
+    /// Shared with the guards pinning the page.
+    pins: Arc<()>,
+    /// Clock of the page cache when the page was last accessed.
+    used: u64,
+    /// Clock of the page cache when the page was referenced before the last time,
+    /// or 0 if referenced only once.
+    prev_used: u64,
+    /// Whether the page was read ahead by a scan, and not accessed since.
+    ahead: bool,
 }
 
 impl Page {
     /// Load contents from a file.
-    fn new(file: &mut File, page: usize) -> io::Result<Self> {
-        let mut buf = [0u8; PAGE_SIZE];
+    fn new(file: &mut dyn PageStore, page: usize) -> Result<Self> {
+        let mut buf = vec![0u8; file.page_size()];
         file.read_page(page, &mut buf)?;
-        Ok(Self { dirty: false, buf })
+        Ok(Self::from_buf(buf))
+    }
+
+    /// Wrap contents read from a file.
+    fn from_buf(buf: Vec<u8>) -> Self {
+        Self {
+            dirty: false,
+            buf: Arc::new(buf),
+            pins: Arc::default(),
+            used: 0,
+            prev_used: 0,
+            ahead: false,
+        }
+    }
+
+    /// Check whether any guard pins the page.
+    fn is_pinned(&self) -> bool {
+        Arc::strong_count(&self.pins) > 1
     }
 
     /// Borrow the buffer for read.
@@ -79,29 +659,131 @@ impl Page {
     }
 
     /// Borrow the buffer for write.
+    ///
+    /// The buffer is copied first if some guard still reads it.
     fn as_buf_mut(&mut self) -> &mut [u8] {
         self.dirty = true;
-        &mut self.buf
+        Arc::make_mut(&mut self.buf).as_mut_slice()
     }
 
-    /// Write back the page into disk.
-    fn write_back(&mut self, file: &mut File, page: usize) -> io::Result<()> {
-        log::debug!("Writing back page {} into file {}", page, file.id);
-        if self.dirty {
-            log::debug!("Page dirty, executing write");
-            file.write_page(page, &self.buf)?;
-            self.dirty = false;
+    /// Write back the page into disk, returning whether it was dirty.
+    fn write_back(
+        &mut self,
+        file: &mut dyn PageStore,
+        page: usize,
+        double_write: bool,
+    ) -> io::Result<bool> {
+        log::debug!("Writing back page {} into file {}", page, file.id());
+        if !self.dirty {
+            return Ok(false);
         }
-        Ok(())
+        log::debug!("Page dirty, executing write");
+        write_pages(file, &[(page, self.as_buf())], double_write)?;
+        self.dirty = false;
+        Ok(true)
     }
 }
 
+/// Write back dirty pages of a file in a batch, in the order of page numbers.
+///
+/// Returns the count of dirty pages written.
+fn write_back_all(
+    file: &mut dyn PageStore,
+    mut pages: Vec<(usize, &mut Page)>,
+    double_write: bool,
+) -> io::Result<usize> {
+    pages.retain(|(_, page_buf)| page_buf.dirty);
+    if pages.is_empty() {
+        return Ok(0);
+    }
+    pages.sort_by_key(|&(page, _)| page);
+    log::debug!("Writing back {} pages into file {}", pages.len(), file.id());
+
+    let bufs: Vec<_> = pages
+        .iter()
+        .map(|(page, buf)| (*page, buf.as_buf()))
+        .collect();
+    write_pages(file, &bufs, double_write)?;
+    let written = pages.len();
+    for (_, page_buf) in pages {
+        page_buf.dirty = false;
+    }
+    Ok(written)
+}
+
+/// Create shards of the page cache, holding a given count of pages in total.
+fn new_shards(size: usize) -> Vec<Mutex<LruCache<(Uuid, usize), Page>>> {
+    let count = CACHE_SHARDS.min(size).max(1);
+    let cap = NonZeroUsize::new(size.div_ceil(count)).unwrap_or(NonZeroUsize::MIN);
+    (0..count).map(|_| Mutex::new(LruCache::new(cap))).collect()
+}
+
+/// Get the shard holding a page, out of a given count of shards.
+fn shard_of((file, page): (Uuid, usize), shards: usize) -> usize {
+    (file.as_u128() as usize ^ page) % shards
+}
+
+/// Guard of a pinned page, which is not evicted until all its guards are dropped.
+///
+/// The page can be read through the guard while other pages are accessed,
+/// with its contents as of when it was pinned, since writes copy the page.
+pub struct PagePin {
+    buf: Arc<Vec<u8>>,
+    _pins: Arc<()>,
+}
+
+impl Deref for PagePin {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+/// Statistics of the page cache on a file.
+#[derive(Clone, Debug, Default)]
+pub struct CacheStats {
+    /// Accesses finding the page cached.
+    pub hits: u64,
+    /// Accesses loading the page from disk.
+    pub misses: u64,
+    /// Pages evicted to make room for others.
+    pub evictions: u64,
+    /// Dirty pages written back to disk.
+    pub dirty_writes: u64,
+    /// Bytes of pages loaded from disk, including pages read ahead.
+    pub bytes_read: u64,
+}
+
 /// Page cache.
 /// The index is file descriptor and page number.
+///
+/// Pages are spread over shards by their keys, each shard with its own lock
+/// and LRU list. A shard evicts the page with the oldest second last reference,
+/// pages referenced once first, and skips pinned pages.
 pub struct PageCache {
-    files: HashMap<Uuid, File>,
-    /// Paged cache.
-    cache: LruCache<(Uuid, usize), Page>,
+    files: HashMap<Uuid, Box<dyn PageStore>>,
+    /// Shards of cached pages.
+    shards: Vec<Mutex<LruCache<(Uuid, usize), Page>>>,
+    /// Count of page accesses, ordering pages across shards by recency.
+    clock: u64,
+    /// Statistics of open files.
+    stats: HashMap<Uuid, CacheStats>,
+    /// Pages loaded by large sequential scans, oldest first,
+    /// with the clock when each was loaded.
+    ring: VecDeque<((Uuid, usize), u64)>,
+    /// How files opened afterwards are accessed.
+    backend: IoBackend,
+    /// Whether to write pages through double-write journals.
+    double_write: bool,
+    /// When written pages are synced to disk.
+    durability: Durability,
+    /// Files written since they were last synced.
+    unsynced: HashSet<Uuid>,
+    /// When the cache was last flushed.
+    last_sync: Instant,
+    /// Size of pages in files opened afterwards.
+    page_size: usize,
 }
 
 impl PageCache {
@@ -109,100 +791,561 @@ impl PageCache {
     pub fn new() -> Self {
         Self {
             files: HashMap::new(),
-            cache: LruCache::new(NonZeroUsize::new(CACHE_SIZE).unwrap()),
+            shards: new_shards(CACHE_SIZE),
+            clock: 0,
+            stats: HashMap::new(),
+            ring: VecDeque::new(),
+            backend: IoBackend::default(),
+            double_write: false,
+            durability: Durability::default(),
+            unsynced: HashSet::new(),
+            last_sync: Instant::now(),
+            page_size: DEFAULT_PAGE_SIZE,
         }
     }
 
+    /// Change the number of pages held in the cache, writing back all cached pages.
+    pub fn set_capacity(&mut self, size: usize) -> io::Result<()> {
+        self.clear()?;
+        self.shards = new_shards(size);
+        Ok(())
+    }
+
+    /// Change the page size of files opened afterwards, writing back all cached pages.
+    pub fn set_page_size(&mut self, size: usize) -> io::Result<()> {
+        if size != self.page_size {
+            log::info!("Setting page size to {size}");
+            self.clear()?;
+            self.page_size = size;
+        }
+        Ok(())
+    }
+
+    /// Get the page size of files opened afterwards.
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Change how files are accessed, closing all files.
+    pub fn set_backend(&mut self, backend: IoBackend) -> io::Result<()> {
+        log::info!("Using {backend:?} file access");
+        self.clear()?;
+        self.backend = backend;
+        Ok(())
+    }
+
+    /// Set whether to write pages through double-write journals.
+    pub fn set_double_write(&mut self, double_write: bool) {
+        log::info!("Double write: {double_write}");
+        self.double_write = double_write;
+    }
+
+    /// Set when written pages are synced to disk.
+    pub fn set_durability(&mut self, durability: Durability) {
+        log::info!("Durability: {durability:?}");
+        self.durability = durability;
+    }
+
     /// Open a file, and return the file descriptor.
     pub fn open(&mut self, name: &Path) -> io::Result<Uuid> {
-        let file = File::open(name)?;
-        let id = file.id;
-        log::info!("Opening file: {name:?} is {id}");
-        self.files.insert(file.id, file);
+        let file: Box<dyn PageStore> = match self.backend {
+            IoBackend::Buffered => Box::new(File::open(name, self.page_size)?),
+            IoBackend::Mmap => Box::new(MmapFile::open(name, self.page_size)?),
+        };
+        self.insert(file)
+    }
+
+    /// Open a file storing compressed pages, and return the file descriptor.
+    pub fn open_compressed(&mut self, name: &Path) -> io::Result<Uuid> {
+        let file = CompressedFile::open(name, self.page_size)?;
+        self.insert(Box::new(file))
+    }
+
+    /// Add an opened file, restoring pages torn by a crash from its
+    /// double-write journal, and return the file descriptor.
+    fn insert(&mut self, mut file: Box<dyn PageStore>) -> io::Result<Uuid> {
+        let id = file.id();
+        log::info!("Opening file: {:?} is {id}", file.path());
+        recover(file.as_mut())?;
+        self.files.insert(id, file);
+        Ok(id)
+    }
+
+    /// Rewrite the first pages of a file into a new file, compressed or not,
+    /// which replaces the file and is opened instead.
+    ///
+    /// Return the file descriptor of the new file.
+    pub fn rewrite(&mut self, file: Uuid, pages: usize, compressed: bool) -> Result<Uuid> {
+        let mut old = self.detach(file)?;
+        let path = old.path().to_owned();
+        let temp = path.with_extension("tmp");
+        log::info!("Rewriting {pages} pages of file {path:?}, compressed: {compressed}");
+
+        let page_size = old.page_size();
+        let mut new: Box<dyn PageStore> = if compressed {
+            Box::new(CompressedFile::open(&temp, page_size)?)
+        } else {
+            Box::new(File::open(&temp, page_size)?)
+        };
+        new.truncate(0)?;
+
+        let mut buf = vec![0u8; old.page_size()];
+        for page in 0..pages {
+            old.read_page(page, &mut buf)?;
+            new.write_page(page, &buf)?;
+        }
+        drop(old);
+        drop(new);
+        fs::rename(&temp, &path)?;
+
+        let id = if compressed {
+            self.open_compressed(&path)?
+        } else {
+            self.open(&path)?
+        };
         Ok(id)
     }
 
     /// Close a file, while writing back dirty pages in the cache.
     pub fn close(&mut self, file: Uuid) -> io::Result<()> {
+        self.detach(file)?;
+        Ok(())
+    }
+
+    /// Write back dirty pages of a file, keeping them cached.
+    pub fn write_back(&mut self, file: Uuid) -> io::Result<()> {
+        let pages: Vec<_> = self
+            .shards
+            .iter_mut()
+            .flat_map(|shard| shard.get_mut().iter_mut())
+            .filter(|(&(fd, _), _)| fd == file)
+            .map(|(&(_, page), page_buf)| (page, page_buf))
+            .collect();
+        let store = self
+            .files
+            .get_mut(&file)
+            .expect("File descriptor not found");
+        let count = write_back_all(store.as_mut(), pages, self.double_write)?;
+        if count > 0 {
+            self.stats_mut(file).dirty_writes += count as u64;
+            self.after_write(file)?;
+        }
+        Ok(())
+    }
+
+    /// Check whether some cached page of a file is dirty.
+    pub fn is_dirty(&mut self, file: Uuid) -> bool {
+        self.shards
+            .iter_mut()
+            .flat_map(|shard| shard.get_mut().iter())
+            .any(|(&(fd, _), page_buf)| fd == file && page_buf.dirty)
+    }
+
+    /// Close a file, dropping its cached pages without writing them back,
+    /// e.g. when the file is modified by others.
+    pub fn discard(&mut self, file: Uuid) {
+        self.files.remove(&file).expect("File descriptor not found");
+        self.unsynced.remove(&file);
+        self.stats.remove(&file);
+        let to_remove: Vec<_> = self
+            .shards
+            .iter_mut()
+            .flat_map(|shard| shard.get_mut().iter())
+            .map(|(&key, _)| key)
+            .filter(|&(fd, _)| fd == file)
+            .collect();
+        to_remove.into_iter().for_each(|key| {
+            self.shard_mut(key).pop(&key);
+        });
+    }
+
+    /// Write back and drop cached pages of a file, and take the file out.
+    fn detach(&mut self, file: Uuid) -> io::Result<Box<dyn PageStore>> {
         let mut file = self.files.remove(&file).expect("File descriptor not found");
 
-        let mut to_remove = Vec::new();
+        let id = file.id();
+        let pages: Vec<_> = self
+            .shards
+            .iter_mut()
+            .flat_map(|shard| shard.get_mut().iter_mut())
+            .filter(|(&(fd, _), _)| fd == id)
+            .map(|(&(_, page), page_buf)| (page, page_buf))
+            .collect();
+        let to_remove: Vec<_> = pages.iter().map(|&(page, _)| (id, page)).collect();
+        let written = write_back_all(file.as_mut(), pages, self.double_write)? > 0;
+        let unsynced = self.unsynced.remove(&id);
+        self.stats.remove(&id);
+        if self.durability != Durability::Off && (written || unsynced) {
+            file.sync()?;
+        }
 
-        self.cache.iter_mut().for_each(|(&(fd, page), page_buf)| {
-            if fd == file.id {
-                to_remove.push((fd, page));
-                page_buf.write_back(&mut file, page).unwrap();
-            }
+        to_remove.into_iter().for_each(|key| {
+            self.shard_mut(key).pop(&key);
         });
 
-        to_remove.iter().for_each(|key| {
-            self.cache.pop(key);
+        Ok(file)
+    }
+
+    /// Cut a file down to a given count of pages, dropping cached pages beyond.
+    pub fn truncate(&mut self, file: Uuid, pages: usize) -> io::Result<()> {
+        let to_remove: Vec<_> = self
+            .shards
+            .iter_mut()
+            .flat_map(|shard| shard.get_mut().iter())
+            .map(|(&key, _)| key)
+            .filter(|&(fd, page)| fd == file && page >= pages)
+            .collect();
+        to_remove.into_iter().for_each(|key| {
+            self.shard_mut(key).pop(&key);
         });
 
-        Ok(())
+        let file = self
+            .files
+            .get_mut(&file)
+            .expect("File descriptor not found");
+        file.truncate(pages)
     }
 
     /// Close all files and clear the cache.
     pub fn clear(&mut self) -> io::Result<()> {
         log::info!("Writing back page cache");
-        for ((file, page), page_buf) in self.cache.iter_mut() {
-            let file = self.files.get_mut(file).expect("File descriptor not found");
-            page_buf.write_back(file, *page)?;
+        self.write_back_cache()?;
+        if self.durability != Durability::Off {
+            self.sync_written()?;
         }
+        self.unsynced.clear();
         self.files.clear();
-        self.cache.clear();
+        self.stats.clear();
+        for shard in &mut self.shards {
+            shard.get_mut().clear();
+        }
+        self.ring.clear();
         Ok(())
     }
 
+    /// Make pages written by a finished statement durable, as the durability mode requires.
+    pub fn commit(&mut self) -> io::Result<()> {
+        match self.durability {
+            Durability::Off | Durability::Always => Ok(()),
+            Durability::Periodic if self.last_sync.elapsed() < SYNC_INTERVAL => Ok(()),
+            Durability::Commit | Durability::Periodic => {
+                self.write_back_cache()?;
+                self.sync_written()
+            }
+        }
+    }
+
+    /// Write back all dirty pages in the cache, keeping them cached.
+    fn write_back_cache(&mut self) -> io::Result<()> {
+        let mut pages: HashMap<Uuid, Vec<_>> = HashMap::new();
+        let cached = self
+            .shards
+            .iter_mut()
+            .flat_map(|shard| shard.get_mut().iter_mut());
+        for (&(file, page), page_buf) in cached {
+            pages.entry(file).or_default().push((page, page_buf));
+        }
+        let mut written = Vec::new();
+        for (fd, pages) in pages {
+            let file = self.files.get_mut(&fd).expect("File descriptor not found");
+            match write_back_all(file.as_mut(), pages, self.double_write)? {
+                0 => (),
+                count => written.push((fd, count)),
+            }
+        }
+        for (fd, count) in written {
+            self.stats_mut(fd).dirty_writes += count as u64;
+            self.after_write(fd)?;
+        }
+        Ok(())
+    }
+
+    /// Sync a file just written into, or remember to sync it later.
+    fn after_write(&mut self, file: Uuid) -> io::Result<()> {
+        if self.durability == Durability::Always {
+            let file = self
+                .files
+                .get_mut(&file)
+                .expect("File descriptor not found");
+            file.sync()
+        } else {
+            self.unsynced.insert(file);
+            Ok(())
+        }
+    }
+
+    /// Sync all files written since they were last synced.
+    fn sync_written(&mut self) -> io::Result<()> {
+        log::debug!("Syncing {} written files", self.unsynced.len());
+        for fd in self.unsynced.drain() {
+            if let Some(file) = self.files.get_mut(&fd) {
+                file.sync()?;
+            }
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Get the cached pages of files in a directory, most recently used first.
+    ///
+    /// Paths of the files are relative to the directory.
+    pub fn hot_pages(&self, dir: &Path) -> Vec<(PathBuf, usize)> {
+        let mut pages: Vec<_> = self
+            .shards
+            .iter()
+            .flat_map(|shard| {
+                let shard = shard.lock();
+                let pages: Vec<_> = shard.iter().map(|(&key, page)| (page.used, key)).collect();
+                pages
+            })
+            .collect();
+        pages.sort_unstable_by_key(|&(used, _)| Reverse(used));
+
+        pages
+            .into_iter()
+            .filter_map(|(_, (fd, page))| {
+                let path = self.files.get(&fd)?.path().strip_prefix(dir).ok()?;
+                Some((path.to_owned(), page))
+            })
+            .collect()
+    }
+
+    /// Get the count of pages the cache holds.
+    fn capacity(&self) -> usize {
+        let caps = self.shards.iter().map(|shard| shard.lock().cap().get());
+        caps.sum()
+    }
+
+    /// Get the shard holding a page.
+    fn shard_mut(&mut self, key: (Uuid, usize)) -> &mut LruCache<(Uuid, usize), Page> {
+        let shard = shard_of(key, self.shards.len());
+        self.shards[shard].get_mut()
+    }
+
+    /// Load pages returned by `hot_pages` into the cache, keeping their order.
+    /// Pages of files not opened are skipped.
+    ///
+    /// Return the number of pages loaded.
+    pub fn prefetch(&mut self, dir: &Path, pages: &[(PathBuf, usize)]) -> Result<usize> {
+        let files: HashMap<PathBuf, Uuid> = self
+            .files
+            .iter()
+            .filter_map(|(&fd, file)| Some((file.path().strip_prefix(dir).ok()?.to_owned(), fd)))
+            .collect();
+
+        let mut loaded = 0;
+        // Least recently used first, so that it is evicted first again
+        for (path, page) in pages.iter().take(self.capacity()).rev() {
+            if let Some(&fd) = files.get(path) {
+                self.cache_probe(fd, *page)?;
+                loaded += 1;
+            }
+        }
+        Ok(loaded)
+    }
+
     /// Probe the cache for a given page on a file.
     /// Reload if cache miss.
-    fn cache_probe(&mut self, file: Uuid, page: usize) -> io::Result<()> {
-        let file = self
+    fn cache_probe(&mut self, file: Uuid, page: usize) -> Result<()> {
+        trace::set_page(page);
+        self.clock += 1;
+        let clock = self.clock;
+
+        let key = (file, page);
+        if let Some(page_buf) = self.shard_mut(key).get_mut(&key) {
+            log::debug!("Cache hit, file {}, page {}", file, page);
+            // Accesses to the same page in a row count as one reference,
+            // and so does the first access to a page read ahead
+            if page_buf.ahead {
+                page_buf.ahead = false;
+            } else if page_buf.used + 1 != clock {
+                page_buf.prev_used = page_buf.used;
+            }
+            page_buf.used = clock;
+            self.stats_mut(file).hits += 1;
+            return Ok(());
+        }
+        log::debug!("Cache miss, file {}, page {}", file, page);
+
+        // Reload the page from disk
+        let store = self
             .files
             .get_mut(&file)
             .expect("File descriptor not found");
+        let mut page_buf = Page::new(store.as_mut(), page)?;
+        page_buf.used = clock;
+        let stats = self.stats_mut(file);
+        stats.misses += 1;
+        stats.bytes_read += page_buf.buf.len() as u64;
+        self.install(key, page_buf)?;
+
+        Ok(())
+    }
 
-        let key = (file.id, page);
-
-        // Cache miss
-        if !self.cache.contains(&key) {
-            log::debug!("Cache miss, file {}, page {}", file.id, page);
-
-            // Reload the page from disk
-            let page_buf = Page::new(file, page)?;
-
-            // Insert the page into cache
-            if let Some(((old_file, old_page), mut old_page_buf)) = self.cache.push(key, page_buf) {
-                // LRUCache.push returns the hit entry or the evicted entry, so we need to check here
-                if (old_file, old_page) != (file.id, page) {
-                    log::debug!("Evicting page {} on file {}", old_page, old_file);
-                    // Evict the least recently used page
-                    let file = self
-                        .files
-                        .get_mut(&old_file)
-                        .expect("File descriptor not found");
-                    old_page_buf.write_back(file, old_page)?;
-                }
+    /// Put a page loaded from disk into the cache, evicting another if the shard is full.
+    fn install(&mut self, key: (Uuid, usize), page_buf: Page) -> io::Result<()> {
+        let shard = self.shard_mut(key);
+        if shard.len() == shard.cap().get() {
+            // Evict the page not pinned with the oldest second last reference
+            let victim = shard
+                .iter()
+                .filter(|(_, page_buf)| !page_buf.is_pinned())
+                .min_by_key(|(_, page_buf)| (page_buf.prev_used, page_buf.used))
+                .map(|(&key, _)| key);
+            if let Some(victim) = victim {
+                self.evict(victim)?;
+            } else {
+                log::warn!("All pages in a cache shard are pinned, growing the shard");
+                let cap = shard.cap().saturating_add(1);
+                shard.resize(cap);
             }
-        } else {
-            log::debug!("Cache hit, file {}, page {}", file.id, page);
         }
+        self.shard_mut(key).put(key, page_buf);
+        Ok(())
+    }
+
+    /// Read pages missed by a scan over a given count of pages in a batch,
+    /// from a given page up to the first page cached, at most `READ_AHEAD_PAGES`.
+    ///
+    /// Pages read by a scan using the ring are loaded into the ring.
+    fn read_ahead(&mut self, file: Uuid, page: usize, pages: usize, ring: bool) -> Result<()> {
+        trace::set_page(page);
+        let limit = pages.min(page + READ_AHEAD_PAGES).max(page + 1);
+        let end = (page + 1..limit)
+            .find(|&next| self.shard_mut((file, next)).contains(&(file, next)))
+            .unwrap_or(limit);
+        log::debug!("Reading ahead pages {page}..{end} on file {file}");
+
+        let store = self
+            .files
+            .get_mut(&file)
+            .expect("File descriptor not found");
+        let mut bufs = vec![vec![0u8; store.page_size()]; end - page];
+        if store.read_pages(page, &mut bufs).is_err() {
+            // Errors on pages read ahead are reported when they are scanned
+            bufs.truncate(1);
+            store.read_page(page, &mut bufs[0])?;
+        }
+        let stats = self.stats_mut(file);
+        stats.misses += 1;
+        stats.bytes_read += bufs.iter().map(|buf| buf.len() as u64).sum::<u64>();
 
+        // The page asked for is cached last, so that pages read ahead do not evict it
+        for (i, buf) in bufs.into_iter().enumerate().rev() {
+            self.clock += 1;
+            let key = (file, page + i);
+            let mut page_buf = Page::from_buf(buf);
+            page_buf.used = self.clock;
+            page_buf.ahead = i > 0;
+            self.install(key, page_buf)?;
+            if ring {
+                self.ring.push_back((key, self.clock));
+            }
+        }
+
+        while self.ring.len() > SCAN_RING_SIZE {
+            let (old_key, loaded) = self.ring.pop_front().unwrap();
+            let untouched = self
+                .shard_mut(old_key)
+                .peek(&old_key)
+                .is_some_and(|page_buf| page_buf.used == loaded && !page_buf.is_pinned());
+            if untouched {
+                self.evict(old_key)?;
+            }
+        }
         Ok(())
     }
 
+    /// Drop a page from the cache, writing it back if dirty.
+    fn evict(&mut self, key: (Uuid, usize)) -> io::Result<()> {
+        let (file, page) = key;
+        log::debug!("Evicting page {} on file {}", page, file);
+        let Some(mut page_buf) = self.shard_mut(key).pop(&key) else {
+            return Ok(());
+        };
+        let store = self
+            .files
+            .get_mut(&file)
+            .expect("File descriptor not found");
+        let written = page_buf.write_back(store.as_mut(), page, self.double_write)?;
+        let stats = self.stats_mut(file);
+        stats.evictions += 1;
+        if written {
+            stats.dirty_writes += 1;
+            self.after_write(file)?;
+        }
+        Ok(())
+    }
+
+    /// Get the statistics of a file.
+    fn stats_mut(&mut self, file: Uuid) -> &mut CacheStats {
+        self.stats.entry(file).or_default()
+    }
+
+    /// Get the statistics of files opened in a directory since they were opened,
+    /// ordered by their paths relative to the directory.
+    pub fn stats(&self, dir: &Path) -> Vec<(PathBuf, CacheStats)> {
+        let mut stats: Vec<_> = self
+            .files
+            .iter()
+            .filter_map(|(fd, file)| {
+                let path = file.path().strip_prefix(dir).ok()?;
+                let stats = self.stats.get(fd).cloned().unwrap_or_default();
+                Some((path.to_owned(), stats))
+            })
+            .collect();
+        stats.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        stats
+    }
+
+    /// Pin a page in the cache, loading it if missed, so that it is not
+    /// evicted until the returned guard is dropped.
+    pub fn pin(&mut self, file: Uuid, page: usize) -> Result<PagePin> {
+        log::debug!("Pinning page {} on file {}", page, file);
+        self.cache_probe(file, page)?;
+        let key = (file, page);
+        let page_buf = self.shard_mut(key).peek(&key).unwrap();
+        Ok(PagePin {
+            buf: page_buf.buf.clone(),
+            _pins: page_buf.pins.clone(),
+        })
+    }
+
     /// Get a given page on a file for read.
-    pub fn get(&mut self, file: Uuid, page: usize) -> io::Result<&[u8]> {
+    pub fn get(&mut self, file: Uuid, page: usize) -> Result<&[u8]> {
         log::debug!("Getting page {} on file {} for read", page, file);
         self.cache_probe(file, page)?;
-        Ok(self.cache.get(&(file, page)).unwrap().as_buf())
+        let key = (file, page);
+        Ok(self.shard_mut(key).peek(&key).unwrap().as_buf())
+    }
+
+    /// Get a given page on a file for read, during a sequential scan over
+    /// a given count of pages.
+    ///
+    /// A missed page is read along with the following pages of the scan in a batch.
+    /// Pages missed by a scan over more than a fraction of the cache are loaded
+    /// into a ring of buffers, and evicted again as the ring is reused unless
+    /// accessed otherwise meanwhile; pages already cached are not marked as used.
+    pub fn scan(&mut self, file: Uuid, page: usize, pages: usize) -> Result<&[u8]> {
+        log::debug!("Scanning page {} on file {}", page, file);
+        let ring = pages > self.capacity() / SCAN_RING_RATIO;
+        let key = (file, page);
+        if !self.shard_mut(key).contains(&key) {
+            self.read_ahead(file, page, pages, ring)?;
+        } else if ring {
+            self.stats_mut(file).hits += 1;
+        } else {
+            self.cache_probe(file, page)?;
+        }
+        Ok(self.shard_mut(key).peek(&key).unwrap().as_buf())
     }
 
     /// Get a given page on a file for write.
-    pub fn get_mut(&mut self, file: Uuid, page: usize) -> io::Result<&mut [u8]> {
+    pub fn get_mut(&mut self, file: Uuid, page: usize) -> Result<&mut [u8]> {
         log::debug!("Getting page {} on file {} for write", page, file);
         self.cache_probe(file, page)?;
-        Ok(self.cache.get_mut(&(file, page)).unwrap().as_buf_mut())
+        let key = (file, page);
+        Ok(self.shard_mut(key).peek_mut(&key).unwrap().as_buf_mut())
     }
 }
 
@@ -224,22 +1367,22 @@ mod tests {
 
         {
             let mut text;
-            let mut file = File::open(Path::new("test_file")).unwrap();
-            let mut buf = [0u8; PAGE_SIZE];
+            let mut file = File::open(Path::new("test_file"), DEFAULT_PAGE_SIZE).unwrap();
+            let mut buf = [0u8; DEFAULT_PAGE_SIZE];
 
             text = "Hello, world!".as_bytes();
-            text.read(&mut buf).unwrap();
+            buf[..text.len()].copy_from_slice(text);
             file.write_page(1, &buf).unwrap();
 
             text = "Goodbye, world!".as_bytes();
-            text.read(&mut buf).unwrap();
+            buf[..text.len()].copy_from_slice(text);
             file.write_page(5, &buf).unwrap();
         }
 
         {
-            let mut text = [0u8; PAGE_SIZE].as_ref();
-            let mut file = File::open(Path::new("test_file")).unwrap();
-            let mut buf = [0u8; PAGE_SIZE];
+            let mut text = [0u8; DEFAULT_PAGE_SIZE].as_ref();
+            let mut file = File::open(Path::new("test_file"), DEFAULT_PAGE_SIZE).unwrap();
+            let mut buf = [0u8; DEFAULT_PAGE_SIZE];
 
             file.read_page(3, &mut buf).unwrap();
             assert_eq!(&buf[..text.len()], text);
@@ -260,7 +1403,7 @@ mod tests {
     fn test_page_cache() {
         setup::init_logging();
 
-        let mut text = [0u8; PAGE_SIZE].as_ref();
+        let mut text = [0u8; DEFAULT_PAGE_SIZE].as_ref();
 
         let mut cache = PageCache::new();
         let fd = cache.open(Path::new("test_page_cache")).unwrap();
@@ -277,13 +1420,13 @@ mod tests {
         {
             buf_mut = cache.get_mut(fd, 5).unwrap();
             text = "Goodbye, world!".as_bytes();
-            text.read(&mut buf_mut).unwrap();
+            buf_mut[..text.len()].copy_from_slice(text);
         }
 
         {
             buf_mut = cache.get_mut(fd, 3).unwrap();
             text = "Hello, world!".as_bytes();
-            text.read(&mut buf_mut).unwrap();
+            buf_mut[..text.len()].copy_from_slice(text);
         }
 
         {
@@ -295,7 +1438,7 @@ mod tests {
         {
             buf_mut = cache.get_mut(fd, 1).unwrap();
             text = "NÓ∑¡".as_bytes();
-            text.read(&mut buf_mut).unwrap();
+            buf_mut[..text.len()].copy_from_slice(text);
         }
 
         // Force write back
@@ -307,7 +1450,7 @@ mod tests {
         {
             buf_mut = cache.get_mut(fd, 666).unwrap();
             text = "So dirty...".as_bytes();
-            text.read(&mut buf_mut).unwrap();
+            buf_mut[..text.len()].copy_from_slice(text);
         }
 
         {
@@ -330,4 +1473,352 @@ mod tests {
 
         fs::remove_file("test_page_cache").unwrap();
     }
+
+    #[test]
+    fn test_checksum() {
+        setup::init_logging();
+
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let path = Path::new("test_checksum");
+        {
+            let mut file = File::open(path, DEFAULT_PAGE_SIZE).unwrap();
+            let mut buf = [0u8; DEFAULT_PAGE_SIZE];
+            buf[..5].copy_from_slice(b"Hello");
+            file.write_page(2, &buf).unwrap();
+
+            // Unwritten pages read as zeros, before and after the end of file
+            file.read_page(0, &mut buf).unwrap();
+            assert!(buf.iter().all(|&byte| byte == 0));
+            file.read_page(9, &mut buf).unwrap();
+            assert!(buf.iter().all(|&byte| byte == 0));
+        }
+
+        // Flip a byte inside page 2
+        let mut contents = fs::read(path).unwrap();
+        contents[2 * (DEFAULT_PAGE_SIZE + CHECKSUM_SIZE) + 1] ^= 0xff;
+        fs::write(path, contents).unwrap();
+
+        let mut cache = PageCache::new();
+        let fd = cache.open(path).unwrap();
+        assert!(matches!(cache.get(fd, 2), Err(Error::PageCorrupted(_, 2))));
+        cache.close(fd).unwrap();
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_mmap() {
+        setup::init_logging();
+
+        let path = Path::new("test_mmap");
+        let stride = DEFAULT_PAGE_SIZE + CHECKSUM_SIZE;
+        let mut buf = [0u8; DEFAULT_PAGE_SIZE];
+        {
+            let mut file = MmapFile::open(path, DEFAULT_PAGE_SIZE).unwrap();
+            file.read_page(0, &mut buf).unwrap();
+            assert!(buf.iter().all(|&byte| byte == 0));
+
+            buf[..5].copy_from_slice(b"Hello");
+            file.write_page(2, &buf).unwrap();
+            buf[..5].copy_from_slice(b"World");
+            file.write_page(0, &buf).unwrap();
+            assert_eq!(fs::metadata(path).unwrap().len(), 3 * stride as u64);
+        }
+
+        // Same format as buffered files
+        {
+            let mut file = File::open(path, DEFAULT_PAGE_SIZE).unwrap();
+            file.read_page(2, &mut buf).unwrap();
+            assert_eq!(&buf[..5], b"Hello");
+            file.read_page(1, &mut buf).unwrap();
+            assert!(buf.iter().all(|&byte| byte == 0));
+            buf[..5].copy_from_slice(b"Again");
+            file.write_page(1, &buf).unwrap();
+        }
+
+        let mut file = MmapFile::open(path, DEFAULT_PAGE_SIZE).unwrap();
+        file.read_page(1, &mut buf).unwrap();
+        assert_eq!(&buf[..5], b"Again");
+        file.read_page(0, &mut buf).unwrap();
+        assert_eq!(&buf[..5], b"World");
+        file.read_page(7, &mut buf).unwrap();
+        assert!(buf.iter().all(|&byte| byte == 0));
+
+        file.truncate(1).unwrap();
+        assert_eq!(fs::metadata(path).unwrap().len(), stride as u64);
+        file.read_page(2, &mut buf).unwrap();
+        assert!(buf.iter().all(|&byte| byte == 0));
+        drop(file);
+
+        // Flip a byte inside page 0
+        let mut contents = fs::read(path).unwrap();
+        contents[1] ^= 0xff;
+        fs::write(path, contents).unwrap();
+
+        let mut cache = PageCache::new();
+        cache.set_backend(IoBackend::Mmap).unwrap();
+        let fd = cache.open(path).unwrap();
+        assert!(matches!(cache.get(fd, 0), Err(Error::PageCorrupted(_, 0))));
+        cache.close(fd).unwrap();
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_compressed_file() {
+        setup::init_logging();
+
+        let path = Path::new("test_compressed_file");
+        let mut buf = [0u8; DEFAULT_PAGE_SIZE];
+        {
+            let mut file = CompressedFile::open(path, DEFAULT_PAGE_SIZE).unwrap();
+            file.read_page(0, &mut buf).unwrap();
+            assert!(buf.iter().all(|&byte| byte == 0));
+
+            for page in 0..16 {
+                buf[..8].copy_from_slice(&(page as u64).to_le_bytes());
+                file.write_page(page, &buf).unwrap();
+            }
+
+            // Not shrinking on compression, stored as is
+            let mut state = 1u32;
+            let mut noise = [0u8; DEFAULT_PAGE_SIZE];
+            for byte in noise.iter_mut() {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                *byte = (state >> 16) as u8;
+            }
+            file.write_page(16, &noise).unwrap();
+        }
+
+        // The unused tails of slots take no disk space
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let metadata = fs::metadata(path).unwrap();
+            assert!(metadata.blocks() * 512 < metadata.len() * 3 / 4);
+        }
+
+        let mut cache = PageCache::new();
+        let fd = cache.open_compressed(path).unwrap();
+        for page in 0..16 {
+            let buf = cache.get(fd, page).unwrap();
+            assert_eq!(buf[..8], (page as u64).to_le_bytes());
+            assert!(buf[8..].iter().all(|&byte| byte == 0));
+        }
+        let noise = cache.get(fd, 16).unwrap().to_vec();
+        assert!(noise.iter().any(|&byte| byte != 0));
+
+        // Rewritten in the plain format and back
+        let fd = cache.rewrite(fd, 17, false).unwrap();
+        assert_eq!(cache.get(fd, 3).unwrap()[..8], 3u64.to_le_bytes());
+        let fd = cache.rewrite(fd, 17, true).unwrap();
+        assert_eq!(cache.get(fd, 16).unwrap(), noise);
+        cache.get_mut(fd, 0).unwrap()[..5].copy_from_slice(b"Hello");
+        cache.close(fd).unwrap();
+
+        // Flip a byte inside the compressed contents of page 0
+        let mut contents = fs::read(path).unwrap();
+        contents[CompressedFile::HEADER_SIZE] ^= 0xff;
+        fs::write(path, contents).unwrap();
+
+        let fd = cache.open_compressed(path).unwrap();
+        assert!(matches!(cache.get(fd, 0), Err(Error::PageCorrupted(_, 0))));
+        assert_eq!(cache.get(fd, 1).unwrap()[..8], 1u64.to_le_bytes());
+        cache.close(fd).unwrap();
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_double_write() {
+        setup::init_logging();
+
+        let path = Path::new("test_double_write");
+        let journal = journal_path(path);
+        let mut cache = PageCache::new();
+        cache.set_double_write(true);
+
+        let fd = cache.open(path).unwrap();
+        for page in 0..4 {
+            cache.get_mut(fd, page).unwrap()[..8].copy_from_slice(&(page as u64).to_le_bytes());
+        }
+        cache.close(fd).unwrap();
+        assert!(!journal.exists());
+
+        // Crash while writing page 1 in place, after its journal is synced
+        let mut record = 1u64.to_le_bytes().to_vec();
+        record.extend_from_slice(b"Hello");
+        record.resize(JOURNAL_PAGE_SIZE + DEFAULT_PAGE_SIZE, 0);
+        let checksum = crc32(&record);
+        record.extend_from_slice(&checksum.to_le_bytes());
+        // An incomplete record, never needed since nothing was written after it
+        record.extend_from_slice(&2u64.to_le_bytes());
+        fs::write(&journal, record).unwrap();
+
+        let mut contents = fs::read(path).unwrap();
+        let offset = DEFAULT_PAGE_SIZE + CHECKSUM_SIZE;
+        contents[offset..offset + 5].copy_from_slice(b"Hel\0\0");
+        contents[offset + DEFAULT_PAGE_SIZE / 2] = 0xff;
+        fs::write(path, contents).unwrap();
+
+        let fd = cache.open(path).unwrap();
+        assert!(!journal.exists());
+        assert_eq!(cache.get(fd, 0).unwrap()[..8], 0u64.to_le_bytes());
+        let buf = cache.get(fd, 1).unwrap();
+        assert_eq!(&buf[..5], b"Hello");
+        assert!(buf[5..].iter().all(|&byte| byte == 0));
+        assert_eq!(cache.get(fd, 2).unwrap()[..8], 2u64.to_le_bytes());
+        cache.close(fd).unwrap();
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_pin() {
+        setup::init_logging();
+
+        let path = Path::new("test_pin");
+        let mut cache = PageCache::new();
+        cache.set_capacity(4).unwrap();
+        let fd = cache.open(path).unwrap();
+        let cached =
+            |cache: &mut PageCache, page| cache.shard_mut((fd, page)).contains(&(fd, page));
+
+        cache.get_mut(fd, 0).unwrap()[..5].copy_from_slice(b"Hello");
+        let pin = cache.pin(fd, 0).unwrap();
+        for page in 1..64 {
+            cache.get(fd, page).unwrap();
+        }
+        assert!(cached(&mut cache, 0));
+        assert!(!cached(&mut cache, 1));
+        assert_eq!(pin[..5], *b"Hello");
+
+        // Writes are not seen through existing guards, and keep the page pinned
+        cache.get_mut(fd, 0).unwrap()[..5].copy_from_slice(b"World");
+        for page in 1..64 {
+            cache.get(fd, page).unwrap();
+        }
+        assert!(cached(&mut cache, 0));
+        assert_eq!(pin[..5], *b"Hello");
+        assert_eq!(cache.pin(fd, 0).unwrap()[..5], *b"World");
+
+        // Evicted once unpinned for pages referenced twice later, and written back
+        drop(pin);
+        for page in 64..128 {
+            cache.get(fd, page).unwrap();
+            cache.get(fd, page + 65).unwrap();
+            cache.get(fd, page).unwrap();
+        }
+        assert!(!cached(&mut cache, 0));
+        assert_eq!(cache.get(fd, 0).unwrap()[..5], *b"World");
+        cache.close(fd).unwrap();
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_scan_resistance() {
+        setup::init_logging();
+
+        let path = Path::new("test_scan_resistance");
+        let mut cache = PageCache::new();
+        cache.set_capacity(64).unwrap();
+        let fd = cache.open(path).unwrap();
+        let cached =
+            |cache: &mut PageCache, page| cache.shard_mut((fd, page)).contains(&(fd, page));
+
+        // Pages referenced twice outlive pages read once
+        for _ in 0..2 {
+            for page in 0..8 {
+                cache.get(fd, page).unwrap();
+            }
+        }
+        for page in 100..400 {
+            cache.get(fd, page).unwrap();
+        }
+        assert!((0..8).all(|page| cached(&mut cache, page)));
+
+        // A large scan takes no more buffers than the ring
+        cache.clear().unwrap();
+        let fd = cache.open(path).unwrap();
+        for page in 0..400 {
+            cache.scan(fd, page, 400).unwrap();
+        }
+        let scanned = (0..400)
+            .filter(|&page| cache.shard_mut((fd, page)).contains(&(fd, page)))
+            .count();
+        assert!(scanned <= SCAN_RING_SIZE);
+        cache.close(fd).unwrap();
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_read_ahead() {
+        setup::init_logging();
+
+        let path = Path::new("test_read_ahead");
+        let mut cache = PageCache::new();
+        let fd = cache.open(path).unwrap();
+        for page in 0..20 {
+            cache.get_mut(fd, page).unwrap()[0] = page as u8;
+        }
+        cache.clear().unwrap();
+
+        let fd = cache.open(path).unwrap();
+        let cached =
+            |cache: &mut PageCache, page| cache.shard_mut((fd, page)).contains(&(fd, page));
+        assert_eq!(cache.scan(fd, 0, 20).unwrap()[0], 0);
+        assert!((0..READ_AHEAD_PAGES).all(|page| cached(&mut cache, page)));
+        assert!(!cached(&mut cache, READ_AHEAD_PAGES));
+        for page in 1..20 {
+            assert_eq!(cache.scan(fd, page, 20).unwrap()[0], page as u8);
+        }
+
+        // Not beyond the end of the scan
+        assert!(!cached(&mut cache, 20));
+        cache.close(fd).unwrap();
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_durability() {
+        setup::init_logging();
+
+        let path = Path::new("test_durability");
+        let mut cache = PageCache::new();
+        let fd = cache.open(path).unwrap();
+        let on_disk = |page: usize| {
+            let contents = fs::read(path).unwrap();
+            let offset = page * (DEFAULT_PAGE_SIZE + CHECKSUM_SIZE);
+            contents.get(offset..offset + 5).map(<[u8]>::to_vec)
+        };
+
+        // Left in the cache until evicted
+        cache.get_mut(fd, 0).unwrap()[..5].copy_from_slice(b"Hello");
+        cache.commit().unwrap();
+        assert_eq!(on_disk(0), None);
+
+        // Not yet due for a flush
+        cache.set_durability(Durability::Periodic);
+        cache.last_sync = Instant::now();
+        cache.commit().unwrap();
+        assert_eq!(on_disk(0), None);
+
+        cache.set_durability(Durability::Commit);
+        cache.commit().unwrap();
+        assert_eq!(on_disk(0).as_deref(), Some(&b"Hello"[..]));
+        assert!(cache.unsynced.is_empty());
+
+        // Still cached after the flush
+        cache.get_mut(fd, 1).unwrap()[..5].copy_from_slice(b"World");
+        assert_eq!(cache.get(fd, 0).unwrap()[..5], *b"Hello");
+        cache.commit().unwrap();
+        assert_eq!(on_disk(1).as_deref(), Some(&b"World"[..]));
+        cache.close(fd).unwrap();
+
+        fs::remove_file(path).unwrap();
+    }
 }
@@ -23,21 +23,30 @@
 //! |------------|------|------|
 //! | *key_size* |   4B |   4B |
 //!
+//! # Key Ordering
+//!
+//! Keys are compared column by column, with NULLs ordered before all other values.
+//! Since a NULL never equals anything in comparisons, records found by NULL keys
+//! are filtered out by where clauses, except for `IS NULL` conditions, which
+//! look up NULLs as ordinary keys at the front of the index.
+//!
 //! # Reference
 //!
 //! Implementation adapted from [OI Wiki](https://oi-wiki.org/ds/bplus-tree/).
 
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
 use std::fmt::{self, Display, Formatter};
-use std::fs::File;
+use std::fs;
 use std::ops::Range;
 use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::config::{LINK_SIZE, PAGE_SIZE};
-use crate::error::Result;
-use crate::file::PageCache;
+use crate::config::LINK_SIZE;
+use crate::error::{Error, Result};
+use crate::file::{PageCache, PagePin};
 use crate::record::{Record, RecordSchema};
 use crate::schema::{Column, ColumnSelector, Selector, Selectors, TableSchema, Type};
 
@@ -93,6 +102,9 @@ pub struct IndexSchema {
     pub columns: Vec<String>,
     /// Root page id.
     pub root: Option<usize>,
+    /// Whether keys in this index must be distinct.
+    #[serde(default)]
+    pub unique: bool,
 }
 
 impl IndexSchema {
@@ -115,12 +127,16 @@ impl IndexSchema {
             name,
             columns: columns.iter().map(|col| col.to_string()).collect(),
             root: None,
+            unique: false,
         }
     }
 }
 
 impl Display for IndexSchema {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if self.unique {
+            write!(f, "UNIQUE ")?;
+        }
         write!(f, "INDEX {}({});", self.name, self.columns.join(", "))
     }
 }
@@ -158,6 +174,10 @@ pub struct Index {
     internal_schema: IndexRecordSchema,
     /// Record schema for leaf nodes.
     leaf_schema: IndexRecordSchema,
+    /// The schema as last loaded or saved, telling whether it is changed since.
+    saved: String,
+    /// Whether the index is dropped without saving its schema.
+    discarded: bool,
 }
 
 impl Index {
@@ -178,6 +198,7 @@ impl Index {
         let index_size = columns.iter().map(|col| col.typ.size()).sum::<usize>() + null_bitmap_size;
         let internal_schema = IndexRecordSchema::from(&columns, false);
         let leaf_schema = IndexRecordSchema::from(&columns, true);
+        let saved = serde_json::to_string(&schema).unwrap_or_default();
         Self {
             fd,
             path: path.to_owned(),
@@ -186,14 +207,26 @@ impl Index {
             columns,
             internal_schema,
             leaf_schema,
+            saved,
+            discarded: false,
         }
     }
 
-    /// Save changes into the schema file.
-    fn save(&self) -> Result<()> {
+    /// Drop the index without saving its schema, e.g. when its table is
+    /// modified by others.
+    pub fn discard(&mut self) {
+        self.discarded = true;
+    }
+
+    /// Save changes into the schema file, if any.
+    pub fn save(&mut self) -> Result<()> {
+        let saved = serde_json::to_string(&self.schema)?;
+        if saved == self.saved {
+            return Ok(());
+        }
         log::debug!("Saving schema to {}", self.path.display());
-        let file = File::create(&self.path)?;
-        serde_json::to_writer(file, &self.schema)?;
+        fs::write(&self.path, &saved)?;
+        self.saved = saved;
         Ok(())
     }
 
@@ -207,6 +240,12 @@ impl Index {
         &self.schema
     }
 
+    /// Get the size of an entry in leaf pages.
+    pub fn get_entry_size(&self) -> usize {
+        // 2 for ids of page and slot
+        self.index_size + 2 * LINK_SIZE
+    }
+
     /// Get the columns in this index.
     pub fn get_columns(&self) -> &[Column] {
         &self.columns
@@ -247,6 +286,39 @@ impl Index {
         Ok(())
     }
 
+    /// Return trailing free pages to the file system.
+    /// Return the number of pages released.
+    pub fn compact(&mut self, fs: &mut PageCache) -> Result<usize> {
+        let mut free = BTreeSet::new();
+        let mut page_id = self.schema.free;
+        while let Some(id) = page_id {
+            free.insert(id);
+            page_id = from_nullable_int(&fs.get(self.fd, id)?[..LINK_SIZE]);
+        }
+
+        let pages = self.schema.pages;
+        while self.schema.pages > 0 && free.remove(&(self.schema.pages - 1)) {
+            self.schema.pages -= 1;
+        }
+        let released = pages - self.schema.pages;
+        if released == 0 {
+            return Ok(0);
+        }
+        log::info!(
+            "Releasing {released} free pages of index {}",
+            self.schema.name
+        );
+
+        // Relink the remaining free pages, lowest first
+        self.schema.free = None;
+        for &page_id in free.iter().rev() {
+            self.free_page(fs, page_id)?;
+        }
+        fs.truncate(self.fd, self.schema.pages)?;
+
+        Ok(released)
+    }
+
     /// Lookup the index of a children in its parent.
     fn lookup(
         &self,
@@ -311,10 +383,8 @@ impl Index {
         ret
     }
 
-    /// Get the leaf node using a key.
-    pub fn index(&self, fs: &mut PageCache, key: &Record) -> Result<Option<LeafIterator>> {
-        log::debug!("Indexing {key:?}");
-
+    /// Walk down the tree to the leaf page where the key should be.
+    fn find_leaf(&self, fs: &mut PageCache, key: &Record) -> Result<Option<usize>> {
         let root_page_id = if let Some(page_id) = self.schema.root {
             page_id
         } else {
@@ -325,61 +395,162 @@ impl Index {
         log::debug!("Root is {root_page_id}");
 
         let mut page_id = root_page_id;
-        let mut page_buf = fs.get(self.fd, page_id)?;
-        let mut page = IndexPage::from_buf(self, page_buf);
-        while !page.is_leaf() {
+        loop {
+            let page_buf = fs.get(self.fd, page_id)?;
+            let page = IndexPage::from_buf(self, page_buf);
+            if page.is_leaf() {
+                log::debug!("Found leaf page {page_id}");
+                return Ok(Some(page_id));
+            }
             let pos = self.find(&page, key);
             page_id = page.get_record(pos).get_child();
             log::debug!("Walk into {page_id}");
-            page_buf = fs.get(self.fd, page_id)?;
-            page = IndexPage::from_buf(self, page_buf);
         }
+    }
+
+    /// Find the position of the first key greater than or equal to the given key.
+    ///
+    /// The position is past the end of the last leaf if all keys are smaller.
+    fn position(&self, fs: &mut PageCache, key: &Record) -> Result<Option<LeafIterator>> {
+        let mut page_id = if let Some(page_id) = self.find_leaf(fs, key)? {
+            page_id
+        } else {
+            return Ok(None);
+        };
 
-        log::debug!("Found leaf page {page_id}");
-
-        // Find the correct position to insert
-        let mut pos = self.find(&page, key);
-        log::debug!("Position is  {pos}");
-        while &page.get_record(pos) < key {
-            pos += 1;
-            for (record, slot, _) in page.iter().skip(pos) {
-                pos = slot;
-                if &record >= key {
-                    break;
+        loop {
+            let page = IndexPage::from_buf(self, fs.get(self.fd, page_id)?);
+            let size = page.get_size();
+            if size > 0 {
+                let pos = self.find(&page, key);
+                if &page.get_record(pos) >= key {
+                    log::debug!("Found at {page_id} {pos}");
+                    return Ok(Some((page_id, pos)));
                 }
             }
-            if pos == page.get_size() {
-                // Go along the linked list
-                log::debug!("Go along the linked list");
-                if let Some(next) = page.get_next() {
-                    page_id = next;
-                    log::debug!("Walk into {page_id}");
-                    page_buf = fs.get(self.fd, page_id)?;
-                    page = IndexPage::from_buf(self, page_buf);
-                    pos = 0;
-                } else {
-                    // No more pages
-                    log::debug!("No more pages");
-                    break;
-                }
+
+            // Go along the linked list
+            if let Some(next) = page.get_next() {
+                page_id = next;
+                log::debug!("Walk into {page_id}");
+            } else {
+                log::debug!("No more pages");
+                return Ok(Some((page_id, size)));
             }
         }
+    }
 
-        log::debug!("Found at {page_id} {pos}");
+    /// Get the leaf node of the first key greater than or equal to the given key.
+    ///
+    /// Returns `None` if all keys are smaller.
+    pub fn index(&self, fs: &mut PageCache, key: &Record) -> Result<Option<LeafIterator>> {
+        log::debug!("Indexing {key:?}");
 
-        Ok(Some((page_id, pos)))
+        let iter = self.position(fs, key)?;
+        if let Some((page_id, slot)) = iter {
+            let page = IndexPage::from_buf(self, fs.get(self.fd, page_id)?);
+            if slot == page.get_size() {
+                return Ok(None);
+            }
+        }
+        Ok(iter)
+    }
+
+    /// Get the leaf node of a key in the index, requiring an exact match.
+    pub fn get(&self, fs: &mut PageCache, key: &Record) -> Result<Option<LeafIterator>> {
+        log::debug!("Looking up {key:?}");
+
+        let page_id = if let Some(page_id) = self.find_leaf(fs, key)? {
+            page_id
+        } else {
+            return Ok(None);
+        };
+        let (page_id, slot) = self.search_leaf(fs, page_id, key)?;
+        Ok(slot.map(|slot| (page_id, slot)))
     }
 
     /// Check if some key exists in the index.
     pub fn contains(&self, fs: &mut PageCache, key: &Record) -> Result<bool> {
         log::debug!("Checking if {key:?} exists");
 
-        let iter = self.index(fs, key)?;
-        if let Some(iter) = iter {
-            let (record, _, _) = self.get_record(fs, iter)?;
-            Ok(key == &record)
+        Ok(self.get(fs, key)?.is_some())
+    }
+
+    /// Check that a key could be inserted into the index.
+    ///
+    /// # Error
+    ///
+    /// Returns an error if the index is unique and the key is present.
+    /// Keys with null never collide.
+    pub fn check_unique(&self, fs: &mut PageCache, key: &Record) -> Result<()> {
+        if self.schema.unique && !key.has_null() && self.contains(fs, key)? {
+            return Err(Error::DuplicateKey(self.schema.name.clone()));
+        }
+        Ok(())
+    }
+
+    /// Check if some keys exist in the index.
+    ///
+    /// Keys should be sorted, so that consecutive keys falling in the same leaf
+    /// are checked without walking down the tree again.
+    pub fn contains_many(&self, fs: &mut PageCache, keys: &[Record]) -> Result<Vec<bool>> {
+        log::debug!("Checking if {} keys exist", keys.len());
+
+        let mut ret = Vec::with_capacity(keys.len());
+        // Current leaf, pinned so that it is not reloaded for every key
+        let mut leaf: Option<(usize, PagePin)> = None;
+        for key in keys {
+            // Walk down the tree only if the key is beyond current leaf
+            let in_leaf = leaf.as_ref().is_some_and(|(_, pin)| {
+                let page = IndexPage::from_buf(self, pin);
+                let size = page.get_size();
+                size > 0 && &page.get_record(size - 1) >= key
+            });
+            let page_id = match &leaf {
+                Some((page_id, _)) if in_leaf => Some(*page_id),
+                _ => self.find_leaf(fs, key)?,
+            };
+
+            if let Some(page_id) = page_id {
+                let (page_id, slot) = self.search_leaf(fs, page_id, key)?;
+                if leaf.as_ref().is_none_or(|&(id, _)| id != page_id) {
+                    leaf = Some((page_id, fs.pin(self.fd, page_id)?));
+                }
+                ret.push(slot.is_some());
+            } else {
+                leaf = None;
+                ret.push(false);
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Binary search a key in the leaf page where it should be.
+    ///
+    /// Returns the page where the search ended, and the slot of the key if exists.
+    fn search_leaf(
+        &self,
+        fs: &mut PageCache,
+        page_id: usize,
+        key: &Record,
+    ) -> Result<(usize, Option<usize>)> {
+        let page = IndexPage::from_buf(self, fs.get(self.fd, page_id)?);
+        if page.get_size() == 0 {
+            return Ok((page_id, None));
+        }
+        let pos = self.find(&page, key);
+        let record = page.get_record(pos);
+        if &record >= key {
+            return Ok((page_id, (&record == key).then_some(pos)));
+        }
+
+        // All keys in this leaf are smaller, so the key can only be the first of the next leaf
+        if let Some(next) = page.get_next() {
+            let page = IndexPage::from_buf(self, fs.get(self.fd, next)?);
+            let found = page.get_size() > 0 && &page.get_record(0) == key;
+            Ok((next, found.then_some(0)))
         } else {
-            Ok(false)
+            Ok((page_id, None))
         }
     }
 
@@ -387,11 +558,27 @@ impl Index {
     pub fn get_record(&self, fs: &mut PageCache, iter: LeafIterator) -> Result<IndexResult> {
         let (page_id, slot) = iter;
         let buf = fs.get(self.fd, page_id)?;
+        Ok(self.read_record(buf, slot))
+    }
+
+    /// Pin the leaf page an iterator points into, keeping it cached while
+    /// pages of the table are accessed for its records.
+    pub fn pin_leaf(&self, fs: &mut PageCache, iter: LeafIterator) -> Result<PagePin> {
+        fs.pin(self.fd, iter.0)
+    }
+
+    /// Get the index record in a slot of a pinned leaf page.
+    pub fn get_pinned_record(&self, leaf: &PagePin, slot: usize) -> IndexResult {
+        self.read_record(leaf, slot)
+    }
+
+    /// Read the index record in a slot of a leaf page.
+    fn read_record(&self, buf: &[u8], slot: usize) -> IndexResult {
         let page = IndexPage::from_buf(self, buf);
         let record = page.get_record(slot);
         let (page, slot) = record.get_index();
         let record = Record::new(record.into_keys());
-        Ok((record, page, slot))
+        (record, page, slot)
     }
 
     /// Increment a leaf iterator.
@@ -411,6 +598,67 @@ impl Index {
         }
     }
 
+    /// Decrement a leaf iterator, walking the leaf linked list backwards.
+    pub fn dec_iter(&self, fs: &mut PageCache, iter: LeafIterator) -> Result<Option<LeafIterator>> {
+        let (page_id, slot) = iter;
+        if slot > 0 {
+            return Ok(Some((page_id, slot - 1)));
+        }
+        let buf = fs.get(self.fd, page_id)?;
+        let page = IndexPage::from_buf(self, buf);
+        let prev = page.get_prev();
+        if let Some(prev) = prev {
+            let buf = fs.get(self.fd, prev)?;
+            let page = IndexPage::from_buf(self, buf);
+            Ok(Some((prev, page.get_size() - 1)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Get the leaf iterator of the smallest key.
+    ///
+    /// Returns `None` if the index is empty.
+    pub fn first_iter(&self, fs: &mut PageCache) -> Result<Option<LeafIterator>> {
+        let mut page_id = if let Some(root) = self.schema.root {
+            root
+        } else {
+            return Ok(None);
+        };
+        loop {
+            let page = IndexPage::from_buf(self, fs.get(self.fd, page_id)?);
+            if page.get_size() == 0 {
+                return Ok(None);
+            }
+            if page.is_leaf() {
+                return Ok(Some((page_id, 0)));
+            }
+            page_id = page.get_record(0).get_child();
+        }
+    }
+
+    /// Get the leaf iterator of the largest key.
+    ///
+    /// Returns `None` if the index is empty.
+    pub fn last_iter(&self, fs: &mut PageCache) -> Result<Option<LeafIterator>> {
+        let mut page_id = if let Some(root) = self.schema.root {
+            root
+        } else {
+            return Ok(None);
+        };
+        loop {
+            let page = IndexPage::from_buf(self, fs.get(self.fd, page_id)?);
+            let size = page.get_size();
+            if size == 0 {
+                return Ok(None);
+            }
+            if page.is_leaf() {
+                return Ok(Some((page_id, size - 1)));
+            }
+            page_id = page.get_record(size - 1).get_child();
+        }
+    }
+
     /// Split one page into two.
     fn split(&mut self, fs: &mut PageCache, page_id: usize, new_page_id: usize) -> Result<()> {
         log::debug!("Splitting {page_id}, generating {new_page_id}");
@@ -450,6 +698,8 @@ impl Index {
     ) -> Result<()> {
         log::debug!("Adding ({key:?}, {page}, {slot}) into index");
 
+        self.check_unique(fs, &key)?;
+
         let record = Record::new_with_index(key.fields, page, slot);
         if self.schema.root.is_none() {
             // Tree empty
@@ -462,7 +712,7 @@ impl Index {
         };
 
         // Find the leaf page and slot to insert
-        let (page_id, slot) = self.index(fs, &record)?.unwrap();
+        let (page_id, slot) = self.position(fs, &record)?.unwrap();
         let buf = fs.get_mut(self.fd, page_id)?;
         let mut page = IndexPageMut::from_buf(self, buf);
         page.insert(slot, record);
@@ -582,6 +832,103 @@ impl Index {
         Ok(())
     }
 
+    /// Build the index bottom-up from a batch of keys.
+    ///
+    /// Keys are sorted and packed into leaves sequentially, and then internal
+    /// levels are built on top of them, avoiding the repeated splits of
+    /// inserting keys one by one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the index is not empty.
+    pub fn bulk_load(&mut self, fs: &mut PageCache, mut keys: Vec<IndexResult>) -> Result<()> {
+        log::info!("Bulk loading {} keys into index", keys.len());
+
+        assert_eq!(self.schema.root, None, "Bulk loading into non-empty index");
+
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        keys.sort_by(|(a, a_page, a_slot), (b, b_page, b_slot)| {
+            let ordering = a.partial_cmp(b).unwrap_or(Ordering::Equal);
+            ordering.then_with(|| (a_page, a_slot).cmp(&(b_page, b_slot)))
+        });
+
+        if self.schema.unique {
+            for pair in keys.windows(2) {
+                if !pair[0].0.has_null() && pair[0].0 == pair[1].0 {
+                    return Err(Error::DuplicateKey(self.schema.name.clone()));
+                }
+            }
+        }
+
+        let mut records: Vec<_> = keys
+            .into_iter()
+            .map(|(key, page, slot)| Record::new_with_index(key.fields, page, slot))
+            .collect();
+        let mut leaf = true;
+        loop {
+            records = self.build_level(fs, records, leaf)?;
+            leaf = false;
+            if records.len() == 1 {
+                self.schema.root = Some(records[0].get_child());
+                return Ok(());
+            }
+        }
+    }
+
+    /// Pack sorted records into a new level of linked pages.
+    ///
+    /// Returns the records pointing to the new pages, for building the level above.
+    fn build_level(
+        &mut self,
+        fs: &mut PageCache,
+        records: Vec<Record>,
+        leaf: bool,
+    ) -> Result<Vec<Record>> {
+        let record_size = if leaf {
+            self.leaf_schema.get_record_size()
+        } else {
+            self.internal_schema.get_record_size()
+        };
+        // -1 for the record that will be inserted, same as a loaded page
+        let max_records = (fs.page_size() - HEADER_SIZE) / record_size - 1;
+
+        // Spread records evenly, so that the last page won't underflow
+        let pages = records.len().div_ceil(max_records);
+        let page_ids = (0..pages)
+            .map(|_| self.new_page(fs))
+            .collect::<Result<Vec<_>>>()?;
+        log::debug!("Building level of {pages} pages, leaf: {leaf}");
+
+        let mut ret = vec![];
+        let mut records = records.into_iter();
+        for (i, &page_id) in page_ids.iter().enumerate() {
+            let size = records.len() / (pages - i);
+            let chunk: Vec<_> = records.by_ref().take(size).collect();
+            let max_key = chunk[size - 1].clone().into_keys();
+
+            if !leaf {
+                for record in &chunk {
+                    let child_buf = fs.get_mut(self.fd, record.get_child())?;
+                    let mut child_page = IndexPageMut::from_buf(self, child_buf);
+                    child_page.set_parent(Some(page_id));
+                }
+            }
+
+            let buf = fs.get_mut(self.fd, page_id)?;
+            let mut page = IndexPageMut::new(self, buf, leaf);
+            page.set_prev(i.checked_sub(1).map(|prev| page_ids[prev]));
+            page.set_next(page_ids.get(i + 1).copied());
+            page.insert_range(0, chunk);
+
+            ret.push(Record::new_with_child(max_key, page_id));
+        }
+
+        Ok(ret)
+    }
+
     /// Remove a key from the index.
     pub fn remove(
         &mut self,
@@ -779,7 +1126,7 @@ impl Index {
         let total_size = left_size + right_size;
 
         // Can't borrow if total size is less than half of max size
-        if total_size < (max_records + 1) / 2 * 2 {
+        if total_size < max_records.div_ceil(2) * 2 {
             return Ok(false);
         }
 
@@ -967,6 +1314,9 @@ impl Index {
 
 impl Drop for Index {
     fn drop(&mut self) {
+        if self.discarded {
+            return;
+        }
         if let Err(err) = self.save() {
             log::error!("Failed to save index schema: {err}")
         }
@@ -1064,7 +1414,7 @@ pub trait LinkedIndexPage<'a> {
 
     /// Is this page underflow.
     fn is_underflow(&self) -> bool {
-        !self.is_root() && self.get_size() < (self.get_max_records() + 1) / 2
+        !self.is_root() && self.get_size() < self.get_max_records().div_ceil(2)
     }
 
     /// Get a record from the page using a slot id.
@@ -1120,7 +1470,7 @@ impl<'a> IndexPage<'a> {
         };
 
         // -1 for the record that will be inserted
-        let max_records = (PAGE_SIZE - HEADER_SIZE) / record_size - 1;
+        let max_records = (buf.len() - HEADER_SIZE) / record_size - 1;
 
         Self {
             index,
@@ -1179,7 +1529,7 @@ impl<'a> IndexPageMut<'a> {
             index.internal_schema.get_record_size()
         };
 
-        let max_records = (PAGE_SIZE - HEADER_SIZE) / record_size;
+        let max_records = (buf.len() - HEADER_SIZE) / record_size;
 
         // Clear buffer
         buf.fill(0);
@@ -1209,7 +1559,7 @@ impl<'a> IndexPageMut<'a> {
         };
 
         // -1 for the record that will be inserted
-        let max_records = (PAGE_SIZE - HEADER_SIZE) / record_size - 1;
+        let max_records = (buf.len() - HEADER_SIZE) / record_size - 1;
 
         Self {
             index,
@@ -1,90 +1,171 @@
+mod cancel;
 mod config;
 mod error;
 mod file;
 mod index;
+mod output;
 mod parser;
+mod plan;
+mod query_log;
 mod record;
 mod schema;
 mod setup;
 mod system;
 mod table;
+mod trace;
 
 use std::fs;
-use std::io;
-use std::time::Instant;
+use std::io::{self, BufWriter, Write};
+use std::process::{Command, ExitCode};
 
-use rustyline::{config::Configurer, error::ReadlineError, DefaultEditor};
+use rustyline::{error::ReadlineError, DefaultEditor};
 
-use config::SHELL_HISTORY;
-use error::Result;
-use file::FS;
-use parser::{parse, QueryStat};
+use config::{OutputFormat, SHELL_HISTORY};
+use error::{Error, Result};
+use output::{new_writer, ResultWriter};
+use parser::{is_complete, parse, terminate};
 use system::System;
 
-/// Write back page cache and shutdown.
-struct Cleaner;
+/// Outcome of running statements, reported as the exit code.
+///
+/// Outcomes are ordered by severity, and the most severe one among all
+/// statements is reported. Code 2 is left to invalid arguments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Outcome {
+    /// All statements succeeded.
+    Success = 0,
+    /// Some statement failed to execute.
+    Execution = 1,
+    /// Some statement failed to parse.
+    Syntax = 3,
+    /// Reading or writing files failed.
+    IO = 4,
+}
+
+impl Outcome {
+    /// Get the outcome of a failed statement.
+    fn of(err: &Error) -> Self {
+        match err {
+            Error::Syntax(_) => Self::Syntax,
+            Error::IO(_) | Error::PageCorrupted(..) => Self::IO,
+            _ => Self::Execution,
+        }
+    }
+}
 
-impl Drop for Cleaner {
-    fn drop(&mut self) {
-        log::info!("Shutting down");
-        let mut fs = FS.lock().expect("Failed to obtain lock on page cache");
-        fs.clear().expect("Failed to write back page cache");
+impl From<Outcome> for ExitCode {
+    fn from(outcome: Outcome) -> Self {
+        ExitCode::from(outcome as u8)
     }
 }
 
-fn batch_main(mut system: System) -> Result<()> {
+/// Run statements, writing results with a writer.
+/// Return the most severe outcome among them.
+fn write_results(
+    out: &mut dyn Write,
+    writer: &mut dyn ResultWriter,
+    system: &mut System,
+    statements: &str,
+) -> Result<Outcome> {
+    let mut outcome = Outcome::Success;
+    writer.begin();
+    for (command, result) in parse(system, statements) {
+        match result {
+            Ok((result, stat)) => writer.write_result(out, command, &result, &stat)?,
+            Err(err) => {
+                log::error!("Error: {err}");
+                writer.write_error(out, command, &err)?;
+                outcome = outcome.max(Outcome::of(&err));
+            }
+        }
+    }
+    Ok(outcome)
+}
+
+/// Run statements from stdin, printing results with a writer.
+///
+/// Lines are accumulated until they end with a complete statement, so
+/// statements and string literals may span lines. Output is buffered and
+/// flushed once per run of statements.
+fn batch_main(mut system: System, writer: &mut dyn ResultWriter) -> Result<Outcome> {
     let mut buf = String::new();
+    let mut line = String::new();
+    let mut out = BufWriter::new(io::stdout().lock());
+    let mut outcome = Outcome::Success;
 
     loop {
+        line.clear();
+        let size = io::stdin().read_line(&mut line)?;
+        if size > 0 {
+            log::info!("Read line: {}", line.trim());
+
+            if buf.is_empty() && line.trim() == "exit" {
+                break;
+            }
+
+            buf.push_str(&line);
+            if !is_complete(&buf) {
+                continue;
+            }
+        }
+
+        outcome = outcome.max(write_results(&mut out, writer, &mut system, buf.trim())?);
+        out.flush()?;
         buf.clear();
-        let size = io::stdin().read_line(&mut buf)?;
-        // EOF reached
+
+        // EOF reached, after running what is left as is
         if size == 0 {
             break;
         }
-        buf = buf.trim().to_string();
-        log::info!("Read line: {buf}");
+    }
 
-        if buf == "exit" {
-            break;
-        }
+    Ok(outcome)
+}
 
-        for (command, result) in parse(&mut system, &buf) {
-            match result {
-                Ok((table, stat)) => {
-                    table.to_csv(io::stdout())?;
-                    if let QueryStat::Desc(constraints, indexes) = stat {
-                        println!();
-                        for constraint in constraints {
-                            println!("{constraint}");
-                        }
-                        for index in indexes {
-                            println!("{index}");
-                        }
-                    }
-                }
-                Err(err) => {
-                    log::error!("Error: {err}");
-                    println!("!ERROR");
-                    println!("{err}");
-                }
-            }
-            println!("@{command}");
-        }
-    }
+/// Run statements given on the command line, printing results as in batch mode.
+fn execute_main(
+    mut system: System,
+    statements: &str,
+    writer: &mut dyn ResultWriter,
+) -> Result<Outcome> {
+    let statements = terminate(statements);
 
-    Ok(())
+    let mut out = BufWriter::new(io::stdout().lock());
+    let outcome = write_results(&mut out, writer, &mut system, &statements)?;
+    out.flush()?;
+    Ok(outcome)
+}
+
+/// Open a statement in `$EDITOR` (`vi` if unset), returning the edited text.
+fn edit_statement(statement: &str) -> Result<String> {
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_owned());
+    let path = std::env::temp_dir().join(format!("yoursql-{}.sql", std::process::id()));
+    fs::write(&path, statement)?;
+
+    // The editor may come with arguments, e.g. `code --wait`
+    let mut args = editor.split_whitespace();
+    let program = args.next().unwrap_or("vi");
+    let status = Command::new(program).args(args).arg(&path).status();
+    let edited = fs::read_to_string(&path);
+    fs::remove_file(&path).ok();
+
+    let status = status?;
+    if !status.success() {
+        return Err(io::Error::other(format!("Editor `{editor}` exited with {status}")).into());
+    }
+    Ok(edited?)
 }
 
-fn shell_main(mut system: System) -> Result<()> {
+fn shell_main(mut system: System, writer: &mut dyn ResultWriter) -> Result<()> {
     let mut rl = DefaultEditor::new()?;
-    rl.set_auto_add_history(true);
     rl.load_history(SHELL_HISTORY).ok();
 
     println!("{}", console::style("Welcome to YourSQL!").green().bold());
 
     // Multi-line buffer
     let mut buf: Option<String> = None;
+    // Last statement, to be re-edited with `\e`
+    let mut last = String::new();
 
     loop {
         let db_name = system.get_current_database();
@@ -98,64 +179,39 @@ fn shell_main(mut system: System) -> Result<()> {
 
         // Set some color on prompt
         match rl.readline(&prompt) {
-            Ok(line) => {
+            Ok(mut line) => {
                 // Skip empty lines
                 if line.trim().is_empty() {
                     continue;
                 }
 
-                if line.trim_end().ends_with(';') {
-                    let command = buf.unwrap_or_default() + &line;
-                    let start_time = Instant::now();
-                    for (_, result) in parse(&mut system, &command) {
-                        match result {
-                            Ok((table, stat)) => {
-                                if !table.is_empty() {
-                                    table.printstd();
-                                }
-                                match stat {
-                                    QueryStat::Query(size) if size > 1 => {
-                                        print!("{size} rows in set");
-                                    }
-                                    QueryStat::Query(1) => {
-                                        print!("1 row in set");
-                                    }
-                                    QueryStat::Query(_) => {
-                                        print!("Empty set");
-                                    }
-                                    QueryStat::Update(size) => {
-                                        print!("Query OK, ");
-                                        if size == 1 {
-                                            print!("1 row affected");
-                                        } else {
-                                            print!("{size} rows affected");
-                                        }
-                                    }
-                                    QueryStat::Desc(constraints, indexes) => {
-                                        for constraint in constraints {
-                                            println!("{constraint}");
-                                        }
-                                        for index in indexes {
-                                            println!("{index}");
-                                        }
-                                        print!("Desc OK");
-                                    }
-                                }
-                                let elapsed = start_time.elapsed();
-                                println!(" ({:.2} sec)", elapsed.as_secs_f64());
-                            }
-                            Err(err) => {
-                                println!("{} {err}", console::style("Error:").bold().red());
-                            }
+                // Edit the pending input, or the last statement if none
+                if line.trim() == "\\e" {
+                    let statement = buf.take().unwrap_or_else(|| last.clone());
+                    match edit_statement(&statement) {
+                        Ok(edited) if !edited.trim().is_empty() => {
+                            line = edited.trim_end().to_owned();
+                            println!("{line}");
+                        }
+                        Ok(_) => continue,
+                        Err(err) => {
+                            println!("{}", console::style(err).red().bold());
+                            continue;
                         }
                     }
-                    buf = None;
-                    continue;
+                }
+
+                let command = buf.take().unwrap_or_default() + &line;
+                if is_complete(&command) {
+                    // Keep whole statements in history instead of single lines
+                    rl.add_history_entry(command.trim_end())?;
+                    last = command.trim_end().to_owned();
+
+                    let mut out = io::stdout().lock();
+                    write_results(&mut out, writer, &mut system, &command)?;
                 } else {
                     // Buffer multi-line input
-                    let buf = buf.get_or_insert_with(String::new);
-                    buf.push_str(&line);
-                    buf.push('\n');
+                    buf = Some(command + "\n");
                 }
             }
             Err(ReadlineError::Interrupted) => buf = None,
@@ -175,43 +231,100 @@ fn shell_main(mut system: System) -> Result<()> {
     Ok(())
 }
 
-fn main() -> Result<()> {
-    let _cleaner = Cleaner;
+fn main() -> ExitCode {
+    match run() {
+        Ok(outcome) => outcome.into(),
+        Err(err) => {
+            eprintln!("Error: {err}");
+            Outcome::of(&err).into()
+        }
+    }
+}
+
+fn run() -> Result<Outcome> {
+    let config = setup::init_config()?;
+    let level = config.log_level.as_deref().unwrap_or("info");
+    setup::init_logging_with(level, config.log_format.unwrap_or_default());
+    log::info!("Config: {:?}", config);
 
-    setup::init_logging();
-    let config = setup::init_config();
+    let path = config.get_path();
 
     // Remove the database directory if it exists.
     if config.init {
-        if config.path.exists() {
+        if path.exists() {
             log::info!("Removing database directory");
-            fs::remove_dir_all(&config.path)?;
+            fs::remove_dir_all(path)?;
         }
-        return Ok(());
+        return Ok(Outcome::Success);
     }
 
     // Create database directory if it doesn't exist.
-    if !config.path.exists() {
+    if !path.exists() {
         log::info!("Creating database directory");
-        fs::create_dir_all(&config.path)?;
+        fs::create_dir_all(path)?;
+    }
+
+    let mut system = system::System::new(path.to_owned());
+
+    let fs = system.page_cache();
+    if let Some(size) = config.cache_size {
+        log::info!("Setting page cache size to {size}");
+        fs.lock().set_capacity(size)?;
+    }
+    if let Some(backend) = config.io_backend {
+        fs.lock().set_backend(backend)?;
+    }
+    fs.lock().set_double_write(config.double_write);
+    if let Some(durability) = config.durability {
+        fs.lock().set_durability(durability);
+    }
+    if let Some(size) = config.page_size {
+        system.set_page_size(size)?;
+    }
+    system.set_warm_cache(config.warm_cache);
+    cancel::install(system.cancel_token())?;
+    if let Some(query_log) = &config.query_log {
+        system.set_query_log(query_log)?;
+    }
+    if let Some(db) = &config.database {
+        system.use_database(db)?;
     }
 
-    let mut system = system::System::new(config.path.clone());
-    if let Some(db) = config.database {
-        system.use_database(&db)?;
+    // Run the startup script. Failed statements are only logged, since they
+    // may be run again on every startup, e.g. creating tables.
+    if let Some(script) = &config.init_script {
+        log::info!("Running startup script {}", script.display());
+        let sql = fs::read_to_string(script)?;
+        for (command, result) in parse(&mut system, &sql) {
+            if let Err(err) = result {
+                log::error!("Startup statement {command} failed: {err}");
+            }
+        }
     }
 
     // Load data into a table.
     if let Some(file) = config.file {
         if let Some(table) = config.table {
-            let count = system.load_table(&table, &file)?;
+            let count = system.load_table(&table, &file, &[])?;
             log::info!("Loaded {} rows into table {}", count, table);
         }
     }
 
-    if config.batch {
-        batch_main(system)
+    // The shell draws tables unless told otherwise, and other modes write CSV
+    let interactive = config.execute.is_none() && !config.batch;
+    let format = config.format.unwrap_or(if interactive {
+        OutputFormat::Table
+    } else {
+        OutputFormat::Csv
+    });
+    let mut writer = new_writer(format, config.quiet);
+
+    if let Some(statements) = &config.execute {
+        execute_main(system, statements, writer.as_mut())
+    } else if config.batch {
+        batch_main(system, writer.as_mut())
     } else {
-        shell_main(system)
+        shell_main(system, writer.as_mut())?;
+        Ok(Outcome::Success)
     }
 }
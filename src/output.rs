@@ -0,0 +1,284 @@
+//! Writers rendering results of statements, one chosen per session.
+//!
+//! The shell renders tables by default, and batch mode CSV, either of which
+//! may be switched to another format, e.g. JSON lines read by scripts.
+
+use std::io::Write;
+use std::ops::Range;
+use std::time::Instant;
+
+use prettytable::{format::consts::FORMAT_NO_LINESEP_WITH_TITLE, Row, Table};
+use serde_json::{json, Map, Value as JsonValue};
+
+use crate::config::OutputFormat;
+use crate::error::{Error, Result};
+use crate::parser::{QueryStat, ResultSet};
+use crate::schema::Value;
+
+/// Renders results of statements.
+pub trait ResultWriter {
+    /// Called before running a line of statements.
+    fn begin(&mut self) {}
+
+    /// Write the result of a statement.
+    fn write_result(
+        &mut self,
+        out: &mut dyn Write,
+        statement: &str,
+        result: &ResultSet,
+        stat: &QueryStat,
+    ) -> Result<()>;
+
+    /// Write the error of a statement.
+    fn write_error(&mut self, out: &mut dyn Write, statement: &str, err: &Error) -> Result<()>;
+}
+
+/// Create a writer of a given format.
+///
+/// In quiet mode, only result rows are written by CSV and JSON writers,
+/// without titles and statement echoes.
+pub fn new_writer(format: OutputFormat, quiet: bool) -> Box<dyn ResultWriter> {
+    match format {
+        OutputFormat::Table => Box::new(TableWriter::new()),
+        OutputFormat::Csv => Box::new(CsvWriter { quiet }),
+        OutputFormat::Json => Box::new(JsonWriter { quiet }),
+    }
+}
+
+/// Writes tables drawn with borders, a summary of each statement and the time
+/// elapsed since the line began, as the shell does.
+pub struct TableWriter {
+    start: Instant,
+}
+
+impl TableWriter {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl ResultWriter for TableWriter {
+    fn begin(&mut self) {
+        self.start = Instant::now();
+    }
+
+    fn write_result(
+        &mut self,
+        out: &mut dyn Write,
+        _statement: &str,
+        result: &ResultSet,
+        stat: &QueryStat,
+    ) -> Result<()> {
+        if !result.rows.is_empty() {
+            let mut table = Table::new();
+            table.set_format(*FORMAT_NO_LINESEP_WITH_TITLE);
+            table.set_titles(Row::from(&result.columns));
+            for row in &result.rows {
+                table.add_row(row.iter().map(ToString::to_string).collect());
+            }
+            table.print(out)?;
+        }
+        match stat {
+            QueryStat::Query(size) if *size > 1 => write!(out, "{size} rows in set")?,
+            QueryStat::Query(1) => write!(out, "1 row in set")?,
+            QueryStat::Query(_) => write!(out, "Empty set")?,
+            QueryStat::Update(1) => write!(out, "Query OK, 1 row affected")?,
+            QueryStat::Update(size) => write!(out, "Query OK, {size} rows affected")?,
+            QueryStat::Desc(constraints, indexes) => {
+                for constraint in constraints {
+                    writeln!(out, "{constraint}")?;
+                }
+                for index in indexes {
+                    writeln!(out, "{index}")?;
+                }
+                write!(out, "Desc OK")?;
+            }
+        }
+        let elapsed = self.start.elapsed();
+        writeln!(out, " ({:.2} sec)", elapsed.as_secs_f64())?;
+        Ok(())
+    }
+
+    fn write_error(&mut self, out: &mut dyn Write, statement: &str, err: &Error) -> Result<()> {
+        writeln!(out, "{} {err}", console::style("Error:").bold().red())?;
+        // Syntax errors are already rendered with spans by pest
+        if let Some(span) = err.span(statement) {
+            writeln!(out, "{}", render_span(statement, span))?;
+        }
+        Ok(())
+    }
+}
+
+/// Render the line of a statement holding a span, with the span underlined.
+fn render_span(statement: &str, span: Range<usize>) -> String {
+    let line_start = statement[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = statement[span.start..]
+        .find('\n')
+        .map_or(statement.len(), |i| span.start + i);
+    let line_no = statement[..span.start].matches('\n').count() + 1;
+    let column = statement[line_start..span.start].chars().count();
+    let width = statement[span].chars().count().max(1);
+    let gutter = " ".repeat(line_no.to_string().len());
+    format!(
+        "{gutter} |\n{line_no} | {}\n{gutter} | {}{}",
+        &statement[line_start..line_end],
+        " ".repeat(column),
+        console::style("^".repeat(width)).red().bold()
+    )
+}
+
+/// Writes results as CSV, each followed by the statement echoed after `@`,
+/// and errors after a `!ERROR` line.
+pub struct CsvWriter {
+    quiet: bool,
+}
+
+impl ResultWriter for CsvWriter {
+    fn write_result(
+        &mut self,
+        out: &mut dyn Write,
+        statement: &str,
+        result: &ResultSet,
+        stat: &QueryStat,
+    ) -> Result<()> {
+        {
+            let mut writer = csv::Writer::from_writer(&mut *out);
+            if !self.quiet && !result.columns.is_empty() {
+                writer.write_record(&result.columns)?;
+            }
+            for row in &result.rows {
+                writer.write_record(row.iter().map(ToString::to_string))?;
+            }
+            writer.flush()?;
+        }
+        if let QueryStat::Desc(constraints, indexes) = stat {
+            writeln!(out)?;
+            for constraint in constraints {
+                writeln!(out, "{constraint}")?;
+            }
+            for index in indexes {
+                writeln!(out, "{index}")?;
+            }
+        }
+        if !self.quiet {
+            writeln!(out, "@{statement}")?;
+        }
+        Ok(())
+    }
+
+    fn write_error(&mut self, out: &mut dyn Write, statement: &str, err: &Error) -> Result<()> {
+        writeln!(out, "!ERROR")?;
+        writeln!(out, "{err}")?;
+        if !self.quiet {
+            writeln!(out, "@{statement}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes a JSON object on a line for each statement, with its column titles,
+/// rows and count of rows, or its error.
+///
+/// Numbers are written as JSON numbers, NULL as `null` and other values as strings.
+pub struct JsonWriter {
+    quiet: bool,
+}
+
+impl JsonWriter {
+    /// Write an object, along with the statement unless quiet.
+    fn write_object(
+        &self,
+        out: &mut dyn Write,
+        statement: &str,
+        mut object: Map<String, JsonValue>,
+    ) -> Result<()> {
+        if !self.quiet {
+            object.insert("statement".to_owned(), statement.into());
+        }
+        serde_json::to_writer(&mut *out, &object)?;
+        writeln!(out)?;
+        Ok(())
+    }
+}
+
+impl ResultWriter for JsonWriter {
+    fn write_result(
+        &mut self,
+        out: &mut dyn Write,
+        statement: &str,
+        result: &ResultSet,
+        stat: &QueryStat,
+    ) -> Result<()> {
+        let columns = &result.columns;
+        let rows: Vec<Vec<_>> = result
+            .rows
+            .iter()
+            .map(|row| row.iter().map(json_value).collect())
+            .collect();
+
+        let mut object = Map::new();
+        match stat {
+            QueryStat::Query(size) => {
+                object.insert("columns".to_owned(), json!(columns));
+                object.insert("rows".to_owned(), json!(rows));
+                object.insert("count".to_owned(), json!(size));
+            }
+            QueryStat::Update(size) => {
+                object.insert("affected".to_owned(), json!(size));
+            }
+            QueryStat::Desc(constraints, indexes) => {
+                let constraints: Vec<_> = constraints.iter().map(ToString::to_string).collect();
+                let indexes: Vec<_> = indexes.iter().map(ToString::to_string).collect();
+                object.insert("columns".to_owned(), json!(columns));
+                object.insert("rows".to_owned(), json!(rows));
+                object.insert("constraints".to_owned(), json!(constraints));
+                object.insert("indexes".to_owned(), json!(indexes));
+            }
+        }
+        self.write_object(out, statement, object)
+    }
+
+    fn write_error(&mut self, out: &mut dyn Write, statement: &str, err: &Error) -> Result<()> {
+        let mut object = Map::new();
+        object.insert("error".to_owned(), err.to_string().into());
+        self.write_object(out, statement, object)
+    }
+}
+
+/// Convert a value into JSON.
+fn json_value(value: &Value) -> JsonValue {
+    match value {
+        Value::Null => JsonValue::Null,
+        Value::Int(v) => json!(v),
+        Value::Bigint(v) => json!(v),
+        Value::Float(v) => json!(v),
+        Value::Varchar(_) | Value::Date(_) | Value::DateTime(_) => value.to_string().into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_values() {
+        let result = ResultSet {
+            columns: vec!["a".to_owned(), "b".to_owned(), "c".to_owned()],
+            rows: vec![
+                vec![Value::Int(1), Value::Float(1.5), "x".into()],
+                vec![Value::Null, Value::Bigint(2), Value::Null],
+            ],
+        };
+        let mut out = vec![];
+        let mut writer = JsonWriter { quiet: true };
+        writer
+            .write_result(&mut out, "SELECT", &result, &QueryStat::Query(2))
+            .unwrap();
+        let object: JsonValue = serde_json::from_slice(&out).unwrap();
+        assert_eq!(object["columns"], json!(["a", "b", "c"]));
+        assert_eq!(object["rows"], json!([[1, 1.5, "x"], [null, 2, null]]));
+        assert_eq!(object["count"], json!(2));
+    }
+}
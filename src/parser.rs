@@ -2,23 +2,27 @@
 
 use std::collections::HashSet;
 use std::path::Path;
+use std::time::Instant;
 
+use chrono::SubsecRound;
 use pest::{
     iterators::{Pair, Pairs},
     Parser,
 };
 use pest_derive::Parser;
-use prettytable::{format::consts::FORMAT_NO_LINESEP_WITH_TITLE, row, Row, Table};
 
 use crate::{
     error::{Error, Result},
     index::IndexSchema,
+    plan::{PlanNode, SelectStatement},
+    query_log,
     record::{Record, RecordSchema},
     schema::{
-        Aggregator, Column, ColumnSelector, Constraint, Expression, Field, Operator, Schema,
-        Selector, Selectors, SetPair, Type, Value, WhereClause,
+        Aggregator, Collation, Column, ColumnSelector, Constraint, Expression, Field, Operator,
+        Schema, Selector, Selectors, SetPair, Ttl, Type, Value, WhereClause,
     },
     system::System,
+    trace,
 };
 
 /// Statistics about the query result.
@@ -35,11 +39,32 @@ pub enum QueryStat {
 #[grammar = "sql.pest"]
 enum SqlParser {}
 
-/// Create an empty table with default format.
-fn fresh_table() -> Table {
-    let mut ret = Table::new();
-    ret.set_format(*FORMAT_NO_LINESEP_WITH_TITLE);
-    ret
+/// Rows returned by a statement, along with the titles of their columns.
+///
+/// Values are kept typed, leaving their rendering to the writer of results.
+#[derive(Default)]
+pub struct ResultSet {
+    /// Titles of columns, empty if the statement returns no rows.
+    pub columns: Vec<String>,
+    /// Rows of values.
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl ResultSet {
+    /// Create an empty result set with titles of columns.
+    fn new(columns: &[&str]) -> Self {
+        Self {
+            columns: columns.iter().map(|&column| column.to_owned()).collect(),
+            rows: vec![],
+        }
+    }
+}
+
+/// Build a row of values from expressions convertible into values.
+macro_rules! row {
+    ($($value:expr),* $(,)?) => {
+        vec![$(Into::<Value>::into($value)),*]
+    };
 }
 
 /// Parse a complete string.
@@ -47,11 +72,11 @@ fn fresh_table() -> Table {
 /// # Returns
 ///
 /// Returns a vector of command-result pairs, in which the result
-/// contains a result table and query statistics.
+/// contains a result set and query statistics.
 pub fn parse<'a>(
     system: &mut System,
     command: &'a str,
-) -> Vec<(&'a str, Result<(Table, QueryStat)>)> {
+) -> Vec<(&'a str, Result<(ResultSet, QueryStat)>)> {
     log::info!("Parsing command: {command}");
 
     let sql = SqlParser::parse(Rule::program, command);
@@ -64,20 +89,69 @@ pub fn parse<'a>(
 
     for statement in sql {
         let command = statement.as_str();
-        match statement.as_rule() {
-            Rule::db_statement => {
-                let result = parse_db_statement(system, statement.into_inner());
-                ret.push((command, result));
+        let rule = statement.as_rule();
+        if !matches!(
+            rule,
+            Rule::db_statement
+                | Rule::table_statement
+                | Rule::alter_statement
+                | Rule::replay_statement
+        ) {
+            continue;
+        }
+
+        let outer = trace::begin_statement();
+        log::info!("Executing statement: {command}");
+        let start_time = Instant::now();
+        // Statements altering tables wait for the index built online,
+        // reporting its failure before their own result
+        if matches!(rule, Rule::db_statement | Rule::alter_statement) {
+            if let Err(err) = system.finish_index_build() {
+                ret.push((command, Err(err)));
             }
+        }
+        if let Err(err) = system.refresh_tables() {
+            log::error!("Failed to refresh tables: {err}");
+        }
+        let result = match rule {
+            Rule::db_statement => parse_db_statement(system, statement.into_inner()),
             Rule::table_statement => {
+                system.begin_plan();
                 let result = parse_table_statement(system, statement.into_inner());
-                ret.push((command, result));
+                if result.is_ok() {
+                    if let Err(err) = system.check_plan(command) {
+                        log::error!("Failed to record plan: {err}");
+                    }
+                }
+                result
             }
-            Rule::alter_statement => {
-                let result = parse_alter_statement(system, statement.into_inner());
-                ret.push((command, result));
+            Rule::alter_statement => parse_alter_statement(system, statement.into_inner()),
+            Rule::replay_statement => parse_replay_statement(system, statement.into_inner()),
+            _ => unreachable!(),
+        };
+        // Changes are saved for other processes even if the statement failed halfway
+        let saved = system.save_tables();
+        if let (Err(_), Err(err)) = (&result, &saved) {
+            log::error!("Failed to save tables: {err}");
+        }
+        // The statement is only done once its writes are as durable as configured
+        let result = result.and_then(|result| {
+            saved?;
+            system.page_cache().lock().commit()?;
+            Ok(result)
+        });
+        let elapsed = start_time.elapsed();
+        // Replaying is not recorded into the query log
+        if rule != Rule::replay_statement {
+            if let Err(err) = system.log_query(command, elapsed) {
+                log::error!("Failed to record query: {err}");
             }
-            _ => continue,
+        }
+        trace::end_statement(outer, elapsed);
+        ret.push((command, result));
+        // Failure of the index built online is reported after the statement
+        if let Err(err) = system.advance_index_build() {
+            ret.push((command, Err(err)));
         }
     }
 
@@ -85,7 +159,47 @@ pub fn parse<'a>(
     ret
 }
 
-fn parse_identifier(pairs: Pairs<Rule>) -> &str {
+/// Check whether input ends with a complete statement, i.e. its last
+/// character outside string literals and comments is a `;`.
+pub fn is_complete(input: &str) -> bool {
+    let mut complete = false;
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                complete = false;
+                if !chars.any(|c| c == '\'') {
+                    return false;
+                }
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            ';' => complete = true,
+            c if c.is_whitespace() => (),
+            _ => complete = false,
+        }
+    }
+    complete
+}
+
+/// Terminate input whose last statement omits its `;`, as in `-e "SELECT ..."`.
+///
+/// The `;` is put on a line of its own, out of any trailing `--` comment.
+pub fn terminate(input: &str) -> String {
+    let input = input.trim();
+    if is_complete(input) {
+        input.to_owned()
+    } else {
+        format!("{input}\n;")
+    }
+}
+
+fn parse_identifier(pairs: Pairs<'_, Rule>) -> &str {
     for pair in pairs {
         match pair.as_rule() {
             Rule::identifier => {
@@ -97,7 +211,7 @@ fn parse_identifier(pairs: Pairs<Rule>) -> &str {
     unreachable!()
 }
 
-fn parse_identifiers(pairs: Pairs<Rule>) -> Vec<&str> {
+fn parse_identifiers(pairs: Pairs<'_, Rule>) -> Vec<&str> {
     let mut ret = vec![];
 
     for pair in pairs {
@@ -112,16 +226,28 @@ fn parse_identifiers(pairs: Pairs<Rule>) -> Vec<&str> {
     ret
 }
 
-fn parse_db_statement(system: &mut System, statement: Pairs<Rule>) -> Result<(Table, QueryStat)> {
+fn parse_db_statement(
+    system: &mut System,
+    statement: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
     log::debug!("Parsing db statement: {statement:?}");
 
     let pair = statement.into_iter().next().unwrap();
     match pair.as_rule() {
         Rule::create_db_statement => parse_create_db_statement(system, pair.into_inner()),
         Rule::drop_db_statement => parse_drop_db_statement(system, pair.into_inner()),
+        Rule::clone_db_statement => parse_clone_db_statement(system, pair.into_inner()),
+        Rule::alter_db_statement => parse_alter_db_statement(system, pair.into_inner()),
         Rule::show_dbs_statement => parse_show_dbs_statement(system, pair.into_inner()),
         Rule::use_db_statement => parse_use_db_statement(system, pair.into_inner()),
         Rule::show_tables_statement => parse_show_tables_statement(system, pair.into_inner()),
+        Rule::show_table_status_statement => {
+            parse_show_table_status_statement(system, pair.into_inner())
+        }
+        Rule::show_cache_status_statement => {
+            parse_show_cache_status_statement(system, pair.into_inner())
+        }
+        Rule::set_statement => parse_set_statement(system, pair.into_inner()),
         _ => unimplemented!(),
     }
 }
@@ -129,42 +255,81 @@ fn parse_db_statement(system: &mut System, statement: Pairs<Rule>) -> Result<(Ta
 fn parse_create_db_statement(
     system: &mut System,
     statement: Pairs<Rule>,
-) -> Result<(Table, QueryStat)> {
+) -> Result<(ResultSet, QueryStat)> {
     log::debug!("Parsing create db statement: {statement:?}");
 
     let name = statement.into_iter().next().unwrap().as_str();
 
     system.create_database(name)?;
 
-    Ok((fresh_table(), QueryStat::Update(1)))
+    Ok((ResultSet::default(), QueryStat::Update(1)))
 }
 
 fn parse_drop_db_statement(
     system: &mut System,
     statement: Pairs<Rule>,
-) -> Result<(Table, QueryStat)> {
+) -> Result<(ResultSet, QueryStat)> {
     log::debug!("Parsing drop db statement: {statement:?}");
 
     let name = statement.into_iter().next().unwrap().as_str();
 
     system.drop_database(name)?;
 
-    Ok((fresh_table(), QueryStat::Update(1)))
+    Ok((ResultSet::default(), QueryStat::Update(1)))
+}
+
+fn parse_clone_db_statement(
+    system: &mut System,
+    statement: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
+    log::debug!("Parsing clone db statement: {statement:?}");
+
+    let names = parse_identifiers(statement);
+
+    system.clone_database(names[0], names[1])?;
+
+    Ok((ResultSet::default(), QueryStat::Update(1)))
+}
+
+fn parse_alter_db_statement(
+    system: &mut System,
+    statement: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
+    log::debug!("Parsing alter db statement: {statement:?}");
+
+    let mut names = vec![];
+    let mut value = None;
+    for pair in statement {
+        match pair.as_rule() {
+            Rule::identifier if names.len() < 2 => names.push(pair.as_str()),
+            Rule::identifier => value = Some(pair.as_str().to_owned()),
+            Rule::value => {
+                value = Some(match parse_value(pair.into_inner().next().unwrap())? {
+                    Value::Varchar(value) => value,
+                    value => value.to_string(),
+                });
+            }
+            _ => continue,
+        }
+    }
+
+    system.alter_database(names[0], names[1], &value.unwrap())?;
+
+    Ok((ResultSet::default(), QueryStat::Update(1)))
 }
 
 fn parse_show_dbs_statement(
     system: &mut System,
     statement: Pairs<Rule>,
-) -> Result<(Table, QueryStat)> {
+) -> Result<(ResultSet, QueryStat)> {
     log::debug!("Parsing show dbs statement: {statement:?}");
 
-    let mut ret = fresh_table();
-    ret.set_titles(row!["DATABASES"]);
+    let mut ret = ResultSet::new(&["DATABASES"]);
 
     let dbs = system.get_databases()?;
 
     dbs.iter().for_each(|db| {
-        ret.add_row(row![db]);
+        ret.rows.push(row![db.as_str()]);
     });
 
     Ok((ret, QueryStat::Query(dbs.len())))
@@ -173,48 +338,166 @@ fn parse_show_dbs_statement(
 fn parse_use_db_statement(
     system: &mut System,
     statement: Pairs<Rule>,
-) -> Result<(Table, QueryStat)> {
+) -> Result<(ResultSet, QueryStat)> {
     log::debug!("Parsing use db statement: {statement:?}");
 
     let name = statement.into_iter().next().unwrap().as_str();
 
     system.use_database(name)?;
 
-    Ok((fresh_table(), QueryStat::Update(0)))
+    Ok((ResultSet::default(), QueryStat::Update(0)))
 }
 
 fn parse_show_tables_statement(
     system: &mut System,
     statement: Pairs<Rule>,
-) -> Result<(Table, QueryStat)> {
+) -> Result<(ResultSet, QueryStat)> {
     log::debug!("Parsing show tables statement: {statement:?}");
 
-    let mut ret = fresh_table();
-    ret.set_titles(row!["TABLES"]);
+    let mut ret = ResultSet::new(&["TABLES"]);
 
     let tables = system.get_tables()?;
 
     tables.iter().for_each(|table| {
-        ret.add_row(row![table]);
+        ret.rows.push(row![table.as_str()]);
     });
 
     Ok((ret, QueryStat::Query(tables.len())))
 }
 
+fn parse_show_table_status_statement(
+    system: &mut System,
+    statement: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
+    log::debug!("Parsing show table status statement: {statement:?}");
+
+    let mut ret = ResultSet::new(&["Name", "Rows", "Pages", "Analyzed", "Collation"]);
+
+    let tables = system.get_tables()?;
+    for table in &tables {
+        let schema = system.get_table_schema(table)?;
+        // Row counts are only known after analysis
+        let (rows, time) = match schema.get_stats() {
+            Some(stats) => (
+                stats.rows.into(),
+                Value::DateTime(stats.time.trunc_subsecs(0)),
+            ),
+            None => (Value::Null, Value::Null),
+        };
+        let collation = schema.get_collation().to_string();
+        ret.rows.push(row![
+            table.as_str(),
+            rows,
+            schema.get_pages(),
+            time,
+            collation
+        ]);
+    }
+
+    Ok((ret, QueryStat::Query(tables.len())))
+}
+
+fn parse_show_cache_status_statement(
+    system: &mut System,
+    statement: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
+    log::debug!("Parsing show cache status statement: {statement:?}");
+
+    let mut ret = ResultSet::new(&[
+        "File",
+        "Hits",
+        "Misses",
+        "Hit rate",
+        "Evictions",
+        "Dirty writes",
+        "Bytes read",
+    ]);
+
+    let files = system.get_cache_status()?;
+    for (file, stats) in &files {
+        let accesses = stats.hits + stats.misses;
+        let hit_rate = if accesses == 0 {
+            Value::Null
+        } else {
+            Value::Varchar(format!("{:.4}", stats.hits as f64 / accesses as f64))
+        };
+        ret.rows.push(row![
+            file.as_str(),
+            stats.hits,
+            stats.misses,
+            hit_rate,
+            stats.evictions,
+            stats.dirty_writes,
+            stats.bytes_read
+        ]);
+    }
+
+    Ok((ret, QueryStat::Query(files.len())))
+}
+
+fn parse_set_statement(
+    system: &mut System,
+    statement: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
+    log::debug!("Parsing set statement: {statement:?}");
+
+    let mut name = None;
+    let mut value = None;
+
+    for pair in statement {
+        match pair.as_rule() {
+            Rule::identifier => {
+                name = Some(pair.as_str());
+            }
+            Rule::value => {
+                value = Some(parse_value(pair.into_inner().next().unwrap())?);
+            }
+            _ => continue,
+        }
+    }
+
+    let name = name.unwrap();
+    let value = value.unwrap();
+
+    system.set_variable(name, value)?;
+
+    Ok((ResultSet::default(), QueryStat::Update(0)))
+}
+
 fn parse_table_statement(
     system: &mut System,
     statement: Pairs<Rule>,
-) -> Result<(Table, QueryStat)> {
+) -> Result<(ResultSet, QueryStat)> {
     let pair = statement.into_iter().next().unwrap();
+    if !matches!(
+        pair.as_rule(),
+        Rule::desc_statement
+            | Rule::check_foreign_key_statement
+            | Rule::select_statement
+            | Rule::explain_statement
+    ) {
+        system.check_writable()?;
+    }
     match pair.as_rule() {
         Rule::create_table_statement => parse_create_table_statement(system, pair.into_inner()),
+        Rule::create_table_as_statement => {
+            parse_create_table_as_statement(system, pair.into_inner())
+        }
         Rule::drop_table_statement => parse_drop_table_statement(system, pair.into_inner()),
         Rule::desc_statement => parse_desc_statement(system, pair.into_inner()),
+        Rule::analyze_statement => parse_analyze_statement(system, pair.into_inner()),
+        Rule::optimize_statement => parse_optimize_statement(system, pair.into_inner()),
         Rule::load_statement => parse_load_statement(system, pair.into_inner()),
+        Rule::purge_statement => parse_purge_statement(system, pair.into_inner()),
+        Rule::undelete_statement => parse_undelete_statement(system, pair.into_inner()),
+        Rule::check_foreign_key_statement => {
+            parse_check_foreign_key_statement(system, pair.into_inner())
+        }
         Rule::insert_statement => parse_insert_statement(system, pair.into_inner()),
         Rule::delete_statement => parse_delete_statement(system, pair.into_inner()),
         Rule::update_statement => parse_update_statement(system, pair.into_inner()),
         Rule::select_statement => parse_select_statement(system, pair.into_inner()),
+        Rule::explain_statement => parse_explain_statement(system, pair.into_inner()),
         _ => unreachable!(),
     }
 }
@@ -225,16 +508,55 @@ fn parse_integer(pair: Pair<Rule>) -> Result<i32> {
 
 fn parse_value(value: Pair<Rule>) -> Result<Value> {
     let ret = match value.as_rule() {
-        Rule::integer => Value::Int(value.as_str().parse()?),
+        Rule::integer => {
+            // Literals out of range of int are bigint
+            let value = value.as_str();
+            match value.parse() {
+                Ok(value) => Value::Int(value),
+                Err(_) => Value::Bigint(value.parse()?),
+            }
+        }
         Rule::float => Value::Float(value.as_str().parse()?),
         Rule::string => Value::Varchar(value.into_inner().next().unwrap().as_str().to_owned()),
         Rule::null => Value::Null,
+        Rule::cast => {
+            let mut pairs = value.into_inner();
+            let value = parse_value(pairs.next().unwrap().into_inner().next().unwrap())?;
+            let typ = parse_type(pairs.next().unwrap());
+            value.convert(&typ)?
+        }
         _ => panic!("Invalid value: {value:?}"),
     };
 
     Ok(ret)
 }
 
+fn parse_type(typ: Pair<Rule>) -> Type {
+    let pair = typ.into_inner().next().unwrap();
+    match pair.as_rule() {
+        Rule::int_t => Type::Int,
+        Rule::bigint_t => Type::Bigint,
+        Rule::float_t => Type::Float,
+        Rule::varchar_t => {
+            let mut size = None;
+            for pair in pair.into_inner() {
+                match pair.as_rule() {
+                    Rule::integer => {
+                        size = Some(pair.as_str().parse().unwrap());
+                    }
+                    _ => continue,
+                }
+            }
+            let size = size.unwrap();
+            Type::Varchar(size)
+        }
+        Rule::text_t => Type::Text,
+        Rule::datetime_t => Type::DateTime,
+        Rule::date_t => Type::Date,
+        _ => panic!("Invalid type: {pair:?}"),
+    }
+}
+
 fn parse_column(pairs: Pairs<Rule>) -> Result<Column> {
     let mut name = None;
     let mut typ = None;
@@ -247,32 +569,7 @@ fn parse_column(pairs: Pairs<Rule>) -> Result<Column> {
                 name = Some(pair.as_str());
             }
             Rule::typ => {
-                let pair = pair.into_inner().next().unwrap();
-                match pair.as_rule() {
-                    Rule::int_t => {
-                        typ = Some(Type::Int);
-                    }
-                    Rule::float_t => {
-                        typ = Some(Type::Float);
-                    }
-                    Rule::varchar_t => {
-                        let mut size = None;
-                        for pair in pair.into_inner() {
-                            match pair.as_rule() {
-                                Rule::integer => {
-                                    size = Some(pair.as_str().parse().unwrap());
-                                }
-                                _ => continue,
-                            }
-                        }
-                        let size = size.unwrap();
-                        typ = Some(Type::Varchar(size));
-                    }
-                    Rule::date_t => {
-                        typ = Some(Type::Date);
-                    }
-                    _ => panic!("Invalid type: {pair:?}"),
-                }
+                typ = Some(parse_type(pair));
             }
             Rule::not_null_clause => {
                 not_null = true;
@@ -317,6 +614,32 @@ fn parse_primary_key(pairs: Pairs<Rule>) -> Result<Constraint> {
     Ok(Constraint::PrimaryKey { name, columns })
 }
 
+fn parse_unique_key(pairs: Pairs<Rule>) -> Result<Constraint> {
+    let mut name = None;
+    let mut columns = vec![];
+
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::identifier => {
+                name = Some(pair.as_str().to_owned());
+            }
+            Rule::identifiers => {
+                for pair in pair.into_inner() {
+                    match pair.as_rule() {
+                        Rule::identifier => {
+                            columns.push(pair.as_str().to_owned());
+                        }
+                        _ => continue,
+                    }
+                }
+            }
+            _ => continue,
+        }
+    }
+
+    Ok(Constraint::Unique { name, columns })
+}
+
 fn parse_foreign_key(pairs: Pairs<Rule>, table: &str) -> Result<Constraint> {
     let mut name = None;
     let mut columns = vec![];
@@ -386,6 +709,7 @@ fn parse_field_list(field_list: Pairs<Rule>, table: &str) -> Result<Vec<Field>>
                 field.into_inner(),
                 table,
             )?)),
+            Rule::unique_key => ret.push(Field::Constraint(parse_unique_key(field.into_inner())?)),
             _ => continue,
         }
     }
@@ -396,7 +720,7 @@ fn parse_field_list(field_list: Pairs<Rule>, table: &str) -> Result<Vec<Field>>
 fn parse_create_table_statement(
     system: &mut System,
     statement: Pairs<Rule>,
-) -> Result<(Table, QueryStat)> {
+) -> Result<(ResultSet, QueryStat)> {
     log::debug!("Parsing create table statement: {statement:?}");
 
     let mut name = None;
@@ -474,46 +798,122 @@ fn parse_create_table_statement(
             pages: 0,
             free: None,
             full: None,
+            overflow: None,
             columns,
             constraints,
             referred_constraints: vec![],
             indexes: vec![],
+            ttl: None,
+            deleted: 0,
+            stats: None,
+            compressed: false,
+            collation: Collation::default(),
+            version: 0,
         },
     )?;
 
-    Ok((fresh_table(), QueryStat::Update(0)))
+    Ok((ResultSet::default(), QueryStat::Update(0)))
+}
+
+fn parse_create_table_as_statement(
+    system: &mut System,
+    statement: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
+    log::debug!("Parsing create table as statement: {statement:?}");
+
+    let mut ret = ResultSet::new(&["rows"]);
+
+    let mut name = None;
+    let mut select = None;
+
+    for pair in statement {
+        match pair.as_rule() {
+            Rule::identifier => {
+                name = Some(pair.as_str());
+            }
+            Rule::select_statement => {
+                select = Some(parse_select(pair.into_inner())?);
+            }
+            _ => continue,
+        }
+    }
+
+    // Guaranteed to be Some by the grammar.
+    let name = name.unwrap();
+    let (schema, results) = system.execute_select(select.unwrap())?;
+
+    let rows = system.create_table_as(name, &schema, results)?;
+    ret.rows.push(row![rows]);
+
+    Ok((ret, QueryStat::Update(rows)))
 }
 
 fn parse_drop_table_statement(
     system: &mut System,
     statement: Pairs<Rule>,
-) -> Result<(Table, QueryStat)> {
+) -> Result<(ResultSet, QueryStat)> {
     log::debug!("Parsing drop table statement: {statement:?}");
 
     let name = statement.into_iter().next().unwrap().as_str();
 
     system.drop_table(name)?;
 
-    Ok((fresh_table(), QueryStat::Update(0)))
+    Ok((ResultSet::default(), QueryStat::Update(0)))
 }
 
-fn parse_desc_statement(system: &mut System, statement: Pairs<Rule>) -> Result<(Table, QueryStat)> {
+fn parse_desc_statement(
+    system: &mut System,
+    statement: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
     log::debug!("Parsing desc statement: {statement:?}");
 
-    let name = statement.into_iter().next().unwrap().as_str();
+    let mut statement = statement.into_iter();
+    let mut pair = statement.next().unwrap();
+    let extended = pair.as_rule() == Rule::extended;
+    if extended {
+        pair = statement.next().unwrap();
+    }
+    let name = pair.as_str();
 
     let schema = system.get_table_schema(name)?;
 
-    let mut ret = fresh_table();
-    ret.set_titles(row!["Field", "Type", "Null", "Default"]);
+    if extended {
+        let page = |page: Option<usize>| page.map_or(Value::Null, Into::into);
+        let mut ret = ResultSet::new(&["Property", "Value"]);
+        ret.rows.push(row!["Pages", schema.get_pages()]);
+        ret.rows.push(row!["Record size", schema.get_record_size()]);
+        ret.rows
+            .push(row!["Max records per page", schema.get_max_records()]);
+        ret.rows
+            .push(row!["Free bitmap size", schema.get_free_bitmap_size()]);
+        ret.rows
+            .push(row!["Free list head", page(schema.get_free())]);
+        ret.rows
+            .push(row!["Full list head", page(schema.get_full())]);
+        ret.rows
+            .push(row!["Overflow list head", page(schema.get_overflow())]);
+        let compression = if schema.is_compressed() {
+            "LZ4"
+        } else {
+            "NONE"
+        };
+        ret.rows.push(row!["Compression", compression]);
+        let ttl = schema
+            .get_ttl()
+            .map_or(Value::Null, |ttl| Value::Varchar(ttl.to_string()));
+        ret.rows.push(row!["TTL", ttl]);
+        ret.rows.push(row!["Deleted records", schema.get_deleted()]);
+        return Ok((ret, QueryStat::Desc(vec![], vec![])));
+    }
+
+    let mut ret = ResultSet::new(&["Field", "Type", "Null", "Default"]);
 
     schema.get_columns().iter().for_each(|column| {
-        let default = match &column.default {
-            Some(value) => value.to_string(),
-            None => "NULL".to_string(),
-        };
+        let default = column.default.clone().unwrap_or(Value::Null);
         let nullable = if column.nullable { "YES" } else { "NO" };
-        ret.add_row(row![column.name, column.typ, nullable, default,]);
+        let typ = column.typ.to_string();
+        ret.rows
+            .push(row![column.name.as_str(), typ, nullable, default]);
     });
 
     let constraints = schema.get_constraints().into();
@@ -527,14 +927,59 @@ fn parse_desc_statement(system: &mut System, statement: Pairs<Rule>) -> Result<(
     Ok((ret, QueryStat::Desc(constraints, indexes)))
 }
 
-fn parse_load_statement(system: &mut System, statement: Pairs<Rule>) -> Result<(Table, QueryStat)> {
+fn parse_analyze_statement(
+    system: &mut System,
+    statement: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
+    log::debug!("Parsing analyze statement: {statement:?}");
+
+    let name = parse_identifier(statement);
+    let stats = system.analyze(name)?;
+
+    let mut ret = ResultSet::new(&["Column", "Distinct", "Nulls", "Min", "Max"]);
+
+    let schema = system.get_table_schema(name)?;
+    for (column, stats) in schema.get_columns().iter().zip(&stats.columns) {
+        ret.rows.push(row![
+            column.name.as_str(),
+            stats.distinct,
+            stats.nulls,
+            stats.min.clone(),
+            stats.max.clone()
+        ]);
+    }
+
+    Ok((ret, QueryStat::Query(stats.columns.len())))
+}
+
+fn parse_optimize_statement(
+    system: &mut System,
+    statement: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
+    log::debug!("Parsing optimize statement: {statement:?}");
+
+    let name = parse_identifier(statement);
+    let files = system.optimize(name)?;
+
+    let mut ret = ResultSet::new(&["File", "Pages", "Released"]);
+    for (file, pages, released) in &files {
+        ret.rows.push(row![file.as_str(), *pages, *released]);
+    }
+
+    Ok((ret, QueryStat::Query(files.len())))
+}
+
+fn parse_load_statement(
+    system: &mut System,
+    statement: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
     log::debug!("Parsing load statement: {statement:?}");
 
-    let mut ret = fresh_table();
-    ret.set_titles(row!["rows"]);
+    let mut ret = ResultSet::new(&["rows"]);
 
     let mut file = None;
     let mut name = None;
+    let mut where_clauses = vec![];
 
     for pair in statement {
         match pair.as_rule() {
@@ -546,6 +991,9 @@ fn parse_load_statement(system: &mut System, statement: Pairs<Rule>) -> Result<(
             Rule::identifier => {
                 name = Some(pair.as_str());
             }
+            Rule::where_and_clause => {
+                where_clauses = parse_where_and_clause(pair.into_inner())?;
+            }
             _ => continue,
         }
     }
@@ -553,8 +1001,8 @@ fn parse_load_statement(system: &mut System, statement: Pairs<Rule>) -> Result<(
     let file = file.unwrap();
     let name = name.unwrap();
 
-    let rows = system.load_table(name, Path::new(file))?;
-    ret.add_row(row![rows]);
+    let rows = system.load_table(name, Path::new(file), &where_clauses)?;
+    ret.rows.push(row![rows]);
 
     Ok((ret, QueryStat::Update(rows)))
 }
@@ -653,6 +1101,16 @@ fn parse_selector(pairs: Pairs<Rule>) -> Result<Selector> {
             Rule::count_clause => {
                 ret = Some(Selector::Count);
             }
+            Rule::cast_clause => {
+                let mut pairs = pair.into_inner();
+                let expr = parse_expression(pairs.next().unwrap().into_inner())?;
+                let typ = parse_type(pairs.next().unwrap());
+                // Constants are checked early, while columns are converted per record
+                if let Expression::Value(value) = &expr {
+                    value.convert(&typ)?;
+                }
+                ret = Some(Selector::Cast(expr, typ));
+            }
             _ => continue,
         }
     }
@@ -732,6 +1190,12 @@ fn parse_expression(pairs: Pairs<Rule>) -> Result<Expression> {
                     pair.into_inner(),
                 )?));
             }
+            Rule::cast_column => {
+                let mut pairs = pair.into_inner();
+                let column = parse_column_selector(pairs.next().unwrap().into_inner())?;
+                let typ = parse_type(pairs.next().unwrap());
+                ret = Some(Expression::Cast(column, typ));
+            }
             _ => continue,
         }
     }
@@ -770,6 +1234,7 @@ fn parse_where_operator_expression(pairs: Pairs<Rule>) -> Result<WhereClause> {
 
 fn parse_where_like_string(pairs: Pairs<Rule>) -> Result<WhereClause> {
     let mut column = None;
+    let mut like = None;
     let mut string = None;
 
     for pair in pairs {
@@ -777,6 +1242,12 @@ fn parse_where_like_string(pairs: Pairs<Rule>) -> Result<WhereClause> {
             Rule::column => {
                 column = Some(parse_column_selector(pair.into_inner())?);
             }
+            Rule::like_clause => {
+                like = Some(true);
+            }
+            Rule::not_like_clause => {
+                like = Some(false);
+            }
             Rule::string => {
                 string = Some(pair.into_inner().next().unwrap().as_str().to_owned());
             }
@@ -785,9 +1256,10 @@ fn parse_where_like_string(pairs: Pairs<Rule>) -> Result<WhereClause> {
     }
 
     let column = column.unwrap();
+    let like = like.unwrap();
     let string = string.unwrap();
 
-    Ok(WhereClause::LikeString(column, string))
+    Ok(WhereClause::LikeString(column, string, like))
 }
 
 fn parse_where_null_clause(pairs: Pairs<Rule>) -> Result<WhereClause> {
@@ -866,7 +1338,7 @@ fn parse_group_by_clause(pairs: Pairs<Rule>) -> Result<ColumnSelector> {
     ret.unwrap()
 }
 
-fn parse_order_by_clause(pairs: Pairs<Rule>) -> Result<(ColumnSelector, bool)> {
+fn parse_order_by_item(pairs: Pairs<Rule>) -> Result<(ColumnSelector, bool)> {
     let mut column = None;
     let mut asc = true;
 
@@ -896,6 +1368,21 @@ fn parse_order_by_clause(pairs: Pairs<Rule>) -> Result<(ColumnSelector, bool)> {
     Ok((column, asc))
 }
 
+fn parse_order_by_clause(pairs: Pairs<Rule>) -> Result<Vec<(ColumnSelector, bool)>> {
+    let mut ret = vec![];
+
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::order_by_item => {
+                ret.push(parse_order_by_item(pair.into_inner())?);
+            }
+            _ => continue,
+        }
+    }
+
+    Ok(ret)
+}
+
 fn parse_limit_clause(pairs: Pairs<Rule>) -> Result<(i32, Option<i32>)> {
     let mut limit = None;
     let mut offset = None;
@@ -917,18 +1404,17 @@ fn parse_limit_clause(pairs: Pairs<Rule>) -> Result<(i32, Option<i32>)> {
     Ok((limit, offset))
 }
 
-fn parse_select_statement(
-    system: &mut System,
-    statement: Pairs<Rule>,
-) -> Result<(Table, QueryStat)> {
+/// Parse a select statement, leaving it to the system to execute.
+fn parse_select(statement: Pairs<Rule>) -> Result<SelectStatement> {
     log::debug!("Parsing select statement: {statement:?}");
 
     let mut selectors = None;
     let mut tables = None;
     let mut where_clauses = vec![];
     let mut group_by_clause = None;
-    let mut order_by_clause = None;
+    let mut order_by_clause = vec![];
     let mut limit_clause = None;
+    let mut with_deleted = false;
 
     for pair in statement {
         match pair.as_rule() {
@@ -945,85 +1431,185 @@ fn parse_select_statement(
                 group_by_clause = Some(parse_group_by_clause(pair.into_inner())?);
             }
             Rule::order_by_clause => {
-                order_by_clause = Some(parse_order_by_clause(pair.into_inner())?);
+                order_by_clause = parse_order_by_clause(pair.into_inner())?;
             }
             Rule::limit_clause => {
                 limit_clause = Some(parse_limit_clause(pair.into_inner())?);
             }
+            Rule::with_deleted => {
+                with_deleted = true;
+            }
             _ => continue,
         }
     }
 
-    let selectors = selectors.unwrap();
     let tables = tables.unwrap();
 
-    let schema = system.get_table_schema(tables[0])?;
+    Ok(SelectStatement {
+        selectors: selectors.unwrap(),
+        tables: tables.into_iter().map(str::to_owned).collect(),
+        where_clauses,
+        group_by: group_by_clause,
+        order_by: order_by_clause,
+        limit: limit_clause,
+        with_deleted,
+    })
+}
 
-    let mut ret = fresh_table();
+fn parse_select_statement(
+    system: &mut System,
+    statement: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
+    let select = parse_select(statement)?;
+    let (schema, results) = system.execute_select(select)?;
 
-    // Set title
-    let columns: Vec<String> = match &selectors {
-        Selectors::All => schema
-            .get_columns()
-            .iter()
-            .map(|column| column.name.clone())
-            .collect(),
-        Selectors::Some(selectors) => selectors.iter().map(|s| s.to_string()).collect(),
+    let mut ret = ResultSet {
+        columns: schema.get_titles(),
+        rows: vec![],
     };
 
-    ret.set_titles(Row::from(columns));
+    for (record, _, _) in results {
+        ret.rows.push(record.fields);
+    }
 
-    let mut results = system.select(
-        &selectors,
-        &tables,
-        where_clauses,
-        group_by_clause,
-        order_by_clause,
-    )?;
+    let len = ret.rows.len();
+
+    Ok((ret, QueryStat::Query(len)))
+}
 
-    if let Some((limit, offset)) = limit_clause {
-        if let Some(offset) = offset {
-            results = results.into_iter().skip(offset as usize).collect();
+/// Build the operator tree of a select statement.
+///
+/// The statement is executed to find out how tables are accessed,
+/// since access paths are only decided during execution.
+fn explain_select_statement(system: &mut System, statement: Pair<Rule>) -> Result<PlanNode> {
+    let mut projection = String::new();
+    let mut filter = None;
+    let mut group_by = None;
+    let mut order_by = None;
+    let mut limit = None;
+    for pair in statement.clone().into_inner() {
+        let text = pair.as_str().trim().to_owned();
+        match pair.as_rule() {
+            Rule::selectors => projection = text,
+            Rule::where_and_clause => filter = Some(text),
+            Rule::group_by_clause => group_by = Some(text),
+            Rule::order_by_clause => order_by = Some(text),
+            Rule::limit_clause => limit = Some(text),
+            _ => continue,
         }
-        results = results.into_iter().take(limit as usize).collect();
     }
 
-    for (record, _, _) in results {
-        let row: Row = record
-            .fields
-            .into_iter()
-            .map(|value| value.to_string())
-            .collect();
-        ret.add_row(row);
+    let select = parse_select(statement.into_inner())?;
+    system.execute_select(select)?;
+
+    // Turn recorded access steps into leaves
+    let mut ordered = false;
+    let mut access = vec![];
+    for step in system.get_plan() {
+        let node = if let Some(join) = step.strip_prefix("index join ") {
+            let (outer, inner) = join.split_once(" -> ").unwrap();
+            PlanNode {
+                operator: step.clone(),
+                children: vec![
+                    PlanNode::leaf(format!("scan {outer}")),
+                    PlanNode::leaf(format!("index lookup {inner}")),
+                ],
+            }
+        } else if let Some(join) = step.strip_prefix("nested loop join ") {
+            let (outer, inner) = join.split_once(" -> ").unwrap();
+            PlanNode {
+                operator: step.clone(),
+                children: vec![
+                    PlanNode::leaf(format!("scan {outer}")),
+                    PlanNode::leaf(format!("scan {inner}")),
+                ],
+            }
+        } else {
+            // Walking an index in order makes sorting unnecessary
+            ordered |= step.ends_with(" asc") || step.ends_with(" desc");
+            PlanNode::leaf(step.clone())
+        };
+        access.push(node);
     }
+    let mut node = match access.len() {
+        1 => access.pop().unwrap(),
+        _ => PlanNode {
+            operator: "union".to_owned(),
+            children: access,
+        },
+    };
 
-    let len = ret.len();
+    if let Some(filter) = filter {
+        node = PlanNode::above(format!("filter {filter}"), node);
+    }
+    node = PlanNode::above(format!("project {projection}"), node);
+    if let Some(group_by) = group_by {
+        node = PlanNode::above(format!("group {group_by}"), node);
+    }
+    if let Some(order_by) = order_by.filter(|_| !ordered) {
+        node = PlanNode::above(format!("sort {order_by}"), node);
+    }
+    if let Some(limit) = limit {
+        node = PlanNode::above(format!("limit {limit}"), node);
+    }
+    Ok(node)
+}
 
+fn parse_explain_statement(
+    system: &mut System,
+    statement: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
+    let mut json = false;
+    let mut tree = None;
+    for pair in statement {
+        match pair.as_rule() {
+            Rule::json_format => json = true,
+            Rule::select_statement => tree = Some(explain_select_statement(system, pair)?),
+            _ => continue,
+        }
+    }
+    let tree = tree.unwrap();
+
+    let mut ret = ResultSet::new(&["EXPLAIN"]);
+    if json {
+        ret.rows.push(row![serde_json::to_string(&tree)?]);
+    } else {
+        for line in tree.render() {
+            ret.rows.push(row![line]);
+        }
+    }
+
+    let len = ret.rows.len();
     Ok((ret, QueryStat::Query(len)))
 }
 
-fn parse_value_list(pairs: Pairs<Rule>) -> Result<Record> {
+/// Parse a list of values to insert, with `None` for `DEFAULT`.
+fn parse_insert_list(pairs: Pairs<Rule>) -> Result<Vec<Option<Value>>> {
     let mut ret = vec![];
 
     for pair in pairs {
         match pair.as_rule() {
-            Rule::value => {
-                ret.push(parse_value(pair.into_inner().next().unwrap())?);
+            Rule::insert_value => {
+                let pair = pair.into_inner().next().unwrap();
+                ret.push(match pair.as_rule() {
+                    Rule::value => Some(parse_value(pair.into_inner().next().unwrap())?),
+                    _ => None,
+                });
             }
             _ => continue,
         }
     }
 
-    Ok(Record::new(ret))
+    Ok(ret)
 }
 
-fn parse_value_lists(pairs: Pairs<Rule>) -> Result<Vec<Record>> {
+fn parse_value_lists(pairs: Pairs<Rule>) -> Result<Vec<Vec<Option<Value>>>> {
     let mut ret = vec![];
 
     for pair in pairs {
         match pair.as_rule() {
-            Rule::value_list => {
-                ret.push(parse_value_list(pair.into_inner())?);
+            Rule::insert_list => {
+                ret.push(parse_insert_list(pair.into_inner())?);
             }
             _ => continue,
         }
@@ -1035,7 +1621,7 @@ fn parse_value_lists(pairs: Pairs<Rule>) -> Result<Vec<Record>> {
 fn parse_insert_statement(
     system: &mut System,
     statement: Pairs<Rule>,
-) -> Result<(Table, QueryStat)> {
+) -> Result<(ResultSet, QueryStat)> {
     log::debug!("Parsing insert statement: {statement:?}");
 
     let mut table = None;
@@ -1059,27 +1645,30 @@ fn parse_insert_statement(
 
     let schema = system.get_table_schema(table)?;
 
-    for record in &values {
-        let record_len = record.fields.len();
-        let schema_len = schema.get_columns().len();
-        if record_len != schema_len {
-            return Err(Error::FieldCountMismatch(record_len, schema_len));
-        }
-        for (field, column) in record.fields.iter().zip(schema.get_columns()) {
+    let mut records = Vec::with_capacity(count);
+    for (row, fields) in values.into_iter().enumerate() {
+        schema.check_field_count(row + 1, fields.len())?;
+        // DEFAULT stands for the default value of the column, or NULL if it has none
+        let fields: Vec<_> = fields
+            .into_iter()
+            .zip(schema.get_columns())
+            .map(|(field, column)| {
+                field.unwrap_or_else(|| column.default.clone().unwrap_or(Value::Null))
+            })
+            .collect();
+        for (field, column) in fields.iter().zip(schema.get_columns()) {
             if !column.nullable && field == &Value::Null {
                 return Err(Error::NotNullable(column.name.clone()));
             }
-            if !field.check_type(&column.typ) {
-                return Err(Error::TypeMismatch(field.clone(), column.typ.clone()));
-            }
+            field.coerce(&column.typ)?;
         }
+        records.push(Record::new(fields));
     }
 
-    system.insert(table, values)?;
+    system.insert(table, records)?;
 
-    let mut ret = fresh_table();
-    ret.set_titles(row!["rows"]);
-    ret.add_row(row![count]);
+    let mut ret = ResultSet::new(&["rows"]);
+    ret.rows.push(row![count]);
     Ok((ret, QueryStat::Update(count)))
 }
 
@@ -1123,7 +1712,7 @@ fn parse_set_clause(pairs: Pairs<Rule>) -> Result<Vec<SetPair>> {
 fn parse_update_statement(
     system: &mut System,
     statement: Pairs<Rule>,
-) -> Result<(Table, QueryStat)> {
+) -> Result<(ResultSet, QueryStat)> {
     log::debug!("Parsing update statement: {statement:?}");
 
     let mut table = None;
@@ -1149,11 +1738,10 @@ fn parse_update_statement(
     let set_pairs = set_pairs.unwrap();
     let where_clauses = where_clauses.unwrap();
 
-    let mut ret = fresh_table();
-    ret.set_titles(row!["rows"]);
+    let mut ret = ResultSet::new(&["rows"]);
 
     let rows: usize = system.update(table, &set_pairs, &where_clauses)?;
-    ret.add_row(row![rows]);
+    ret.rows.push(row![rows]);
 
     Ok((ret, QueryStat::Update(rows)))
 }
@@ -1161,7 +1749,7 @@ fn parse_update_statement(
 fn parse_delete_statement(
     system: &mut System,
     statement: Pairs<Rule>,
-) -> Result<(Table, QueryStat)> {
+) -> Result<(ResultSet, QueryStat)> {
     log::debug!("Parsing delete statement: {statement:?}");
 
     let mut table = None;
@@ -1181,30 +1769,132 @@ fn parse_delete_statement(
 
     let table = table.unwrap();
 
-    let mut ret = fresh_table();
-    ret.set_titles(row!["rows"]);
+    let mut ret = ResultSet::new(&["rows"]);
 
     let rows: usize = system.delete(table, &where_clauses)?;
-    ret.add_row(row![rows]);
+    ret.rows.push(row![rows]);
+
+    Ok((ret, QueryStat::Update(rows)))
+}
+
+fn parse_undelete_statement(
+    system: &mut System,
+    statement: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
+    log::debug!("Parsing undelete statement: {statement:?}");
+
+    let mut table = None;
+    let mut where_clauses = vec![];
+
+    for pair in statement {
+        match pair.as_rule() {
+            Rule::identifier => {
+                table = Some(pair.as_str());
+            }
+            Rule::where_and_clause => {
+                where_clauses = parse_where_and_clause(pair.into_inner())?;
+            }
+            _ => continue,
+        }
+    }
+
+    let table = table.unwrap();
+
+    let mut ret = ResultSet::new(&["rows"]);
+
+    let rows = system.undelete(table, &where_clauses)?;
+    ret.rows.push(row![rows]);
+
+    Ok((ret, QueryStat::Update(rows)))
+}
+
+fn parse_purge_statement(
+    system: &mut System,
+    statement: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
+    log::debug!("Parsing purge statement: {statement:?}");
+
+    let mut statement = statement.into_iter();
+    let clause = statement.next().unwrap().as_rule();
+    let table = statement.next().unwrap().as_str();
+
+    let mut ret = ResultSet::new(&["rows"]);
+
+    let rows = match clause {
+        Rule::expired_clause => system.purge_expired(table)?,
+        Rule::deleted_clause => system.purge_deleted(table)?,
+        _ => unreachable!(),
+    };
+    ret.rows.push(row![rows]);
 
     Ok((ret, QueryStat::Update(rows)))
 }
 
+fn parse_check_foreign_key_statement(
+    system: &mut System,
+    statement: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
+    log::debug!("Parsing check foreign key statement: {statement:?}");
+
+    let mut statement = statement.into_iter();
+    let constraint = statement.next().unwrap().as_str();
+    let table = statement.next().unwrap().as_str();
+    let delete = statement.next().is_some();
+    if delete {
+        system.check_writable()?;
+    }
+
+    let records = system.check_foreign_key(table, constraint, delete)?;
+
+    let schema = system.get_table_schema(table)?;
+    let ret = ResultSet {
+        columns: schema
+            .get_columns()
+            .iter()
+            .map(|c| c.name.clone())
+            .collect(),
+        rows: records.into_iter().map(|record| record.fields).collect(),
+    };
+
+    let len = ret.rows.len();
+    let stat = if delete {
+        QueryStat::Update(len)
+    } else {
+        QueryStat::Query(len)
+    };
+    Ok((ret, stat))
+}
+
 fn parse_alter_statement(
     system: &mut System,
     statement: Pairs<Rule>,
-) -> Result<(Table, QueryStat)> {
+) -> Result<(ResultSet, QueryStat)> {
     log::debug!("Parsing alter statement: {statement:?}");
 
+    system.check_writable()?;
+
     let pair = statement.into_iter().next().unwrap();
     match pair.as_rule() {
-        Rule::alter_add_index => parse_add_index_statement(system, pair.into_inner()),
+        Rule::create_index_statement | Rule::alter_add_index => {
+            parse_add_index_statement(system, pair.into_inner())
+        }
         Rule::alter_drop_index => parse_drop_index_statement(system, pair.into_inner()),
+        Rule::alter_rebuild_index => parse_rebuild_index_statement(system, pair.into_inner()),
         Rule::alter_add_primary_key => parse_add_primary_key_statement(system, pair.into_inner()),
         Rule::alter_drop_primary_key => parse_drop_primary_key_statement(system, pair.into_inner()),
         Rule::alter_add_foreign_key => parse_add_foreign_key_statement(system, pair.into_inner()),
         Rule::alter_drop_foreign_key => parse_drop_foreign_key_statement(system, pair.into_inner()),
         Rule::alter_add_unique => parse_add_unique(system, pair.into_inner()),
+        Rule::alter_modify_column | Rule::alter_change_column => {
+            parse_modify_column_statement(system, pair.into_inner())
+        }
+        Rule::alter_rename_table | Rule::alter_rename_column => {
+            parse_rename_statement(system, pair.into_inner())
+        }
+        Rule::alter_set_ttl | Rule::alter_drop_ttl => {
+            parse_ttl_statement(system, pair.into_inner())
+        }
+        Rule::alter_set_compression => parse_compression_statement(system, pair.into_inner()),
         _ => unreachable!(),
     }
 }
@@ -1212,10 +1902,12 @@ fn parse_alter_statement(
 fn parse_add_index_statement(
     system: &mut System,
     pairs: Pairs<Rule>,
-) -> Result<(Table, QueryStat)> {
+) -> Result<(ResultSet, QueryStat)> {
     let mut table = None;
     let mut index_name = None;
     let mut columns = None;
+    let mut unique = false;
+    let mut concurrently = false;
 
     for pair in pairs {
         match pair.as_rule() {
@@ -1228,6 +1920,12 @@ fn parse_add_index_statement(
             Rule::identifiers => {
                 columns = Some(parse_identifiers(pair.into_inner()));
             }
+            Rule::unique_clause => {
+                unique = true;
+            }
+            Rule::concurrently_clause => {
+                concurrently = true;
+            }
             _ => continue,
         }
     }
@@ -1235,15 +1933,21 @@ fn parse_add_index_statement(
     let table = table.unwrap();
     let columns = columns.unwrap();
 
-    system.add_index(true, None, table, index_name, &columns, true)?;
+    if concurrently {
+        system.add_index_online(table, index_name, &columns, unique)?;
+    } else if unique {
+        system.add_unique_index(table, index_name, &columns)?;
+    } else {
+        system.add_index(true, None, table, index_name, &columns, true)?;
+    }
 
-    Ok((fresh_table(), QueryStat::Update(0)))
+    Ok((ResultSet::default(), QueryStat::Update(0)))
 }
 
 fn parse_drop_index_statement(
     system: &mut System,
     pairs: Pairs<Rule>,
-) -> Result<(Table, QueryStat)> {
+) -> Result<(ResultSet, QueryStat)> {
     let mut table = None;
     let mut index_name = None;
 
@@ -1264,13 +1968,37 @@ fn parse_drop_index_statement(
 
     system.drop_index(table, index_name)?;
 
-    Ok((fresh_table(), QueryStat::Update(0)))
+    Ok((ResultSet::default(), QueryStat::Update(0)))
+}
+
+fn parse_rebuild_index_statement(
+    system: &mut System,
+    pairs: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
+    let mut table = None;
+    let mut index_name = None;
+
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::identifier => {
+                table = Some(pair.as_str());
+            }
+            Rule::index_path => {
+                index_name = Some(pair.as_str());
+            }
+            _ => continue,
+        }
+    }
+
+    system.rebuild_index(table.unwrap(), index_name.unwrap())?;
+
+    Ok((ResultSet::default(), QueryStat::Update(0)))
 }
 
 fn parse_add_primary_key_statement(
     system: &mut System,
     pairs: Pairs<Rule>,
-) -> Result<(Table, QueryStat)> {
+) -> Result<(ResultSet, QueryStat)> {
     let mut table = None;
     let mut constraint = None;
     let mut columns = None;
@@ -1295,13 +2023,13 @@ fn parse_add_primary_key_statement(
 
     system.add_primary_key(table, constraint, &columns)?;
 
-    Ok((fresh_table(), QueryStat::Update(0)))
+    Ok((ResultSet::default(), QueryStat::Update(0)))
 }
 
 fn parse_drop_primary_key_statement(
     system: &mut System,
     pairs: Pairs<Rule>,
-) -> Result<(Table, QueryStat)> {
+) -> Result<(ResultSet, QueryStat)> {
     let mut table = None;
     let mut constraint = None;
 
@@ -1321,13 +2049,13 @@ fn parse_drop_primary_key_statement(
 
     system.drop_primary_key(table, constraint)?;
 
-    Ok((fresh_table(), QueryStat::Update(0)))
+    Ok((ResultSet::default(), QueryStat::Update(0)))
 }
 
 fn parse_add_foreign_key_statement(
     system: &mut System,
     pairs: Pairs<Rule>,
-) -> Result<(Table, QueryStat)> {
+) -> Result<(ResultSet, QueryStat)> {
     let mut table = None;
     let mut constraint = None;
     let mut columns = None;
@@ -1369,13 +2097,13 @@ fn parse_add_foreign_key_statement(
 
     system.add_foreign_key(table, constraint, &columns, ref_table, &ref_columns)?;
 
-    Ok((fresh_table(), QueryStat::Update(0)))
+    Ok((ResultSet::default(), QueryStat::Update(0)))
 }
 
 fn parse_drop_foreign_key_statement(
     system: &mut System,
     pairs: Pairs<Rule>,
-) -> Result<(Table, QueryStat)> {
+) -> Result<(ResultSet, QueryStat)> {
     let mut table = None;
     let mut constraint = None;
 
@@ -1396,10 +2124,10 @@ fn parse_drop_foreign_key_statement(
 
     system.drop_foreign_key(table, constraint)?;
 
-    Ok((fresh_table(), QueryStat::Update(0)))
+    Ok((ResultSet::default(), QueryStat::Update(0)))
 }
 
-fn parse_add_unique(system: &mut System, pairs: Pairs<Rule>) -> Result<(Table, QueryStat)> {
+fn parse_add_unique(system: &mut System, pairs: Pairs<Rule>) -> Result<(ResultSet, QueryStat)> {
     let mut table = None;
     let mut constraint = None;
     let mut columns = None;
@@ -1424,5 +2152,238 @@ fn parse_add_unique(system: &mut System, pairs: Pairs<Rule>) -> Result<(Table, Q
 
     system.add_unique(table, constraint, &columns)?;
 
-    Ok((fresh_table(), QueryStat::Update(0)))
+    Ok((ResultSet::default(), QueryStat::Update(0)))
+}
+
+fn parse_modify_column_statement(
+    system: &mut System,
+    pairs: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
+    let mut table = None;
+    let mut name = None;
+    let mut column = None;
+
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::identifier => {
+                table = Some(pair.as_str());
+            }
+            Rule::column_identifier => {
+                name = Some(pair.as_str());
+            }
+            Rule::field_def => {
+                column = Some(parse_column(pair.into_inner())?);
+            }
+            _ => continue,
+        }
+    }
+
+    let table = table.unwrap();
+    let column = column.unwrap();
+    let name = name.unwrap_or(&column.name).to_owned();
+
+    let rows = system.modify_column(table, &name, column)?;
+
+    let mut ret = ResultSet::new(&["rows"]);
+    ret.rows.push(row![rows]);
+
+    Ok((ret, QueryStat::Update(rows)))
+}
+
+fn parse_rename_statement(
+    system: &mut System,
+    pairs: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
+    let mut table = None;
+    let mut column = None;
+    let mut new_name = None;
+
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::identifier => {
+                table = Some(pair.as_str());
+            }
+            Rule::column_identifier => {
+                column = Some(pair.as_str());
+            }
+            Rule::new_identifier => {
+                new_name = Some(pair.as_str());
+            }
+            _ => continue,
+        }
+    }
+
+    let table = table.unwrap();
+    let new_name = new_name.unwrap();
+
+    if let Some(column) = column {
+        system.rename_column(table, column, new_name)?;
+    } else {
+        system.rename_table(table, new_name)?;
+    }
+
+    Ok((ResultSet::default(), QueryStat::Update(0)))
+}
+
+fn parse_ttl_statement(system: &mut System, pairs: Pairs<Rule>) -> Result<(ResultSet, QueryStat)> {
+    let mut table = None;
+    let mut column = None;
+    let mut interval = None;
+    let mut unit = 1;
+
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::identifier => {
+                table = Some(pair.as_str());
+            }
+            Rule::column_identifier => {
+                column = Some(pair.as_str().to_owned());
+            }
+            Rule::integer => {
+                interval = Some(pair.as_str().parse::<i64>()?);
+            }
+            Rule::time_unit => {
+                unit = match pair.into_inner().next().unwrap().as_rule() {
+                    Rule::second => 1,
+                    Rule::minute => 60,
+                    Rule::hour => 60 * 60,
+                    Rule::day => 24 * 60 * 60,
+                    _ => unreachable!(),
+                };
+            }
+            _ => continue,
+        }
+    }
+
+    let table = table.unwrap();
+    let ttl = column.map(|column| Ttl {
+        column,
+        seconds: interval.unwrap() * unit,
+    });
+
+    system.set_ttl(table, ttl)?;
+
+    Ok((ResultSet::default(), QueryStat::Update(0)))
+}
+
+fn parse_compression_statement(
+    system: &mut System,
+    pairs: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
+    let mut table = None;
+    let mut compressed = false;
+
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::identifier => {
+                table = Some(pair.as_str());
+            }
+            Rule::compression => {
+                compressed = match pair.into_inner().next().unwrap().as_rule() {
+                    Rule::lz4_compression => true,
+                    Rule::no_compression => false,
+                    _ => unreachable!(),
+                };
+            }
+            _ => continue,
+        }
+    }
+
+    system.set_compression(table.unwrap(), compressed)?;
+
+    Ok((ResultSet::default(), QueryStat::Update(0)))
+}
+
+fn parse_replay_statement(
+    system: &mut System,
+    pairs: Pairs<Rule>,
+) -> Result<(ResultSet, QueryStat)> {
+    log::debug!("Parsing replay statement: {pairs:?}");
+
+    let mut file = None;
+
+    for pair in pairs {
+        match pair.as_rule() {
+            Rule::string => {
+                file = Some(pair.into_inner().next().unwrap().as_str());
+            }
+            _ => continue,
+        }
+    }
+
+    let file = file.unwrap();
+    let entries = query_log::read(Path::new(file))?;
+
+    let mut replica = system.replica()?;
+
+    let mut ret = ResultSet::new(&["statement", "logged", "replayed", "result"]);
+
+    for entry in &entries {
+        if let Some(db) = &entry.database {
+            if replica.get_current_database() != db {
+                replica.use_database(db)?;
+            }
+        }
+
+        let start_time = Instant::now();
+        let statement = format!("{};", entry.statement);
+        let results = parse(&mut replica, &statement);
+        let elapsed = start_time.elapsed();
+
+        let result = match results.iter().find_map(|(_, result)| result.as_ref().err()) {
+            Some(err) => err.to_string(),
+            None => "OK".to_owned(),
+        };
+        ret.rows.push(row![
+            entry.statement.as_str(),
+            format!("{:.6}", entry.duration),
+            format!("{:.6}", elapsed.as_secs_f64()),
+            result
+        ]);
+    }
+
+    replica.close_all()?;
+
+    Ok((ret, QueryStat::Query(entries.len())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_terminate() {
+        for input in [
+            "SELECT * FROM p",
+            "SELECT * FROM p -- c",
+            "SELECT * FROM p; SELECT * FROM q -- c;",
+        ] {
+            let statements = terminate(input);
+            assert!(is_complete(&statements), "{input}");
+            let sql = SqlParser::parse(Rule::program, &statements);
+            assert!(sql.is_ok(), "{input}");
+        }
+        assert_eq!(terminate(" SELECT * FROM p;\n"), "SELECT * FROM p;");
+    }
+
+    #[test]
+    fn test_is_complete() {
+        for (input, complete) in [
+            // Semicolons in string literals do not end statements
+            ("SELECT * FROM p WHERE s = ';'", false),
+            ("SELECT * FROM p WHERE s = ';';", true),
+            ("INSERT INTO p VALUES ('a;", false),
+            // Nor do those in comments
+            ("SELECT * FROM p -- c;", false),
+            ("SELECT * FROM p; -- c", true),
+            ("SELECT * FROM p -- c\n;", true),
+            // Only whitespace may follow the last one
+            ("SELECT * FROM p; \n\t", true),
+            ("SELECT * FROM p; SELECT", false),
+            ("", false),
+            (" \n", false),
+        ] {
+            assert_eq!(is_complete(input), complete, "{input:?}");
+        }
+    }
 }
@@ -0,0 +1,170 @@
+//! Query plans: statements as parsed, executed apart from parsing,
+//! and plan history, used to detect plan regressions between runs.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Serialize;
+
+use crate::config::PLAN_HISTORY;
+use crate::error::Result;
+use crate::schema::{ColumnSelector, Selectors, WhereClause};
+
+static STRING_LITERAL: Lazy<Regex> = Lazy::new(|| Regex::new(r"'[^']*'").unwrap());
+static NUMBER_LITERAL: Lazy<Regex> = Lazy::new(|| Regex::new(r"\b\d+(\.\d+)?\b").unwrap());
+static WHITESPACE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\s+").unwrap());
+
+/// A select statement as parsed, without touching any table,
+/// executed by `System::execute_select`.
+#[derive(Clone, Debug)]
+pub struct SelectStatement {
+    pub selectors: Selectors,
+    pub tables: Vec<String>,
+    pub where_clauses: Vec<WhereClause>,
+    pub group_by: Option<ColumnSelector>,
+    /// Columns to order by, each with whether ascending.
+    pub order_by: Vec<(ColumnSelector, bool)>,
+    /// Count of results to return, with count of results to skip first.
+    pub limit: Option<(i32, Option<i32>)>,
+    /// Whether soft-deleted records are selected too.
+    pub with_deleted: bool,
+}
+
+/// Normalize a statement, so that statements differing only
+/// in literals share the same plan fingerprint.
+pub fn normalize(statement: &str) -> String {
+    let statement = STRING_LITERAL.replace_all(statement, "?");
+    let statement = NUMBER_LITERAL.replace_all(&statement, "?");
+    let statement = WHITESPACE.replace_all(&statement, " ");
+    statement.trim().to_owned()
+}
+
+/// Plans of normalized statements seen in a database.
+pub struct PlanHistory {
+    /// Path to the history file.
+    path: PathBuf,
+    /// Mapping from normalized statement to its plan.
+    plans: HashMap<String, String>,
+}
+
+impl PlanHistory {
+    /// Open the plan history of a database. An empty history
+    /// is created if not exists.
+    pub fn open(db: &Path) -> Result<Self> {
+        let path = db.join(PLAN_HISTORY);
+        let plans = if path.exists() {
+            serde_json::from_reader(File::open(&path)?)?
+        } else {
+            HashMap::new()
+        };
+        Ok(Self { path, plans })
+    }
+
+    /// Record the plan of a statement.
+    ///
+    /// # Returns
+    ///
+    /// Returns the previous plan if the plan has changed.
+    pub fn record(&mut self, statement: &str, plan: String) -> Result<Option<String>> {
+        let statement = normalize(statement);
+        if self.plans.get(&statement) == Some(&plan) {
+            return Ok(None);
+        }
+
+        let old = self.plans.insert(statement, plan);
+        serde_json::to_writer(File::create(&self.path)?, &self.plans)?;
+        Ok(old)
+    }
+}
+
+/// A node of the operator tree shown by `EXPLAIN`.
+#[derive(Debug, Serialize)]
+pub struct PlanNode {
+    /// The operator with its arguments.
+    pub operator: String,
+    /// Operators feeding records into this one.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<PlanNode>,
+}
+
+impl PlanNode {
+    /// Create a node without children.
+    pub fn leaf(operator: String) -> Self {
+        Self {
+            operator,
+            children: vec![],
+        }
+    }
+
+    /// Create a node above a child.
+    pub fn above(operator: String, child: PlanNode) -> Self {
+        Self {
+            operator,
+            children: vec![child],
+        }
+    }
+
+    /// Render the tree with one node a line, children indented below their parent.
+    pub fn render(&self) -> Vec<String> {
+        let mut lines = vec![self.operator.clone()];
+        for (i, child) in self.children.iter().enumerate() {
+            let last = i + 1 == self.children.len();
+            for (j, line) in child.render().into_iter().enumerate() {
+                let prefix = match (j, last) {
+                    (0, false) => "├── ",
+                    (0, true) => "└── ",
+                    (_, false) => "│   ",
+                    (_, true) => "    ",
+                };
+                lines.push(format!("{prefix}{line}"));
+            }
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize("SELECT * FROM t1  WHERE t1.a = 42 AND\n t1.b = 'foo';"),
+            "SELECT * FROM t1 WHERE t1.a = ? AND t1.b = ?;"
+        );
+        assert_eq!(
+            normalize("SELECT * FROM t WHERE t.a < 3.14;"),
+            normalize("SELECT * FROM t WHERE t.a < 2.72;")
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let join = PlanNode {
+            operator: "join".to_owned(),
+            children: vec![
+                PlanNode::above("filter".to_owned(), PlanNode::leaf("scan a".to_owned())),
+                PlanNode::leaf("scan b".to_owned()),
+            ],
+        };
+        let tree = PlanNode::above("limit 1".to_owned(), join);
+        assert_eq!(
+            tree.render(),
+            [
+                "limit 1",
+                "└── join",
+                "    ├── filter",
+                "    │   └── scan a",
+                "    └── scan b",
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&PlanNode::leaf("scan a".to_owned())).unwrap(),
+            r#"{"operator":"scan a"}"#
+        );
+    }
+}
@@ -0,0 +1,68 @@
+//! Query log, recording executed statements so that they can be replayed.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+
+/// An executed statement in the log.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LogEntry {
+    /// Time when the statement started.
+    pub time: DateTime<Local>,
+    /// Time spent on the statement, in seconds.
+    pub duration: f64,
+    /// Database selected when the statement started.
+    pub database: Option<String>,
+    /// The statement.
+    pub statement: String,
+}
+
+/// A query log file, one JSON entry per line.
+pub struct QueryLog {
+    file: File,
+}
+
+impl QueryLog {
+    /// Open a query log. New entries are appended to the file.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    /// Append an executed statement to the log.
+    pub fn record(
+        &mut self,
+        database: Option<&str>,
+        statement: &str,
+        duration: Duration,
+    ) -> Result<()> {
+        let entry = LogEntry {
+            time: Local::now() - duration,
+            duration: duration.as_secs_f64(),
+            database: database.map(|db| db.to_owned()),
+            statement: statement.to_owned(),
+        };
+        serde_json::to_writer(&mut self.file, &entry)?;
+        writeln!(self.file)?;
+        Ok(())
+    }
+}
+
+/// Read all entries in a query log.
+pub fn read(path: &Path) -> Result<Vec<LogEntry>> {
+    let mut ret = vec![];
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        ret.push(serde_json::from_str(&line)?);
+    }
+    Ok(ret)
+}
@@ -1,9 +1,15 @@
 //! Data record management.
 
+use std::cmp::Ordering;
+
 use bit_set::BitSet;
+use chrono::NaiveDate;
 
+use crate::config::VARCHAR_PREFIX_SIZE;
 use crate::error::{Error, Result};
-use crate::schema::{Column, ColumnSelector, Selector, Selectors, SetPair, Type, Value};
+use crate::schema::{
+    parse_datetime, Column, ColumnSelector, Expression, Selector, Selectors, SetPair, Type, Value,
+};
 
 /// Record schema.
 ///
@@ -31,6 +37,22 @@ pub trait RecordSchema {
             .unwrap()
     }
 
+    /// Check the count of fields provided in a row, numbered from 1 in its statement.
+    ///
+    /// # Error
+    ///
+    /// Returns `RowFieldCountMismatch` listing the expected columns if the count differs.
+    fn check_field_count(&self, row: usize, provided: usize) -> Result<()> {
+        let columns = self.get_columns();
+        if provided != columns.len() {
+            let expected = columns.len();
+            let names: Vec<_> = columns.iter().map(|col| col.name.as_str()).collect();
+            let names = names.join(", ");
+            return Err(Error::RowFieldCountMismatch(row, provided, expected, names));
+        }
+        Ok(())
+    }
+
     /// Get the number of fields used for comparison.
     fn get_cmp_keys(&self) -> usize {
         self.get_columns().len()
@@ -57,17 +79,29 @@ pub trait RecordSchema {
 /// the schema may optionally specify a subset of fields to be used as keys.
 ///
 /// Due to some laziness, it's only supported to use the first fields as keys.
+///
+/// # Text fields
+///
+/// Text fields are stored in overflow pages of the table, with the record
+/// only holding the page and length. They are deserialized as empty strings,
+/// and the table fills in the contents using the pointers in `overflow`.
 #[derive(Clone, Debug)]
 pub struct Record {
     pub fields: Vec<Value>,
     pub index_keys: usize,
+    /// Overflow pages of text fields, as (column, first page, length).
+    pub overflow: Vec<(usize, usize, usize)>,
 }
 
 impl Record {
     /// Create a new record.
     pub fn new(fields: Vec<Value>) -> Self {
         let index_keys = fields.len();
-        Self { fields, index_keys }
+        Self {
+            fields,
+            index_keys,
+            overflow: vec![],
+        }
     }
 
     /// Create a new record with page and slot field for indexing.
@@ -75,14 +109,22 @@ impl Record {
         let index_keys = fields.len();
         fields.push(Value::Int(page as i32));
         fields.push(Value::Int(slot as i32));
-        Self { fields, index_keys }
+        Self {
+            fields,
+            index_keys,
+            overflow: vec![],
+        }
     }
 
     /// Create a new record with child field for indexing.
     pub fn new_with_child(mut fields: Vec<Value>, child: usize) -> Self {
         let index_keys = fields.len();
         fields.push(Value::Int(child as i32));
-        Self { fields, index_keys }
+        Self {
+            fields,
+            index_keys,
+            overflow: vec![],
+        }
     }
 
     /// Turn the record into keys used for indexing.
@@ -102,9 +144,17 @@ impl Record {
         Self {
             fields,
             index_keys: self.index_keys,
+            overflow: vec![],
         }
     }
 
+    /// Keep only the first `len` keys, so that the record can be compared with a key prefix.
+    pub fn into_prefix(mut self, len: usize) -> Self {
+        self.fields.truncate(len);
+        self.index_keys = self.index_keys.min(len);
+        self
+    }
+
     /// Check whether there are null values in this record.
     pub fn has_null(&self) -> bool {
         self.fields.iter().any(|v| v == &Value::Null)
@@ -164,8 +214,8 @@ impl Record {
         self.fields[size - 1] = Value::Int(slot as i32);
     }
 
-    /// Check the record against a schema.
-    pub fn check<S: RecordSchema>(&self, schema: &S) -> Result<()> {
+    /// Check the record against a schema, and coerce the fields into the column types.
+    pub fn coerce<S: RecordSchema>(mut self, schema: &S) -> Result<Self> {
         let provided = self.fields.len();
         let expected = schema.get_columns().len();
         if provided != expected {
@@ -177,12 +227,10 @@ impl Record {
             if value == &Value::Null && !column.nullable {
                 return Err(Error::NotNullable(column.name.clone()));
             }
-            if !value.check_type(&column.typ) {
-                return Err(Error::TypeMismatch(value.clone(), column.typ.clone()));
-            }
+            self.fields[i] = value.coerce(&column.typ)?;
         }
 
-        Ok(())
+        Ok(self)
     }
 
     /// Deserialize a record from a buffer.
@@ -191,6 +239,7 @@ impl Record {
         offset += schema.get_null_bitmap_size();
 
         let mut fields = Vec::new();
+        let mut overflow = Vec::new();
         for (i, column) in schema.get_columns().iter().enumerate() {
             // Null field
             if nulls.contains(i) {
@@ -202,15 +251,27 @@ impl Record {
             let value_buf = &buf[offset..offset + column.typ.size()];
             let value = match &column.typ {
                 Type::Int => Value::Int(i32::from_le_bytes(value_buf.try_into().unwrap())),
+                Type::Bigint => Value::Bigint(i64::from_le_bytes(value_buf.try_into().unwrap())),
                 Type::Float => Value::Float(f64::from_le_bytes(value_buf.try_into().unwrap())),
                 Type::Varchar(_) => {
-                    let s = String::from_utf8_lossy(value_buf).to_string();
-                    Value::Varchar(s)
+                    let (len, s) = value_buf.split_at(VARCHAR_PREFIX_SIZE);
+                    let len = u16::from_le_bytes(len.try_into().unwrap()) as usize;
+                    Value::Varchar(String::from_utf8_lossy(&s[..len]).to_string())
                 }
                 Type::Date => {
                     let s = String::from_utf8_lossy(value_buf).to_string();
                     Value::Date(s.parse().expect("Date parse error"))
                 }
+                Type::DateTime => {
+                    let s = String::from_utf8_lossy(value_buf).to_string();
+                    Value::DateTime(parse_datetime(&s).expect("DateTime parse error"))
+                }
+                Type::Text => {
+                    let page = u32::from_le_bytes(value_buf[..4].try_into().unwrap());
+                    let len = u32::from_le_bytes(value_buf[4..].try_into().unwrap());
+                    overflow.push((i, page as usize, len as usize));
+                    Value::Varchar(String::new())
+                }
             };
 
             fields.push(value);
@@ -219,6 +280,7 @@ impl Record {
         Self {
             fields,
             index_keys: schema.get_cmp_keys(),
+            overflow,
         }
     }
 
@@ -231,25 +293,57 @@ impl Record {
 
         for (i, field) in self.fields.iter().enumerate() {
             let value = field;
-            let value_buf = &mut buf[offset..offset + schema.get_columns()[i].typ.size()];
+            let typ = &schema.get_columns()[i].typ;
+            let value_buf = &mut buf[offset..offset + typ.size()];
             match value {
                 Value::Null => {
                     nulls.insert(i);
                 }
+                // Int literals are widened in bigint columns
+                Value::Int(v) if typ == &Type::Bigint => {
+                    value_buf.copy_from_slice(&(*v as i64).to_le_bytes());
+                }
                 Value::Int(v) => {
                     value_buf.copy_from_slice(&v.to_le_bytes());
                 }
+                Value::Bigint(v) => {
+                    value_buf.copy_from_slice(&v.to_le_bytes());
+                }
                 Value::Float(v) => {
                     value_buf.copy_from_slice(&v.to_le_bytes());
                 }
+                // Text fields are saved as pointers to overflow pages
+                Value::Varchar(_) if typ == &Type::Text => {
+                    let &(_, page, len) = self
+                        .overflow
+                        .iter()
+                        .find(|(column, _, _)| *column == i)
+                        .expect("Text field not saved into overflow pages");
+                    value_buf[..4].copy_from_slice(&(page as u32).to_le_bytes());
+                    value_buf[4..].copy_from_slice(&(len as u32).to_le_bytes());
+                }
+                // Date and datetime strings are normalized in their columns
+                Value::Varchar(v) if typ == &Type::Date => {
+                    let v: NaiveDate = v.parse().expect("Date parse error");
+                    value_buf.copy_from_slice(v.to_string().as_bytes());
+                }
+                Value::Varchar(v) if typ == &Type::DateTime => {
+                    let v = parse_datetime(v).expect("DateTime parse error");
+                    value_buf.copy_from_slice(v.to_string().as_bytes());
+                }
                 Value::Varchar(v) => {
+                    let (len, s) = value_buf.split_at_mut(VARCHAR_PREFIX_SIZE);
+                    len.copy_from_slice(&(v.len() as u16).to_le_bytes());
                     // Fill the rest with zeros
-                    value_buf[..v.len()].copy_from_slice(v.as_bytes());
-                    value_buf[v.len()..].fill(0);
+                    s[..v.len()].copy_from_slice(v.as_bytes());
+                    s[v.len()..].fill(0);
                 }
                 Value::Date(v) => {
                     value_buf.copy_from_slice(v.to_string().as_bytes());
                 }
+                Value::DateTime(v) => {
+                    value_buf.copy_from_slice(v.to_string().as_bytes());
+                }
             }
 
             offset += schema.get_columns()[i].typ.size();
@@ -276,6 +370,14 @@ impl Record {
                         Selector::Aggregate(_, ColumnSelector(_, column)) => {
                             fields.push(self.fields[schema.get_column_index(column)].clone())
                         }
+                        Selector::Cast(Expression::Value(value), _) => {
+                            fields.push(value.clone());
+                        }
+                        Selector::Cast(
+                            Expression::Column(ColumnSelector(_, column))
+                            | Expression::Cast(ColumnSelector(_, column), _),
+                            _,
+                        ) => fields.push(self.fields[schema.get_column_index(column)].clone()),
                         Selector::Count => {
                             fields.push(Value::Int(1));
                         }
@@ -330,6 +432,24 @@ impl Record {
                             let column_index = schemas[table_index].get_column_index(column);
                             fields.push(records[table_index].fields[column_index].clone());
                         }
+                        Selector::Cast(Expression::Value(value), _) => {
+                            fields.push(value.clone());
+                        }
+                        Selector::Cast(
+                            Expression::Column(ColumnSelector(table, column))
+                            | Expression::Cast(ColumnSelector(table, column), _),
+                            _,
+                        ) => {
+                            let table = table
+                                .clone()
+                                .ok_or(Error::InexactColumn(column.to_owned()))?;
+                            let table_index = tables
+                                .iter()
+                                .position(|&t| t == table)
+                                .ok_or(Error::TableNotFound(table))?;
+                            let column_index = schemas[table_index].get_column_index(column);
+                            fields.push(records[table_index].fields[column_index].clone());
+                        }
                         Selector::Count => {
                             fields.push(Value::Int(1));
                         }
@@ -366,8 +486,23 @@ impl PartialEq for Record {
 }
 
 impl PartialOrd for Record {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.fields[..self.index_keys].partial_cmp(&other.fields[..other.index_keys])
+    /// Compare keys of records. Nulls are ordered before all other values,
+    /// so that nullable columns can be indexed.
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let keys = self.fields[..self.index_keys].iter();
+        let other_keys = other.fields[..other.index_keys].iter();
+        for (a, b) in keys.zip(other_keys) {
+            let ordering = match (a, b) {
+                (Value::Null, Value::Null) => Ordering::Equal,
+                (Value::Null, _) => Ordering::Less,
+                (_, Value::Null) => Ordering::Greater,
+                _ => a.partial_cmp(b)?,
+            };
+            if ordering.is_ne() {
+                return Some(ordering);
+            }
+        }
+        Some(self.index_keys.cmp(&other.index_keys))
     }
 }
 
@@ -375,8 +510,8 @@ impl PartialOrd for Record {
 mod tests {
     use std::path::PathBuf;
 
-    use crate::config::PAGE_SIZE;
-    use crate::schema::{Column, Schema, TableSchema, Value};
+    use crate::config::DEFAULT_PAGE_SIZE;
+    use crate::schema::{Collation, Column, Schema, TableSchema, Value};
     use crate::setup;
 
     use super::*;
@@ -390,6 +525,7 @@ mod tests {
                 pages: 0,
                 free: None,
                 full: None,
+                overflow: None,
                 columns: vec![
                     Column {
                         name: "id".to_string(),
@@ -413,12 +549,19 @@ mod tests {
                 constraints: vec![],
                 referred_constraints: vec![],
                 indexes: vec![],
+                ttl: None,
+                deleted: 0,
+                stats: None,
+                compressed: false,
+                collation: Collation::default(),
+                version: 0,
             },
             &PathBuf::new(),
+            DEFAULT_PAGE_SIZE,
         )
         .unwrap();
 
-        let mut buf = [0u8; PAGE_SIZE];
+        let mut buf = [0u8; DEFAULT_PAGE_SIZE];
         let record = Record {
             fields: vec![
                 Value::Int(1),
@@ -426,6 +569,7 @@ mod tests {
                 Value::Float(100.0),
             ],
             index_keys: 3,
+            overflow: vec![],
         };
         record.save_into(&mut buf, 0, &schema);
 
@@ -450,6 +594,7 @@ mod tests {
                 Value::Null,
             ],
             index_keys: 3,
+            overflow: vec![],
         };
         record.save_into(&mut buf, 0, &schema);
 
@@ -466,6 +611,23 @@ mod tests {
             _ => panic!("Wrong type"),
         }
         assert_eq!(record.fields[2], Value::Null);
+
+        // Trailing zeros are kept as part of the string
+        let record = Record {
+            fields: vec![
+                Value::Int(3),
+                Value::Varchar("Carol\0".to_string()),
+                Value::Null,
+            ],
+            index_keys: 3,
+            overflow: vec![],
+        };
+        record.save_into(&mut buf, 0, &schema);
+
+        let record = Record::from(&buf, 0, &schema);
+
+        assert_eq!(record.fields[1], Value::Varchar("Carol\0".to_string()));
+        assert_ne!(record.fields[1], Value::Varchar("Carol".to_string()));
     }
 
     #[test]
@@ -477,6 +639,7 @@ mod tests {
                 pages: 0,
                 free: None,
                 full: None,
+                overflow: None,
                 columns: vec![
                     Column {
                         name: "c0".to_string(),
@@ -536,12 +699,19 @@ mod tests {
                 constraints: vec![],
                 referred_constraints: vec![],
                 indexes: vec![],
+                ttl: None,
+                deleted: 0,
+                stats: None,
+                compressed: false,
+                collation: Collation::default(),
+                version: 0,
             },
             &PathBuf::new(),
+            DEFAULT_PAGE_SIZE,
         )
         .unwrap();
 
-        let mut buf = [0u8; PAGE_SIZE];
+        let mut buf = [0u8; DEFAULT_PAGE_SIZE];
         let record = Record {
             fields: vec![
                 Value::Int(123),
@@ -555,6 +725,7 @@ mod tests {
                 Value::Null,
             ],
             index_keys: 9,
+            overflow: vec![],
         };
 
         record.save_into(&mut buf, 0, &schema);
@@ -575,4 +746,188 @@ mod tests {
         assert_eq!(record.fields[7], Value::Null);
         assert_eq!(record.fields[8], Value::Null);
     }
+
+    #[test]
+    fn test_bigint() {
+        setup::init_logging();
+
+        let schema = TableSchema::new(
+            Schema {
+                pages: 0,
+                free: None,
+                full: None,
+                overflow: None,
+                columns: vec![
+                    Column {
+                        name: "a".to_string(),
+                        typ: Type::Bigint,
+                        nullable: false,
+                        default: None,
+                    },
+                    Column {
+                        name: "b".to_string(),
+                        typ: Type::Bigint,
+                        nullable: false,
+                        default: None,
+                    },
+                ],
+                constraints: vec![],
+                referred_constraints: vec![],
+                indexes: vec![],
+                ttl: None,
+                deleted: 0,
+                stats: None,
+                compressed: false,
+                collation: Collation::default(),
+                version: 0,
+            },
+            &PathBuf::new(),
+            DEFAULT_PAGE_SIZE,
+        )
+        .unwrap();
+
+        let mut buf = [0u8; DEFAULT_PAGE_SIZE];
+        let record = Record {
+            fields: vec![Value::Bigint(i64::MIN), Value::Int(-1)],
+            index_keys: 2,
+            overflow: vec![],
+        };
+        record.save_into(&mut buf, 0, &schema);
+
+        let record = Record::from(&buf, 0, &schema);
+
+        assert_eq!(record.fields[0], Value::Bigint(i64::MIN));
+        assert_eq!(record.fields[1], Value::Bigint(-1));
+        assert_eq!(record.fields[1], Value::Int(-1));
+    }
+
+    #[test]
+    fn test_datetime() {
+        setup::init_logging();
+
+        let schema = TableSchema::new(
+            Schema {
+                pages: 0,
+                free: None,
+                full: None,
+                overflow: None,
+                columns: vec![
+                    Column {
+                        name: "a".to_string(),
+                        typ: Type::DateTime,
+                        nullable: false,
+                        default: None,
+                    },
+                    Column {
+                        name: "b".to_string(),
+                        typ: Type::DateTime,
+                        nullable: false,
+                        default: None,
+                    },
+                ],
+                constraints: vec![],
+                referred_constraints: vec![],
+                indexes: vec![],
+                ttl: None,
+                deleted: 0,
+                stats: None,
+                compressed: false,
+                collation: Collation::default(),
+                version: 0,
+            },
+            &PathBuf::new(),
+            DEFAULT_PAGE_SIZE,
+        )
+        .unwrap();
+
+        let mut buf = [0u8; DEFAULT_PAGE_SIZE];
+        let record = Record {
+            fields: vec![
+                Value::from("2023-01-02 03:04:05", &Type::DateTime).unwrap(),
+                Value::Varchar("2023-1-2 3:4:5.678".to_string()),
+            ],
+            index_keys: 2,
+            overflow: vec![],
+        };
+        record.save_into(&mut buf, 0, &schema);
+
+        let record = Record::from(&buf, 0, &schema);
+
+        assert_eq!(record.fields[0], record.fields[1]);
+        assert_eq!(record.fields[0].to_string(), "2023-01-02 03:04:05");
+        assert!(record.fields[0] > Value::Varchar("2023-01-02".to_string()));
+    }
+
+    #[test]
+    fn test_text() {
+        setup::init_logging();
+
+        let schema = TableSchema::new(
+            Schema {
+                pages: 0,
+                free: None,
+                full: None,
+                overflow: None,
+                columns: vec![
+                    Column {
+                        name: "a".to_string(),
+                        typ: Type::Int,
+                        nullable: false,
+                        default: None,
+                    },
+                    Column {
+                        name: "b".to_string(),
+                        typ: Type::Text,
+                        nullable: true,
+                        default: None,
+                    },
+                ],
+                constraints: vec![],
+                referred_constraints: vec![],
+                indexes: vec![],
+                ttl: None,
+                deleted: 0,
+                stats: None,
+                compressed: false,
+                collation: Collation::default(),
+                version: 0,
+            },
+            &PathBuf::new(),
+            DEFAULT_PAGE_SIZE,
+        )
+        .unwrap();
+
+        let mut buf = [0u8; DEFAULT_PAGE_SIZE];
+        let record = Record {
+            fields: vec![Value::Int(1), Value::Varchar("a".repeat(10000))],
+            index_keys: 2,
+            overflow: vec![(1, 42, 10000)],
+        };
+        record.save_into(&mut buf, 0, &schema);
+
+        let record = Record::from(&buf, 0, &schema);
+
+        assert_eq!(record.fields[0], Value::Int(1));
+        assert_eq!(record.fields[1], Value::Varchar(String::new()));
+        assert_eq!(record.overflow, vec![(1, 42, 10000)]);
+    }
+
+    #[test]
+    fn test_null_order() {
+        setup::init_logging();
+
+        // Keys of a nullable unique column are totally ordered, NULLs first
+        let null = Record::new(vec![Value::Null]);
+        let one = Record::new(vec![Value::Int(1)]);
+        assert_eq!(null.partial_cmp(&one), Some(Ordering::Less));
+        assert_eq!(one.partial_cmp(&null), Some(Ordering::Greater));
+        assert_eq!(null.partial_cmp(&null), Some(Ordering::Equal));
+
+        // Compared field by field, then a prefix before longer keys
+        let key = Record::new(vec![Value::Int(1), Value::Null]);
+        let longer = Record::new(vec![Value::Int(1), Value::Null, Value::Int(0)]);
+        assert!(key > Record::new(vec![Value::Null, Value::Int(2)]));
+        assert!(key < Record::new(vec![Value::Int(1), Value::Int(0)]));
+        assert!(key < longer);
+    }
 }
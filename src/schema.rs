@@ -1,20 +1,22 @@
 //! Table schema.
 
 use std::{
+    borrow::Cow,
     cmp::Ordering,
     collections::HashMap,
     fmt::{self, Display, Formatter},
-    fs::File,
+    fs::{self, File},
     hash::{Hash, Hasher},
-    ops::{Add, Div},
+    ops::Div,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
-use chrono::NaiveDate;
+use chrono::{NaiveDate, NaiveDateTime, Timelike};
 use regex::RegexBuilder;
 use serde::{Deserialize, Serialize};
 
-use crate::config::{LINK_SIZE, PAGE_SIZE};
+use crate::config::{DELETED_TIME_SIZE, LINK_SIZE, VARCHAR_PREFIX_SIZE};
 use crate::error::{Error, Result};
 use crate::index::IndexSchema;
 use crate::record::Record;
@@ -24,9 +26,12 @@ use crate::record::RecordSchema;
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
 pub enum Type {
     Int,
+    Bigint,
     Float,
     Varchar(usize),
     Date,
+    DateTime,
+    Text,
 }
 
 impl Type {
@@ -34,9 +39,14 @@ impl Type {
     pub fn size(&self) -> usize {
         match self {
             Type::Int => 4,
+            Type::Bigint => 8,
             Type::Float => 8,
-            Type::Varchar(len) => *len,
+            // Length prefix followed by the string
+            Type::Varchar(len) => VARCHAR_PREFIX_SIZE + *len,
             Type::Date => 10,
+            Type::DateTime => 19,
+            // Page and length of the overflow pages
+            Type::Text => 8,
         }
     }
 }
@@ -45,9 +55,12 @@ impl Display for Type {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
             Type::Int => write!(f, "INT"),
+            Type::Bigint => write!(f, "BIGINT"),
             Type::Float => write!(f, "FLOAT"),
             Type::Varchar(len) => write!(f, "VARCHAR({})", len),
             Type::Date => write!(f, "DATE"),
+            Type::DateTime => write!(f, "DATETIME"),
+            Type::Text => write!(f, "TEXT"),
         }
     }
 }
@@ -57,9 +70,11 @@ impl Display for Type {
 pub enum Value {
     Null,
     Int(i32),
+    Bigint(i64),
     Float(f64),
     Varchar(String),
     Date(NaiveDate),
+    DateTime(NaiveDateTime),
 }
 
 impl PartialEq for Value {
@@ -67,31 +82,44 @@ impl PartialEq for Value {
         match (self, other) {
             (Value::Null, Value::Null) => true,
             (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Bigint(a), Value::Bigint(b)) => a == b,
             (Value::Float(a), Value::Float(b)) => a == b,
-            (Value::Varchar(a), Value::Varchar(b)) => {
-                a.trim_end_matches('\0') == b.trim_end_matches('\0')
-            }
+            (Value::Varchar(a), Value::Varchar(b)) => a == b,
             (Value::Date(a), Value::Date(b)) => a == b,
-            // Weak type: string ang date
-            (Value::Varchar(a), Value::Date(b)) => a.trim_end_matches('\0') == b.to_string(),
-            (Value::Date(a), Value::Varchar(b)) => a.to_string() == b.trim_end_matches('\0'),
-            _ => false,
+            (Value::DateTime(a), Value::DateTime(b)) => a == b,
+            _ => self.unify(other).is_some_and(|(a, b)| a == b),
         }
     }
 }
 
+/// Values equal across types are hashed in the common type they are compared in,
+/// so that equal values always hash the same.
 impl Hash for Value {
     fn hash<H: Hasher>(&self, state: &mut H) {
         match self {
             Value::Null => 0.hash(state),
-            Value::Int(v) => v.hash(state),
-            Value::Float(v) => v.to_bits().hash(state),
-            Value::Varchar(v) => v.trim_end_matches('\0').hash(state),
-            Value::Date(v) => v.hash(state),
+            // Hashed as float, since int, bigint and float are compared as floats
+            Value::Int(v) => hash_float(*v as f64, state),
+            Value::Bigint(v) => hash_float(*v as f64, state),
+            Value::Float(v) => hash_float(*v, state),
+            // Hashed as datetime if it is one, since strings equal dates and datetimes
+            Value::Varchar(v) => match parse_datetime(v) {
+                Ok(datetime) => datetime.hash(state),
+                Err(_) => v.hash(state),
+            },
+            // Hashed as datetime, since date and datetime may equal
+            Value::Date(v) => v.and_hms_opt(0, 0, 0).unwrap().hash(state),
+            Value::DateTime(v) => v.hash(state),
         }
     }
 }
 
+/// Hash a float, with zeros of both signs hashed the same.
+fn hash_float<H: Hasher>(v: f64, state: &mut H) {
+    let v = if v == 0.0 { 0.0 } else { v };
+    v.to_bits().hash(state)
+}
+
 impl Eq for Value {}
 
 impl PartialOrd for Value {
@@ -99,19 +127,12 @@ impl PartialOrd for Value {
         match (self, other) {
             (Value::Null, Value::Null) => Some(Ordering::Equal),
             (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
+            (Value::Bigint(a), Value::Bigint(b)) => a.partial_cmp(b),
             (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
-            (Value::Varchar(a), Value::Varchar(b)) => a
-                .trim_end_matches('\0')
-                .partial_cmp(b.trim_end_matches('\0')),
+            (Value::Varchar(a), Value::Varchar(b)) => a.partial_cmp(b),
             (Value::Date(a), Value::Date(b)) => a.partial_cmp(b),
-            // Weak type: string ang date
-            (Value::Varchar(a), Value::Date(b)) => {
-                a.trim_end_matches('\0').partial_cmp(&b.to_string())
-            }
-            (Value::Date(a), Value::Varchar(b)) => {
-                a.to_string().as_str().partial_cmp(b.trim_end_matches('\0'))
-            }
-            _ => None,
+            (Value::DateTime(a), Value::DateTime(b)) => a.partial_cmp(b),
+            _ => self.unify(other).and_then(|(a, b)| a.partial_cmp(&b)),
         }
     }
 }
@@ -121,27 +142,109 @@ impl Value {
     pub fn from(s: &str, typ: &Type) -> Result<Self> {
         match typ {
             Type::Int => Ok(Value::Int(s.parse()?)),
+            Type::Bigint => Ok(Value::Bigint(s.parse()?)),
             Type::Float => Ok(Value::Float(s.parse()?)),
-            Type::Varchar(_) => Ok(Value::Varchar(s.to_owned())),
+            Type::Varchar(_) | Type::Text => Ok(Value::Varchar(s.to_owned())),
             Type::Date => Ok(Value::Date(s.parse()?)),
+            Type::DateTime => Ok(Value::DateTime(parse_datetime(s)?)),
         }
     }
 
-    /// Check if the value matches the type.
+    /// Check if the value is exactly of the type, without any coercion.
     pub fn check_type(&self, typ: &Type) -> bool {
         matches!(
             (self, typ),
             (Value::Null, _)
                 | (Value::Int(_), Type::Int)
+                | (Value::Bigint(_), Type::Bigint)
                 | (Value::Float(_), Type::Float)
                 | (Value::Date(_), Type::Date)
+                | (Value::DateTime(_), Type::DateTime)
+                | (Value::Varchar(_), Type::Text)
         ) || matches!(
             (self, typ), (Value::Varchar(a), Type::Varchar(len)) if a.len() <= *len
-        ) || matches!(
-            (self, typ), (Value::Varchar(a) , Type::Date) if a.parse::<NaiveDate>().is_ok()
         )
     }
 
+    /// Implicitly coerce the value into a type, as in comparisons, inserts and set clauses.
+    ///
+    /// Only widening coercions are applied: ints into bigints and floats, bigints into floats,
+    /// strings into dates and datetimes, and dates into datetimes.
+    ///
+    /// # Error
+    ///
+    /// Returns `TypeMismatch` if the value can't be implicitly coerced into the type.
+    pub fn coerce(&self, typ: &Type) -> Result<Self> {
+        match (self, typ) {
+            (Value::Int(_), Type::Bigint | Type::Float)
+            | (Value::Bigint(_), Type::Float)
+            | (Value::Varchar(_) | Value::Date(_), Type::Date | Type::DateTime) => {
+                self.convert(typ)
+            }
+            _ if self.check_type(typ) => Ok(self.clone()),
+            _ => Err(Error::TypeMismatch(self.clone(), typ.clone())),
+        }
+    }
+
+    /// Coerce two values of different types into a common type, so that they can be compared.
+    ///
+    /// Returns `None` if there is no common type, or the coercion fails.
+    fn unify(&self, other: &Self) -> Option<(Self, Self)> {
+        let typ = match (self, other) {
+            (Value::Int(_), Value::Bigint(_)) | (Value::Bigint(_), Value::Int(_)) => Type::Bigint,
+            (Value::Int(_) | Value::Bigint(_), Value::Float(_))
+            | (Value::Float(_), Value::Int(_) | Value::Bigint(_)) => Type::Float,
+            (Value::Varchar(_), Value::Date(_)) | (Value::Date(_), Value::Varchar(_)) => Type::Date,
+            (Value::Varchar(_) | Value::Date(_), Value::DateTime(_))
+            | (Value::DateTime(_), Value::Varchar(_) | Value::Date(_)) => Type::DateTime,
+            _ => return None,
+        };
+        Some((self.coerce(&typ).ok()?, other.coerce(&typ).ok()?))
+    }
+
+    /// Convert the value into another type.
+    ///
+    /// # Error
+    ///
+    /// Returns `TypeMismatch` if the value can't be represented in the new type.
+    pub fn convert(&self, typ: &Type) -> Result<Self> {
+        let mismatch = || Error::TypeMismatch(self.clone(), typ.clone());
+        let ret = match (self, typ) {
+            (Value::Null, _) => Value::Null,
+            (Value::Int(v), Type::Float) => Value::Float(*v as f64),
+            (Value::Int(v), Type::Bigint) => Value::Bigint(*v as i64),
+            (Value::Bigint(v), Type::Float) => Value::Float(*v as f64),
+            (Value::Bigint(v), Type::Int) => Value::Int(i32::try_from(*v).map_err(|_| mismatch())?),
+            (Value::Float(v), Type::Int) => {
+                if v.fract() != 0.0 || *v < i32::MIN as f64 || *v > i32::MAX as f64 {
+                    return Err(mismatch());
+                }
+                Value::Int(*v as i32)
+            }
+            (Value::Float(v), Type::Bigint) => {
+                if v.fract() != 0.0 || *v < i64::MIN as f64 || *v >= i64::MAX as f64 {
+                    return Err(mismatch());
+                }
+                Value::Bigint(*v as i64)
+            }
+            (
+                Value::Varchar(v),
+                Type::Int | Type::Bigint | Type::Float | Type::Date | Type::DateTime,
+            ) => Value::from(v, typ).map_err(|_| mismatch())?,
+            (Value::Int(v), Type::Varchar(_) | Type::Text) => Value::Varchar(v.to_string()),
+            (Value::Bigint(v), Type::Varchar(_) | Type::Text) => Value::Varchar(v.to_string()),
+            (Value::Float(v), Type::Varchar(_) | Type::Text) => Value::Varchar(v.to_string()),
+            (Value::Date(v), Type::Varchar(_) | Type::Text) => Value::Varchar(v.to_string()),
+            (Value::Date(v), Type::DateTime) => Value::DateTime(v.and_hms_opt(0, 0, 0).unwrap()),
+            (Value::DateTime(v), Type::Varchar(_) | Type::Text) => Value::Varchar(v.to_string()),
+            _ => self.clone(),
+        };
+        if !ret.check_type(typ) {
+            return Err(mismatch());
+        }
+        Ok(ret)
+    }
+
     /// Compare with other value, and return the smaller one.
     pub fn min<'a>(&'a self, other: &'a Self) -> &'a Self {
         match (self, other) {
@@ -154,6 +257,13 @@ impl Value {
                     other
                 }
             }
+            (Value::Bigint(a), Value::Bigint(b)) => {
+                if a < b {
+                    self
+                } else {
+                    other
+                }
+            }
             (Value::Float(a), Value::Float(b)) => {
                 if a < b {
                     self
@@ -168,6 +278,13 @@ impl Value {
                     other
                 }
             }
+            (Value::DateTime(a), Value::DateTime(b)) => {
+                if a < b {
+                    self
+                } else {
+                    other
+                }
+            }
             _ => self,
         }
     }
@@ -184,6 +301,13 @@ impl Value {
                     other
                 }
             }
+            (Value::Bigint(a), Value::Bigint(b)) => {
+                if a > b {
+                    self
+                } else {
+                    other
+                }
+            }
             (Value::Float(a), Value::Float(b)) => {
                 if a > b {
                     self
@@ -198,20 +322,33 @@ impl Value {
                     other
                 }
             }
+            (Value::DateTime(a), Value::DateTime(b)) => {
+                if a > b {
+                    self
+                } else {
+                    other
+                }
+            }
             _ => self,
         }
     }
 }
 
-impl Add for Value {
-    type Output = Value;
-
-    fn add(self, rhs: Self) -> Self::Output {
+impl Value {
+    /// Add two values up for SUM, with integers widened into BIGINT.
+    ///
+    /// Returns an error if the sum is out of range.
+    pub fn checked_add(self, rhs: Self) -> Result<Value> {
         match (self, rhs) {
-            (Value::Int(a), Value::Int(b)) => Value::Int(a + b),
-            (Value::Float(a), Value::Float(b)) => Value::Float(a + b),
-            (Value::Varchar(a), Value::Varchar(b)) => Value::Varchar(a + &b),
-            _ => Value::Null,
+            (Value::Int(a), b) => Value::Bigint(a.into()).checked_add(b),
+            (a, Value::Int(b)) => a.checked_add(Value::Bigint(b.into())),
+            (Value::Bigint(a), Value::Bigint(b)) => a
+                .checked_add(b)
+                .map(Value::Bigint)
+                .ok_or(Error::SumOverflow),
+            (Value::Float(a), Value::Float(b)) => Ok(Value::Float(a + b)),
+            (Value::Varchar(a), Value::Varchar(b)) => Ok(Value::Varchar(a + &b)),
+            (a, b) => Err(Error::SumTypeMismatch(a, b)),
         }
     }
 }
@@ -222,24 +359,72 @@ impl Div<usize> for Value {
     fn div(self, rhs: usize) -> Self::Output {
         match self {
             Value::Int(v) => Value::Float(v as f64 / rhs as f64),
+            Value::Bigint(v) => Value::Float(v as f64 / rhs as f64),
             Value::Float(v) => Value::Float(v / rhs as f64),
             _ => Value::Null,
         }
     }
 }
 
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Varchar(value.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Varchar(value)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(value: usize) -> Self {
+        Value::Bigint(value as i64)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(value: u64) -> Self {
+        Value::Bigint(value as i64)
+    }
+}
+
 impl Display for Value {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
             Value::Null => write!(f, "NULL"),
             Value::Int(v) => write!(f, "{v}"),
+            Value::Bigint(v) => write!(f, "{v}"),
             Value::Float(v) => write!(f, "{v:.2}"),
-            Value::Varchar(v) => write!(f, "{}", v.trim_end_matches('\0')),
+            Value::Varchar(v) => write!(f, "{}", v),
             Value::Date(v) => write!(f, "{}", v),
+            Value::DateTime(v) => write!(f, "{}", v),
         }
     }
 }
 
+/// Parse a datetime in the form of `YYYY-MM-DD HH:MM:SS`.
+///
+/// A date without time is taken as midnight, and fractional seconds are truncated.
+pub fn parse_datetime(s: &str) -> Result<NaiveDateTime> {
+    let datetime = match NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f") {
+        Ok(datetime) => datetime,
+        Err(err) => match s.parse::<NaiveDate>() {
+            Ok(date) => date.and_hms_opt(0, 0, 0).unwrap(),
+            Err(_) => return Err(err.into()),
+        },
+    };
+    let datetime = datetime.with_nanosecond(0).unwrap();
+    if datetime.to_string().len() != Type::DateTime.size() {
+        return Err(Error::TypeMismatch(
+            Value::Varchar(s.to_owned()),
+            Type::DateTime,
+        ));
+    }
+    Ok(datetime)
+}
+
 /// A column in a table.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Column {
@@ -251,11 +436,7 @@ pub struct Column {
 
 impl Column {
     pub fn new(name: String, typ: Type, nullable: bool, default: Option<Value>) -> Result<Self> {
-        if let Some(value) = &default {
-            if !value.check_type(&typ) {
-                return Err(Error::TypeMismatch(value.clone(), typ));
-            }
-        }
+        let default = default.map(|value| value.coerce(&typ)).transpose()?;
 
         Ok(Self {
             name,
@@ -308,6 +489,10 @@ impl Constraint {
                     if !schema.has_column(column) {
                         return Err(Error::ColumnNotFound(column.clone()));
                     }
+                    if schema.get_column(column).typ == Type::Text {
+                        return Err(Error::TextIndex(column.clone()));
+                    }
+                    schema.collation.check_unique(schema.get_column(column))?;
                 }
             }
             Self::ForeignKey {
@@ -331,29 +516,18 @@ impl Constraint {
                     }
                     let column0 = schema0.get_column(column0);
                     let column1 = schema1.get_column(column1);
+                    if column0.typ == Type::Text {
+                        return Err(Error::TextIndex(column0.name.clone()));
+                    }
                     if column0.typ != column1.typ {
                         return Err(Error::ForeignKeyTypeMismatch);
                     }
                 }
 
-                // Requires the referenced keys be primary key
-                let pk = schema1
-                    .constraints
-                    .iter()
-                    .find(|c| matches!(c, Constraint::PrimaryKey { .. }))
-                    .ok_or(Error::ForeignKeyNotPrimaryKey)?;
-
-                let pk_columns = pk.get_columns();
-
-                if ref_columns.len() != pk_columns.len() {
+                // Requires the referenced keys be primary key or unique
+                if !schema1.constraints.iter().any(|c| c.is_key(ref_columns)) {
                     return Err(Error::ForeignKeyNotPrimaryKey);
                 }
-
-                for column in ref_columns {
-                    if !pk_columns.contains(column) {
-                        return Err(Error::ForeignKeyNotPrimaryKey);
-                    }
-                }
             }
         }
         Ok(())
@@ -377,6 +551,48 @@ impl Constraint {
         }
     }
 
+    /// Check if this is a primary key or unique constraint on exactly these columns.
+    pub fn is_key(&self, columns: &[String]) -> bool {
+        match self {
+            Self::PrimaryKey { columns: key, .. } | Self::Unique { columns: key, .. } => {
+                key.len() == columns.len() && columns.iter().all(|c| key.contains(c))
+            }
+            Self::ForeignKey { .. } => false,
+        }
+    }
+
+    /// Reorder the columns of a foreign key to follow the primary key or unique
+    /// constraint it references, so that its keys could be looked up in the
+    /// index of the referenced key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the constraint is not a foreign key referencing one of the keys.
+    pub fn align_to_key(&mut self, keys: &[Constraint]) {
+        let Self::ForeignKey {
+            columns,
+            ref_columns,
+            ..
+        } = self
+        else {
+            panic!("Constraint is not a foreign key");
+        };
+        let key = keys
+            .iter()
+            .find(|key| key.is_key(ref_columns))
+            .expect("Foreign key references no key");
+        let (aligned, ref_aligned) = key
+            .get_columns()
+            .iter()
+            .map(|column| {
+                let i = ref_columns.iter().position(|c| c == column).unwrap();
+                (columns[i].clone(), column.clone())
+            })
+            .unzip();
+        *columns = aligned;
+        *ref_columns = ref_aligned;
+    }
+
     /// Get the display name of this constraint.
     pub fn get_display_name(&self) -> String {
         self.get_name().unwrap_or("<anonymous>").to_owned()
@@ -527,6 +743,7 @@ impl Selectors {
                                 return Err(Error::ColumnNotFound(column.clone()));
                             }
                         }
+                        Selector::Cast(expr, _) => expr.check(schema)?,
                         Selector::Count => {}
                     }
                 }
@@ -553,6 +770,7 @@ impl Selectors {
                         Selector::Aggregate(_, column_selector) => {
                             column_selector.check_tables(schemas, tables)?;
                         }
+                        Selector::Cast(expr, _) => expr.check_tables(schemas, tables)?,
                         Selector::Count => {}
                     }
                 }
@@ -562,22 +780,184 @@ impl Selectors {
     }
 }
 
-/// Column selector in the form table.column,
-/// where table part is optional
+/// Schema of query results, with a column for each selector.
+///
+/// It is planned before a select statement is executed, and carried along with the results,
+/// so that titles, grouping, aggregation and ordering agree on positions of fields.
 #[derive(Clone, Debug)]
-pub struct ColumnSelector(pub Option<String>, pub String);
+pub struct OutputSchema {
+    /// Selectors producing the fields.
+    selectors: Vec<Selector>,
+    /// Columns of the fields, named after the selectors.
+    columns: Vec<Column>,
+}
 
-impl PartialEq for ColumnSelector {
-    fn eq(&self, other: &Self) -> bool {
-        if let (Some(table0), Some(table1)) = (&self.0, &other.0) {
-            table0 == table1 && self.1 == other.1
-        } else {
-            self.1 == other.1
+impl OutputSchema {
+    /// Plan the output schema of selectors on some tables.
+    ///
+    /// `*` is expanded into all columns of the tables in order. When selecting from
+    /// multiple tables, the expanded columns are named with their tables.
+    pub fn new(selectors: &Selectors, schemas: &[&TableSchema], tables: &[&str]) -> Result<Self> {
+        let mut ret = Self {
+            selectors: vec![],
+            columns: vec![],
+        };
+        match selectors {
+            Selectors::All => {
+                for (schema, table) in schemas.iter().zip(tables) {
+                    let table = (tables.len() > 1).then(|| table.to_string());
+                    for column in schema.get_columns() {
+                        let column = ColumnSelector(table.clone(), column.name.clone());
+                        ret.push(Selector::Column(column), schemas, tables)?;
+                    }
+                }
+            }
+            Selectors::Some(selectors) => {
+                for selector in selectors {
+                    ret.push(selector.clone(), schemas, tables)?;
+                }
+            }
         }
+        Ok(ret)
+    }
+
+    /// Add a selector as the last field, and return its position.
+    ///
+    /// The selector is qualified with its table, while the field is named as written.
+    ///
+    /// # Error
+    ///
+    /// Returns error if the selector does not refer to exactly one column of the tables.
+    pub fn push(
+        &mut self,
+        selector: Selector,
+        schemas: &[&TableSchema],
+        tables: &[&str],
+    ) -> Result<usize> {
+        let name = selector.to_string();
+        let selector = selector.qualify(schemas, tables)?;
+        let column = match &selector {
+            Selector::Column(column_selector) => {
+                let column = column_selector.resolve(schemas, tables)?;
+                Column {
+                    name,
+                    typ: column.typ.clone(),
+                    nullable: column.nullable,
+                    default: None,
+                }
+            }
+            Selector::Aggregate(aggregator, column_selector) => {
+                let column = column_selector.resolve(schemas, tables)?;
+                let typ = match aggregator {
+                    Aggregator::Avg => Type::Float,
+                    Aggregator::Sum if column.typ == Type::Int => Type::Bigint,
+                    _ => column.typ.clone(),
+                };
+                Column {
+                    name,
+                    typ,
+                    nullable: true,
+                    default: None,
+                }
+            }
+            Selector::Cast(expr, typ) => Column {
+                name,
+                typ: typ.clone(),
+                nullable: expr.nullable(schemas, tables)?,
+                default: None,
+            },
+            Selector::Count => Column {
+                name,
+                typ: Type::Int,
+                nullable: false,
+                default: None,
+            },
+        };
+        self.selectors.push(selector);
+        self.columns.push(column);
+        Ok(self.columns.len() - 1)
+    }
+
+    /// Keep only the first `len` fields.
+    pub fn truncate(&mut self, len: usize) {
+        self.selectors.truncate(len);
+        self.columns.truncate(len);
+    }
+
+    /// Get the selector producing each field.
+    pub fn get_selectors(&self) -> &[Selector] {
+        &self.selectors
+    }
+
+    /// Get titles of the fields.
+    pub fn get_titles(&self) -> Vec<String> {
+        self.columns.iter().map(|c| c.name.clone()).collect()
+    }
+
+    /// Check if any field is aggregated.
+    pub fn has_aggregate(&self) -> bool {
+        self.selectors
+            .iter()
+            .any(|s| matches!(s, Selector::Aggregate(..) | Selector::Count))
+    }
+}
+
+impl RecordSchema for OutputSchema {
+    fn get_columns(&self) -> &[Column] {
+        &self.columns
     }
 }
 
+/// Column selector in the form table.column,
+/// where table part is optional
+///
+/// Selectors are compared as they are written, so they should be qualified
+/// with `qualify` before comparing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnSelector(pub Option<String>, pub String);
+
 impl ColumnSelector {
+    /// Find the table containing the column referred to by this selector.
+    ///
+    /// # Error
+    ///
+    /// Returns `InexactColumn` if the table part is omitted while multiple tables have the column.
+    fn find_table(&self, schemas: &[&TableSchema], tables: &[&str]) -> Result<usize> {
+        let ColumnSelector(table, column) = self;
+        let i = match table {
+            Some(table) => tables
+                .iter()
+                .position(|t| t == table)
+                .ok_or_else(|| Error::TableNotFound(table.clone()))?,
+            None => {
+                let mut found = (0..schemas.len()).filter(|&i| schemas[i].has_column(column));
+                match (found.next(), found.next()) {
+                    (Some(i), None) => i,
+                    (Some(_), Some(_)) => return Err(Error::InexactColumn(column.clone())),
+                    (None, _) => return Err(Error::ColumnNotFound(column.clone())),
+                }
+            }
+        };
+        if !schemas[i].has_column(column) {
+            return Err(Error::ColumnNotFound(column.clone()));
+        }
+        Ok(i)
+    }
+
+    /// Find the column referred to by this selector in some tables.
+    pub fn resolve<'a>(&self, schemas: &[&'a TableSchema], tables: &[&str]) -> Result<&'a Column> {
+        let i = self.find_table(schemas, tables)?;
+        Ok(schemas[i].get_column(&self.1))
+    }
+
+    /// Make the selector explicit about its table.
+    ///
+    /// The table part may be omitted if only one of the tables has the column.
+    pub fn qualify(&self, schemas: &[&TableSchema], tables: &[&str]) -> Result<Self> {
+        let i = self.find_table(schemas, tables)?;
+        Ok(ColumnSelector(Some(tables[i].to_owned()), self.1.clone()))
+    }
+
     /// Check the column selector against some table schemas.
     ///
     /// # Error
@@ -606,6 +986,15 @@ impl ColumnSelector {
     }
 }
 
+impl Display for ColumnSelector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(table) = &self.0 {
+            write!(f, "{}.", table)?;
+        }
+        write!(f, "{}", self.1)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum Aggregator {
     Avg,
@@ -615,11 +1004,15 @@ pub enum Aggregator {
 }
 
 impl Aggregator {
-    pub fn aggregate(&self, values: Vec<Value>) -> Value {
-        match self {
+    /// Aggregate some values. NULLs are skipped, and the result is NULL if no value is left.
+    ///
+    /// Integers are summed up as BIGINT, and an error is returned on overflow.
+    pub fn aggregate(&self, values: Vec<Value>) -> Result<Value> {
+        let values: Vec<_> = values.into_iter().filter(|v| v != &Value::Null).collect();
+        Ok(match self {
             Aggregator::Avg => {
                 let len = values.len();
-                let sum = Self::Sum.aggregate(values);
+                let sum = Self::Sum.aggregate(values)?;
                 sum / len
             }
             Aggregator::Min => values
@@ -632,8 +1025,16 @@ impl Aggregator {
                 .reduce(Value::max)
                 .cloned()
                 .unwrap_or(Value::Null),
-            Aggregator::Sum => values.into_iter().reduce(Add::add).unwrap_or(Value::Null),
-        }
+            Aggregator::Sum => {
+                let mut values = values.into_iter();
+                let first = match values.next() {
+                    Some(Value::Int(v)) => Value::Bigint(v.into()),
+                    Some(first) => first,
+                    None => return Ok(Value::Null),
+                };
+                values.try_fold(first, Value::checked_add)?
+            }
+        })
     }
 }
 
@@ -653,9 +1054,38 @@ impl Display for Aggregator {
 pub enum Selector {
     Column(ColumnSelector),
     Aggregate(Aggregator, ColumnSelector),
+    Cast(Expression, Type),
     Count,
 }
 
+impl Selector {
+    /// Make the selector explicit about its table.
+    pub fn qualify(&self, schemas: &[&TableSchema], tables: &[&str]) -> Result<Self> {
+        Ok(match self {
+            Selector::Column(column) => Selector::Column(column.qualify(schemas, tables)?),
+            Selector::Aggregate(aggregator, column) => {
+                Selector::Aggregate(aggregator.clone(), column.qualify(schemas, tables)?)
+            }
+            Selector::Cast(expr, typ) => {
+                Selector::Cast(expr.qualify(schemas, tables)?, typ.clone())
+            }
+            Selector::Count => Selector::Count,
+        })
+    }
+
+    /// Convert a field selected by this selector.
+    ///
+    /// Records hold the operand of a cast, which is converted after selection,
+    /// since the conversion may fail.
+    pub fn cast(&self, value: &Value) -> Result<Value> {
+        match self {
+            Selector::Cast(Expression::Cast(_, inner), typ) => value.convert(inner)?.convert(typ),
+            Selector::Cast(_, typ) => value.convert(typ),
+            _ => Ok(value.clone()),
+        }
+    }
+}
+
 impl Display for Selector {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
@@ -673,6 +1103,7 @@ impl Display for Selector {
                 write!(f, "{}", column)?;
                 write!(f, ")")?;
             }
+            Selector::Cast(expr, typ) => write!(f, "CAST({expr} AS {typ})")?,
             Selector::Count => write!(f, "COUNT(*)")?,
         }
         Ok(())
@@ -684,8 +1115,8 @@ impl Display for Selector {
 pub struct SetPair(pub String, pub Value);
 
 impl SetPair {
-    /// Check the set pair against a table schema.
-    pub fn check(&self, schema: &TableSchema) -> Result<()> {
+    /// Check the set pair against a table schema, and coerce the value into the column type.
+    pub fn coerce(&self, schema: &TableSchema) -> Result<Self> {
         let SetPair(column, value) = &self;
         if !schema.has_column(column) {
             return Err(Error::ColumnNotFound(column.to_owned()));
@@ -694,14 +1125,63 @@ impl SetPair {
         if !column.nullable && matches!(value, Value::Null) {
             return Err(Error::NotNullable(column.name.clone()));
         }
-        let typ = &column.typ;
-        if !value.check_type(typ) {
-            return Err(Error::TypeMismatch(value.clone(), typ.clone()));
+        Ok(SetPair(column.name.clone(), value.coerce(&column.typ)?))
+    }
+}
+
+/// Rule of comparing strings, set per database and copied into new tables.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Collation {
+    /// Strings are compared by bytes.
+    #[default]
+    Binary,
+    /// Strings are compared ignoring case.
+    NoCase,
+}
+
+impl Collation {
+    /// Fold a value into the form compared under this collation.
+    pub fn fold<'a>(&self, value: &'a Value) -> Cow<'a, Value> {
+        match (self, value) {
+            (Collation::NoCase, Value::Varchar(v)) => Cow::Owned(Value::Varchar(v.to_lowercase())),
+            _ => Cow::Borrowed(value),
+        }
+    }
+
+    /// Check that a column could be a key of a primary key or unique index.
+    ///
+    /// Keys are compared by bytes in indexes, so strings differing only in case
+    /// could not be told equal under the NOCASE collation.
+    pub fn check_unique(&self, column: &Column) -> Result<()> {
+        if *self == Collation::NoCase && matches!(column.typ, Type::Varchar(_)) {
+            return Err(Error::CaseInsensitiveKey(column.name.clone()));
         }
         Ok(())
     }
 }
 
+impl FromStr for Collation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "binary" => Ok(Collation::Binary),
+            "nocase" => Ok(Collation::NoCase),
+            _ => Err(Error::UnknownCollation(s.to_owned())),
+        }
+    }
+}
+
+impl Display for Collation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Collation::Binary => write!(f, "binary"),
+            Collation::NoCase => write!(f, "nocase"),
+        }
+    }
+}
+
 /// SQL operator.
 #[derive(Clone, Debug)]
 pub enum Operator {
@@ -718,13 +1198,72 @@ pub enum Operator {
 pub enum Expression {
     Value(Value),
     Column(ColumnSelector),
+    Cast(ColumnSelector, Type),
+}
+
+impl Expression {
+    /// Check the expression against a table schema.
+    pub fn check(&self, schema: &TableSchema) -> Result<()> {
+        match self {
+            Expression::Value(_) => Ok(()),
+            Expression::Column(ColumnSelector(_, column))
+            | Expression::Cast(ColumnSelector(_, column), _) => {
+                if !schema.has_column(column) {
+                    return Err(Error::ColumnNotFound(column.clone()));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Check the expression against some tables.
+    pub fn check_tables(&self, schemas: &[&TableSchema], tables: &[&str]) -> Result<()> {
+        match self {
+            Expression::Value(_) => Ok(()),
+            Expression::Column(column_selector) | Expression::Cast(column_selector, _) => {
+                column_selector.check_tables(schemas, tables)
+            }
+        }
+    }
+
+    /// Make the column selector in the expression explicit about its table.
+    pub fn qualify(&self, schemas: &[&TableSchema], tables: &[&str]) -> Result<Self> {
+        Ok(match self {
+            Expression::Value(_) => self.clone(),
+            Expression::Column(column) => Expression::Column(column.qualify(schemas, tables)?),
+            Expression::Cast(column, typ) => {
+                Expression::Cast(column.qualify(schemas, tables)?, typ.clone())
+            }
+        })
+    }
+
+    /// Check if the expression may evaluate to NULL.
+    pub fn nullable(&self, schemas: &[&TableSchema], tables: &[&str]) -> Result<bool> {
+        Ok(match self {
+            Expression::Value(value) => value == &Value::Null,
+            Expression::Column(column) | Expression::Cast(column, _) => {
+                column.resolve(schemas, tables)?.nullable
+            }
+        })
+    }
+}
+
+impl Display for Expression {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Expression::Value(Value::Varchar(v)) => write!(f, "'{v}'"),
+            Expression::Value(v) => write!(f, "{v}"),
+            Expression::Column(column) => write!(f, "{column}"),
+            Expression::Cast(column, typ) => write!(f, "CAST({column} AS {typ})"),
+        }
+    }
 }
 
 /// Where clause.
 #[derive(Clone, Debug)]
 pub enum WhereClause {
     OperatorExpression(ColumnSelector, Operator, Expression),
-    LikeString(ColumnSelector, String),
+    LikeString(ColumnSelector, String, bool),
     IsNull(ColumnSelector, bool),
 }
 
@@ -736,17 +1275,9 @@ impl WhereClause {
                 if !schema.has_column(column) {
                     return Err(Error::ColumnNotFound(column.clone()));
                 }
-                match expr {
-                    Expression::Value(_) => Ok(()),
-                    Expression::Column(ColumnSelector(_, column)) => {
-                        if !schema.has_column(column) {
-                            return Err(Error::ColumnNotFound(column.clone()));
-                        }
-                        Ok(())
-                    }
-                }
+                expr.check(schema)
             }
-            WhereClause::LikeString(ColumnSelector(_, column), _) => {
+            WhereClause::LikeString(ColumnSelector(_, column), _, _) => {
                 if !schema.has_column(column) {
                     return Err(Error::ColumnNotFound(column.clone()));
                 }
@@ -770,14 +1301,9 @@ impl WhereClause {
         match self {
             WhereClause::OperatorExpression(column_selector, _, expr) => {
                 column_selector.check_tables(schemas, tables)?;
-                match expr {
-                    Expression::Value(_) => Ok(()),
-                    Expression::Column(column_selector) => {
-                        column_selector.check_tables(schemas, tables)
-                    }
-                }
+                expr.check_tables(schemas, tables)
             }
-            WhereClause::LikeString(column_selector, _) => {
+            WhereClause::LikeString(column_selector, _, _) => {
                 column_selector.check_tables(schemas, tables)
             }
             WhereClause::IsNull(column_selector, _) => {
@@ -786,59 +1312,143 @@ impl WhereClause {
         }
     }
 
-    /// Check if the where clause matches a record.
+    /// Make column selectors in the where clause explicit about their tables.
+    pub fn qualify(&self, schemas: &[&TableSchema], tables: &[&str]) -> Result<Self> {
+        Ok(match self {
+            WhereClause::OperatorExpression(column, operator, expr) => {
+                WhereClause::OperatorExpression(
+                    column.qualify(schemas, tables)?,
+                    operator.clone(),
+                    expr.qualify(schemas, tables)?,
+                )
+            }
+            WhereClause::LikeString(column, pattern, like) => {
+                WhereClause::LikeString(column.qualify(schemas, tables)?, pattern.clone(), *like)
+            }
+            WhereClause::IsNull(column, is_null) => {
+                WhereClause::IsNull(column.qualify(schemas, tables)?, *is_null)
+            }
+        })
+    }
+
+    /// Check if the where clause matches a record, i.e. evaluates to true.
     pub fn matches(&self, record: &Record, schema: &TableSchema) -> bool {
+        self.evaluate(record, schema) == Some(true)
+    }
+
+    /// Evaluate the where clause on a record, following three-valued logic.
+    ///
+    /// Returns `None` if the result is unknown, which happens when a NULL is compared.
+    pub fn evaluate(&self, record: &Record, schema: &TableSchema) -> Option<bool> {
         match self {
             WhereClause::OperatorExpression(ColumnSelector(_, column), op, expr) => {
                 let column = schema.get_column(column);
                 let expr = match expr {
-                    Expression::Value(v) => v,
+                    Expression::Value(v) => Cow::Borrowed(v),
                     Expression::Column(ColumnSelector(_, column)) => {
                         let column = schema.get_column(column);
-                        &record.fields[schema.column_map[&column.name]]
+                        Cow::Borrowed(&record.fields[schema.column_map[&column.name]])
+                    }
+                    // A value that can't be converted is unknown, like NULL
+                    Expression::Cast(ColumnSelector(_, column), typ) => {
+                        let column = schema.get_column(column);
+                        let value = &record.fields[schema.column_map[&column.name]];
+                        Cow::Owned(value.convert(typ).ok()?)
                     }
                 };
                 let value = &record.fields[schema.column_map[&column.name]];
-                match op {
+                if value == &Value::Null || *expr == Value::Null {
+                    return None;
+                }
+                let collation = schema.get_collation();
+                let (value, expr) = (collation.fold(value), collation.fold(&expr));
+                Some(match op {
                     Operator::Eq => value == expr,
                     Operator::Ne => value != expr,
                     Operator::Lt => value < expr,
                     Operator::Le => value <= expr,
                     Operator::Gt => value > expr,
                     Operator::Ge => value >= expr,
-                }
+                })
             }
-            WhereClause::LikeString(ColumnSelector(_, column), pattern) => {
+            WhereClause::LikeString(ColumnSelector(_, column), pattern, like) => {
                 let column = schema.get_column(column);
                 let value = &record.fields[schema.column_map[&column.name]];
-                if let Value::Varchar(v) = value {
-                    let v = v.trim_end_matches('\0');
+                if value == &Value::Null {
+                    return None;
+                }
+                let matched = if let Value::Varchar(v) = value {
                     let pattern = regex::escape(pattern);
                     let pattern = pattern.replace('_', ".");
                     let pattern = pattern.replace('%', ".*");
                     let pattern = format!("^{pattern}$");
                     let re = RegexBuilder::new(&pattern)
                         .multi_line(true)
+                        .case_insensitive(schema.get_collation() == Collation::NoCase)
                         .build()
                         .expect("Failed to build regex");
                     re.is_match(v)
                 } else {
                     false
-                }
+                };
+                Some(matched == *like)
             }
             WhereClause::IsNull(ColumnSelector(_, column), is_null) => {
                 let column = schema.get_column(column);
                 let value = &record.fields[schema.column_map[&column.name]];
-                if *is_null {
-                    matches!(value, Value::Null)
-                } else {
-                    !matches!(value, Value::Null)
-                }
+                Some(matches!(value, Value::Null) == *is_null)
             }
         }
     }
 }
 
+/// Time to live of records in a table.
+///
+/// A record expires when the time in its column is earlier than now by more
+/// than the given seconds.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Ttl {
+    /// Column holding the time of a record, of type `DATE` or `DATETIME`.
+    pub column: String,
+    /// Seconds before a record expires.
+    pub seconds: i64,
+}
+
+impl Display for Ttl {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} INTERVAL {} SECOND", self.column, self.seconds)
+    }
+}
+
+/// Statistics of a column, collected by `ANALYZE TABLE`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ColumnStats {
+    /// Count of distinct non-null values.
+    pub distinct: usize,
+    /// Count of null values.
+    pub nulls: usize,
+    /// The smallest non-null value, or null if there is none.
+    pub min: Value,
+    /// The largest non-null value, or null if there is none.
+    pub max: Value,
+}
+
+/// Statistics of a table, collected by `ANALYZE TABLE`.
+///
+/// They are not maintained by later changes, until the table is analyzed again.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TableStats {
+    /// Time of the analysis.
+    pub time: NaiveDateTime,
+    /// Count of records.
+    pub rows: usize,
+    /// Statistics of columns, in the order of columns.
+    pub columns: Vec<ColumnStats>,
+    /// Count of records inserted since the analysis.
+    #[serde(default)]
+    pub modified: usize,
+}
+
 /// A table schema. This type is for serialization.
 #[derive(Deserialize, Serialize)]
 pub struct Schema {
@@ -848,6 +1458,9 @@ pub struct Schema {
     pub free: Option<usize>,
     /// Page number of the first full page of the table.
     pub full: Option<usize>,
+    /// Page number of the first unused overflow page of the table.
+    #[serde(default)]
+    pub overflow: Option<usize>,
     /// Columns of the table.
     pub columns: Vec<Column>,
     /// Constraints on the table.
@@ -856,6 +1469,32 @@ pub struct Schema {
     pub referred_constraints: Vec<(String, Constraint)>,
     /// Indexes on the table.
     pub indexes: Vec<IndexSchema>,
+    /// Time to live of records in the table.
+    #[serde(default)]
+    pub ttl: Option<Ttl>,
+    /// Count of records deleted softly, kept in their slots until purged.
+    #[serde(default)]
+    pub deleted: usize,
+    /// Statistics of the table from the last analysis.
+    #[serde(default)]
+    pub stats: Option<TableStats>,
+    /// Whether pages of the data file are compressed.
+    #[serde(default)]
+    pub compressed: bool,
+    /// Rule of comparing strings, from the database default when created.
+    #[serde(default)]
+    pub collation: Collation,
+    /// Count of times the schema is saved, telling whether it is changed by
+    /// another process since loaded.
+    #[serde(default)]
+    pub version: u64,
+}
+
+/// Version of a schema file, read without the rest of the schema.
+#[derive(Deserialize)]
+struct SchemaVersion {
+    #[serde(default)]
+    version: u64,
 }
 
 impl Schema {
@@ -891,17 +1530,25 @@ pub struct TableSchema {
     null_bitmap_size: usize,
     /// The length of a record.
     record_size: usize,
+    /// The length of a slot, a record followed by its deletion time.
+    slot_size: usize,
+    /// Size of pages in the files of the table.
+    page_size: usize,
     /// Maximum count of records available in a page.
     max_records: usize,
-    /// Size of free slot bitmap in bytes.
+    /// Size of free slot bitmap in bytes, the same as the deleted slot bitmap.
     free_bitmap_size: usize,
     /// Mapping from column name to index.
     column_map: HashMap<String, usize>,
+    /// The schema as last loaded or saved, telling whether it is changed since.
+    saved: String,
+    /// Whether the schema is dropped without saving, as it is out of date.
+    discarded: bool,
 }
 
 impl TableSchema {
-    /// Initialize schema information.
-    pub fn new(schema: Schema, path: &Path) -> Result<Self> {
+    /// Initialize schema information, laid out in pages of some size.
+    pub fn new(schema: Schema, path: &Path, page_size: usize) -> Result<Self> {
         let columns = schema.columns.clone();
         let offsets = columns
             .iter()
@@ -919,17 +1566,20 @@ impl TableSchema {
         let null_bitmap_size = columns.len().div_ceil(8);
         let record_size = null_bitmap_size + columns.iter().map(|c| c.typ.size()).sum::<usize>();
 
+        let slot_size = record_size + DELETED_TIME_SIZE;
+
         // Allocate page space to fit as many records as possible.
-        let mut max_records = PAGE_SIZE / record_size;
+        let mut max_records = page_size / slot_size;
         let mut free_bitmap_size = max_records.div_ceil(8);
-        let mut total_size = max_records * record_size + free_bitmap_size + 2 * LINK_SIZE;
-        while total_size > PAGE_SIZE {
+        let mut total_size = max_records * slot_size + 2 * free_bitmap_size + 2 * LINK_SIZE;
+        while total_size > page_size {
             max_records -= 1;
             free_bitmap_size = max_records.div_ceil(8);
-            total_size = max_records * record_size + free_bitmap_size + 2 * LINK_SIZE;
+            total_size = max_records * slot_size + 2 * free_bitmap_size + 2 * LINK_SIZE;
         }
         log::info!("Max records {max_records} with {free_bitmap_size} bytes free bitmap");
 
+        let saved = serde_json::to_string(&schema)?;
         Ok(Self {
             schema,
             path: path.to_owned(),
@@ -937,17 +1587,56 @@ impl TableSchema {
             offsets,
             null_bitmap_size,
             record_size,
+            slot_size,
+            page_size,
             max_records,
             free_bitmap_size,
             column_map,
+            saved,
+            discarded: false,
         })
     }
 
-    /// Save changes into the schema file.
-    fn save(&self) -> Result<()> {
+    /// Read the version of a schema file.
+    pub fn read_version(path: &Path) -> Result<u64> {
+        let version: SchemaVersion = serde_json::from_reader(File::open(path)?)?;
+        Ok(version.version)
+    }
+
+    /// Drop the schema without saving changes, e.g. when the schema file
+    /// is modified by others.
+    pub fn discard(&mut self) {
+        self.discarded = true;
+    }
+
+    /// Get the version of the schema as loaded or last saved.
+    pub fn get_version(&self) -> u64 {
+        self.schema.version
+    }
+
+    /// Check whether the schema is changed since loaded or last saved.
+    pub fn is_changed(&self) -> bool {
+        serde_json::to_string(&self.schema).map_or(true, |schema| schema != self.saved)
+    }
+
+    /// Save the schema into its file as a new version.
+    ///
+    /// Returns an error without saving if the file is saved by another
+    /// process since loaded.
+    pub fn save(&mut self) -> Result<()> {
         log::info!("Saving schema to {}", self.path.display());
-        let file = File::create(&self.path)?;
-        serde_json::to_writer(file, &self.schema)?;
+        if Self::read_version(&self.path)? != self.schema.version {
+            let table = self.path.parent().and_then(Path::file_name);
+            let table = table.unwrap_or_default().to_string_lossy();
+            return Err(Error::TableConflict(table.into_owned()));
+        }
+        self.schema.version += 1;
+        let saved = serde_json::to_string(&self.schema)?;
+        // Written aside and renamed, so that others never read a partial schema
+        let temp = self.path.with_extension("json.tmp");
+        fs::write(&temp, &saved)?;
+        fs::rename(temp, &self.path)?;
+        self.saved = saved;
         Ok(())
     }
 
@@ -961,6 +1650,11 @@ impl TableSchema {
         self.record_size
     }
 
+    /// Get the length of a slot in a page.
+    pub fn get_slot_size(&self) -> usize {
+        self.slot_size
+    }
+
     /// Check whether a given column is in a table.
     pub fn has_column(&self, name: &str) -> bool {
         self.column_map.contains_key(name)
@@ -1073,6 +1767,11 @@ impl TableSchema {
         &self.columns[self.column_map[name]]
     }
 
+    /// Get the size of pages in the files of the table.
+    pub fn get_page_size(&self) -> usize {
+        self.page_size
+    }
+
     /// Get the maximum count of records available in a page.
     pub fn get_max_records(&self) -> usize {
         self.max_records
@@ -1113,6 +1812,109 @@ impl TableSchema {
         self.schema.full = full;
     }
 
+    /// Get the first unused overflow page in the table.
+    pub fn get_overflow(&self) -> Option<usize> {
+        self.schema.overflow
+    }
+
+    /// Set the first unused overflow page in the table.
+    pub fn set_overflow(&mut self, overflow: Option<usize>) {
+        self.schema.overflow = overflow;
+    }
+
+    /// Get the time to live of records in the table.
+    pub fn get_ttl(&self) -> Option<&Ttl> {
+        self.schema.ttl.as_ref()
+    }
+
+    /// Set the time to live of records in the table.
+    pub fn set_ttl(&mut self, ttl: Option<Ttl>) {
+        self.schema.ttl = ttl;
+    }
+
+    /// Check if pages of the data file are compressed.
+    pub fn is_compressed(&self) -> bool {
+        self.schema.compressed
+    }
+
+    /// Set whether pages of the data file are compressed.
+    pub fn set_compressed(&mut self, compressed: bool) {
+        self.schema.compressed = compressed;
+    }
+
+    /// Get the count of records deleted softly from the table.
+    pub fn get_deleted(&self) -> usize {
+        self.schema.deleted
+    }
+
+    /// Set the count of records deleted softly from the table.
+    pub fn set_deleted(&mut self, deleted: usize) {
+        self.schema.deleted = deleted;
+    }
+
+    /// Get the rule of comparing strings in the table.
+    pub fn get_collation(&self) -> Collation {
+        self.schema.collation
+    }
+
+    /// Get statistics of the table from the last analysis.
+    pub fn get_stats(&self) -> Option<&TableStats> {
+        self.schema.stats.as_ref()
+    }
+
+    /// Set statistics of the table.
+    pub fn set_stats(&mut self, stats: Option<TableStats>) {
+        self.schema.stats = stats;
+    }
+
+    /// Estimate count of records matching the where clauses.
+    ///
+    /// Statistics from the last analysis are used if present, otherwise every
+    /// page is assumed full and fixed selectivities are used.
+    pub fn estimate_rows(&self, where_clauses: &[WhereClause]) -> f64 {
+        let stats = self.get_stats();
+        let rows = match stats {
+            Some(stats) => stats.rows,
+            None => self.get_pages() * self.get_max_records(),
+        } as f64;
+        let mut estimation = rows;
+        for where_clause in where_clauses {
+            let (column, selectivity) = match where_clause {
+                WhereClause::OperatorExpression(
+                    ColumnSelector(_, column),
+                    Operator::Eq,
+                    Expression::Value(_),
+                ) => (column, 0.1),
+                WhereClause::OperatorExpression(ColumnSelector(_, column), _, _)
+                | WhereClause::LikeString(ColumnSelector(_, column), _, _) => (column, 0.3),
+                WhereClause::IsNull(ColumnSelector(_, column), _) => (column, 0.1),
+            };
+            let index = self.get_column_index(column);
+            let column = stats.and_then(|stats| stats.columns.get(index));
+            estimation *= match (where_clause, column) {
+                (
+                    WhereClause::OperatorExpression(_, Operator::Eq, Expression::Value(_)),
+                    Some(column),
+                ) => 1.0 / column.distinct.max(1) as f64,
+                (WhereClause::IsNull(_, is_null), Some(column)) if rows > 0.0 => {
+                    let nulls = column.nulls as f64 / rows;
+                    if *is_null {
+                        nulls
+                    } else {
+                        1.0 - nulls
+                    }
+                }
+                _ => selectivity,
+            };
+        }
+        estimation
+    }
+
+    /// Release the last page of the table.
+    pub fn pop_page(&mut self) {
+        self.schema.pages -= 1;
+    }
+
     /// Allocate a new page for the table.
     pub fn new_page(&mut self) -> usize {
         let page = self.schema.pages;
@@ -1140,6 +1942,9 @@ impl RecordSchema for TableSchema {
 
 impl Drop for TableSchema {
     fn drop(&mut self) {
+        if self.discarded || !self.is_changed() {
+            return;
+        }
         if let Err(err) = self.save() {
             log::error!("Failed to save schema: {err}");
         }
@@ -1,17 +1,92 @@
 //! Basic setup functions.
 
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
 use clap::Parser;
 use env_logger::{self, Env};
+use serde::Serialize;
+
+use crate::config::{Config, LogFormat, CONFIG_FILE};
+use crate::error::Result;
+use crate::trace::{self, Context};
 
-use crate::config::Config;
+/// A log record in JSON format.
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    time: String,
+    level: &'a str,
+    target: &'a str,
+    #[serde(flatten)]
+    context: Context,
+    message: String,
+}
 
+/// Initialize logging for tests, with default level and format.
+#[cfg(test)]
 pub fn init_logging() {
-    let _ = env_logger::try_init_from_env(Env::new().default_filter_or("info"));
+    init_logging_with("info", LogFormat::Text);
+}
+
+/// Initialize logging with a default level, which is overridden by `RUST_LOG`,
+/// and a format. Fields of the tracing context are attached to each record.
+pub fn init_logging_with(level: &str, format: LogFormat) {
+    let _ = env_logger::Builder::from_env(Env::new().default_filter_or(level))
+        .format(move |buf, record| {
+            let context = trace::get();
+            match format {
+                LogFormat::Text => {
+                    let mut fields = String::new();
+                    if let Some(statement) = context.statement {
+                        fields += &format!(" statement={statement}");
+                    }
+                    if let Some(table) = &context.table {
+                        fields += &format!(" table={table}");
+                    }
+                    if let Some(page) = context.page {
+                        fields += &format!(" page={page}");
+                    }
+                    if let Some(duration) = context.duration_ms {
+                        fields += &format!(" duration_ms={duration:.3}");
+                    }
+                    writeln!(
+                        buf,
+                        "[{} {:<5} {}{fields}] {}",
+                        buf.timestamp(),
+                        buf.default_styled_level(record.level()),
+                        record.target(),
+                        record.args()
+                    )
+                }
+                LogFormat::Json => {
+                    let record = JsonRecord {
+                        time: buf.timestamp().to_string(),
+                        level: record.level().as_str(),
+                        target: record.target(),
+                        context,
+                        message: record.args().to_string(),
+                    };
+                    writeln!(buf, "{}", serde_json::to_string(&record)?)
+                }
+            }
+        })
+        .try_init();
     log::info!("Logging initialized");
 }
 
-pub fn init_config() -> Config {
-    let config = Config::parse();
-    log::info!("Config: {:?}", config);
-    config
+/// Parse command line arguments, and fill in the rest from the config file.
+///
+/// The default config file is skipped if it does not exist.
+pub fn init_config() -> Result<Config> {
+    let mut config = Config::parse();
+    let file = match &config.config {
+        Some(path) => Some(fs::read_to_string(path)?),
+        None if Path::new(CONFIG_FILE).exists() => Some(fs::read_to_string(CONFIG_FILE)?),
+        None => None,
+    };
+    if let Some(file) = file {
+        config.merge(toml::from_str(&file)?);
+    }
+    Ok(config)
 }
@@ -1,33 +1,310 @@
 //! Database system management.
 
+use std::cmp::Ordering;
 use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
+use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, TimeDelta, Utc};
 use csv::ReaderBuilder;
+use serde::{Deserialize, Serialize};
 
+use crate::cancel::CancelToken;
+use crate::config::{
+    DB_META, DDL_JOURNAL, DEFAULT_PAGE_SIZE, FORMAT_VERSION, HOT_PAGES, INDEX_BUILD_BATCH,
+    MAX_PAGE_SIZE, MAX_REPORTED_DUPLICATES, MIN_PAGE_SIZE, SKIP_SCAN_RATIO, TABLE_CACHE_SIZE,
+};
 use crate::error::{Error, Result};
-use crate::file::{PageCache, FS};
-use crate::index::{Index, IndexSchema, LeafIterator};
+use crate::file::{CacheStats, PageCache, SharedCache};
+use crate::index::{Index, IndexResult, IndexSchema, LeafIterator};
+use crate::plan::{PlanHistory, SelectStatement};
+use crate::query_log::QueryLog;
 use crate::record::{Record, RecordSchema};
 use crate::schema::{
-    ColumnSelector, Constraint, Expression, Operator, Schema, Selector, Selectors, SetPair,
-    TableSchema, Value, WhereClause,
+    parse_datetime, Collation, Column, ColumnSelector, ColumnStats, Constraint, Expression,
+    Operator, OutputSchema, Schema, Selector, Selectors, SetPair, TableSchema, TableStats, Ttl,
+    Type, Value, WhereClause,
 };
 use crate::table::{SelectResult, Table};
+use crate::trace;
+
+/// Metadata of a database, saved in its directory.
+#[derive(Debug, Deserialize, Serialize)]
+struct DatabaseMeta {
+    /// Version of the layout of files, the first if not saved.
+    #[serde(default)]
+    format: u32,
+    /// Size of pages in files of the database.
+    page_size: usize,
+    /// Default rule of comparing strings, copied into tables created afterwards.
+    #[serde(default)]
+    collation: Collation,
+    /// Offset of the time zone of the current time, e.g. `+08:00`, local if none.
+    #[serde(default)]
+    time_zone: Option<String>,
+    /// Largest size of files of the database in bytes, unlimited if none.
+    #[serde(default)]
+    quota: Option<u64>,
+    /// Whether the database is a snapshot cloned from another one,
+    /// whose tables are not modified.
+    #[serde(default)]
+    read_only: bool,
+}
+
+impl DatabaseMeta {
+    /// Load metadata of a database.
+    ///
+    /// Databases created before metadata was saved are of the first format,
+    /// with the default page size.
+    fn load(path: &Path) -> Result<Self> {
+        let meta = path.join(DB_META);
+        if meta.exists() {
+            Ok(serde_json::from_reader(File::open(meta)?)?)
+        } else {
+            Ok(Self {
+                format: 0,
+                page_size: DEFAULT_PAGE_SIZE,
+                collation: Collation::default(),
+                time_zone: None,
+                quota: None,
+                read_only: false,
+            })
+        }
+    }
+
+    /// Save metadata of a database.
+    fn save(&self, path: &Path) -> Result<()> {
+        serde_json::to_writer(File::create(path.join(DB_META))?, self)?;
+        Ok(())
+    }
+
+    /// Parse the time zone, falling back to the local one if invalid.
+    fn get_time_zone(&self) -> Option<FixedOffset> {
+        let time_zone = self.time_zone.as_ref()?;
+        match time_zone.parse() {
+            Ok(offset) => Some(offset),
+            Err(err) => {
+                log::warn!("Invalid time zone {time_zone}, using local time: {err}");
+                None
+            }
+        }
+    }
+}
+
+/// An index built online, attached to its table once all pages are scanned.
+///
+/// Pages whose records change meanwhile are tracked by the table, and
+/// scanned again before the index is loaded.
+struct IndexBuild {
+    /// Name of the table.
+    table: String,
+    /// Schema of the index, not yet saved in the table.
+    schema: IndexSchema,
+    /// Next page of the table to scan.
+    next_page: usize,
+    /// Keys of records on scanned pages.
+    keys: Vec<IndexResult>,
+    /// Time when the build started.
+    start: Instant,
+}
+
+/// A DDL operation in progress, journaled in the database until it returns,
+/// so that files left behind by a crash are cleaned up on its next use.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+enum DdlOperation {
+    /// Creating a table, along with indexes of its constraints.
+    CreateTable { table: String },
+    /// Creating an index on a table.
+    CreateIndex { table: String, index: String },
+    /// Rewriting a table with a column modified, staged in `<table>.new`
+    /// and swapped in through `<table>.old`.
+    ModifyColumn { table: String },
+    /// Rebuilding an index of a table from its records.
+    RebuildIndex { table: String, index: String },
+}
+
+impl DdlOperation {
+    /// Undo an interrupted operation in a database.
+    ///
+    /// A table being created is removed. Files of an index being created
+    /// are removed, unless the index is saved in its table. Files of an
+    /// index being rebuilt are removed, leaving it unavailable.
+    fn recover(&self, db: &Path) -> Result<()> {
+        match self {
+            DdlOperation::CreateTable { table } => {
+                let table = db.join(table);
+                if table.exists() {
+                    log::warn!("Removing table {} of interrupted DDL", table.display());
+                    fs::remove_dir_all(table)?;
+                }
+            }
+            DdlOperation::CreateIndex { table, index } => {
+                let table = db.join(table);
+                let meta = table.join("meta.json");
+                if let Ok(file) = File::open(meta) {
+                    let schema: Schema = serde_json::from_reader(file)?;
+                    if schema.indexes.iter().any(|schema| &schema.name == index) {
+                        return Ok(());
+                    }
+                }
+                log::warn!("Removing index {index} of interrupted DDL");
+                for suffix in ["index.bin", "index.json"] {
+                    let path = table.join(format!("{index}.{suffix}"));
+                    if path.exists() {
+                        fs::remove_file(path)?;
+                    }
+                }
+            }
+            DdlOperation::ModifyColumn { table } => {
+                let path = db.join(table);
+                let new = db.join(format!("{table}.new"));
+                let old = db.join(format!("{table}.old"));
+                // Interrupted while swapping, the old table is put back
+                if !path.exists() && old.exists() {
+                    log::warn!("Restoring table {table} of interrupted DDL");
+                    fs::rename(&old, &path)?;
+                }
+                for dir in [new, old] {
+                    if dir.exists() {
+                        log::warn!("Removing {} of interrupted DDL", dir.display());
+                        fs::remove_dir_all(dir)?;
+                    }
+                }
+            }
+            DdlOperation::RebuildIndex { table, index } => {
+                log::warn!("Removing index {index} of interrupted rebuild");
+                let table = db.join(table);
+                for suffix in ["index.bin", "index.json"] {
+                    let path = table.join(format!("{index}.{suffix}"));
+                    if path.exists() {
+                        fs::remove_file(path)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Bookkeeping of an open table.
+struct TableHandle {
+    /// The statement which last used the table.
+    used: u64,
+    /// Whether the table is changed since last saved.
+    modified: bool,
+}
+
+/// Conditions of where clauses on columns, for matching indexes.
+#[derive(Default)]
+struct IndexConditions {
+    /// Left and right bounds for the condition.
+    left: HashMap<String, Vec<i64>>,
+    right: HashMap<String, Vec<i64>>,
+    /// Values of columns compared for equality, for point lookups.
+    equal: HashMap<String, Value>,
+    /// Columns with some bound.
+    known_columns: HashSet<String>,
+}
+
+impl IndexConditions {
+    /// Get the tightest bounds of a column as index keys.
+    ///
+    /// Bounds are clamped for int columns, since records are checked again later.
+    fn bounds(&self, schema: &TableSchema, column: &str) -> (Value, Value) {
+        let left = self.left.get(column).and_then(|v| v.iter().max().copied());
+        let right = self.right.get(column).and_then(|v| v.iter().min().copied());
+        let left = left.unwrap_or(i64::MIN);
+        let right = right.unwrap_or(i64::MAX);
+
+        log::info!("Left bound: {left}, right bound: {right}");
+
+        match schema.get_column(column).typ {
+            Type::Bigint => (Value::Bigint(left), Value::Bigint(right)),
+            Type::DateTime => {
+                let datetime = |v: i64| match DateTime::from_timestamp(v, 0) {
+                    Some(datetime) => datetime.naive_utc(),
+                    None if v < 0 => NaiveDateTime::MIN,
+                    None => NaiveDateTime::MAX,
+                };
+                (
+                    Value::DateTime(datetime(left)),
+                    Value::DateTime(datetime(right)),
+                )
+            }
+            _ => {
+                let clamp = |v: i64| v.clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+                (Value::Int(clamp(left)), Value::Int(clamp(right)))
+            }
+        }
+    }
+
+    /// Get the largest index key of a column, following all keys of the same prefix.
+    fn max_key(schema: &TableSchema, column: &str) -> Value {
+        Self::default().bounds(schema, column).1
+    }
+}
 
 /// Database system manager.
 pub struct System {
     /// Path to data directory.
     base: PathBuf,
+    /// Page cache of files of opened databases.
+    fs: SharedCache,
     /// Current name of selected database.
     db_name: Option<String>,
     /// Current selected database.
     db: Option<PathBuf>,
     /// Mapping from table name to the table.
     tables: HashMap<String, Table>,
+    /// Mapping from table name to bookkeeping of the open table.
+    handles: HashMap<String, TableHandle>,
+    /// Count of statements begun, for finding least recently used tables.
+    statements: u64,
     /// Mapping from index name to the index.
     indexes: HashMap<(String, String), Index>,
+    /// Steps of the plan of current statement.
+    plan: Vec<String>,
+    /// Plan history of current database.
+    plans: Option<PlanHistory>,
+    /// Whether to break ties in ordering by record position.
+    stable_pagination: bool,
+    /// Whether to check foreign keys on modification.
+    foreign_key_checks: bool,
+    /// Whether to reject selected columns neither grouped nor aggregated.
+    only_full_group_by: bool,
+    /// Whether to keep deleted records aside instead of dropping them.
+    soft_delete: bool,
+    /// Fraction of analyzed records inserted afterwards before a table is
+    /// analyzed again automatically, 0 to disable.
+    auto_analyze_ratio: f64,
+    /// Page size of newly created databases.
+    page_size: usize,
+    /// Whether to load pages cached when a database was last used on switching to it.
+    warm_cache: bool,
+    /// Whether to rebuild indexes whose files are missing on opening them,
+    /// instead of leaving them unavailable.
+    rebuild_missing_indexes: bool,
+    /// Cancellation requests of long-running statements.
+    cancel: Arc<CancelToken>,
+    /// Index being built online, at most one at a time.
+    index_build: Option<IndexBuild>,
+    /// DDL operations in progress, journaled in current database.
+    ddl: Vec<DdlOperation>,
+    /// Global query log.
+    query_log: Option<QueryLog>,
+    /// Whether to record statements of this session into the query log.
+    query_log_enabled: bool,
+    /// Default collation of current database.
+    collation: Collation,
+    /// Time zone of current database, local if none.
+    time_zone: Option<FixedOffset>,
+    /// Quota of current database in bytes, unlimited if none.
+    quota: Option<u64>,
+    /// Whether current database is a read-only snapshot.
+    read_only: bool,
 }
 
 impl System {
@@ -35,11 +312,118 @@ impl System {
     pub fn new(base: PathBuf) -> Self {
         Self {
             base,
+            fs: SharedCache::default(),
             db_name: None,
             db: None,
             tables: HashMap::new(),
+            handles: HashMap::new(),
+            statements: 0,
             indexes: HashMap::new(),
+            plan: vec![],
+            plans: None,
+            stable_pagination: false,
+            foreign_key_checks: true,
+            only_full_group_by: false,
+            soft_delete: false,
+            auto_analyze_ratio: 0.2,
+            page_size: DEFAULT_PAGE_SIZE,
+            warm_cache: false,
+            rebuild_missing_indexes: false,
+            cancel: Arc::default(),
+            index_build: None,
+            ddl: vec![],
+            query_log: None,
+            query_log_enabled: true,
+            collation: Collation::default(),
+            time_zone: None,
+            quota: None,
+            read_only: false,
+        }
+    }
+
+    /// Set a session variable.
+    pub fn set_variable(&mut self, name: &str, value: Value) -> Result<()> {
+        log::info!("Setting variable {name} to {value}");
+
+        let as_bool = |value: Value| match value {
+            Value::Int(0) => Ok(false),
+            Value::Int(1) => Ok(true),
+            _ => Err(Error::TypeMismatch(value, Type::Int)),
+        };
+
+        match name.to_lowercase().as_str() {
+            "stable_pagination" => self.stable_pagination = as_bool(value)?,
+            "foreign_key_checks" => {
+                let checks = as_bool(value)?;
+                if checks && !self.foreign_key_checks {
+                    self.check_foreign_keys()?;
+                }
+                self.foreign_key_checks = checks;
+            }
+            "query_log" => self.query_log_enabled = as_bool(value)?,
+            "only_full_group_by" => self.only_full_group_by = as_bool(value)?,
+            "soft_delete" => self.soft_delete = as_bool(value)?,
+            "rebuild_missing_indexes" => self.rebuild_missing_indexes = as_bool(value)?,
+            "auto_analyze_ratio" => {
+                self.auto_analyze_ratio = match value {
+                    Value::Int(v) if v >= 0 => v as f64,
+                    Value::Float(v) if v >= 0.0 => v,
+                    _ => return Err(Error::TypeMismatch(value, Type::Float)),
+                }
+            }
+            _ => return Err(Error::UnknownVariable(name.to_owned())),
+        }
+        Ok(())
+    }
+
+    /// Validate all foreign keys in current database,
+    /// walking the referrer index of each foreign key once.
+    fn check_foreign_keys(&mut self) -> Result<()> {
+        log::info!("Checking all foreign keys");
+
+        for table_name in self.get_tables()? {
+            self.open_table(&table_name)?;
+            self.open_indexes(&table_name)?;
+
+            let table = self.get_table(&table_name)?;
+            let foreign_keys: Vec<_> = table
+                .get_schema()
+                .get_foreign_keys()
+                .into_iter()
+                .cloned()
+                .collect();
+
+            for fk in &foreign_keys {
+                let ref_table = fk.get_ref_table();
+                self.open_table(ref_table)?;
+                self.open_indexes(ref_table)?;
+
+                let index = self.get_index(&table_name, &fk.get_index_name(true))?;
+                let ref_index = self.get_index(ref_table, &self.get_referred_index_name(fk)?)?;
+
+                let mut fs = self.fs.lock_arc();
+
+                // Nulls are ordered first, so this is the smallest key
+                let key = Record::new(vec![Value::Null; fk.get_columns().len()]);
+                let mut keys: Vec<Record> = vec![];
+                let mut iter = index.index(&mut fs, &key)?;
+                while let Some(it) = iter {
+                    let (key, _, _) = index.get_record(&mut fs, it)?;
+                    // Skip checks for null
+                    if !key.has_null() && keys.last() != Some(&key) {
+                        keys.push(key);
+                    }
+                    iter = index.inc_iter(&mut fs, it)?;
+                }
+
+                // Keys from the index are sorted
+                if ref_index.contains_many(&mut fs, &keys)?.contains(&false) {
+                    return Err(Error::ReferencedFieldsNotExist(fk.get_display_name()));
+                }
+            }
         }
+
+        Ok(())
     }
 
     /// Get current selected database.
@@ -66,13 +450,44 @@ impl System {
             }
         }
 
+        // Files laid out differently are not readable
+        let meta = DatabaseMeta::load(&path)?;
+        if meta.format != FORMAT_VERSION {
+            log::error!("Database {name} is in format {}", meta.format);
+            return Err(Error::UnsupportedFormat(
+                name.to_owned(),
+                meta.format,
+                FORMAT_VERSION,
+            ));
+        }
+        let page_size = meta.page_size;
+        Self::recover_ddl(&path)?;
+
+        self.save_hot_pages()?;
+
         log::info!("Switching to database {}, flushing cache", name);
-        FS.lock()?.clear()?;
+        {
+            let mut fs = self.fs.lock_arc();
+            fs.clear()?;
+            fs.set_page_size(page_size)?;
+        }
         self.tables.clear();
+        self.handles.clear();
         self.indexes.clear();
 
+        self.plans = Some(PlanHistory::open(&path)?);
         self.db_name = Some(name.to_owned());
         self.db = Some(path);
+        self.collation = meta.collation;
+        self.time_zone = meta.get_time_zone();
+        self.quota = meta.quota;
+        self.read_only = meta.read_only;
+
+        if self.warm_cache {
+            if let Err(err) = self.warm_up() {
+                log::warn!("Failed to warm up cache: {err}");
+            }
+        }
 
         log::info!("Using database {}", name);
         Ok(())
@@ -111,10 +526,144 @@ impl System {
             return Err(err.into());
         }
 
+        let meta = DatabaseMeta {
+            format: FORMAT_VERSION,
+            page_size: self.page_size,
+            collation: Collation::default(),
+            time_zone: None,
+            quota: None,
+            read_only: false,
+        };
+        meta.save(&path)?;
+
         log::info!("Database {} created", name);
         Ok(())
     }
 
+    /// Change a setting of a database, saved in its metadata.
+    ///
+    /// The setting is one of `collation`, the default collation of tables
+    /// created afterwards, `time_zone`, the offset of the current time used
+    /// by the database, where `SYSTEM` stands for the local time zone, and
+    /// `quota`, the largest size of the database in bytes, where 0 or `NULL`
+    /// removes the limit.
+    pub fn alter_database(&mut self, name: &str, setting: &str, value: &str) -> Result<()> {
+        log::info!("Setting {setting} of database {name} to {value}");
+
+        let path = self.base.join(name);
+        if !path.exists() {
+            log::error!("Database {} not found", name);
+            return Err(Error::DatabaseNotFound(name.to_owned()));
+        }
+
+        let mut meta = DatabaseMeta::load(&path)?;
+        if meta.read_only {
+            log::error!("Database {name} is a read-only snapshot");
+            return Err(Error::ReadOnlyDatabase(name.to_owned()));
+        }
+        match setting.to_lowercase().as_str() {
+            "collation" => meta.collation = value.parse()?,
+            "time_zone" if value.eq_ignore_ascii_case("system") => meta.time_zone = None,
+            "time_zone" => {
+                let offset: FixedOffset = value
+                    .parse()
+                    .map_err(|_| Error::InvalidTimeZone(value.to_owned()))?;
+                meta.time_zone = Some(offset.to_string());
+            }
+            "quota" => {
+                let quota = match value.to_lowercase().as_str() {
+                    "null" => 0,
+                    _ => value.parse()?,
+                };
+                meta.quota = (quota > 0).then_some(quota);
+            }
+            _ => return Err(Error::UnknownVariable(setting.to_owned())),
+        }
+        meta.save(&path)?;
+
+        if self.db_name.as_deref() == Some(name) {
+            self.collation = meta.collation;
+            self.time_zone = meta.get_time_zone();
+            self.quota = meta.quota;
+        }
+        Ok(())
+    }
+
+    /// Estimate the size of files of current database in bytes.
+    ///
+    /// Pages allocated by open tables and indexes are counted even before
+    /// they are written back.
+    fn database_size(&self) -> Result<u64> {
+        let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
+        let mut size = dir_size(db)?;
+
+        let mut files = vec![];
+        for (name, table) in &self.tables {
+            let path = db.join(name).join("data.bin");
+            let schema = table.get_schema();
+            files.push((path, schema.get_pages(), schema.get_page_size()));
+        }
+        for ((table_name, name), index) in &self.indexes {
+            let path = db.join(table_name).join(format!("{name}.index.bin"));
+            let page_size = self.get_table(table_name)?.get_schema().get_page_size();
+            files.push((path, index.get_schema().pages, page_size));
+        }
+        for (path, pages, page_size) in files {
+            let written = fs::metadata(path).map_or(0, |meta| meta.len());
+            size += ((pages * page_size) as u64).saturating_sub(written);
+        }
+        Ok(size)
+    }
+
+    /// Fail with `Error::ReadOnlyDatabase` if current database is a snapshot,
+    /// before a statement modifying it.
+    pub fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            let name = self.db_name.clone().unwrap_or_default();
+            log::error!("Database {name} is a read-only snapshot");
+            return Err(Error::ReadOnlyDatabase(name));
+        }
+        Ok(())
+    }
+
+    /// Fail with `Error::QuotaExceeded` if current database would grow beyond
+    /// its quota by some more bytes.
+    fn check_quota(&self, extra: u64) -> Result<()> {
+        let Some(quota) = self.quota else {
+            return Ok(());
+        };
+        let size = self.database_size()? + extra;
+        if size > quota {
+            let name = self.db_name.clone().unwrap_or_default();
+            log::error!("Database {name} would take {size} bytes, beyond its quota {quota}");
+            return Err(Error::QuotaExceeded(name, size, quota));
+        }
+        Ok(())
+    }
+
+    /// Estimate the bytes taken by inserting records into a table and its indexes,
+    /// including overflow pages of text fields.
+    fn insert_size(&self, table_name: &str, indexes: &[String], records: &[Record]) -> Result<u64> {
+        let table = self.get_table(table_name)?;
+        let mut record_size = table.get_schema().get_record_size();
+        for index_name in indexes {
+            record_size += self.get_index(table_name, index_name)?.get_entry_size();
+        }
+        let overflow_size: usize = records
+            .iter()
+            .map(|record| table.get_overflow_size(record))
+            .sum();
+        Ok((records.len() * record_size + overflow_size) as u64)
+    }
+
+    /// Get the current time in the time zone of current database.
+    fn now(&self) -> NaiveDateTime {
+        match self.time_zone {
+            Some(offset) => Utc::now().with_timezone(&offset).naive_local(),
+            None => Local::now().naive_local(),
+        }
+    }
+
     /// Drop a database.
     /// Error when the name is not found.
     ///
@@ -134,8 +683,10 @@ impl System {
                 log::info!("Dropping current database. Flushing cache.");
                 self.db_name = None;
                 self.db = None;
-                FS.lock()?.clear()?;
+                self.plans = None;
+                self.fs.lock_arc().clear()?;
                 self.tables.clear();
+                self.handles.clear();
                 self.indexes.clear();
             }
         }
@@ -149,117 +700,579 @@ impl System {
         Ok(())
     }
 
-    /// Open a table, hold its file descriptor and schema.
-    fn open_table(&mut self, name: &str) -> Result<()> {
-        if self.tables.contains_key(name) {
-            return Ok(());
+    /// Clone a database into a read-only snapshot, copying all its files.
+    ///
+    /// Tables of the snapshot are only queried; it could be dropped as usual.
+    ///
+    /// # Cache Flushing
+    ///
+    /// The cache is flushed when cloning current database.
+    pub fn clone_database(&mut self, name: &str, new_name: &str) -> Result<()> {
+        let path = self.base.join(name);
+        if !path.exists() {
+            log::error!("Database {} not found", name);
+            return Err(Error::DatabaseNotFound(name.to_owned()));
         }
-
-        let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
-        let table = db.join(name);
-
-        if !table.exists() {
-            log::error!("Table {} not found", name);
-            return Err(Error::TableNotFound(name.to_owned()));
+        let new_path = self.base.join(new_name);
+        if new_path.exists() {
+            log::error!("Database {} already exists", new_name);
+            return Err(Error::DatabaseExists(new_name.to_owned()));
         }
 
-        let mut fs = FS.lock()?;
+        // Cloning current database. Flush cache so that files are up to date.
+        if let Some(db) = &self.db {
+            if path.canonicalize()? == db.canonicalize()? {
+                log::info!("Cloning current database. Flushing cache.");
+                self.close_all()?;
+            }
+        }
 
-        let fd = fs.open(&table.join("data.bin"))?;
+        let cloned = copy_dir(&path, &new_path).and_then(|_| {
+            let mut meta = DatabaseMeta::load(&new_path)?;
+            meta.read_only = true;
+            meta.save(&new_path)
+        });
+        if let Err(err) = cloned {
+            log::error!("Failed to clone database {}: {}", name, err);
+            fs::remove_dir_all(&new_path).ok();
+            return Err(err);
+        }
 
-        let meta = table.join("meta.json");
-        let file = File::open(meta.clone())?;
-        let schema = serde_json::from_reader(file)?;
+        log::info!("Database {} cloned into {}", name, new_name);
+        Ok(())
+    }
 
-        let table = Table::new(fd, TableSchema::new(schema, &meta)?);
+    /// Set the page size of databases created afterwards.
+    /// Error when the size is not a power of two within the allowed range.
+    pub fn set_page_size(&mut self, size: usize) -> Result<()> {
+        if !size.is_power_of_two() || !(MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&size) {
+            return Err(Error::InvalidPageSize(size));
+        }
+        self.page_size = size;
+        Ok(())
+    }
 
-        self.tables.insert(name.to_owned(), table);
+    /// Set whether to load pages cached when a database was last used on switching to it.
+    pub fn set_warm_cache(&mut self, warm_cache: bool) {
+        self.warm_cache = warm_cache;
+    }
 
-        Ok(())
+    /// Get the token to cancel long-running statements, e.g. on interrupt.
+    pub fn cancel_token(&self) -> Arc<CancelToken> {
+        self.cancel.clone()
     }
 
-    /// Get a table for read.
-    fn get_table(&self, name: &str) -> Result<&Table> {
-        self.tables
-            .get(name)
-            .ok_or(Error::TableNotFound(name.to_owned()))
+    /// Get the page cache of this system.
+    pub fn page_cache(&self) -> SharedCache {
+        self.fs.clone()
     }
 
-    /// Get a table for write.
-    fn get_table_mut(&mut self, name: &str) -> Result<&mut Table> {
-        self.tables
-            .get_mut(name)
-            .ok_or(Error::TableNotFound(name.to_owned()))
+    /// Save the pages of current database in the cache, to warm up the cache
+    /// when the database is used next time.
+    fn save_hot_pages(&self) -> Result<()> {
+        let Some(db) = self.db.as_ref().filter(|db| db.exists()) else {
+            return Ok(());
+        };
+        let pages = self.fs.lock_arc().hot_pages(db);
+        log::info!("Saving {} hot pages", pages.len());
+        serde_json::to_writer(File::create(db.join(HOT_PAGES))?, &pages)?;
+        Ok(())
     }
 
-    /// Open all indexes on a table.
-    ///
-    /// # Returns
-    ///
-    /// Returns the name of indexes on the table.
-    ///
-    /// # Warning
+    /// Load the pages saved when current database was last used into the cache.
     ///
-    /// Please open the table before opening its indexes.
-    fn open_indexes(&mut self, table_name: &str) -> Result<Vec<String>> {
-        let table = self.get_table(table_name)?;
-        let indexes: Vec<_> = table
-            .get_schema()
-            .get_indexes()
+    /// Tables with saved pages are opened along with their indexes, so that
+    /// the pages are loaded for the files used by them.
+    fn warm_up(&mut self) -> Result<()> {
+        let db = self.db.clone().ok_or(Error::NoDatabaseSelected)?;
+        let path = db.join(HOT_PAGES);
+        if !path.exists() {
+            return Ok(());
+        }
+        let pages: Vec<(PathBuf, usize)> = serde_json::from_reader(File::open(path)?)?;
+
+        let tables: HashSet<_> = pages
             .iter()
-            .map(|index| index.name.clone())
+            .filter_map(|(file, _)| file.iter().next()?.to_str())
             .collect();
-        for index in &indexes {
-            self.open_index(table_name, index)?;
+        for table in self.get_tables()? {
+            if tables.contains(table.as_str()) {
+                self.open_table(&table)?;
+                self.open_indexes(&table)?;
+            }
         }
-        Ok(indexes)
+
+        let loaded = self.fs.lock_arc().prefetch(&db, &pages)?;
+        log::info!("Warmed up cache with {loaded} pages");
+        Ok(())
     }
 
-    /// Open a index, hold its file descriptor and schema.
-    fn open_index(&mut self, table_name: &str, name: &str) -> Result<()> {
-        if self
-            .indexes
-            .contains_key(&(table_name.to_owned(), name.to_owned()))
-        {
-            return Ok(());
+    /// Record executed statements into a query log.
+    pub fn set_query_log(&mut self, path: &Path) -> Result<()> {
+        log::info!("Recording queries into {}", path.display());
+        self.query_log = Some(QueryLog::open(path)?);
+        Ok(())
+    }
+
+    /// Record an executed statement into the query log, if enabled.
+    pub fn log_query(&mut self, statement: &str, duration: Duration) -> Result<()> {
+        if let Some(query_log) = &mut self.query_log {
+            if self.query_log_enabled {
+                query_log.record(self.db_name.as_deref(), statement, duration)?;
+            }
         }
+        Ok(())
+    }
 
-        log::info!("Opening index {table_name}.{name}");
-        let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
-        let table = db.join(table_name);
+    /// Create a system on a copy of the data directory, for replaying queries.
+    ///
+    /// The copy is a sibling of the data directory, e.g. `data.replay`,
+    /// and is removed when the returned replica is dropped.
+    ///
+    /// # Cache Flushing
+    ///
+    /// The cache is flushed before copying.
+    pub fn replica(&mut self) -> Result<Replica> {
+        // Relative paths like `.` have no file name to append to
+        let base = self.base.canonicalize()?;
+        let mut name = base.file_name().unwrap_or_default().to_owned();
+        name.push(".replay");
+        let path = base.with_file_name(name);
+        if path.starts_with(&base) {
+            return Err(Error::ReplicaInsideBase(path.display().to_string()));
+        }
+        log::info!("Copying data directory into {}", path.display());
 
-        let mut fs = FS.lock()?;
+        self.close_all()?;
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        if let Err(err) = copy_dir(&self.base, &path) {
+            if let Err(err) = fs::remove_dir_all(&path) {
+                log::error!("Failed to remove replica: {err}");
+            }
+            return Err(err);
+        }
 
-        let fd = fs.open(&table.join(format!("{name}.index.bin")))?;
+        Ok(Replica {
+            system: Some(System::new(path.clone())),
+            path,
+        })
+    }
 
-        let meta = table.join(format!("{name}.index.json"));
-        let file = File::open(meta.clone())?;
-        let schema = serde_json::from_reader(file)?;
+    /// Get steps of the plan of current statement.
+    pub fn get_plan(&self) -> &[String] {
+        &self.plan
+    }
 
-        let table = self.get_table(table_name)?;
+    /// Start recording the plan of a new statement.
+    pub fn begin_plan(&mut self) {
+        self.plan.clear();
+    }
 
-        self.indexes.insert(
-            (table_name.to_owned(), name.to_owned()),
-            Index::new(fd, schema, &meta, table.get_schema()),
-        );
+    /// Finish recording the plan of a statement, and warn if the plan
+    /// differs from the one recorded last time.
+    pub fn check_plan(&mut self, statement: &str) -> Result<()> {
+        if self.plan.is_empty() {
+            return Ok(());
+        }
+        let plan = self.plan.join("; ");
+        log::info!("Plan: {plan}");
 
+        if let Some(plans) = &mut self.plans {
+            if let Some(old) = plans.record(statement, plan.clone())? {
+                log::warn!("Plan of statement changed: `{old}` -> `{plan}`");
+            }
+        }
         Ok(())
     }
 
-    /// Get a index for read.
-    fn get_index(&self, table: &str, name: &str) -> Result<&Index> {
-        let key = (table.to_owned(), name.to_owned());
-        self.indexes
-            .get(&key)
-            .ok_or(Error::IndexNotFound(name.to_owned(), table.to_owned()))
+    /// Open a table, hold its file descriptor and schema.
+    ///
+    /// On first use in a statement, an open table saved by another process
+    /// since is loaded again, unless it is changed by this process as well.
+    fn open_table(&mut self, name: &str) -> Result<()> {
+        trace::set_table(name);
+
+        let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
+        let table = db.join(name);
+        let meta = table.join("meta.json");
+
+        if let Some(handle) = self.handles.get(name) {
+            if handle.used == self.statements {
+                return Ok(());
+            }
+            let version = self.get_table(name)?.get_schema().get_version();
+            if meta.exists() && TableSchema::read_version(&meta)? == version {
+                self.handles.get_mut(name).unwrap().used = self.statements;
+                return Ok(());
+            }
+            if handle.modified || self.is_dirty(name) {
+                return Err(Error::TableConflict(name.to_owned()));
+            }
+            log::warn!("Table {name} is modified by another process, loading again");
+            self.close_table(name, true)?;
+        }
+
+        if !table.exists() {
+            log::error!("Table {} not found", name);
+            return Err(Error::TableNotFound(name.to_owned()));
+        }
+
+        let file = File::open(meta.clone())?;
+        let schema: Schema = serde_json::from_reader(file)?;
+
+        let mut fs = self.fs.lock_arc();
+
+        let data = table.join("data.bin");
+        let fd = if schema.compressed {
+            fs.open_compressed(&data)?
+        } else {
+            fs.open(&data)?
+        };
+
+        let table = Table::new(fd, TableSchema::new(schema, &meta, fs.page_size())?);
+
+        self.tables.insert(name.to_owned(), table);
+        let handle = TableHandle {
+            used: self.statements,
+            modified: false,
+        };
+        self.handles.insert(name.to_owned(), handle);
+
+        Ok(())
     }
 
-    /// Get a index for write.
-    fn get_index_mut(&mut self, table: &str, name: &str) -> Result<&mut Index> {
+    /// Close a table along with its indexes.
+    ///
+    /// Dirty pages are written back and schemas saved, unless discarded.
+    fn close_table(&mut self, name: &str, discard: bool) -> Result<()> {
+        self.handles.remove(name);
+        let Some(mut table) = self.tables.remove(name) else {
+            return Ok(());
+        };
+        let keys: Vec<_> = self
+            .indexes
+            .keys()
+            .filter(|(table_name, _)| table_name == name)
+            .cloned()
+            .collect();
+
+        let mut fs = self.fs.lock_arc();
+        if discard {
+            table.discard();
+            fs.discard(table.get_fd());
+        } else {
+            fs.close(table.get_fd())?;
+        }
+        for key in keys {
+            let mut index = self.indexes.remove(&key).unwrap();
+            if discard {
+                index.discard();
+                fs.discard(index.get_fd());
+            } else {
+                fs.close(index.get_fd())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check whether some cached page of a table or its indexes is dirty.
+    fn is_dirty(&self, name: &str) -> bool {
+        let mut fs = self.fs.lock_arc();
+        let table = self.tables.get(name).map(Table::get_fd);
+        let indexes = self
+            .indexes
+            .iter()
+            .filter(|((table_name, _), _)| table_name == name)
+            .map(|(_, index)| index.get_fd());
+        table.into_iter().chain(indexes).any(|fd| fs.is_dirty(fd))
+    }
+
+    /// Prepare open tables for a new statement.
+    ///
+    /// Beyond [`TABLE_CACHE_SIZE`], the least recently used tables are
+    /// closed, except the one an index is being built on and ones with
+    /// changes not yet saved.
+    pub fn refresh_tables(&mut self) -> Result<()> {
+        self.statements += 1;
+        if self.handles.len() <= TABLE_CACHE_SIZE {
+            return Ok(());
+        }
+        let building = self.index_build.as_ref().map(|build| build.table.as_str());
+        let mut handles: Vec<_> = self
+            .handles
+            .iter()
+            .filter(|&(name, handle)| Some(name.as_str()) != building && !handle.modified)
+            .map(|(name, handle)| (handle.used, name.clone()))
+            .collect();
+        handles.sort_unstable();
+        let count = self.handles.len() - TABLE_CACHE_SIZE;
+        for (_, name) in handles.into_iter().take(count) {
+            log::info!("Closing least recently used table {name}");
+            self.close_table(&name, false)?;
+        }
+        Ok(())
+    }
+
+    /// Save tables changed by the statement just executed, so that other
+    /// processes see the changes.
+    ///
+    /// Dirty pages of each table and its indexes are written back before
+    /// its schema is saved as a new version. A table saved by another
+    /// process since loaded is left unsaved, with an error returned.
+    pub fn save_tables(&mut self) -> Result<()> {
+        let Some(db) = &self.db else {
+            return Ok(());
+        };
+        let mut names: Vec<_> = self
+            .handles
+            .iter()
+            .filter(|(_, handle)| handle.modified)
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort_unstable();
+
+        let mut fs = self.fs.lock_arc();
+        for name in names {
+            let table = self.tables.get_mut(&name).unwrap();
+            let meta = db.join(&name).join("meta.json");
+            if TableSchema::read_version(&meta)? != table.get_schema().get_version() {
+                return Err(Error::TableConflict(name));
+            }
+
+            fs.write_back(table.get_fd())?;
+            for ((table_name, _), index) in &mut self.indexes {
+                if *table_name == name {
+                    fs.write_back(index.get_fd())?;
+                    index.save()?;
+                }
+            }
+            table.save_schema()?;
+            self.handles.get_mut(&name).unwrap().modified = false;
+        }
+        Ok(())
+    }
+
+    /// Get a table for read.
+    fn get_table(&self, name: &str) -> Result<&Table> {
+        self.tables
+            .get(name)
+            .ok_or(Error::TableNotFound(name.to_owned()))
+    }
+
+    /// Get a table for write, to be saved after the statement.
+    fn get_table_mut(&mut self, name: &str) -> Result<&mut Table> {
+        if let Some(handle) = self.handles.get_mut(name) {
+            handle.modified = true;
+        }
+        self.tables
+            .get_mut(name)
+            .ok_or(Error::TableNotFound(name.to_owned()))
+    }
+
+    /// Open all indexes on a table.
+    ///
+    /// # Returns
+    ///
+    /// Returns the name of indexes available on the table, without ones
+    /// whose files are missing.
+    ///
+    /// # Warning
+    ///
+    /// Please open the table before opening its indexes.
+    fn open_indexes(&mut self, table_name: &str) -> Result<Vec<String>> {
+        let table = self.get_table(table_name)?;
+        let indexes: Vec<_> = table
+            .get_schema()
+            .get_indexes()
+            .iter()
+            .map(|index| index.name.clone())
+            .collect();
+        for index in &indexes {
+            self.open_index(table_name, index)?;
+        }
+        Ok(indexes
+            .into_iter()
+            .filter(|index| self.is_index_open(table_name, index))
+            .collect())
+    }
+
+    /// Open all indexes on a table to be modified.
+    ///
+    /// A table with some index unavailable is not modified, so that the
+    /// index misses neither records nor constraint checks once rebuilt.
+    fn open_indexes_mut(&mut self, table_name: &str) -> Result<Vec<String>> {
+        let indexes = self.open_indexes(table_name)?;
+        let table = self.get_table(table_name)?;
+        if let Some(index) = table
+            .get_schema()
+            .get_indexes()
+            .iter()
+            .find(|index| !indexes.contains(&index.name))
+        {
+            return Err(Error::IndexUnavailable(
+                index.name.clone(),
+                table_name.to_owned(),
+            ));
+        }
+        Ok(indexes)
+    }
+
+    /// Open a index, hold its file descriptor and schema.
+    ///
+    /// An index whose files are missing, e.g. removed by hand, is left
+    /// unavailable with a warning: it is not used by queries, and its table
+    /// can't be modified until it is rebuilt. It is rebuilt at once from
+    /// records of the table if `rebuild_missing_indexes` is set.
+    fn open_index(&mut self, table_name: &str, name: &str) -> Result<()> {
+        if self.is_index_open(table_name, name) {
+            return Ok(());
+        }
+
+        log::info!("Opening index {table_name}.{name}");
+        let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
+        let table = db.join(table_name);
+
+        let data = table.join(format!("{name}.index.bin"));
+        let meta = table.join(format!("{name}.index.json"));
+        if !data.exists() || !meta.exists() {
+            if self.rebuild_missing_indexes {
+                log::warn!("Files of index {table_name}.{name} are missing, rebuilding");
+                return self.rebuild_index(table_name, name);
+            }
+            log::warn!("Files of index {table_name}.{name} are missing, leaving it unavailable");
+            return Ok(());
+        }
+
+        let mut fs = self.fs.lock_arc();
+
+        let fd = fs.open(&data)?;
+
+        let file = File::open(meta.clone())?;
+        let schema = serde_json::from_reader(file)?;
+
+        let table = self.get_table(table_name)?;
+
+        self.indexes.insert(
+            (table_name.to_owned(), name.to_owned()),
+            Index::new(fd, schema, &meta, table.get_schema()),
+        );
+
+        Ok(())
+    }
+
+    /// Execute rebuild index statement.
+    ///
+    /// The index is built from scratch, with its schema saved in the table
+    /// and records of the table, whether its files are missing or not.
+    /// The rebuild is journaled, and the index is left unavailable if it
+    /// fails or is interrupted by a crash.
+    pub fn rebuild_index(&mut self, table_name: &str, name: &str) -> Result<()> {
+        log::info!("Rebuilding index {table_name}.{name}");
+
+        self.open_table(table_name)?;
+        let table = self.get_table(table_name)?;
+        let mut schema = table
+            .get_schema()
+            .get_indexes()
+            .iter()
+            .find(|index| index.name == name)
+            .cloned()
+            .ok_or(Error::IndexNotFound(name.to_owned(), table_name.to_owned()))?;
+        schema.pages = 0;
+        schema.free = None;
+        schema.root = None;
+
+        let key = (table_name.to_owned(), name.to_owned());
+        if let Some(index) = self.indexes.remove(&key) {
+            let mut fs = self.fs.lock_arc();
+            fs.discard(index.get_fd());
+        }
+
+        let operation = DdlOperation::RebuildIndex {
+            table: table_name.to_owned(),
+            index: name.to_owned(),
+        };
+        self.begin_ddl(operation.clone())?;
+        let result = self.build_index_files(table_name, &schema);
+        if result.is_err() {
+            if let Some(index) = self.indexes.remove(&key) {
+                let mut fs = self.fs.lock_arc();
+                fs.discard(index.get_fd());
+            }
+            let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
+            operation.recover(db)?;
+        }
+        self.end_ddl(&operation)?;
+        result
+    }
+
+    /// Create files of an index and fill it with records of the table,
+    /// written back before returning.
+    fn build_index_files(&mut self, table_name: &str, schema: &IndexSchema) -> Result<()> {
+        self.create_index_files(table_name, schema)?;
+        self.open_index(table_name, &schema.name)?;
+        let columns: Vec<_> = schema.columns.iter().map(String::as_str).collect();
+        self.init_index(table_name, &schema.name, &columns)?;
+
+        let mut fs = self.fs.lock_arc();
+        let index = self
+            .indexes
+            .get_mut(&(table_name.to_owned(), schema.name.clone()))
+            .unwrap();
+        fs.write_back(index.get_fd())?;
+        index.save()
+    }
+
+    /// Check whether an index is open, i.e. available for use.
+    fn is_index_open(&self, table: &str, name: &str) -> bool {
+        self.indexes
+            .contains_key(&(table.to_owned(), name.to_owned()))
+    }
+
+    /// Error on an index not open, which is unavailable if saved in its table.
+    fn index_error(&self, table: &str, name: &str) -> Error {
+        let saved = self
+            .tables
+            .get(table)
+            .is_some_and(|t| t.get_schema().has_index(name));
+        if saved {
+            Error::IndexUnavailable(name.to_owned(), table.to_owned())
+        } else {
+            Error::IndexNotFound(name.to_owned(), table.to_owned())
+        }
+    }
+
+    /// Get a index for read.
+    fn get_index(&self, table: &str, name: &str) -> Result<&Index> {
         let key = (table.to_owned(), name.to_owned());
         self.indexes
-            .get_mut(&key)
-            .ok_or(Error::IndexNotFound(name.to_owned(), table.to_owned()))
+            .get(&key)
+            .ok_or_else(|| self.index_error(table, name))
+    }
+
+    /// Get a index for write, to be saved along with its table after the statement.
+    fn get_index_mut(&mut self, table: &str, name: &str) -> Result<&mut Index> {
+        if !self.is_index_open(table, name) {
+            return Err(self.index_error(table, name));
+        }
+        if let Some(handle) = self.handles.get_mut(table) {
+            handle.modified = true;
+        }
+        let key = (table.to_owned(), name.to_owned());
+        Ok(self.indexes.get_mut(&key).unwrap())
+    }
+
+    /// Get the statistics of the page cache on open files of current database,
+    /// with paths relative to the database.
+    pub fn get_cache_status(&self) -> Result<Vec<(String, CacheStats)>> {
+        let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
+        let stats = self.fs.lock_arc().stats(db);
+        Ok(stats
+            .into_iter()
+            .map(|(path, stats)| (path.display().to_string(), stats))
+            .collect())
     }
 
     /// Get a list of tables in current database.
@@ -292,18 +1305,122 @@ impl System {
         Ok(table.get_schema())
     }
 
+    /// Undo DDL operations interrupted in a database, in reverse order, and
+    /// remove its journal.
+    fn recover_ddl(db: &Path) -> Result<()> {
+        let journal = db.join(DDL_JOURNAL);
+        if !journal.exists() {
+            return Ok(());
+        }
+        let operations: Vec<DdlOperation> = serde_json::from_reader(File::open(&journal)?)?;
+        for operation in operations.iter().rev() {
+            operation.recover(db)?;
+        }
+        fs::remove_file(journal)?;
+        Ok(())
+    }
+
+    /// Write DDL operations in progress into the journal of current database,
+    /// or remove the journal if none.
+    fn save_ddl(&self) -> Result<()> {
+        let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
+        let journal = db.join(DDL_JOURNAL);
+        if self.ddl.is_empty() {
+            fs::remove_file(journal)?;
+            return Ok(());
+        }
+        let file = File::create(journal)?;
+        serde_json::to_writer(&file, &self.ddl)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Journal a DDL operation before touching its files.
+    fn begin_ddl(&mut self, operation: DdlOperation) -> Result<()> {
+        self.ddl.push(operation);
+        if let Err(err) = self.save_ddl() {
+            self.ddl.pop();
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Remove a DDL operation from the journal once it returns.
+    fn end_ddl(&mut self, operation: &DdlOperation) -> Result<()> {
+        self.ddl.retain(|op| op != operation);
+        self.save_ddl()
+    }
+
+    /// Get the name of the index looked up for keys referenced by a foreign key,
+    /// that of the primary key or unique constraint on the referenced columns.
+    ///
+    /// # Warning
+    ///
+    /// Please open the referenced table before looking up.
+    fn get_referred_index_name(&self, fk: &Constraint) -> Result<String> {
+        let Constraint::ForeignKey {
+            ref_table,
+            ref_columns,
+            ..
+        } = fk
+        else {
+            panic!("Constraint is not a foreign key");
+        };
+        let schema = self.get_table(ref_table)?.get_schema();
+        schema
+            .get_constraints()
+            .iter()
+            .find(|c| c.is_key(ref_columns) && c.get_columns() == ref_columns.as_slice())
+            .map(|c| c.get_index_name(false))
+            .ok_or(Error::ForeignKeyNotPrimaryKey)
+    }
+
     /// Create a table.
+    ///
+    /// The operation is journaled, so that files of the table are removed on
+    /// next use of the database if interrupted by a crash, or at once if failed.
     pub fn create_table(&mut self, name: &str, schema: Schema) -> Result<()> {
         log::info!("Creating table {}", name);
 
         let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
-        let table = db.join(name);
-
-        if table.exists() {
+        if db.join(name).exists() {
             log::error!("Table {} already exists", name);
             return Err(Error::TableExists(name.to_owned()));
         }
 
+        let operation = DdlOperation::CreateTable {
+            table: name.to_owned(),
+        };
+        self.begin_ddl(operation.clone())?;
+        let ref_tables: Vec<_> = schema
+            .constraints
+            .iter()
+            .filter_map(|c| match c {
+                Constraint::ForeignKey { ref_table, .. } => Some(ref_table.clone()),
+                _ => None,
+            })
+            .collect();
+        let result = self.create_table_files(name, schema);
+        if result.is_err() {
+            // Referenced tables are loaded again without constraints added,
+            // which are not saved yet.
+            self.close_table(name, true)?;
+            for table in &ref_tables {
+                self.close_table(table, true)?;
+            }
+            let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
+            operation.recover(db)?;
+        }
+        self.end_ddl(&operation)?;
+        result
+    }
+
+    /// Create files of a table and indexes of its constraints.
+    fn create_table_files(&mut self, name: &str, mut schema: Schema) -> Result<()> {
+        let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
+        schema.collation = self.collation;
+        let table = db.join(name);
+
         // Check constraint schemas
         for constraint in &schema.constraints {
             match constraint {
@@ -318,6 +1435,13 @@ impl System {
                 }
             }
         }
+        for constraint in &mut schema.constraints {
+            if let Constraint::ForeignKey { ref_table, .. } = constraint {
+                let ref_table = ref_table.clone();
+                let keys = self.get_table(&ref_table)?.get_schema().get_constraints();
+                constraint.align_to_key(keys);
+            }
+        }
 
         fs::create_dir(table.clone())?;
 
@@ -352,7 +1476,6 @@ impl System {
                     name,
                     columns,
                     ref_table,
-                    ref_columns,
                     ..
                 } => {
                     log::info!("Creating index for foreign key {name:?}");
@@ -367,23 +1490,23 @@ impl System {
                         true,
                     )?;
 
-                    log::info!("Creating index for foreign key referenced table {ref_table:?}");
-                    let ref_columns: Vec<_> = ref_columns.iter().map(|c| c.as_str()).collect();
-                    let prefix = format!("fk_referred.{}", table_name);
+                    log::info!("Adding referred constraint to referenced table {ref_table:?}");
+                    let ref_table = self.get_table_mut(ref_table)?;
+                    ref_table.add_referred_constraint(table_name.to_owned(), constraint.clone());
+                }
+                Constraint::Unique { name, columns } => {
+                    log::info!("Creating index for unique {name:?}");
+                    let name = name.as_deref();
+                    let columns: Vec<_> = columns.iter().map(|c| c.as_str()).collect();
                     self.add_index(
                         false,
-                        Some(&prefix),
-                        ref_table,
+                        Some("unique"),
+                        table_name,
                         name,
-                        ref_columns.as_slice(),
+                        columns.as_slice(),
                         true,
                     )?;
-
-                    log::info!("Adding referred constraint to referenced table {ref_table:?}");
-                    let ref_table = self.get_table_mut(ref_table)?;
-                    ref_table.add_referred_constraint(table_name.to_owned(), constraint.clone());
                 }
-                _ => unreachable!(),
             }
         }
 
@@ -394,6 +1517,8 @@ impl System {
     pub fn drop_table(&mut self, name: &str) -> Result<()> {
         log::info!("Dropping table {}", name);
 
+        self.finish_index_build()?;
+
         // Check foreign key.
         self.open_table(name)?;
         let table = self.get_table(name)?;
@@ -411,30 +1536,14 @@ impl System {
             .into_iter()
             .cloned()
             .collect();
-        let mut fk_indexes = vec![];
         for fk in foreign_keys {
             let ref_table = fk.get_ref_table();
             let ref_table = self.get_table_mut(ref_table)?;
             ref_table.remove_referred_constraint_of_table(name);
-            fk_indexes.push((fk.get_ref_table().to_owned(), fk.get_index_name(false)));
         }
 
         // Writing back dirty pages in the cache.
-        if let Some(table) = self.tables.remove(name) {
-            let mut fs = FS.lock()?;
-            fs.close(table.get_fd())?;
-        }
-        let keys: Vec<_> = self
-            .indexes
-            .keys()
-            .filter(|(table_name, _)| table_name == name)
-            .cloned()
-            .collect();
-        for index in keys {
-            let index = self.indexes.remove(&index).unwrap();
-            let mut fs = FS.lock()?;
-            fs.close(index.get_fd())?;
-        }
+        self.close_table(name, false)?;
 
         let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
         let table = db.join(name);
@@ -446,32 +1555,484 @@ impl System {
 
         fs::remove_dir_all(table)?;
 
-        for (table_name, index_name) in fk_indexes {
-            self.drop_index(&table_name, &index_name)?;
+        Ok(())
+    }
+
+    /// Modify the definition of a column, converting existing values into the new type.
+    ///
+    /// All values are converted before touching data, so the table is left intact
+    /// if some value can't be converted.
+    ///
+    /// # Returns
+    ///
+    /// Returns the count of records rewritten.
+    pub fn modify_column(
+        &mut self,
+        table_name: &str,
+        name: &str,
+        mut column: Column,
+    ) -> Result<usize> {
+        log::info!("Modifying column {name} of table {table_name}");
+
+        self.open_table(table_name)?;
+        let table = self.get_table(table_name)?;
+        let schema = table.get_schema();
+        if !schema.has_column(name) {
+            return Err(Error::ColumnNotFound(name.to_owned()));
+        }
+        if name != column.name && schema.has_column(&column.name) {
+            return Err(Error::DuplicateColumn(column.name));
+        }
+
+        // Types on both sides of a foreign key must match.
+        for fk in schema.get_foreign_keys() {
+            if fk.get_columns().iter().any(|c| c == name) {
+                return Err(Error::ForeignKeyTypeMismatch);
+            }
+        }
+        for (_, fk) in schema.get_referred_constraints() {
+            if let Constraint::ForeignKey { ref_columns, .. } = fk {
+                if ref_columns.iter().any(|c| c == name) {
+                    return Err(Error::ForeignKeyTypeMismatch);
+                }
+            }
+        }
+
+        // The time of records must stay a date.
+        if let Some(ttl) = schema.get_ttl() {
+            if ttl.column == name && !matches!(column.typ, Type::Date | Type::DateTime) {
+                return Err(Error::TtlColumnType(column.name, column.typ));
+            }
+        }
+
+        // Keys of unique indexes must still be told apart by bytes.
+        let unique = schema.get_constraints().iter().any(|c| {
+            matches!(c, Constraint::PrimaryKey { .. } | Constraint::Unique { .. })
+                && c.get_columns().iter().any(|c| c == name)
+        }) || schema
+            .get_indexes()
+            .iter()
+            .any(|index| index.unique && index.columns.iter().any(|c| c == name));
+        if unique {
+            schema.get_collation().check_unique(&column)?;
+        }
+
+        // Columns of the primary key stay NOT NULL.
+        if let Some(Constraint::PrimaryKey { columns, .. }) = schema.get_primary_key() {
+            if columns.iter().any(|c| c == name) {
+                column.nullable = false;
+            }
+        }
+
+        // Convert all records before touching data.
+        let column_index = schema.get_column_index(name);
+        let mut records = vec![];
+        let mut deleted = vec![];
+        {
+            let mut fs = self.fs.lock_arc();
+            for (mut record, _, _) in table.select(&mut fs, &Selectors::All, &[])? {
+                let value = record.fields[column_index].convert(&column.typ)?;
+                if !column.nullable && matches!(value, Value::Null) {
+                    return Err(Error::NotNullable(column.name.clone()));
+                }
+                record.fields[column_index] = value;
+                records.push(record);
+            }
+            for (mut record, page, slot) in table.select_deleted(&mut fs, &Selectors::All, &[])? {
+                let value = record.fields[column_index].convert(&column.typ)?;
+                record.fields[column_index] = value;
+                deleted.push((record, table.get_deleted_time(&mut fs, page, slot)?));
+            }
+        }
+
+        self.close_all()?;
+
+        // The table is written anew aside, and swapped in once complete,
+        // so that the old one is kept intact on failure.
+        let operation = DdlOperation::ModifyColumn {
+            table: table_name.to_owned(),
+        };
+        self.begin_ddl(operation.clone())?;
+        let staging = format!("{table_name}.new");
+        let result = self
+            .write_modified_table(table_name, &staging, column_index, column, records, deleted)
+            .and_then(|count| {
+                let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
+                let old = db.join(format!("{table_name}.old"));
+                fs::rename(db.join(table_name), &old)?;
+                fs::rename(db.join(&staging), db.join(table_name))?;
+                fs::remove_dir_all(old)?;
+                Ok(count)
+            });
+        if result.is_err() {
+            self.close_table(&staging, true)?;
+            let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
+            operation.recover(db)?;
+        }
+        self.end_ddl(&operation)?;
+        result
+    }
+
+    /// Write a table with a column modified into a staging table, with pages of
+    /// the table and its indexes cleared before converted records are inserted.
+    ///
+    /// Records deleted softly are kept deleted along with their deletion time.
+    /// A renamed column is renamed in the staged schema, along with implicit
+    /// indexes named after it.
+    fn write_modified_table(
+        &mut self,
+        table_name: &str,
+        staging: &str,
+        column_index: usize,
+        column: Column,
+        records: Vec<Record>,
+        deleted: Vec<(Record, NaiveDateTime)>,
+    ) -> Result<usize> {
+        let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
+        let table = db.join(staging);
+        fs::create_dir(&table)?;
+
+        let mut schema: Schema =
+            serde_json::from_reader(File::open(db.join(table_name).join("meta.json"))?)?;
+        schema.pages = 0;
+        schema.free = None;
+        schema.full = None;
+        let name = &schema.columns[column_index].name;
+        if *name != column.name {
+            let (name, constraints) = (name.clone(), schema.constraints.clone());
+            Self::rename_schema_column(table_name, &mut schema, table_name, &name, &column.name);
+            let names = Self::implicit_index_names(&constraints, &schema.constraints);
+            for index in &mut schema.indexes {
+                if let Some(name) = names.get(&index.name) {
+                    index.name = name.clone();
+                }
+            }
+        }
+        schema.columns[column_index] = column;
+        schema.deleted = 0;
+        schema.stats = None;
+        for index in &mut schema.indexes {
+            index.pages = 0;
+            index.free = None;
+            index.root = None;
+
+            fs::File::create(table.join(format!("{}.index.bin", index.name)))?;
+            let file = fs::File::create(table.join(format!("{}.index.json", index.name)))?;
+            serde_json::to_writer(file, &index)?;
+        }
+        fs::File::create(table.join("data.bin"))?;
+        schema.version += 1;
+        serde_json::to_writer(fs::File::create(table.join("meta.json"))?, &schema)?;
+
+        // Insert converted records, which may collide once converted.
+        let name = staging;
+        self.open_table(name)?;
+        let indexes = self.open_indexes(name)?;
+        self.check_distinct(name, &indexes, &records)?;
+
+        let count = records.len();
+        let mut fs = self.fs.lock_arc();
+        for record in records {
+            let table = self.get_table_mut(name)?;
+            let (page_id, slot) = table.insert(&mut fs, record.clone())?;
+
+            for index_name in &indexes {
+                let index = self.get_index(name, index_name)?;
+                let table = self.get_table(name)?;
+
+                let selector = index.get_selector();
+                let key = record.select(&selector, table.get_schema());
+
+                let index = self.get_index_mut(name, index_name)?;
+                index.insert(&mut fs, key, page_id, slot)?;
+            }
+        }
+
+        // Records deleted softly are kept deleted, out of indexes.
+        for (record, time) in deleted {
+            let table = self.get_table_mut(name)?;
+            let (page_id, slot) = table.insert(&mut fs, record)?;
+            table.delete_page_slot(&mut fs, page_id, slot, &[], Some(time))?;
+        }
+        drop(fs);
+
+        self.save_tables()?;
+        self.close_table(name, false)?;
+        Ok(count)
+    }
+
+    /// Flush and close all tables and indexes, so that their files can be
+    /// manipulated directly.
+    pub fn close_all(&mut self) -> Result<()> {
+        log::info!("Closing all tables and indexes");
+        self.finish_index_build()?;
+        self.tables.clear();
+        self.handles.clear();
+        self.indexes.clear();
+        self.fs.lock_arc().clear()?;
+        Ok(())
+    }
+
+    /// Rewrite schemas of all tables in current database.
+    ///
+    /// Implicit indexes whose names change along with their constraints
+    /// are renamed accordingly.
+    ///
+    /// # Warning
+    ///
+    /// Please close all tables and indexes before rewriting schemas.
+    fn rewrite_schemas<F: Fn(&str, &mut Schema)>(&self, rewrite: F) -> Result<()> {
+        let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
+
+        for table_name in self.get_tables()? {
+            let table = db.join(&table_name);
+            let meta = table.join("meta.json");
+            let mut schema: Schema = serde_json::from_reader(File::open(&meta)?)?;
+
+            let constraints = schema.constraints.clone();
+            rewrite(&table_name, &mut schema);
+
+            // Mapping from old index names to new ones.
+            let names = Self::implicit_index_names(&constraints, &schema.constraints);
+
+            for index in &mut schema.indexes {
+                let name = names.get(&index.name).unwrap_or(&index.name).to_owned();
+
+                let old_meta = table.join(format!("{}.index.json", index.name));
+                let mut index_schema: IndexSchema =
+                    serde_json::from_reader(File::open(&old_meta)?)?;
+                index_schema.name = name.clone();
+                index_schema.columns = index.columns.clone();
+
+                if name != index.name {
+                    log::info!("Renaming index {} to {name}", index.name);
+                    fs::rename(
+                        table.join(format!("{}.index.bin", index.name)),
+                        table.join(format!("{name}.index.bin")),
+                    )?;
+                    fs::remove_file(old_meta)?;
+                    index.name = name.clone();
+                }
+
+                let file = fs::File::create(table.join(format!("{name}.index.json")))?;
+                serde_json::to_writer(file, &index_schema)?;
+            }
+
+            schema.version += 1;
+            serde_json::to_writer(fs::File::create(&meta)?, &schema)?;
         }
 
         Ok(())
     }
 
+    /// Map names of implicit indexes of constraints to their names once the
+    /// constraints are rewritten.
+    fn implicit_index_names(old: &[Constraint], new: &[Constraint]) -> HashMap<String, String> {
+        old.iter()
+            .zip(new)
+            .map(|(old, new)| {
+                let referrer = matches!(old, Constraint::ForeignKey { .. });
+                (old.get_index_name(referrer), new.get_index_name(referrer))
+            })
+            .collect()
+    }
+
+    /// Rename a table, updating constraints referencing it.
+    pub fn rename_table(&mut self, name: &str, new_name: &str) -> Result<()> {
+        log::info!("Renaming table {name} to {new_name}");
+
+        let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
+        let table = db.join(name);
+        let new_table = db.join(new_name);
+
+        if !table.exists() {
+            return Err(Error::TableNotFound(name.to_owned()));
+        }
+        if new_table.exists() {
+            return Err(Error::TableExists(new_name.to_owned()));
+        }
+
+        self.close_all()?;
+        fs::rename(table, new_table)?;
+
+        self.rewrite_schemas(|_, schema| {
+            let rename = |table: &mut String| {
+                if table == name {
+                    *table = new_name.to_owned();
+                }
+            };
+            for constraint in &mut schema.constraints {
+                if let Constraint::ForeignKey {
+                    referrer,
+                    ref_table,
+                    ..
+                } = constraint
+                {
+                    rename(referrer);
+                    rename(ref_table);
+                }
+            }
+            for (referrer, constraint) in &mut schema.referred_constraints {
+                rename(referrer);
+                if let Constraint::ForeignKey {
+                    referrer,
+                    ref_table,
+                    ..
+                } = constraint
+                {
+                    rename(referrer);
+                    rename(ref_table);
+                }
+            }
+        })
+    }
+
+    /// Rename a column of a table, updating constraints and indexes on it.
+    pub fn rename_column(&mut self, table_name: &str, name: &str, new_name: &str) -> Result<()> {
+        log::info!("Renaming column {name} of table {table_name} to {new_name}");
+
+        self.open_table(table_name)?;
+        let schema = self.get_table(table_name)?.get_schema();
+        if !schema.has_column(name) {
+            return Err(Error::ColumnNotFound(name.to_owned()));
+        }
+        if schema.has_column(new_name) {
+            return Err(Error::DuplicateColumn(new_name.to_owned()));
+        }
+
+        self.close_all()?;
+
+        self.rewrite_schemas(|table, schema| {
+            Self::rename_schema_column(table, schema, table_name, name, new_name)
+        })
+    }
+
+    /// Rename a column of a table in the schema of some table, which has the
+    /// column if it is the table, or may have constraints referencing it.
+    fn rename_schema_column(
+        table: &str,
+        schema: &mut Schema,
+        table_name: &str,
+        name: &str,
+        new_name: &str,
+    ) {
+        let rename = |columns: &mut Vec<String>| {
+            for column in columns {
+                if column == name {
+                    *column = new_name.to_owned();
+                }
+            }
+        };
+
+        if table == table_name {
+            for column in &mut schema.columns {
+                if column.name == name {
+                    column.name = new_name.to_owned();
+                }
+            }
+            for index in &mut schema.indexes {
+                rename(&mut index.columns);
+            }
+            if let Some(ttl) = &mut schema.ttl {
+                if ttl.column == name {
+                    ttl.column = new_name.to_owned();
+                }
+            }
+        }
+
+        let constraints = schema
+            .constraints
+            .iter_mut()
+            .chain(schema.referred_constraints.iter_mut().map(|(_, c)| c));
+        for constraint in constraints {
+            match constraint {
+                Constraint::PrimaryKey { columns, .. } | Constraint::Unique { columns, .. } => {
+                    if table == table_name {
+                        rename(columns);
+                    }
+                }
+                Constraint::ForeignKey {
+                    columns,
+                    referrer,
+                    ref_table,
+                    ref_columns,
+                    ..
+                } => {
+                    if referrer == table_name {
+                        rename(columns);
+                    }
+                    if ref_table == table_name {
+                        rename(ref_columns);
+                    }
+                }
+            }
+        }
+    }
+
     /// Load batched data into a table.
-    pub fn load_table(&mut self, name: &str, file: &Path) -> Result<usize> {
+    ///
+    /// Only records matching the where clauses are inserted.
+    pub fn load_table(
+        &mut self,
+        name: &str,
+        file: &Path,
+        where_clauses: &[WhereClause],
+    ) -> Result<usize> {
         log::info!("Loading data into table {}", name);
 
         self.open_table(name)?;
-        let indexes = self.open_indexes(name)?;
+        let table = self.get_table(name)?;
+        for where_clause in where_clauses {
+            where_clause.check(table.get_schema())?
+        }
 
-        let mut count = 0;
-        let mut reader = ReaderBuilder::new().has_headers(false).from_path(file)?;
-        for result in reader.records() {
+        let indexes = self.open_indexes_mut(name)?;
+
+        // Empty indexes are built in bulk after loading, others are inserted into
+        // record by record. Unique indexes are inserted into directly, so that
+        // no duplicate is loaded.
+        let mut bulk_indexes = HashMap::new();
+        for index_name in &indexes {
+            let schema = self.get_index(name, index_name)?.get_schema();
+            if schema.root.is_none() && !schema.unique {
+                bulk_indexes.insert(index_name.clone(), vec![]);
+            }
+        }
+
+        // Records are all read before inserting any, so that a load beyond
+        // the quota is rejected as a whole
+        let mut records = vec![];
+        // Field counts are checked against the table rather than the first line
+        let mut reader = ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_path(file)?;
+        let schema = self.get_table(name)?.get_schema();
+        for (row, result) in reader.records().enumerate() {
             let record = result?;
             log::debug!("Loading record {record:?}");
+            schema.check_field_count(row + 1, record.len())?;
             let mut fields = vec![];
-            let table = self.get_table_mut(name)?;
-            for (field, column) in record.iter().zip(table.get_schema().get_columns()) {
+            for (field, column) in record.iter().zip(schema.get_columns()) {
                 fields.push(Value::from(field, &column.typ)?);
             }
-            let mut fs = FS.lock()?;
-            let (page_id, slot) = table.insert(&mut fs, Record::new(fields.clone()))?;
+
+            let record = Record::new(fields);
+            if where_clauses
+                .iter()
+                .all(|where_clause| where_clause.matches(&record, schema))
+            {
+                records.push(record);
+            }
+        }
+        self.check_quota(self.insert_size(name, &indexes, &records)?)?;
+
+        let mut count = 0;
+        for record in records {
+            let fields = record.fields.clone();
+            let mut fs = self.fs.lock_arc();
+            let table = self.get_table_mut(name)?;
+            let (page_id, slot) = table.insert(&mut fs, record)?;
             count += 1;
 
             // Insert into indexes
@@ -488,176 +2049,249 @@ impl System {
                 let selector = Selectors::Some(columns);
                 let key = Record::new(fields.clone()).select(&selector, table.get_schema());
 
+                if let Some(keys) = bulk_indexes.get_mut(index_name) {
+                    keys.push((key, page_id, slot));
+                    continue;
+                }
                 let index = self.get_index_mut(name, index_name)?;
                 index.insert(&mut fs, key, page_id, slot)?;
             }
         }
 
+        {
+            let mut fs = self.fs.lock_arc();
+            for (index_name, keys) in bulk_indexes {
+                let index = self.get_index_mut(name, &index_name)?;
+                index.bulk_load(&mut fs, keys)?;
+            }
+        }
+
+        self.track_inserted(name, count)?;
         Ok(count)
     }
 
-    /// Perform grouping on some query results.
+    /// Perform grouping on some query results, by the field at some position.
+    ///
+    /// Groups are in the order of their first records. Strings equal under
+    /// the collation fall into the same group.
     pub fn group(
         &self,
-        selectors: &[Selector],
         results: Vec<SelectResult>,
-        group_by: &ColumnSelector,
+        position: usize,
+        collation: Collation,
     ) -> Vec<Vec<SelectResult>> {
-        log::info!("Grouping on {group_by:?}");
-
-        let mut ret = vec![];
-        let mut group = HashMap::new();
-        let mut group_by_index = None;
-
-        for (i, selector) in selectors.iter().enumerate() {
-            if let Selector::Column(c) = selector {
-                if c == group_by {
-                    group_by_index = Some(i);
-                }
-            };
-        }
-
-        for (record, page, slot) in results {
-            let group_by_value = record.fields[group_by_index.unwrap()].clone();
-            let group = group.entry(group_by_value).or_insert_with(Vec::new);
-            group.push((record, page, slot));
-        }
-
-        for (_, group) in group {
-            ret.push(group);
+        log::info!("Grouping on field {position}");
+
+        let mut ret: Vec<Vec<SelectResult>> = vec![];
+        let mut groups = HashMap::new();
+
+        for result in results {
+            let value = collation.fold(&result.0.fields[position]).into_owned();
+            let i = *groups.entry(value).or_insert_with(|| {
+                ret.push(vec![]);
+                ret.len() - 1
+            });
+            ret[i].push(result);
         }
 
         ret
     }
 
-    /// Perform aggregation on some query results.
+    /// Perform aggregation on groups of query results.
+    ///
+    /// Each aggregated field of the output schema is computed over the group,
+    /// while the other fields are taken from the first record of the group.
     pub fn aggregate(
         &self,
-        selectors: &[Selector],
-        results: Vec<Vec<SelectResult>>,
-        grouped: bool,
-    ) -> Vec<SelectResult> {
+        schema: &OutputSchema,
+        groups: Vec<Vec<SelectResult>>,
+    ) -> Result<Vec<SelectResult>> {
+        let selectors = schema.get_selectors();
         let mut ret = vec![];
 
-        for group in results {
-            let mut fields = vec![];
+        for group in groups {
+            let (mut fields, page, slot) = match group.first() {
+                Some((record, page, slot)) => (record.fields.clone(), *page, *slot),
+                None => (vec![Value::Null; selectors.len()], 0, 0),
+            };
 
             for (i, selector) in selectors.iter().enumerate() {
                 match selector {
                     Selector::Aggregate(aggregator, _) => {
-                        let mut values = vec![];
-                        for (record, _, _) in &group {
-                            values.push(record.fields[i].clone());
-                        }
-                        fields.push(aggregator.aggregate(values));
+                        let values = group
+                            .iter()
+                            .map(|(record, _, _)| record.fields[i].clone())
+                            .collect();
+                        fields[i] = aggregator.aggregate(values)?;
                     }
                     Selector::Count => {
-                        fields.push(Value::Int(group.len() as i32));
-                    }
-                    _ => {
-                        fields.push(group[0].0.fields[i].clone());
+                        fields[i] = Value::Int(group.len() as i32);
                     }
+                    Selector::Column(_) | Selector::Cast(..) => (),
                 }
             }
 
-            ret.push((Record::new(fields), 0, 0));
-        }
-
-        if grouped {
-            // Remove the added group column
-            for (record, _, _) in &mut ret {
-                record.fields.pop();
-            }
+            ret.push((Record::new(fields), page, slot));
         }
 
-        ret
+        Ok(ret)
     }
 
     /// Perform ordering on some query results.
     ///
+    /// NULLs come first in ascending order and last in descending order,
+    /// and strings are compared under the collation.
+    /// If stable pagination is set, ties are broken by the position of records,
+    /// so that pages of the results never overlap.
+    ///
     /// # Parameters
     ///
-    /// - `extra`: whether to remove extra columns added for sorting.
+    /// - `keys`: column indexes to sort on, each with whether in ascending order.
     pub fn order(
         &self,
-        order_index: usize,
+        keys: &[(usize, bool)],
         results: Vec<SelectResult>,
-        order_by: ColumnSelector,
-        asc: bool,
-        extra: bool,
+        collation: Collation,
     ) -> Vec<SelectResult> {
-        log::info!("Ordering on {order_by:?}");
+        log::info!("Ordering on {keys:?}");
 
         let mut ret = results;
         ret.sort_by(|a, b| {
-            let a = &a.0.fields[order_index];
-            let b = &b.0.fields[order_index];
-            // Use string comparison as a fallback
-            if asc {
-                a.partial_cmp(b)
-                    .unwrap_or(a.to_string().cmp(&b.to_string()))
+            for &(order_index, asc) in keys {
+                let a = &*collation.fold(&a.0.fields[order_index]);
+                let b = &*collation.fold(&b.0.fields[order_index]);
+                // Nulls are the smallest, and string comparison is used as a fallback
+                let ordering = match (a, b) {
+                    (Value::Null, Value::Null) => Ordering::Equal,
+                    (Value::Null, _) => Ordering::Less,
+                    (_, Value::Null) => Ordering::Greater,
+                    _ => a
+                        .partial_cmp(b)
+                        .unwrap_or(a.to_string().cmp(&b.to_string())),
+                };
+                let ordering = if asc { ordering } else { ordering.reverse() };
+                if ordering.is_ne() {
+                    return ordering;
+                }
+            }
+            if self.stable_pagination {
+                (a.1, a.2).cmp(&(b.1, b.2))
             } else {
-                b.partial_cmp(a)
-                    .unwrap_or(b.to_string().cmp(&a.to_string()))
+                Ordering::Equal
             }
         });
 
-        if extra {
-            // Remove the added order column
-            for (record, _, _) in &mut ret {
-                record.fields.pop();
+        ret
+    }
+
+    /// Execute a parsed select statement.
+    ///
+    /// Returns the output schema along with the results within the limit.
+    pub fn execute_select(
+        &mut self,
+        statement: SelectStatement,
+    ) -> Result<(OutputSchema, Vec<SelectResult>)> {
+        let tables: Vec<_> = statement.tables.iter().map(String::as_str).collect();
+        let (schema, mut results) = self.select(
+            &statement.selectors,
+            &tables,
+            statement.where_clauses,
+            statement.group_by,
+            statement.order_by,
+            statement.with_deleted,
+        )?;
+
+        if let Some((limit, offset)) = statement.limit {
+            if let Some(offset) = offset {
+                results = results.into_iter().skip(offset as usize).collect();
             }
+            results = results.into_iter().take(limit as usize).collect();
         }
 
-        ret
+        Ok((schema, results))
     }
 
     /// Execute select statement.
+    ///
+    /// Returns the output schema along with the results.
     pub fn select(
         &mut self,
         selectors: &Selectors,
         tables: &[&str],
         where_clauses: Vec<WhereClause>,
         group_by: Option<ColumnSelector>,
-        order_by: Option<(ColumnSelector, bool)>,
-    ) -> Result<Vec<SelectResult>> {
+        order_by: Vec<(ColumnSelector, bool)>,
+        with_deleted: bool,
+    ) -> Result<(OutputSchema, Vec<SelectResult>)> {
         log::info!("Executing select statement");
 
-        // Add group as last column
-        let selectors = if let Some(group_by) = &group_by {
-            match selectors {
-                Selectors::All => Selectors::All,
-                Selectors::Some(selectors) => {
-                    let mut selectors = selectors.clone();
-                    selectors.push(Selector::Column(group_by.clone()));
-                    Selectors::Some(selectors)
-                }
-            }
-        } else {
-            selectors.clone()
-        };
-        // Add order as last column
-        let selectors = if let Some((order_by, _)) = &order_by {
-            match selectors {
-                Selectors::All => Selectors::All,
-                Selectors::Some(selectors) => {
-                    let mut selectors = selectors.clone();
-                    selectors.push(Selector::Column(order_by.clone()));
-                    Selectors::Some(selectors)
-                }
-            }
-        } else {
-            selectors
+        if with_deleted && tables.len() > 1 {
+            return Err(Error::NotImplemented("Deleted records in joins"));
+        }
+
+        // Plan the output schema, with group and order columns added as last columns.
+        // All column selectors are qualified with their tables.
+        for table in tables {
+            self.open_table(table)?;
+        }
+        let mut schemas = vec![];
+        for table in tables {
+            schemas.push(self.get_table(table)?.get_schema());
+        }
+        // Results are grouped and ordered under the collation of the first table
+        let collation = schemas[0].get_collation();
+        let mut schema = OutputSchema::new(selectors, &schemas, tables)?;
+        let selected = schema.get_columns().len();
+        let group_position = match group_by {
+            Some(group_by) => Some(schema.push(Selector::Column(group_by), &schemas, tables)?),
+            None => None,
         };
-        let selectors = &selectors;
+        let mut keys = vec![];
+        for (order_by, asc) in order_by {
+            let position = schema.push(Selector::Column(order_by), &schemas, tables)?;
+            keys.push((position, asc));
+        }
+        let where_clauses = where_clauses
+            .iter()
+            .map(|where_clause| where_clause.qualify(&schemas, tables))
+            .collect::<Result<Vec<_>>>()?;
 
-        let ret = match tables.len() {
-            0 => unreachable!(),
-            1 => {
+        if self.only_full_group_by {
+            Self::check_full_group_by(selectors, &schema, selected, group_position)?;
+        }
+
+        let selectors = &Selectors::Some(schema.get_selectors().to_vec());
+
+        // Order columns, if results could be ordered by walking an index.
+        // Ties in an index are not ordered by record position.
+        let asc = keys.first().map(|&(_, asc)| asc);
+        let order_columns: Vec<_> = keys
+            .iter()
+            .map(|&(position, _)| match &schema.get_selectors()[position] {
+                Selector::Column(ColumnSelector(_, column)) => column.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+        let order_columns = match asc {
+            Some(asc)
+                if group_position.is_none()
+                    && !schema.has_aggregate()
+                    && !self.stable_pagination
+                    && !with_deleted
+                    && keys.iter().all(|&(_, a)| a == asc) =>
+            {
+                Some((order_columns, asc))
+            }
+            _ => None,
+        };
+        let mut ordered = false;
+
+        let mut ret = match tables.len() {
+            0 => unreachable!(),
+            1 => {
                 assert_eq!(tables.len(), 1);
 
                 let table_name = tables[0];
-                self.open_table(tables[0])?;
                 let table = self.get_table(tables[0])?;
 
                 selectors.check(table.get_schema())?;
@@ -668,14 +2302,22 @@ impl System {
                 // Open all indexes of this table.
                 self.open_indexes(table_name)?;
 
+                let order_index = match &order_columns {
+                    Some((columns, asc)) => self
+                        .match_order_index(table_name, columns)
+                        .map(|index_name| (index_name, *asc)),
+                    None => None,
+                };
+
                 let table = self.get_table(table_name)?;
 
-                let mut fs = FS.lock()?;
+                let mut fs = self.fs.lock_arc();
 
                 // Check index availability
                 let index = self.match_index(&mut fs, tables[0], where_clauses.as_slice())?;
-                if let Some((index_name, left_iter, right_key)) = index {
+                let mut ret = if let Some((index_name, left_iter, right_key)) = index {
                     log::info!("Using index {index_name}");
+                    self.plan.push(format!("index {table_name}.{index_name}"));
 
                     // Use index
                     let mut iter = left_iter;
@@ -684,9 +2326,11 @@ impl System {
 
                     loop {
                         let index = self.get_index(table_name, &index_name)?;
-                        let (record, page, slot) = index.get_record(&mut fs, iter)?;
+                        // Keep the leaf cached while pages of the table are fetched
+                        let leaf = index.pin_leaf(&mut fs, iter)?;
+                        let (record, page, slot) = index.get_pinned_record(&leaf, iter.1);
                         // Iteration ended
-                        if record > right_key {
+                        if record.into_prefix(right_key.fields.len()) > right_key {
                             break ret;
                         }
                         let table = self.get_table(table_name)?;
@@ -705,87 +2349,190 @@ impl System {
                             break ret;
                         }
                     }
-                } else {
-                    table.select(&mut fs, selectors, where_clauses.as_slice())?
-                }
-            }
-            2 => self.join_select(selectors, tables, where_clauses)?,
-            _ => return Err(Error::NotImplemented("Join on multiple tables")),
-        };
+                } else if let Some((index_name, left, right)) =
+                    self.match_skip_index(table_name, where_clauses.as_slice())?
+                {
+                    log::info!("Using index {index_name} by skip scan");
+                    let step = format!("skip scan {table_name}.{index_name}");
+                    self.plan.push(step);
 
-        // Perform order
-        let ret = if let Some((order_by, asc)) = order_by {
-            let (order_index, extra) = match selectors {
-                Selectors::All => {
-                    if tables.len() == 1 {
-                        let table = self.get_table(tables[0])?;
-                        if !table.get_schema().has_column(&order_by.1) {
-                            return Err(Error::ColumnNotFound(order_by.1.to_owned()));
-                        }
-                        let order_index = table.get_schema().get_column_index(&order_by.1);
-                        (order_index, false)
-                    } else {
-                        if order_by.0.is_none() {
-                            return Err(Error::InexactColumn(order_by.1.to_owned()));
-                        }
-                        let ColumnSelector(table, column) = &order_by;
-                        let table = table.as_deref().unwrap();
+                    let index = self.get_index(table_name, &index_name)?;
+                    let table = self.get_table(table_name)?;
+                    let column = &index.get_schema().columns[1];
+                    let max_key = IndexConditions::max_key(table.get_schema(), column);
 
-                        let mut order_index = 0;
+                    let mut ret = vec![];
 
-                        for t in tables {
-                            if t == &table {
-                                let table = self.get_table(t)?;
-                                if !table.get_schema().has_column(column) {
-                                    return Err(Error::ColumnNotFound(column.to_owned()));
-                                }
-                                order_index += table.get_schema().get_column_index(column);
+                    // Range scan the second column within each distinct leading value
+                    let mut next = index.first_iter(&mut fs)?;
+                    while let Some(first) = next {
+                        let (key, _, _) = index.get_record(&mut fs, first)?;
+                        let leading = key.fields[0].clone();
+                        let left_key = Record::new(vec![leading.clone(), left.clone()]);
+                        let right_key = Record::new(vec![leading.clone(), right.clone()]);
+
+                        let mut iter = index.index(&mut fs, &left_key)?;
+                        while let Some(curr) = iter {
+                            let leaf = index.pin_leaf(&mut fs, curr)?;
+                            let (record, page, slot) = index.get_pinned_record(&leaf, curr.1);
+                            if record.into_prefix(2) > right_key {
                                 break;
-                            } else {
-                                let table = self.get_table(t)?;
-                                order_index += table.get_schema().get_columns().len();
                             }
+                            if let Some(record) = table.select_page_slot(
+                                &mut fs,
+                                page,
+                                slot,
+                                selectors,
+                                where_clauses.as_slice(),
+                            )? {
+                                ret.push((record, page, slot));
+                            }
+                            iter = index.inc_iter(&mut fs, curr)?;
                         }
 
-                        (order_index, false)
+                        // Skip to the next leading value, past keys of the largest second value
+                        let last_key = Record::new(vec![leading.clone(), max_key.clone()]);
+                        next = index.index(&mut fs, &last_key)?;
+                        while let Some(curr) = next {
+                            let (key, _, _) = index.get_record(&mut fs, curr)?;
+                            if key.fields[0] != leading {
+                                break;
+                            }
+                            next = index.inc_iter(&mut fs, curr)?;
+                        }
                     }
-                }
-                Selectors::Some(columns) => (columns.len() - 1, true),
-            };
 
-            self.order(order_index, ret, order_by, asc, extra)
-        } else {
-            ret
-        };
+                    ret
+                } else if let Some((index_name, asc)) = order_index {
+                    let order = if asc { "asc" } else { "desc" };
+                    log::info!("Using index {index_name} for order {order}");
+                    let step = format!("index {table_name}.{index_name} {order}");
+                    self.plan.push(step);
+
+                    // Walk the index in order, backwards if descending
+                    let index = self.get_index(table_name, &index_name)?;
+                    let mut iter = if asc {
+                        index.first_iter(&mut fs)?
+                    } else {
+                        index.last_iter(&mut fs)?
+                    };
 
-        // Perform aggregation
-        match selectors {
-            Selectors::All => Ok(ret),
-            Selectors::Some(selectors) => {
-                // Whether aggregation is needed
-                let mut aggregate = false;
-                for selector in selectors {
-                    match selector {
-                        Selector::Aggregate { .. } | Selector::Count => {
-                            aggregate = true;
+                    let mut ret = vec![];
+
+                    while let Some(curr) = iter {
+                        let leaf = index.pin_leaf(&mut fs, curr)?;
+                        let (_, page, slot) = index.get_pinned_record(&leaf, curr.1);
+                        let table = self.get_table(table_name)?;
+                        if let Some(record) = table.select_page_slot(
+                            &mut fs,
+                            page,
+                            slot,
+                            selectors,
+                            where_clauses.as_slice(),
+                        )? {
+                            ret.push((record, page, slot));
                         }
-                        _ => (),
+                        iter = if asc {
+                            index.inc_iter(&mut fs, curr)?
+                        } else {
+                            index.dec_iter(&mut fs, curr)?
+                        };
                     }
-                }
 
-                let mut ret = if let Some(group_by) = &group_by {
-                    self.group(selectors, ret, group_by)
+                    ordered = true;
+                    ret
                 } else {
-                    vec![ret]
+                    let ret = table.select(&mut fs, selectors, where_clauses.as_slice())?;
+                    self.plan.push(format!("scan {table_name}"));
+                    ret
                 };
 
-                Ok(if aggregate {
-                    self.aggregate(selectors.as_slice(), ret, group_by.is_some())
-                } else {
-                    ret.pop().unwrap_or_default()
-                })
+                // Records deleted softly come after all live records
+                if with_deleted {
+                    let table = self.get_table(table_name)?;
+                    ret.extend(table.select_deleted(
+                        &mut fs,
+                        selectors,
+                        where_clauses.as_slice(),
+                    )?);
+                }
+
+                ret
+            }
+            2 => self.join_select(selectors, tables, where_clauses)?,
+            _ => return Err(Error::NotImplemented("Join on multiple tables")),
+        };
+
+        // Convert casted fields before grouping, so that groups see the new values
+        for (record, _, _) in &mut ret {
+            for (field, selector) in record.fields.iter_mut().zip(schema.get_selectors()) {
+                if let Selector::Cast(..) = selector {
+                    *field = selector.cast(field)?;
+                }
+            }
+        }
+
+        // Perform grouping and aggregation
+        let ret = if let Some(position) = group_position {
+            let groups = self.group(ret, position, collation);
+            self.aggregate(&schema, groups)?
+        } else if schema.has_aggregate() {
+            self.aggregate(&schema, vec![ret])?
+        } else {
+            ret
+        };
+
+        // Perform order
+        let mut ret = if keys.is_empty() || ordered {
+            ret
+        } else {
+            self.order(&keys, ret, collation)
+        };
+
+        // Remove the added group and order columns
+        for (record, _, _) in &mut ret {
+            record.fields.truncate(selected);
+        }
+        schema.truncate(selected);
+
+        Ok((schema, ret))
+    }
+
+    /// Check that selected columns are all grouped or aggregated.
+    ///
+    /// Otherwise, values of such columns are taken from the first record
+    /// of each group, which is rejected when `only_full_group_by` is set.
+    ///
+    /// The first `selected` fields of the output schema are the selected ones,
+    /// and `group_position` is the position of the group column if any.
+    fn check_full_group_by(
+        selectors: &Selectors,
+        schema: &OutputSchema,
+        selected: usize,
+        group_position: Option<usize>,
+    ) -> Result<()> {
+        if let (Selectors::All, Some(_)) = (selectors, group_position) {
+            return Err(Error::NotGrouped("*".to_owned()));
+        }
+        let group_by = group_position.map(|position| &schema.get_selectors()[position]);
+        let selectors = &schema.get_selectors()[..selected];
+        let aggregate = selectors
+            .iter()
+            .any(|s| matches!(s, Selector::Aggregate(..) | Selector::Count));
+        for selector in selectors {
+            if let Selector::Column(column)
+            | Selector::Cast(Expression::Column(column) | Expression::Cast(column, _), _) =
+                selector
+            {
+                match group_by {
+                    Some(Selector::Column(group_by)) if column == group_by => (),
+                    Some(_) => return Err(Error::NotGrouped(column.1.clone())),
+                    None if aggregate => return Err(Error::MixedAggregate),
+                    None => (),
+                }
             }
         }
+        Ok(())
     }
 
     fn join_select(
@@ -844,6 +2591,17 @@ impl System {
                 } else {
                     (column1, column0)
                 })
+            } else if let WhereClause::OperatorExpression(
+                ColumnSelector(Some(table0), _),
+                _,
+                Expression::Cast(ColumnSelector(Some(table1), _), _),
+            ) = where_clause
+            {
+                // Casted columns are compared within a table only
+                if table0 != table1 {
+                    Err(Error::JoinOperation)?;
+                }
+                real_where_clauses.push(where_clause.clone());
             } else {
                 // Not a join condition
                 real_where_clauses.push(where_clause.clone());
@@ -859,34 +2617,54 @@ impl System {
             cond.1
         );
 
-        let mut index_to_use = None;
-
-        // Try to use index
+        // Find out indexes on join columns
+        let mut index0 = None;
         for index in &indexes0 {
             let index = self.get_index(table0_name, index)?;
             if index.get_columns().len() == 1 && &index.get_columns()[0].name == cond.0 {
-                log::info!("Use index of {} on table {table0_name}", cond.0);
-                index_to_use = Some(index);
+                index0 = Some(index);
+                break;
+            }
+        }
+        let mut index1 = None;
+        for index in &indexes1 {
+            let index = self.get_index(table1_name, index)?;
+            if index.get_columns().len() == 1 && &index.get_columns()[0].name == cond.1 {
+                index1 = Some(index);
                 break;
             }
         }
 
-        if index_to_use.is_none() {
+        // Estimate the cost of each order: the outer table is scanned once, while
+        // the inner table is looked up through its index or scanned for each
+        // outer record.
+        let cost = |outer_name, outer: &TableSchema, inner: &TableSchema, index: bool| {
+            let rows = outer.estimate_rows(&match_where_clauses(&real_where_clauses, outer_name));
+            let inner_cost = if index {
+                1.0
+            } else {
+                inner.get_pages().max(1) as f64
+            };
+            outer.get_pages() as f64 + rows * inner_cost
+        };
+        let schema0 = self.get_table(table0_name)?.get_schema();
+        let schema1 = self.get_table(table1_name)?.get_schema();
+        let cost0 = cost(table1_name, schema1, schema0, index0.is_some());
+        let cost1 = cost(table0_name, schema0, schema1, index1.is_some());
+        log::info!("Cost is {cost0} with inner {table0_name}, {cost1} with inner {table1_name}");
+
+        let mut index_to_use = index0;
+        if cost1 < cost0 {
             // Swap tables
             (table0_name, table1_name) = (table1_name, table0_name);
             cond = (cond.1, cond.0);
-
-            for index in &indexes1 {
-                let index = self.get_index(table0_name, index)?;
-                if index.get_columns().len() == 1 && &index.get_columns()[0].name == cond.0 {
-                    log::info!("Use index of {} on table {table0_name}", cond.0);
-                    index_to_use = Some(index);
-                    break;
-                }
-            }
+            index_to_use = index1;
+        }
+        if index_to_use.is_some() {
+            log::info!("Use index of {} on table {table0_name}", cond.0);
         }
 
-        // Now, table0 will have index if possible, so we use table1 as outer table
+        // Now, table0 is the cheaper inner table, so we use table1 as outer table
         // and table0 as inner table.
 
         let (inner_table_name, outer_table_name) = (table0_name, table1_name);
@@ -906,7 +2684,7 @@ impl System {
                     WhereClause::OperatorExpression(ColumnSelector(table_selector, _), _, _) => {
                         table_selector.as_ref().unwrap() == table_name
                     }
-                    WhereClause::LikeString(ColumnSelector(table_selector, _), _) => {
+                    WhereClause::LikeString(ColumnSelector(table_selector, _), _, _) => {
                         table_selector.as_ref().unwrap() == table_name
                     }
                     WhereClause::IsNull(ColumnSelector(table_selector, _), _) => {
@@ -923,9 +2701,15 @@ impl System {
         let schemas = [outer_table.get_schema(), inner_table.get_schema()];
         let tables = [outer_table_name, inner_table_name];
 
+        let plan = if index_to_use.is_some() {
+            format!("index join {outer_table_name} -> {inner_table_name}.{inner_cond}")
+        } else {
+            format!("nested loop join {outer_table_name} -> {inner_table_name}")
+        };
+
         let mut ret = vec![];
 
-        let mut fs = FS.lock()?;
+        let mut fs = self.fs.lock_arc();
 
         if let Some(index) = index_to_use {
             log::info!("Use index on join select");
@@ -943,6 +2727,10 @@ impl System {
                 for (outer_record, _, _) in block {
                     // Query index
                     let join_cond = outer_record.fields[outer_cond_index].clone();
+                    // NULL never equals anything
+                    if join_cond == Value::Null {
+                        continue;
+                    }
                     let key = Record::new(vec![join_cond]);
                     let iter = index.index(&mut fs, &key)?;
                     if iter.is_none() {
@@ -951,7 +2739,8 @@ impl System {
 
                     let mut iter = iter.unwrap();
                     loop {
-                        let (index_record, page_id, slot) = index.get_record(&mut fs, iter)?;
+                        let leaf = index.pin_leaf(&mut fs, iter)?;
+                        let (index_record, page_id, slot) = index.get_pinned_record(&leaf, iter.1);
                         // Iteration ended
                         if index_record > key {
                             break;
@@ -1026,9 +2815,76 @@ impl System {
             }
         }
 
+        self.plan.push(plan);
+
         Ok(ret)
     }
 
+    /// Create a table from query results, and return the number of records inserted.
+    ///
+    /// Columns are named after the selected columns, or the aggregator and column
+    /// for aggregated fields, e.g. `sum_price`. Casted columns keep their names,
+    /// while casted constants are named `cast`. No constraint is copied.
+    pub fn create_table_as(
+        &mut self,
+        name: &str,
+        schema: &OutputSchema,
+        results: Vec<SelectResult>,
+    ) -> Result<usize> {
+        log::info!("Creating table {name} from query results");
+
+        let mut columns = vec![];
+        let mut names = HashSet::new();
+        for (selector, column) in schema.get_selectors().iter().zip(schema.get_columns()) {
+            let column_name = match selector {
+                Selector::Column(ColumnSelector(_, column)) => column.clone(),
+                Selector::Aggregate(aggregator, ColumnSelector(_, column)) => {
+                    format!("{}_{column}", aggregator.to_string().to_lowercase())
+                }
+                Selector::Cast(
+                    Expression::Column(ColumnSelector(_, column))
+                    | Expression::Cast(ColumnSelector(_, column), _),
+                    _,
+                ) => column.clone(),
+                Selector::Cast(Expression::Value(_), _) => "cast".to_owned(),
+                Selector::Count => "count".to_owned(),
+            };
+            if !names.insert(column_name.clone()) {
+                return Err(Error::DuplicateColumn(column_name));
+            }
+            columns.push(Column {
+                name: column_name,
+                ..column.clone()
+            });
+        }
+
+        self.create_table(
+            name,
+            Schema {
+                pages: 0,
+                free: None,
+                full: None,
+                overflow: None,
+                columns,
+                constraints: vec![],
+                referred_constraints: vec![],
+                indexes: vec![],
+                ttl: None,
+                deleted: 0,
+                stats: None,
+                compressed: false,
+                collation: Collation::default(),
+                version: 0,
+            },
+        )?;
+
+        let count = results.len();
+        let records = results.into_iter().map(|(record, _, _)| record).collect();
+        self.insert(name, records)?;
+
+        Ok(count)
+    }
+
     /// Execute insert statement.
     pub fn insert(&mut self, table: &str, records: Vec<Record>) -> Result<()> {
         log::info!("Executing insert statement");
@@ -1037,82 +2893,22 @@ impl System {
 
         self.open_table(table)?;
         // Open all indexes of this table.
-        let indexes = self.open_indexes(table_name)?;
+        let indexes = self.open_indexes_mut(table_name)?;
 
         let table = self.get_table(table)?;
 
         let schema = table.get_schema();
-        for record in &records {
-            record.check(schema)?;
-        }
+        let records = records
+            .into_iter()
+            .map(|record| record.coerce(schema))
+            .collect::<Result<Vec<_>>>()?;
+        let count = records.len();
+        self.check_quota(self.insert_size(table_name, &indexes, &records)?)?;
 
         for record in records {
-            let table = self.get_table(table_name)?;
-            let schema = table.get_schema();
-            let constraints = schema.get_constraints().to_owned();
-
-            // Check constraints.
-            for constraint in &constraints {
-                match constraint {
-                    Constraint::PrimaryKey { .. } => {
-                        let index_name = constraint.get_index_name(false);
-
-                        let index = self.get_index(table_name, &index_name)?;
-                        let table = self.get_table(table_name)?;
-
-                        let selector = index.get_selector();
-                        let key = record.select(&selector, table.get_schema());
-
-                        let mut fs = FS.lock()?;
-                        if index.contains(&mut fs, &key)? {
-                            Err(Error::DuplicateValue(constraint.get_display_name()))?;
-                        }
-                    }
-                    Constraint::ForeignKey { ref_table, .. } => {
-                        self.open_table(ref_table)?;
-                        self.open_indexes(ref_table)?;
-
-                        let index_name = constraint.get_index_name(true);
-                        let table = self.get_table(table_name)?;
-                        let index = self.get_index(table_name, &index_name)?;
-                        let selector = index.get_selector();
-                        let key = record.select(&selector, table.get_schema());
-
-                        // Skip checks for null
-                        if key.has_null() {
-                            continue;
-                        }
-
-                        let index_name = constraint.get_index_name(false);
-                        let index = self.get_index(ref_table, &index_name)?;
-
-                        log::info!("Checking fk: indexing {key:?} in {ref_table}");
-
-                        let mut fs = FS.lock()?;
-                        if !index.contains(&mut fs, &key)? {
-                            Err(Error::ReferencedFieldsNotExist(
-                                constraint.get_display_name(),
-                            ))?;
-                        }
-                    }
-                    Constraint::Unique { .. } => {
-                        let index_name = constraint.get_index_name(false);
-
-                        let index = self.get_index(table_name, &index_name)?;
-                        let table = self.get_table(table_name)?;
-
-                        let selector = index.get_selector();
-                        let key = record.select(&selector, table.get_schema());
+            self.check_record(table_name, &indexes, &record)?;
 
-                        let mut fs = FS.lock()?;
-                        if index.contains(&mut fs, &key)? {
-                            Err(Error::DuplicateValue(constraint.get_display_name()))?;
-                        }
-                    }
-                }
-            }
-
-            let mut fs = FS.lock()?;
+            let mut fs = self.fs.lock_arc();
 
             let table = self.get_table_mut(table_name)?;
             let (page_id, slot) = table.insert(&mut fs, record.clone())?;
@@ -1138,6 +2934,154 @@ impl System {
             }
         }
 
+        self.track_inserted(table_name, count)
+    }
+
+    /// Check a record to be inserted against constraints and unique indexes of a table.
+    ///
+    /// # Warning
+    ///
+    /// Please open the table and its indexes before checking.
+    fn check_record(
+        &mut self,
+        table_name: &str,
+        indexes: &[String],
+        record: &Record,
+    ) -> Result<()> {
+        let table = self.get_table(table_name)?;
+        let schema = table.get_schema();
+        let constraints = schema.get_constraints().to_owned();
+
+        // Check constraints.
+        for constraint in &constraints {
+            match constraint {
+                Constraint::PrimaryKey { .. } => {
+                    let index_name = constraint.get_index_name(false);
+
+                    let index = self.get_index(table_name, &index_name)?;
+                    let table = self.get_table(table_name)?;
+
+                    let selector = index.get_selector();
+                    let key = record.select(&selector, table.get_schema());
+
+                    let mut fs = self.fs.lock_arc();
+                    if index.contains(&mut fs, &key)? {
+                        Err(Error::DuplicateValue(constraint.get_display_name()))?;
+                    }
+                }
+                Constraint::ForeignKey { ref_table, .. } => {
+                    if !self.foreign_key_checks {
+                        continue;
+                    }
+
+                    self.open_table(ref_table)?;
+                    self.open_indexes(ref_table)?;
+
+                    let index_name = constraint.get_index_name(true);
+                    let table = self.get_table(table_name)?;
+                    let index = self.get_index(table_name, &index_name)?;
+                    let selector = index.get_selector();
+                    let key = record.select(&selector, table.get_schema());
+
+                    // Skip checks for null
+                    if key.has_null() {
+                        continue;
+                    }
+
+                    let index_name = self.get_referred_index_name(constraint)?;
+                    let index = self.get_index(ref_table, &index_name)?;
+
+                    log::info!("Checking fk: indexing {key:?} in {ref_table}");
+
+                    let mut fs = self.fs.lock_arc();
+                    if !index.contains(&mut fs, &key)? {
+                        Err(Error::ReferencedFieldsNotExist(
+                            constraint.get_display_name(),
+                        ))?;
+                    }
+                }
+                Constraint::Unique { .. } => {
+                    let index_name = constraint.get_index_name(false);
+
+                    let index = self.get_index(table_name, &index_name)?;
+                    let table = self.get_table(table_name)?;
+
+                    let selector = index.get_selector();
+                    let key = record.select(&selector, table.get_schema());
+
+                    // Null values never collide
+                    if key.has_null() {
+                        continue;
+                    }
+
+                    let mut fs = self.fs.lock_arc();
+                    if index.contains(&mut fs, &key)? {
+                        Err(Error::DuplicateValue(constraint.get_display_name()))?;
+                    }
+                }
+            }
+        }
+
+        // Check unique indexes.
+        for index_name in indexes {
+            let index = self.get_index(table_name, index_name)?;
+            if !index.get_schema().unique {
+                continue;
+            }
+            let table = self.get_table(table_name)?;
+
+            let selector = index.get_selector();
+            let key = record.select(&selector, table.get_schema());
+
+            let mut fs = self.fs.lock_arc();
+            index.check_unique(&mut fs, &key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Check that no two records to be inserted together collide with each other
+    /// on primary keys, unique constraints or unique indexes of a table.
+    ///
+    /// # Warning
+    ///
+    /// Please open the table and its indexes before checking.
+    fn check_distinct(
+        &self,
+        table_name: &str,
+        indexes: &[String],
+        records: &[Record],
+    ) -> Result<()> {
+        let table = self.get_table(table_name)?;
+        let schema = table.get_schema();
+
+        let collides = |index_name: &str| -> Result<bool> {
+            let selector = self.get_index(table_name, index_name)?.get_selector();
+            // Null values never collide
+            let mut keys: Vec<_> = records
+                .iter()
+                .map(|record| record.select(&selector, schema))
+                .filter(|key| !key.has_null())
+                .collect();
+            keys.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+            Ok(keys.windows(2).any(|pair| pair[0] == pair[1]))
+        };
+
+        for constraint in schema.get_constraints() {
+            if let Constraint::ForeignKey { .. } = constraint {
+                continue;
+            }
+            if collides(&constraint.get_index_name(false))? {
+                return Err(Error::DuplicateValue(constraint.get_display_name()));
+            }
+        }
+        for index_name in indexes {
+            let index = self.get_index(table_name, index_name)?;
+            if index.get_schema().unique && collides(index_name)? {
+                return Err(Error::DuplicateKey(index_name.clone()));
+            }
+        }
+
         Ok(())
     }
 
@@ -1155,9 +3099,13 @@ impl System {
 
         self.open_table(table)?;
         let table = self.get_table(table)?;
+        let set_pairs = set_pairs
+            .iter()
+            .map(|set_pair| set_pair.coerce(table.get_schema()))
+            .collect::<Result<Vec<_>>>()?;
+        let set_pairs = set_pairs.as_slice();
         let mut set_columns = HashSet::new();
         for set_pair in set_pairs {
-            set_pair.check(table.get_schema())?;
             // Check duplicate column names.
             if set_columns.contains(&set_pair.0) {
                 Err(Error::DuplicateColumn(set_pair.0.to_owned()))?;
@@ -1170,7 +3118,7 @@ impl System {
         }
 
         // Open all indexes of this table.
-        let indexes = self.open_indexes(name)?;
+        let indexes = self.open_indexes_mut(name)?;
 
         let table = self.get_table(table_name)?;
         let schema = table.get_schema();
@@ -1195,7 +3143,9 @@ impl System {
         let foreign_keys = foreign_keys
             .iter()
             .filter(|fk| {
-                if let Constraint::ForeignKey { columns, .. } = fk {
+                if !self.foreign_key_checks {
+                    false
+                } else if let Constraint::ForeignKey { columns, .. } = fk {
                     columns.iter().any(|column| set_columns.contains(column))
                 } else {
                     false
@@ -1207,7 +3157,9 @@ impl System {
         let referred_constraints = referred_constraints
             .iter()
             .filter(|(_, fk)| {
-                if let Constraint::ForeignKey { ref_columns, .. } = fk {
+                if !self.foreign_key_checks {
+                    false
+                } else if let Constraint::ForeignKey { ref_columns, .. } = fk {
                     ref_columns
                         .iter()
                         .any(|column| set_columns.contains(column))
@@ -1229,16 +3181,36 @@ impl System {
             })
             .cloned()
             .collect::<Vec<_>>();
+        let unique_indexes = schema
+            .get_indexes()
+            .iter()
+            .filter(|index| {
+                let columns = &index.columns;
+                index.unique && columns.iter().any(|column| set_columns.contains(column))
+            })
+            .map(|index| index.name.clone())
+            .collect::<Vec<_>>();
 
-        log::info!("Constraints affected by this update: {primary_key:?}, {foreign_keys:?}, {referred_constraints:?}");
+        log::info!("Constraints affected by this update: {primary_key:?}, {foreign_keys:?}, {referred_constraints:?}, {uniques:?}");
 
         // Check constraints.
-        if primary_key.is_some() || !foreign_keys.is_empty() || !referred_constraints.is_empty() {
+        if primary_key.is_some()
+            || !foreign_keys.is_empty()
+            || !referred_constraints.is_empty()
+            || !uniques.is_empty()
+            || !unique_indexes.is_empty()
+        {
             log::info!("Checking constraints in update");
 
             // Peek records to be updated.
-            let records =
-                self.select(&Selectors::All, &[name], where_clauses.to_vec(), None, None)?;
+            let (_, records) = self.select(
+                &Selectors::All,
+                &[name],
+                where_clauses.to_vec(),
+                None,
+                vec![],
+                false,
+            )?;
 
             // Open table and indexes of constraints.
             for fk in &foreign_keys {
@@ -1253,7 +3225,7 @@ impl System {
                 self.open_indexes(referrer)?;
             }
 
-            let mut fs = FS.lock()?;
+            let mut fs = self.fs.lock_arc();
             let mut updated_count = 0;
 
             for (record, page_id, slot) in &records {
@@ -1310,6 +3282,11 @@ impl System {
                         continue;
                     }
 
+                    // Null values never collide
+                    if key_updated.has_null() {
+                        continue;
+                    }
+
                     log::info!("Checking unique: {key:?}");
 
                     if index.contains(&mut fs, &key_updated)? {
@@ -1317,10 +3294,29 @@ impl System {
                     }
                 }
 
+                // Check unique indexes.
+                for index_name in &unique_indexes {
+                    log::info!("Checking unique index {index_name}");
+
+                    let index = self.get_index(table_name, index_name)?;
+                    let table = self.get_table(table_name)?;
+
+                    let selector = index.get_selector();
+                    let key = record.select(&selector, table.get_schema());
+                    let key_updated = record_updated.select(&selector, table.get_schema());
+
+                    // Key not updated
+                    if key == key_updated {
+                        continue;
+                    }
+
+                    index.check_unique(&mut fs, &key_updated)?;
+                }
+
                 // Check foreign key constraints.
                 for fk in &foreign_keys {
                     let ref_table = fk.get_ref_table();
-                    let index_name = fk.get_index_name(false);
+                    let index_name = fk.get_index_name(true);
 
                     log::info!("Checking foreign key {}", &index_name);
 
@@ -1331,7 +3327,7 @@ impl System {
                     let key = record.select(&selector, table.get_schema());
                     let key_updated = record_updated.select(&selector, table.get_schema());
 
-                    let index_name = fk.get_index_name(false);
+                    let index_name = self.get_referred_index_name(fk)?;
                     let index = self.get_index(ref_table, &index_name)?;
 
                     log::info!("Key before update: {key:?}");
@@ -1360,7 +3356,7 @@ impl System {
 
                     log::info!("Checking foreign key {}", &index_name);
 
-                    let index_name = fk.get_index_name(false);
+                    let index_name = self.get_referred_index_name(fk)?;
                     let table = self.get_table(table_name)?;
                     let index = self.get_index(table_name, &index_name)?;
                     let selector = index.get_selector();
@@ -1416,7 +3412,7 @@ impl System {
             return Ok(updated_count);
         }
 
-        let mut fs = FS.lock()?;
+        let mut fs = self.fs.lock_arc();
 
         let mut updated = vec![];
 
@@ -1424,6 +3420,7 @@ impl System {
         let index = self.match_index(&mut fs, name, where_clauses)?;
         if let Some((index_name, left_iter, right_key)) = index {
             log::info!("Using index {index_name}");
+            self.plan.push(format!("index {name}.{index_name}"));
 
             let table_name = name;
 
@@ -1432,9 +3429,10 @@ impl System {
 
             loop {
                 let index = self.get_index(table_name, &index_name)?;
-                let (record, page, slot) = index.get_record(&mut fs, iter)?;
+                let leaf = index.pin_leaf(&mut fs, iter)?;
+                let (record, page, slot) = index.get_pinned_record(&leaf, iter.1);
                 // Iteration ended
-                if record > right_key {
+                if record.into_prefix(right_key.fields.len()) > right_key {
                     break;
                 }
                 let table = self.get_table_mut(table_name)?;
@@ -1451,6 +3449,7 @@ impl System {
                 }
             }
         } else {
+            self.plan.push(format!("scan {name}"));
             let table = self.get_table_mut(name)?;
             updated = table.update(&mut fs, set_pairs, where_clauses)?;
         }
@@ -1498,7 +3497,7 @@ impl System {
         }
 
         // Open all indexes of this table.
-        let indexes = self.open_indexes(name)?;
+        let indexes = self.open_indexes_mut(name)?;
 
         let table = self.get_table(name)?;
         let referred_constraints = table.get_schema().get_referred_constraints().to_owned();
@@ -1513,43 +3512,57 @@ impl System {
         let referred_constraints = table.get_schema().get_referred_constraints();
 
         // Check foreign key constraints.
-        if !referred_constraints.is_empty() {
+        if self.foreign_key_checks && !referred_constraints.is_empty() {
             // Peek records to be deleted.
-            let records =
-                self.select(&Selectors::All, &[name], where_clauses.to_vec(), None, None)?;
+            let (_, records) = self.select(
+                &Selectors::All,
+                &[name],
+                where_clauses.to_vec(),
+                None,
+                vec![],
+                false,
+            )?;
 
-            let mut fs = FS.lock()?;
+            let mut fs = self.fs.lock_arc();
 
             let table = self.get_table(name)?;
             let referred_constraints = table.get_schema().get_referred_constraints();
             for (referrer, fk) in referred_constraints {
                 if let Constraint::ForeignKey { .. } = fk {
-                    let index_name = fk.get_index_name(false);
+                    let index_name = self.get_referred_index_name(fk)?;
                     let index = self.get_index(table_name, &index_name)?;
                     let selector = index.get_selector();
 
                     let index_name = fk.get_index_name(true);
                     let index = self.get_index(referrer, &index_name)?;
 
-                    for (record, _, _) in &records {
-                        let key = record.select(&selector, table.get_schema());
+                    // Rows with null keys are never referenced
+                    let mut keys: Vec<_> = records
+                        .iter()
+                        .map(|(record, _, _)| record.select(&selector, table.get_schema()))
+                        .filter(|key| !key.has_null())
+                        .collect();
+                    keys.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+                    keys.dedup();
 
-                        if index.contains(&mut fs, &key)? {
-                            Err(Error::RowReferencedByForeignKey(fk.get_display_name()))?;
-                        }
+                    if index.contains_many(&mut fs, &keys)?.contains(&true) {
+                        Err(Error::RowReferencedByForeignKey(fk.get_display_name()))?;
                     }
                 }
             }
         }
 
         let mut deleted = vec![];
+        // Records deleted softly are kept in their slots, but out of indexes.
+        let time = self.soft_delete.then(|| self.now());
 
-        let mut fs = FS.lock()?;
+        let mut fs = self.fs.lock_arc();
 
         // Check index availability
         let index = self.match_index(&mut fs, name, where_clauses)?;
         if let Some((index_name, left_iter, right_key)) = index {
             log::info!("Using index {index_name}");
+            self.plan.push(format!("index {name}.{index_name}"));
 
             let table_name = name;
 
@@ -1558,13 +3571,16 @@ impl System {
 
             loop {
                 let index = self.get_index(table_name, &index_name)?;
-                let (record, page, slot) = index.get_record(&mut fs, iter)?;
+                let leaf = index.pin_leaf(&mut fs, iter)?;
+                let (record, page, slot) = index.get_pinned_record(&leaf, iter.1);
                 // Iteration ended
-                if record > right_key {
+                if record.into_prefix(right_key.fields.len()) > right_key {
                     break;
                 }
                 let table = self.get_table_mut(table_name)?;
-                if let Some(record) = table.delete_page_slot(&mut fs, page, slot, where_clauses)? {
+                if let Some(record) =
+                    table.delete_page_slot(&mut fs, page, slot, where_clauses, time)?
+                {
                     deleted.push((record, page, slot));
                 }
                 let index = self.get_index(table_name, &index_name)?;
@@ -1575,8 +3591,9 @@ impl System {
                 }
             }
         } else {
+            self.plan.push(format!("scan {name}"));
             let table = self.get_table_mut(name)?;
-            deleted = table.delete(&mut fs, where_clauses)?;
+            deleted = table.delete(&mut fs, where_clauses, time)?;
         }
 
         let deleted_count = deleted.len();
@@ -1604,117 +3621,498 @@ impl System {
         Ok(deleted_count)
     }
 
-    /// Match the condition against the index, and return the index leaf iterator
-    /// if the query can be speeded up by the index.
-    fn match_index(
-        &self,
-        fs: &mut PageCache,
-        table_name: &str,
-        where_clauses: &[WhereClause],
-    ) -> Result<Option<(String, LeafIterator, Record)>> {
-        log::info!("Matching index for table {}", table_name);
+    /// Set the time to live of records in a table, or remove it with `None`.
+    pub fn set_ttl(&mut self, table_name: &str, ttl: Option<Ttl>) -> Result<()> {
+        log::info!("Setting TTL of table {table_name} to {ttl:?}");
+
+        self.open_table(table_name)?;
+        let table = self.get_table_mut(table_name)?;
+
+        if let Some(Ttl { column, .. }) = &ttl {
+            let schema = table.get_schema();
+            if !schema.has_column(column) {
+                return Err(Error::ColumnNotFound(column.to_owned()));
+            }
+            let typ = &schema.get_column(column).typ;
+            if !matches!(typ, Type::Date | Type::DateTime) {
+                return Err(Error::TtlColumnType(column.to_owned(), typ.clone()));
+            }
+        }
+
+        table.set_ttl(ttl);
+        Ok(())
+    }
+
+    /// Execute alter table set compression statement.
+    ///
+    /// The data file is rewritten with pages compressed or not.
+    pub fn set_compression(&mut self, table_name: &str, compressed: bool) -> Result<()> {
+        log::info!("Setting compression of table {table_name} to {compressed}");
+
+        self.open_table(table_name)?;
+        let mut fs = self.fs.lock_arc();
+        let table = self.get_table_mut(table_name)?;
+        let schema = table.get_schema();
+        if schema.is_compressed() == compressed {
+            return Ok(());
+        }
+
+        let pages = schema.get_pages();
+        let fd = fs.rewrite(table.get_fd(), pages, compressed)?;
+        table.set_compressed(fd, compressed);
+        Ok(())
+    }
+
+    /// Delete expired records from a table.
+    ///
+    /// # Returns
+    ///
+    /// Returns the count of records deleted.
+    pub fn purge_expired(&mut self, table_name: &str) -> Result<usize> {
+        log::info!("Purging expired records of table {table_name}");
+
+        self.open_table(table_name)?;
+        let table = self.get_table(table_name)?;
+        let Ttl { column, seconds } = table
+            .get_schema()
+            .get_ttl()
+            .cloned()
+            .ok_or_else(|| Error::NoTtl(table_name.to_owned()))?;
+
+        let expiry = self.now() - TimeDelta::seconds(seconds);
+        let where_clause = WhereClause::OperatorExpression(
+            ColumnSelector(None, column),
+            Operator::Lt,
+            Expression::Value(Value::DateTime(expiry)),
+        );
+        self.delete(table_name, &[where_clause])
+    }
+
+    /// Collect statistics of a table, and keep them in its schema.
+    pub fn analyze(&mut self, table_name: &str) -> Result<TableStats> {
+        log::info!("Analyzing table {table_name}");
+
+        self.open_table(table_name)?;
+        let table = self.get_table(table_name)?;
+        let records = {
+            let mut fs = self.fs.lock_arc();
+            table.select(&mut fs, &Selectors::All, &[])?
+        };
+
+        let column_count = table.get_schema().get_columns().len();
+        let mut distinct = vec![HashSet::new(); column_count];
+        let empty = ColumnStats {
+            distinct: 0,
+            nulls: 0,
+            min: Value::Null,
+            max: Value::Null,
+        };
+        let mut columns = vec![empty; column_count];
+        for (record, _, _) in &records {
+            for (i, value) in record.fields.iter().enumerate() {
+                let column = &mut columns[i];
+                if let Value::Null = value {
+                    column.nulls += 1;
+                    continue;
+                }
+                // Debug output keeps floats exact, unlike display
+                distinct[i].insert(format!("{value:?}"));
+                if matches!(column.min, Value::Null) || value < &column.min {
+                    column.min = value.clone();
+                }
+                if matches!(column.max, Value::Null) || value > &column.max {
+                    column.max = value.clone();
+                }
+            }
+        }
+        for (column, values) in columns.iter_mut().zip(distinct) {
+            column.distinct = values.len();
+        }
+
+        let stats = TableStats {
+            time: self.now(),
+            rows: records.len(),
+            columns,
+            modified: 0,
+        };
+        let table = self.get_table_mut(table_name)?;
+        table.set_stats(Some(stats.clone()));
+        Ok(stats)
+    }
+
+    /// Return unused pages at the end of the files of a table and its indexes
+    /// to the file system.
+    ///
+    /// Return the name, remaining pages and released pages of each file.
+    pub fn optimize(&mut self, table_name: &str) -> Result<Vec<(String, usize, usize)>> {
+        log::info!("Optimizing table {table_name}");
+
+        self.open_table(table_name)?;
+        let indexes = self.open_indexes_mut(table_name)?;
+
+        let mut ret = vec![];
+        let mut fs = self.fs.lock_arc();
+        let table = self.get_table_mut(table_name)?;
+        let released = table.compact(&mut fs)?;
+        ret.push(("data".to_owned(), table.get_schema().get_pages(), released));
+        for name in indexes {
+            let index = self.get_index_mut(table_name, &name)?;
+            let released = index.compact(&mut fs)?;
+            ret.push((name, index.get_schema().pages, released));
+        }
+        Ok(ret)
+    }
+
+    /// Count records inserted into an analyzed table, and analyze it again once
+    /// they exceed `auto_analyze_ratio` of the records analyzed last time.
+    ///
+    /// Tables never analyzed are left alone.
+    fn track_inserted(&mut self, table_name: &str, count: usize) -> Result<()> {
+        let ratio = self.auto_analyze_ratio;
+        let table = self.get_table_mut(table_name)?;
+        let Some(mut stats) = table.get_schema().get_stats().cloned() else {
+            return Ok(());
+        };
+        stats.modified += count;
+        if ratio > 0.0 && stats.modified as f64 > ratio * stats.rows as f64 {
+            log::info!("Analyzing {table_name} after {} insertions", stats.modified);
+            self.analyze(table_name)?;
+        } else {
+            table.set_stats(Some(stats));
+        }
+        Ok(())
+    }
+
+    /// Find an index whose leading columns are the given ones, so that records
+    /// could be ordered on these columns by walking the index.
+    ///
+    /// Strings are ordered by bytes in indexes, so string columns could only
+    /// be ordered this way under the binary collation.
+    fn match_order_index(&self, table_name: &str, columns: &[String]) -> Option<String> {
+        if columns.is_empty() {
+            return None;
+        }
+        let schema = self.get_table(table_name).ok()?.get_schema();
+        if schema.get_collation() != Collation::Binary
+            && columns
+                .iter()
+                .any(|c| matches!(schema.get_column(c).typ, Type::Varchar(_)))
+        {
+            return None;
+        }
+        schema
+            .get_indexes()
+            .iter()
+            .filter(|index| self.is_index_open(table_name, &index.name))
+            .find(|index| index.columns.starts_with(columns))
+            .map(|index| index.name.clone())
+    }
+
+    /// Reclaim slots of records deleted softly from a table.
+    ///
+    /// # Returns
+    ///
+    /// Returns the count of records reclaimed.
+    pub fn purge_deleted(&mut self, table_name: &str) -> Result<usize> {
+        log::info!("Purging deleted records of table {table_name}");
 
+        self.open_table(table_name)?;
+        let mut fs = self.fs.lock_arc();
+        let table = self.get_table_mut(table_name)?;
+        table.purge_deleted(&mut fs)
+    }
+
+    /// Restore records deleted softly from a table, if matching the where clauses.
+    ///
+    /// All records to be restored are checked against constraints first,
+    /// so that either all of them are restored or none is.
+    ///
+    /// # Returns
+    ///
+    /// Returns the count of records restored.
+    pub fn undelete(&mut self, table_name: &str, where_clauses: &[WhereClause]) -> Result<usize> {
+        log::info!("Restoring deleted records of table {table_name}");
+
+        self.open_table(table_name)?;
+        let indexes = self.open_indexes_mut(table_name)?;
         let table = self.get_table(table_name)?;
+        for where_clause in where_clauses {
+            where_clause.check(table.get_schema())?;
+        }
+
+        let deleted = {
+            let mut fs = self.fs.lock_arc();
+            table.select_deleted(&mut fs, &Selectors::All, where_clauses)?
+        };
+
+        // Check constraints of all records before restoring any of them.
+        let records: Vec<_> = deleted
+            .iter()
+            .map(|(record, _, _)| record.clone())
+            .collect();
+        for record in &records {
+            self.check_record(table_name, &indexes, record)?;
+        }
+        self.check_distinct(table_name, &indexes, &records)?;
+
+        let count = deleted.len();
+        let mut fs = self.fs.lock_arc();
+        for (record, page_id, slot) in deleted {
+            let table = self.get_table_mut(table_name)?;
+            table.undelete_page_slot(&mut fs, page_id, slot)?;
+
+            for index_name in &indexes {
+                let index = self.get_index(table_name, index_name)?;
+                let table = self.get_table(table_name)?;
 
-        // Left and right bounds for the condition.
-        let mut left: HashMap<String, Vec<i32>> = HashMap::new();
-        let mut right: HashMap<String, Vec<i32>> = HashMap::new();
+                let selector = index.get_selector();
+                let key = record.select(&selector, table.get_schema());
+
+                let index = self.get_index_mut(table_name, index_name)?;
+                index.insert(&mut fs, key, page_id, slot)?;
+            }
+        }
+        drop(fs);
+
+        self.track_inserted(table_name, count)?;
+        Ok(count)
+    }
 
-        let mut known_columns: HashSet<String> = Default::default();
+    /// Collect bounds of columns in the where clauses for index lookups.
+    ///
+    /// Returns `None` if some condition compares columns, which rules out indexes.
+    fn index_conditions(
+        schema: &TableSchema,
+        where_clauses: &[WhereClause],
+    ) -> Option<IndexConditions> {
+        let mut conditions = IndexConditions::default();
+        let IndexConditions {
+            left,
+            right,
+            equal,
+            known_columns,
+        } = &mut conditions;
         for where_clause in where_clauses {
+            // NULLs are ordered first in indexes, so they are looked up as ordinary keys
+            if let WhereClause::IsNull(column, true) = where_clause {
+                if schema.has_column(&column.1) {
+                    equal.entry(column.1.clone()).or_insert(Value::Null);
+                }
+                continue;
+            }
             if let WhereClause::OperatorExpression(column, operator, expression) = where_clause {
                 match expression {
-                    Expression::Column(_) => return Ok(None),
+                    Expression::Column(_) | Expression::Cast(..) => return None,
                     Expression::Value(v) => {
                         let column_name = column.1.clone();
-                        // Only index on int supported yet
-                        if let Value::Int(value) = v {
-                            match operator {
-                                Operator::Eq => {
-                                    known_columns.insert(column_name.clone());
-                                    left.entry(column_name.clone()).or_default().push(*value);
-                                    right.entry(column_name).or_default().push(*value);
-                                }
-                                Operator::Ne => {
-                                    // Ne is ignored
-                                }
-                                Operator::Lt => {
-                                    known_columns.insert(column_name.clone());
-                                    right.entry(column_name).or_default().push(*value - 1);
-                                }
-                                Operator::Le => {
-                                    known_columns.insert(column_name.clone());
-                                    right.entry(column_name).or_default().push(*value);
-                                }
-                                Operator::Gt => {
-                                    known_columns.insert(column_name.clone());
-                                    left.entry(column_name).or_default().push(*value + 1);
-                                }
-                                Operator::Ge => {
-                                    known_columns.insert(column_name.clone());
-                                    left.entry(column_name).or_default().push(*value);
+                        if !schema.has_column(&column_name) {
+                            continue;
+                        }
+                        let typ = &schema.get_column(&column_name).typ;
+                        // Strings are keyed by bytes, unlike comparisons ignoring case
+                        let binary = schema.get_collation() == Collation::Binary
+                            || !matches!(typ, Type::Varchar(_));
+                        if let (Operator::Eq, true) = (operator, binary) {
+                            if let Ok(value) = v.convert(typ) {
+                                equal.entry(column_name.clone()).or_insert(value);
+                            }
+                        }
+                        // Only index on int and datetime supported yet
+                        let value = match (v, typ) {
+                            (Value::Int(value), Type::Int | Type::Bigint) => *value as i64,
+                            (Value::Bigint(value), Type::Int | Type::Bigint) => *value,
+                            // Datetimes are compared by seconds since epoch
+                            (Value::DateTime(value), Type::DateTime) => value.and_utc().timestamp(),
+                            (Value::Varchar(value), Type::DateTime) => {
+                                match parse_datetime(value) {
+                                    Ok(value) => value.and_utc().timestamp(),
+                                    Err(_) => continue,
                                 }
                             }
+                            _ => continue,
+                        };
+                        match operator {
+                            Operator::Eq => {
+                                known_columns.insert(column_name.clone());
+                                left.entry(column_name.clone()).or_default().push(value);
+                                right.entry(column_name).or_default().push(value);
+                            }
+                            Operator::Ne => {
+                                // Ne is ignored
+                            }
+                            Operator::Lt => {
+                                known_columns.insert(column_name.clone());
+                                right
+                                    .entry(column_name)
+                                    .or_default()
+                                    .push(value.saturating_sub(1));
+                            }
+                            Operator::Le => {
+                                known_columns.insert(column_name.clone());
+                                right.entry(column_name).or_default().push(value);
+                            }
+                            Operator::Gt => {
+                                known_columns.insert(column_name.clone());
+                                left.entry(column_name)
+                                    .or_default()
+                                    .push(value.saturating_add(1));
+                            }
+                            Operator::Ge => {
+                                known_columns.insert(column_name.clone());
+                                left.entry(column_name).or_default().push(value);
+                            }
                         }
                     }
                 }
             };
         }
 
-        if known_columns.is_empty() {
-            return Ok(None);
-        }
-
         log::info!("Known columns in condition: {known_columns:?}");
 
-        // The conditions are only on one column, and the comparisons are all values
+        Some(conditions)
+    }
+
+    /// Match the condition against the index, and return the index leaf iterator
+    /// if the query can be speeded up by the index.
+    fn match_index(
+        &self,
+        fs: &mut PageCache,
+        table_name: &str,
+        where_clauses: &[WhereClause],
+    ) -> Result<Option<(String, LeafIterator, Record)>> {
+        log::info!("Matching index for table {}", table_name);
+
+        let table = self.get_table(table_name)?;
+
+        let Some(conditions) = Self::index_conditions(table.get_schema(), where_clauses) else {
+            return Ok(None);
+        };
+        let IndexConditions {
+            equal,
+            known_columns,
+            ..
+        } = &conditions;
+
+        // Choose the index with the longest prefix of columns compared for equality,
+        // preferring one with a range on the next column.
+        let mut best = None;
         for index in table.get_schema().get_indexes() {
+            if !self.is_index_open(table_name, &index.name) {
+                continue;
+            }
             log::info!("Checking index {}", index.name);
-            if index.columns.len() == 1 && known_columns.contains(&index.columns[0]) {
-                let left = left.remove(&index.columns[0]).unwrap_or_default();
-                let right = right.remove(&index.columns[0]).unwrap_or_default();
+            let prefix = index
+                .columns
+                .iter()
+                .take_while(|c| equal.contains_key(*c))
+                .count();
+            let range = index
+                .columns
+                .get(prefix)
+                .is_some_and(|c| known_columns.contains(c));
+            let best_score = best.as_ref().map_or((0, false), |&(_, p, r)| (p, r));
+            if (prefix, range) > best_score {
+                best = Some((index, prefix, range));
+            }
+        }
+        let Some((index, prefix, range)) = best else {
+            return Ok(None);
+        };
 
-                // Use this index
-                let index = self.get_index(table_name, &index.name)?;
+        log::info!("Using {prefix} equal columns of index {}", index.name);
 
-                // Filter conditions
-                let left = left.iter().max().unwrap_or(&i32::MIN);
-                let right = right.iter().min().unwrap_or(&i32::MAX);
+        let mut left_key: Vec<_> = index.columns[..prefix]
+            .iter()
+            .map(|c| equal[c].clone())
+            .collect();
+        let mut right_key = left_key.clone();
+
+        if range {
+            let column = &index.columns[prefix];
+            let (left, right) = conditions.bounds(table.get_schema(), column);
+            left_key.push(left);
+            right_key.push(right);
+        }
 
-                log::info!("Left bound: {left}, right bound: {right}");
+        let index_name = index.name.clone();
+        let index = self.get_index(table_name, &index_name)?;
 
-                let left_key = Record::new(vec![Value::Int(*left)]);
-                let right_key = Record::new(vec![Value::Int(*right)]);
+        // Keys shorter than the index are compared as prefixes
+        let left_key = Record::new(left_key);
+        let right_key = Record::new(right_key);
 
-                let left_iter = index.index(fs, &left_key)?;
-                let right_iter = index.index(fs, &right_key)?;
+        let left_iter = index.index(fs, &left_key)?;
 
-                log::info!("Left iter: {left_iter:?}, right iter: {right_iter:?}");
+        log::info!("Left iter: {left_iter:?}");
 
-                if left_iter.is_none() {
-                    return Ok(None);
-                }
-                if right_iter.is_none() {
-                    return Ok(None);
-                }
+        // All keys are smaller than the left bound
+        Ok(left_iter.map(|left_iter| (index_name, left_iter, right_key)))
+    }
 
-                let left_iter = left_iter.unwrap();
+    /// Match the condition against composite indexes whose second column is
+    /// bounded but whose leading column is not, to be scanned by skipping.
+    ///
+    /// The leading column must have few distinct values per statistics from
+    /// the last analysis, since every distinct value costs a lookup.
+    /// Returns the index name and bounds of its second column.
+    fn match_skip_index(
+        &self,
+        table_name: &str,
+        where_clauses: &[WhereClause],
+    ) -> Result<Option<(String, Value, Value)>> {
+        let schema = self.get_table(table_name)?.get_schema();
+        let Some(stats) = schema.get_stats() else {
+            return Ok(None);
+        };
+        let Some(conditions) = Self::index_conditions(schema, where_clauses) else {
+            return Ok(None);
+        };
 
-                return Ok(Some((
-                    index.get_schema().name.clone(),
-                    left_iter,
-                    right_key,
-                )));
+        // Choose the index with the fewest distinct leading values
+        let mut best = None;
+        for index in schema.get_indexes() {
+            let [leading, second, ..] = index.columns.as_slice() else {
+                continue;
+            };
+            if !self.is_index_open(table_name, &index.name) {
+                continue;
+            }
+            if !conditions.known_columns.contains(second) {
+                continue;
+            }
+            let Some(column) = stats.columns.get(schema.get_column_index(leading)) else {
+                continue;
+            };
+            // Nulls are skipped as one more leading value
+            let distinct = column.distinct + (column.nulls > 0) as usize;
+            if distinct * SKIP_SCAN_RATIO > stats.rows {
+                continue;
+            }
+            if best.as_ref().is_none_or(|&(_, d)| distinct < d) {
+                best = Some((index, distinct));
             }
         }
+        let Some((index, distinct)) = best else {
+            return Ok(None);
+        };
+
+        let name = index.name.clone();
+        log::info!("Skip scanning {distinct} leading values of index {name}");
 
-        Ok(None)
+        let (left, right) = conditions.bounds(schema, &index.columns[1]);
+        Ok(Some((name, left, right)))
     }
 
     /// Initialize index, adding all existing records into the index.
+    ///
+    /// Progress is logged per page, and the build could be cancelled between pages.
     fn init_index(&mut self, table_name: &str, index_name: &str, columns: &[&str]) -> Result<()> {
         log::info!("Initializing index {table_name}.{index_name}");
 
+        let cancel = self.cancel.clone();
+        let _guard = cancel.begin();
+        let start = Instant::now();
+
         let table = self.get_table(table_name)?;
         let columns: Vec<_> = columns
             .iter()
@@ -1722,19 +4120,25 @@ impl System {
             .collect();
         let selectors = Selectors::Some(columns);
 
-        let mut fs = FS.lock()?;
+        let mut fs = self.fs.lock_arc();
 
+        let mut keys = vec![];
         let pages = table.get_schema().get_pages();
         for i in 0..pages {
-            log::info!("Adding index for page {i}");
             let table = self.get_table(table_name)?;
-            let keys = table.select_page(&mut fs, i, &selectors, &[])?;
-            let index = self.get_index_mut(table_name, index_name)?;
-            for (key, _, slot) in keys {
-                index.insert(&mut fs, key, i, slot)?;
-            }
+            let page_keys = table.select_page(&mut fs, i, &selectors, &[])?;
+            keys.extend(page_keys.into_iter().map(|(key, _, slot)| (key, i, slot)));
+            cancel.check()?;
+            log::info!("Indexed {}/{pages} pages of table {table_name}", i + 1);
         }
 
+        let index = self.get_index_mut(table_name, index_name)?;
+        index.bulk_load(&mut fs, keys)?;
+
+        log::info!(
+            "Built index {table_name}.{index_name} in {:?}",
+            start.elapsed()
+        );
         Ok(())
     }
 
@@ -1754,20 +4158,68 @@ impl System {
     ) -> Result<()> {
         log::info!("Executing add index statement");
 
-        self.open_table(table_name)?;
-        let table = self.get_table(table_name)?;
+        self.check_index_columns(explicit, table_name, columns)?;
 
-        let schema = table.get_schema();
+        let schema = IndexSchema::new(explicit, prefix, index_name, columns);
+        self.create_index(table_name, schema, init)
+    }
+
+    /// Execute create unique index statement.
+    ///
+    /// Existing records are indexed at once, and the index is dropped again if
+    /// some key is duplicate.
+    pub fn add_unique_index(
+        &mut self,
+        table_name: &str,
+        index_name: Option<&str>,
+        columns: &[&str],
+    ) -> Result<()> {
+        log::info!("Executing add unique index statement");
+
+        self.check_index_columns(true, table_name, columns)?;
+        let schema = self.get_table(table_name)?.get_schema();
+        let collation = schema.get_collation();
         for &column in columns {
-            if !schema.has_column(column) {
-                return Err(Error::ColumnNotFound(column.to_owned()));
-            }
+            collation.check_unique(schema.get_column(column))?;
         }
 
-        // Duplicate index is only checked on explicit indexes.
-        if explicit {
-            for index in schema.get_indexes() {
-                if index.columns == columns {
+        let mut schema = IndexSchema::new(true, None, index_name, columns);
+        schema.unique = true;
+        let index_name = schema.name.clone();
+
+        self.create_index(table_name, schema, false)?;
+        if let Err(err) = self.init_index(table_name, &index_name, columns) {
+            self.drop_index(table_name, &index_name)?;
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    /// Check that an index could be built on given columns of a table.
+    fn check_index_columns(
+        &mut self,
+        explicit: bool,
+        table_name: &str,
+        columns: &[&str],
+    ) -> Result<()> {
+        self.open_table(table_name)?;
+        let table = self.get_table(table_name)?;
+
+        let schema = table.get_schema();
+        for &column in columns {
+            if !schema.has_column(column) {
+                return Err(Error::ColumnNotFound(column.to_owned()));
+            }
+            if schema.get_column(column).typ == Type::Text {
+                return Err(Error::TextIndex(column.to_owned()));
+            }
+        }
+
+        // Duplicate index is only checked on explicit indexes.
+        if explicit {
+            for index in schema.get_indexes() {
+                if index.columns == columns {
                     return Err(Error::DuplicateIndex(
                         columns.iter().map(|&s| s.to_owned()).collect(),
                     ));
@@ -1775,33 +4227,255 @@ impl System {
             }
         }
 
-        let schema = IndexSchema::new(explicit, prefix, index_name, columns);
+        Ok(())
+    }
+
+    /// Create files of an index and attach it to a table, journaled so that
+    /// the files are removed on next use of the database if interrupted.
+    ///
+    /// # Parameters
+    ///
+    /// - `init`: whether to initialize the index.
+    fn create_index(&mut self, table_name: &str, schema: IndexSchema, init: bool) -> Result<()> {
+        let operation = DdlOperation::CreateIndex {
+            table: table_name.to_owned(),
+            index: schema.name.clone(),
+        };
+        self.begin_ddl(operation.clone())?;
+        let result = self.attach_index(table_name, schema, init);
+        self.end_ddl(&operation)?;
+        result
+    }
+
+    /// Create files of an index and attach it to a table.
+    ///
+    /// # Parameters
+    ///
+    /// - `init`: whether to initialize the index.
+    fn attach_index(&mut self, table_name: &str, schema: IndexSchema, init: bool) -> Result<()> {
         let index_name = schema.name.clone();
+        let columns = schema.columns.clone();
+
+        self.create_index_files(table_name, &schema)?;
+
+        self.open_table(table_name)?;
+        let table = self.get_table_mut(table_name)?;
+        table.add_index(schema);
+
+        self.open_index(table_name, &index_name)?;
+        if init {
+            // Drop the partially built index on failure, e.g. cancellation
+            let columns: Vec<_> = columns.iter().map(String::as_str).collect();
+            if let Err(err) = self.init_index(table_name, &index_name, &columns) {
+                self.drop_index(table_name, &index_name)?;
+                return Err(err);
+            }
+        }
+
+        Ok(())
+    }
 
+    /// Create files of an index.
+    fn create_index_files(&self, table_name: &str, schema: &IndexSchema) -> Result<()> {
         let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
         let table = db.join(table_name);
 
-        let filename = format!("{}.index.bin", index_name);
+        let filename = format!("{}.index.bin", schema.name);
         let data = table.join(filename);
         fs::File::create(data)?;
 
-        let filename = format!("{}.index.json", index_name);
+        let filename = format!("{}.index.json", schema.name);
         let meta = table.join(filename);
         let mut file = fs::File::create(meta)?;
-        serde_json::to_writer(&mut file, &schema)?;
+        serde_json::to_writer(&mut file, schema)?;
+        Ok(())
+    }
 
-        self.open_table(table_name)?;
-        let table = self.get_table_mut(table_name)?;
-        table.add_index(schema);
+    /// Execute create index concurrently statement.
+    ///
+    /// The index is built online, scanning a batch of pages after each
+    /// statement, and used once all pages are scanned. Statements altering
+    /// tables wait for the build to finish.
+    pub fn add_index_online(
+        &mut self,
+        table_name: &str,
+        index_name: Option<&str>,
+        columns: &[&str],
+        unique: bool,
+    ) -> Result<()> {
+        log::info!("Executing create index concurrently statement");
+
+        self.finish_index_build()?;
+        self.check_index_columns(true, table_name, columns)?;
+
+        let mut schema = IndexSchema::new(true, None, index_name, columns);
+        schema.unique = unique;
+        // Journaled until the build finishes or fails
+        self.begin_ddl(DdlOperation::CreateIndex {
+            table: table_name.to_owned(),
+            index: schema.name.clone(),
+        })?;
+        self.create_index_files(table_name, &schema)?;
+
+        self.get_table_mut(table_name)?.track_changes();
+        self.index_build = Some(IndexBuild {
+            table: table_name.to_owned(),
+            schema,
+            next_page: 0,
+            keys: vec![],
+            start: Instant::now(),
+        });
+        Ok(())
+    }
 
-        self.open_index(table_name, &index_name)?;
-        if init {
-            self.init_index(table_name, &index_name, columns)?;
+    /// Scan the next batch of pages for the index being built online, if any.
+    ///
+    /// Failure of the build is returned, to be reported along with the
+    /// statement just executed.
+    pub fn advance_index_build(&mut self) -> Result<()> {
+        self.step_index_build(INDEX_BUILD_BATCH)
+    }
+
+    /// Scan all remaining pages for the index being built online, if any.
+    ///
+    /// Failure of the build is returned, to be reported by the statement
+    /// waiting for it.
+    pub fn finish_index_build(&mut self) -> Result<()> {
+        self.step_index_build(usize::MAX)
+    }
+
+    /// Scan up to a batch of pages for the index being built online, and
+    /// attach the index once all pages are scanned.
+    ///
+    /// The index is dropped if the build fails, with the error wrapped in
+    /// `Error::IndexBuildFailed`.
+    fn step_index_build(&mut self, batch: usize) -> Result<()> {
+        let Some(build) = &self.index_build else {
+            return Ok(());
+        };
+        let index_name = build.schema.name.clone();
+        self.try_step_index_build(batch).map_err(|err| {
+            log::error!("Failed to build index {index_name} online: {err}");
+            Error::IndexBuildFailed(index_name, err.to_string())
+        })
+    }
+
+    /// Scan up to a batch of pages for the index being built online, as
+    /// [`System::step_index_build`] without wrapping the error.
+    fn try_step_index_build(&mut self, batch: usize) -> Result<()> {
+        let Some(mut build) = self.index_build.take() else {
+            return Ok(());
+        };
+
+        let scanned = match self.scan_index_build(&mut build, batch) {
+            Ok(scanned) => scanned,
+            Err(err) => {
+                self.discard_index_build(&build)?;
+                return Err(err);
+            }
+        };
+        if !scanned {
+            self.index_build = Some(build);
+            return Ok(());
+        }
+
+        let IndexBuild {
+            table: table_name,
+            schema,
+            keys,
+            start,
+            ..
+        } = build;
+        let index_name = schema.name.clone();
+        self.end_ddl(&DdlOperation::CreateIndex {
+            table: table_name.clone(),
+            index: index_name.clone(),
+        })?;
+
+        self.get_table_mut(&table_name)?.add_index(schema);
+        self.open_index(&table_name, &index_name)?;
+
+        let mut fs = self.fs.lock_arc();
+        let index = self.get_index_mut(&table_name, &index_name)?;
+        if let Err(err) = index.bulk_load(&mut fs, keys) {
+            drop(fs);
+            self.drop_index(&table_name, &index_name)?;
+            return Err(err);
         }
 
+        log::info!(
+            "Built index {table_name}.{index_name} online in {:?}",
+            start.elapsed()
+        );
         Ok(())
     }
 
+    /// Scan up to a batch of pages for an index built online. Once all pages
+    /// are scanned, pages changed since the build started are scanned again.
+    ///
+    /// Return whether all pages are scanned.
+    fn scan_index_build(&mut self, build: &mut IndexBuild, batch: usize) -> Result<bool> {
+        let cancel = self.cancel.clone();
+        let _guard = cancel.begin();
+
+        let columns: Vec<_> = build
+            .schema
+            .columns
+            .iter()
+            .map(|s| Selector::Column(ColumnSelector(None, s.to_owned())))
+            .collect();
+        let selectors = Selectors::Some(columns);
+
+        let mut fs = self.fs.lock_arc();
+
+        let table = self.get_table(&build.table)?;
+        let pages = table.get_schema().get_pages();
+        let end = build.next_page.saturating_add(batch).min(pages);
+        for i in build.next_page..end {
+            let page_keys = table.select_page(&mut fs, i, &selectors, &[])?;
+            let page_keys = page_keys.into_iter().map(|(key, _, slot)| (key, i, slot));
+            build.keys.extend(page_keys);
+            build.next_page = i + 1;
+            cancel.check()?;
+            log::info!("Indexed {}/{pages} pages of table {}", i + 1, build.table);
+        }
+        if build.next_page < pages {
+            return Ok(false);
+        }
+
+        // Apply the side log
+        let table = self.get_table_mut(&build.table)?;
+        let changes = table.take_changes();
+        log::info!("Scanning {} changed pages again", changes.len());
+        build.keys.retain(|(_, page, _)| !changes.contains(page));
+
+        let table = self.get_table(&build.table)?;
+        for &i in changes.range(..pages) {
+            let page_keys = table.select_page(&mut fs, i, &selectors, &[])?;
+            let page_keys = page_keys.into_iter().map(|(key, _, slot)| (key, i, slot));
+            build.keys.extend(page_keys);
+        }
+        Ok(true)
+    }
+
+    /// Remove files of an index whose online build failed.
+    fn discard_index_build(&mut self, build: &IndexBuild) -> Result<()> {
+        log::info!("Discarding index {}.{}", build.table, build.schema.name);
+
+        if let Ok(table) = self.get_table_mut(&build.table) {
+            table.take_changes();
+        }
+
+        let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
+        let table = db.join(&build.table);
+        fs::remove_file(table.join(format!("{}.index.bin", build.schema.name)))?;
+        fs::remove_file(table.join(format!("{}.index.json", build.schema.name)))?;
+        self.end_ddl(&DdlOperation::CreateIndex {
+            table: build.table.clone(),
+            index: build.schema.name.clone(),
+        })
+    }
+
     /// Execute drop index statement.
     pub fn drop_index(&mut self, table_name: &str, index_name: &str) -> Result<()> {
         log::info!("Executing drop index statement on {index_name}");
@@ -1826,6 +4500,32 @@ impl System {
             }
         }
 
+        // Foreign keys look up the referenced keys through the index of a key
+        // on them, which is kept unless another key is on the same columns
+        let schema = table.get_schema();
+        let target = unique_index_name.as_deref().unwrap_or(index_name);
+        let constraints = schema.get_constraints();
+        let key = constraints.iter().find(|c| {
+            matches!(c, Constraint::PrimaryKey { .. } | Constraint::Unique { .. })
+                && c.get_index_name(false) == target
+        });
+        if let Some(key) = key {
+            let columns = key.get_columns();
+            let other_keys = constraints
+                .iter()
+                .filter(|c| c.get_index_name(false) != target)
+                .any(|c| c.is_key(columns) && c.get_columns() == columns);
+            if !other_keys {
+                for (_, fk) in schema.get_referred_constraints() {
+                    if let Constraint::ForeignKey { ref_columns, .. } = fk {
+                        if ref_columns.as_slice() == columns {
+                            return Err(Error::IndexReferencedByForeignKey(fk.get_display_name()));
+                        }
+                    }
+                }
+            }
+        }
+
         let table = self.get_table_mut(table_name)?;
         let index_name = if let Some(unique) = &unique {
             table.remove_constraint(&unique.clone());
@@ -1839,7 +4539,7 @@ impl System {
             .indexes
             .remove(&(table_name.to_owned(), index_name.to_owned()))
         {
-            let mut fs = FS.lock()?;
+            let mut fs = self.fs.lock_arc();
             fs.close(index.get_fd())?;
         }
 
@@ -1857,13 +4557,18 @@ impl System {
         let db = self.db.as_ref().ok_or(Error::NoDatabaseSelected)?;
         let table = db.join(table_name);
 
+        // Files of an unavailable index may be missing
         let filename = format!("{}.index.bin", index_name);
         let data = table.join(filename);
-        fs::remove_file(data)?;
+        if data.exists() {
+            fs::remove_file(data)?;
+        }
 
         let filename = format!("{}.index.json", index_name);
         let meta = table.join(filename);
-        fs::remove_file(meta)?;
+        if meta.exists() {
+            fs::remove_file(meta)?;
+        }
 
         self.open_table(table_name)?;
         let table = self.get_table_mut(table_name)?;
@@ -1885,10 +4590,15 @@ impl System {
         let table = self.get_table(table_name)?;
 
         let schema = table.get_schema();
+        let collation = schema.get_collation();
         for &column in columns {
             if !schema.has_column(column) {
                 return Err(Error::ColumnNotFound(column.to_owned()));
             }
+            if schema.get_column(column).typ == Type::Text {
+                return Err(Error::TextIndex(column.to_owned()));
+            }
+            collation.check_unique(schema.get_column(column))?;
         }
 
         for constraint in schema.get_constraints() {
@@ -1912,41 +4622,66 @@ impl System {
             false,
         )?;
 
-        // Initialize the index, while checking for duplicate primary key.
+        // Initialize the index, while collecting all duplicate primary keys.
         let index_name = constraint.get_index_name(false);
         let index = self.get_index(table_name, &index_name)?;
         let selector = index.get_selector();
 
-        let mut fs = FS.lock()?;
+        let cancel = self.cancel.clone();
+        let _guard = cancel.begin();
+        let start = Instant::now();
+
+        let mut fs = self.fs.lock_arc();
+
+        let mut duplicates = Vec::new();
+        let mut reported = HashSet::new();
+        let mut more = 0;
 
         let table = self.get_table(table_name)?;
         let pages = table.get_schema().get_pages();
         for i in 0..pages {
-            log::info!("Adding index for page {i}");
             let table = self.get_table(table_name)?;
             let keys = table.select_page(&mut fs, i, &selector, &[])?;
 
-            let mut failed = false;
             let index = self.get_index_mut(table_name, &index_name)?;
             for (key, _, slot) in keys {
                 log::info!("Checking primary key {key:?}");
                 if index.contains(&mut fs, &key)? {
-                    failed = true;
-                    break;
+                    let fields: Vec<_> = key.fields.iter().map(ToString::to_string).collect();
+                    let key = format!("({})", fields.join(", "));
+                    if reported.insert(key.clone()) {
+                        log::warn!("Duplicate primary key {key} in table {table_name}");
+                        if duplicates.len() < MAX_REPORTED_DUPLICATES {
+                            duplicates.push(key);
+                        } else {
+                            more += 1;
+                        }
+                    }
                 } else {
                     index.insert(&mut fs, key, i, slot)?;
                 }
             }
 
-            if failed {
+            if let Err(err) = cancel.check() {
                 drop(fs);
                 self.drop_index(table_name, &index_name)?;
-                return Err(Error::DuplicateValue(
-                    constraint_name.unwrap_or("<anonymous>").to_string(),
-                ));
+                return Err(err);
             }
+            log::info!("Indexed {}/{pages} pages of table {table_name}", i + 1);
+        }
+
+        if !duplicates.is_empty() {
+            drop(fs);
+            self.drop_index(table_name, &index_name)?;
+            let name = constraint.get_display_name();
+            return Err(Error::DuplicateValues(name, duplicates, more));
         }
 
+        log::info!(
+            "Built index {table_name}.{index_name} in {:?}",
+            start.elapsed()
+        );
+
         let table = self.get_table_mut(table_name)?;
         table.add_constraint(constraint);
 
@@ -2004,7 +4739,7 @@ impl System {
         self.open_table(table_name)?;
         self.open_table(ref_table_name)?;
 
-        let constraint = Constraint::ForeignKey {
+        let mut constraint = Constraint::ForeignKey {
             name: constraint_name.map(|s| s.to_owned()),
             columns: columns.iter().map(|&s| s.to_owned()).collect(),
             referrer: table_name.to_owned(),
@@ -2018,40 +4753,40 @@ impl System {
         let schema0 = table.get_schema().get_schema();
         let schema1 = ref_table.get_schema().get_schema();
         constraint.check(&[schema0, schema1])?;
+        constraint.align_to_key(ref_table.get_schema().get_constraints());
 
         log::info!("Creating index for foreign key {constraint_name:?}");
+        let columns: Vec<_> = constraint
+            .get_columns()
+            .iter()
+            .map(|c| c.as_str())
+            .collect();
         self.add_index(
             false,
             Some("fk_referrer"),
             table_name,
             constraint_name,
-            columns,
-            false,
-        )?;
-
-        let prefix = format!("fk_referred.{}", table_name);
-        self.add_index(
+            &columns,
             false,
-            Some(&prefix),
-            ref_table_name,
-            constraint_name,
-            ref_columns,
-            true,
         )?;
 
         // Initialize the index, while checking for foreign key existence.
+        self.open_indexes(ref_table_name)?;
         let index_name = constraint.get_index_name(true);
-        let index_name_referred = constraint.get_index_name(false);
+        let index_name_referred = self.get_referred_index_name(&constraint)?;
 
         let index = self.get_index(table_name, &index_name)?;
         let selector = index.get_selector();
 
-        let mut fs = FS.lock()?;
+        let cancel = self.cancel.clone();
+        let _guard = cancel.begin();
+        let start = Instant::now();
+
+        let mut fs = self.fs.lock_arc();
 
         let table = self.get_table(table_name)?;
         let pages = table.get_schema().get_pages();
         for i in 0..pages {
-            log::info!("Adding index for page {i}");
             let table = self.get_table(table_name)?;
             let keys = table.select_page(&mut fs, i, &selector, &[])?;
 
@@ -2069,7 +4804,6 @@ impl System {
             if failed {
                 drop(fs);
                 self.drop_index(table_name, &index_name)?;
-                self.drop_index(ref_table_name, &index_name_referred)?;
                 return Err(Error::ReferencedFieldsNotExist(
                     constraint.get_display_name(),
                 ));
@@ -2081,8 +4815,20 @@ impl System {
             for (key, _, slot) in keys {
                 index.insert(&mut fs, key, i, slot)?;
             }
+
+            if let Err(err) = cancel.check() {
+                drop(fs);
+                self.drop_index(table_name, &index_name)?;
+                return Err(err);
+            }
+            log::info!("Indexed {}/{pages} pages of table {table_name}", i + 1);
         }
 
+        log::info!(
+            "Built index {table_name}.{index_name} in {:?}",
+            start.elapsed()
+        );
+
         let table = self.get_table_mut(table_name)?;
         table.add_constraint(constraint.clone());
 
@@ -2121,9 +4867,8 @@ impl System {
         let index_name = constraint.get_index_name(true);
         self.drop_index(table_name, &index_name)?;
 
-        let index_name = constraint.get_index_name(false);
         let ref_table_name = constraint.get_ref_table();
-        self.drop_index(ref_table_name, &index_name)?;
+        self.open_table(ref_table_name)?;
 
         let table = self.get_table_mut(table_name)?;
         table.remove_constraint(constraint_name);
@@ -2134,6 +4879,110 @@ impl System {
         Ok(())
     }
 
+    /// Execute check foreign key statement.
+    ///
+    /// Finds records whose referenced key is missing, e.g. inserted while
+    /// foreign key checks were disabled, and deletes them if asked to.
+    ///
+    /// # Returns
+    ///
+    /// Returns the orphan records, in the order of their keys.
+    pub fn check_foreign_key(
+        &mut self,
+        table_name: &str,
+        constraint_name: &str,
+        delete: bool,
+    ) -> Result<Vec<Record>> {
+        log::info!("Executing check foreign key statement");
+
+        self.open_table(table_name)?;
+        self.open_indexes(table_name)?;
+
+        let table = self.get_table(table_name)?;
+        let fk = table
+            .get_schema()
+            .get_foreign_keys()
+            .into_iter()
+            .find(|fk| fk.get_name() == Some(constraint_name))
+            .cloned()
+            .ok_or_else(|| Error::ConstraintNotFound(constraint_name.to_owned()))?;
+
+        let ref_table = fk.get_ref_table();
+        self.open_table(ref_table)?;
+        self.open_indexes(ref_table)?;
+
+        let index = self.get_index(table_name, &fk.get_index_name(true))?;
+        let ref_index = self.get_index(ref_table, &self.get_referred_index_name(&fk)?)?;
+
+        let cancel = self.cancel.clone();
+        let _guard = cancel.begin();
+
+        let mut fs = self.fs.lock_arc();
+
+        // Walk the referrer index, grouping records by their keys
+        let key = Record::new(vec![Value::Null; fk.get_columns().len()]);
+        let mut groups: Vec<(Record, Vec<(usize, usize)>)> = vec![];
+        let mut iter = index.index(&mut fs, &key)?;
+        while let Some(it) = iter {
+            let (key, page, slot) = index.get_record(&mut fs, it)?;
+            // Records with null keys reference nothing
+            if !key.has_null() {
+                match groups.last_mut() {
+                    Some((last, records)) if *last == key => records.push((page, slot)),
+                    _ => groups.push((key, vec![(page, slot)])),
+                }
+            }
+            cancel.check()?;
+            iter = index.inc_iter(&mut fs, it)?;
+        }
+
+        let keys: Vec<_> = groups.iter().map(|(key, _)| key.clone()).collect();
+        let exists = ref_index.contains_many(&mut fs, &keys)?;
+        let orphans: Vec<_> = groups
+            .into_iter()
+            .zip(exists)
+            .filter(|(_, exists)| !exists)
+            .map(|(group, _)| group)
+            .collect();
+
+        let table = self.get_table(table_name)?;
+        let mut records = vec![];
+        for (_, slots) in &orphans {
+            for &(page, slot) in slots {
+                if let Some(record) =
+                    table.select_page_slot(&mut fs, page, slot, &Selectors::All, &[])?
+                {
+                    records.push(record);
+                }
+            }
+        }
+        log::info!(
+            "Found {} orphan records of foreign key {constraint_name}",
+            records.len()
+        );
+        drop(fs);
+
+        if delete {
+            for (key, _) in orphans {
+                let where_clauses: Vec<_> = fk
+                    .get_columns()
+                    .iter()
+                    .zip(key.fields)
+                    .map(|(column, value)| {
+                        WhereClause::OperatorExpression(
+                            ColumnSelector(None, column.to_owned()),
+                            Operator::Eq,
+                            Expression::Value(value),
+                        )
+                    })
+                    .collect();
+                self.delete(table_name, &where_clauses)?;
+            }
+        }
+
+        Ok(records)
+    }
+
     /// Execute add unique statement.
     pub fn add_unique(
         &mut self,
@@ -2147,10 +4996,15 @@ impl System {
         let table = self.get_table(table_name)?;
 
         let schema = table.get_schema();
+        let collation = schema.get_collation();
         for &column in columns {
             if !schema.has_column(column) {
                 return Err(Error::ColumnNotFound(column.to_owned()));
             }
+            if schema.get_column(column).typ == Type::Text {
+                return Err(Error::TextIndex(column.to_owned()));
+            }
+            collation.check_unique(schema.get_column(column))?;
         }
 
         let constraint = Constraint::Unique {
@@ -2173,12 +5027,15 @@ impl System {
         let index = self.get_index(table_name, &index_name)?;
         let selector = index.get_selector();
 
-        let mut fs = FS.lock()?;
+        let cancel = self.cancel.clone();
+        let _guard = cancel.begin();
+        let start = Instant::now();
+
+        let mut fs = self.fs.lock_arc();
 
         let table = self.get_table(table_name)?;
         let pages = table.get_schema().get_pages();
         for i in 0..pages {
-            log::info!("Adding index for page {i}");
             let table = self.get_table(table_name)?;
             let keys = table.select_page(&mut fs, i, &selector, &[])?;
 
@@ -2186,7 +5043,7 @@ impl System {
             let index = self.get_index_mut(table_name, &index_name)?;
             for (key, _, slot) in keys {
                 log::info!("Checking unique key {key:?}");
-                if index.contains(&mut fs, &key)? {
+                if !key.has_null() && index.contains(&mut fs, &key)? {
                     failed = true;
                     break;
                 } else {
@@ -2201,8 +5058,20 @@ impl System {
                     constraint_name.unwrap_or("<anonymous>").to_string(),
                 ));
             }
+
+            if let Err(err) = cancel.check() {
+                drop(fs);
+                self.drop_index(table_name, &index_name)?;
+                return Err(err);
+            }
+            log::info!("Indexed {}/{pages} pages of table {table_name}", i + 1);
         }
 
+        log::info!(
+            "Built index {table_name}.{index_name} in {:?}",
+            start.elapsed()
+        );
+
         let table = self.get_table_mut(table_name)?;
         table.add_constraint(constraint);
 
@@ -2210,11 +5079,90 @@ impl System {
     }
 }
 
+/// Get the total size of files in a directory, recursively.
+fn dir_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Copy a directory recursively.
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let path = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &path)?;
+        } else {
+            fs::copy(entry.path(), path)?;
+        }
+    }
+    Ok(())
+}
+
+impl Drop for System {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish_index_build() {
+            log::error!("{err}");
+        }
+        if let Err(err) = self.save_hot_pages() {
+            log::error!("Failed to save hot pages: {err}");
+        }
+        if let Err(err) = self.fs.lock().clear() {
+            log::error!("Failed to write back page cache: {err}");
+        }
+    }
+}
+
+/// System running on a copy of the data directory, removing the copy on drop.
+pub struct Replica {
+    /// System on the copy, dropped before the copy is removed.
+    system: Option<System>,
+    /// Path to the copy of data directory.
+    path: PathBuf,
+}
+
+impl Deref for Replica {
+    type Target = System;
+
+    fn deref(&self) -> &System {
+        self.system.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for Replica {
+    fn deref_mut(&mut self) -> &mut System {
+        self.system.as_mut().unwrap()
+    }
+}
+
+impl Drop for Replica {
+    fn drop(&mut self) {
+        self.system.take();
+        log::info!("Removing replica {}", self.path.display());
+        if let Err(err) = fs::remove_dir_all(&self.path) {
+            log::error!("Failed to remove replica: {err}");
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::ops::Range;
     use std::path::PathBuf;
 
-    use crate::setup;
+    use chrono::NaiveDate;
+
+    use crate::schema::Aggregator;
+    use crate::{parser, setup};
 
     use super::*;
 
@@ -2225,8 +5173,19 @@ mod tests {
         let base = PathBuf::from("test_create_database");
         fs::create_dir(&base).unwrap();
         let name = "test_create_database";
-        System::new(base.clone()).create_database(name).unwrap();
+        let mut system = System::new(base.clone());
+        system.create_database(name).unwrap();
         assert!(base.join(name).exists());
+        let meta = DatabaseMeta::load(&base.join(name)).unwrap();
+        assert_eq!(meta.format, FORMAT_VERSION);
+
+        // Databases in an older format, e.g. without metadata, are rejected
+        fs::create_dir(base.join("old")).unwrap();
+        assert!(matches!(
+            system.use_database("old"),
+            Err(Error::UnsupportedFormat(_, 0, FORMAT_VERSION))
+        ));
+        system.drop_database("old").unwrap();
         fs::remove_dir_all(base).unwrap();
     }
 
@@ -2263,4 +5222,2398 @@ mod tests {
         assert!(system.db.is_none());
         fs::remove_dir_all(base).unwrap();
     }
+
+    /// Execute statements, and return the rows of the last result.
+    fn query(system: &mut System, sql: &str) -> Vec<Vec<String>> {
+        let mut rows = vec![];
+        for (_, result) in parser::parse(system, sql) {
+            let (result, _) = result.unwrap();
+            rows = result
+                .rows
+                .iter()
+                .map(|row| row.iter().map(ToString::to_string).collect())
+                .collect();
+        }
+        rows
+    }
+
+    #[test]
+    fn test_join_group_aggregate() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_join_group_aggregate");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE a (id INT NOT NULL, g INT, PRIMARY KEY (id));
+            CREATE TABLE b (aid INT, v INT, w FLOAT);
+            INSERT INTO a VALUES (1, 10), (2, 10), (3, 20), (4, 5);
+            INSERT INTO b VALUES (1, 5, 1.0), (1, 6, 2.0), (2, 7, 3.0), (3, 8, 4.0), (3, 1, 5.0);",
+        );
+
+        let rows = query(
+            &mut system,
+            "SELECT COUNT(*), SUM(b.v), MAX(b.w) FROM a, b WHERE a.id = b.aid;",
+        );
+        assert_eq!(rows, [["5", "27", "5.00"]]);
+
+        let rows = query(
+            &mut system,
+            "SELECT a.g, COUNT(*), SUM(b.v) FROM a, b WHERE a.id = b.aid GROUP BY a.g;",
+        );
+        assert_eq!(rows, [["10", "3", "18"], ["20", "2", "9"]]);
+
+        let rows = query(
+            &mut system,
+            "SELECT SUM(b.v), a.g FROM a, b WHERE a.id = b.aid GROUP BY a.g ORDER BY a.g DESC;",
+        );
+        assert_eq!(rows, [["9", "20"], ["18", "10"]]);
+
+        let rows = query(&mut system, "SELECT COUNT(*) FROM a WHERE a.id > 4;");
+        assert_eq!(rows, [["0"]]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_output_schema() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_output_schema");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE a (id INT NOT NULL, g INT, PRIMARY KEY (id));
+            CREATE TABLE b (aid INT, v INT);
+            INSERT INTO a VALUES (1, 10), (2, 20);
+            INSERT INTO b VALUES (1, 5), (2, 6);",
+        );
+
+        let join = || {
+            vec![WhereClause::OperatorExpression(
+                ColumnSelector(Some("a".to_owned()), "id".to_owned()),
+                Operator::Eq,
+                Expression::Column(ColumnSelector(Some("b".to_owned()), "aid".to_owned())),
+            )]
+        };
+
+        // Fields follow the order of tables in the statement, whichever is joined first
+        let (schema, results) = system
+            .select(&Selectors::All, &["b", "a"], join(), None, vec![], false)
+            .unwrap();
+        assert_eq!(schema.get_titles(), ["b.aid", "b.v", "a.id", "a.g"]);
+        for (record, _, _) in &results {
+            assert_eq!(record.fields.len(), 4);
+            assert_eq!(record.fields[0], record.fields[2]);
+        }
+
+        let selectors = Selectors::Some(vec![
+            Selector::Aggregate(
+                Aggregator::Avg,
+                ColumnSelector(Some("b".to_owned()), "v".to_owned()),
+            ),
+            Selector::Count,
+        ]);
+        let order_by = vec![(ColumnSelector(Some("a".to_owned()), "g".to_owned()), false)];
+        let (schema, results) = system
+            .select(&selectors, &["a", "b"], join(), None, order_by, false)
+            .unwrap();
+        assert_eq!(schema.get_titles(), ["AVG(b.v)", "COUNT(*)"]);
+        assert_eq!(schema.get_columns()[0].typ, Type::Float);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0.fields, [Value::Float(5.5), Value::Int(2)]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_column_disambiguation() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_column_disambiguation");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE a (id INT NOT NULL, g INT, PRIMARY KEY (id));
+            CREATE TABLE b (aid INT, v INT, g INT);
+            INSERT INTO a VALUES (1, 10), (2, 20);
+            INSERT INTO b VALUES (1, 5, 0), (2, 6, 0), (2, 7, 0);
+            SET only_full_group_by = 1;",
+        );
+
+        // Columns in only one of the tables need no table part
+        let rows = query(
+            &mut system,
+            "SELECT id, v FROM a, b WHERE id = aid AND v > 5 ORDER BY v;",
+        );
+        assert_eq!(rows, [["2", "6"], ["2", "7"]]);
+        let rows = query(
+            &mut system,
+            "SELECT a.g, SUM(v) FROM a, b WHERE id = aid GROUP BY a.g ORDER BY a.g;",
+        );
+        assert_eq!(rows, [["10", "5"], ["20", "13"]]);
+
+        // Columns in both tables are ambiguous
+        for sql in [
+            "SELECT g FROM a, b WHERE id = aid;",
+            "SELECT id FROM a, b WHERE id = aid AND g = 0;",
+            "SELECT a.g, SUM(v) FROM a, b WHERE id = aid GROUP BY g;",
+        ] {
+            let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+            assert!(matches!(result, Err(Error::InexactColumn(_))), "{sql}");
+        }
+
+        // Grouping on a column of another table is not mistaken for the selected one
+        let (_, result) = parser::parse(
+            &mut system,
+            "SELECT a.g, COUNT(*) FROM a, b WHERE id = aid GROUP BY b.g;",
+        )
+        .pop()
+        .unwrap();
+        assert!(matches!(result, Err(Error::NotGrouped(_))));
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_index_prefix() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_index_prefix");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (a INT, b INT, c VARCHAR(4));
+            ALTER TABLE t ADD INDEX (a, b);
+            INSERT INTO t VALUES (1, 1, 'x'), (1, 2, 'y'), (1, 3, 'z'), (2, 1, 'w'), (0, 9, 'u');",
+        );
+
+        // Equality on the leading column, with an optional range on the next one
+        for (sql, expected) in [
+            ("SELECT c FROM t WHERE a = 1;", &[["x"], ["y"], ["z"]][..]),
+            ("SELECT c FROM t WHERE a = 1 AND b > 1;", &[["y"], ["z"]]),
+            ("SELECT c FROM t WHERE a = 1 AND b < 2;", &[["x"]]),
+            ("SELECT c FROM t WHERE a = 2 AND b = 1;", &[["w"]]),
+            ("SELECT c FROM t WHERE a = 1 AND b > 5;", &[]),
+        ] {
+            let rows = query(&mut system, sql);
+            assert_eq!(rows, expected, "{sql}");
+            assert!(system.plan[0].starts_with("index"), "{sql}");
+        }
+
+        // Conditions not on the leading column can't use the index
+        let rows = query(&mut system, "SELECT c FROM t WHERE b = 1;");
+        assert_eq!(rows, [["x"], ["w"]]);
+        assert_eq!(system.plan, ["scan t"]);
+
+        // Updates and deletes stop at the end of the prefix
+        query(&mut system, "UPDATE t SET c = 'q' WHERE a = 1 AND b < 3;");
+        query(&mut system, "DELETE FROM t WHERE a = 0;");
+        let rows = query(&mut system, "SELECT c FROM t;");
+        assert_eq!(rows, [["q"], ["q"], ["z"], ["w"]]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_null_semantics() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_null_semantics");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE a (id INT NOT NULL, v INT, s VARCHAR(8), PRIMARY KEY (id));
+            CREATE TABLE b (v INT);
+            ALTER TABLE b ADD INDEX (v);
+            INSERT INTO a VALUES (1, 5, 'x'), (2, NULL, NULL), (3, 7, 'y');
+            INSERT INTO b VALUES (5), (NULL);",
+        );
+
+        // Comparisons with NULL are unknown, so neither side matches
+        let rows = query(&mut system, "SELECT id FROM a WHERE v = 5;");
+        assert_eq!(rows, [["1"]]);
+        let rows = query(&mut system, "SELECT id FROM a WHERE v <> 5;");
+        assert_eq!(rows, [["3"]]);
+        let rows = query(&mut system, "SELECT id FROM a WHERE v = NULL;");
+        assert!(rows.is_empty());
+        let rows = query(&mut system, "SELECT id FROM a WHERE s LIKE '%';");
+        assert_eq!(rows, [["1"], ["3"]]);
+        let rows = query(&mut system, "SELECT id FROM a WHERE s NOT LIKE 'x%';");
+        assert_eq!(rows, [["3"]]);
+        let rows = query(&mut system, "SELECT id FROM a WHERE v IS NULL;");
+        assert_eq!(rows, [["2"]]);
+
+        // Aggregators skip NULLs, while COUNT(*) counts all records
+        let rows = query(
+            &mut system,
+            "SELECT COUNT(*), SUM(v), AVG(v), MIN(v) FROM a;",
+        );
+        assert_eq!(rows, [["3", "12", "6.00", "5"]]);
+        let rows = query(&mut system, "SELECT SUM(v) FROM a WHERE id = 2;");
+        assert_eq!(rows, [["NULL"]]);
+
+        // NULLs come first in ascending order, and last in descending order
+        let rows = query(&mut system, "SELECT id FROM a ORDER BY v;");
+        assert_eq!(rows, [["2"], ["1"], ["3"]]);
+        let rows = query(&mut system, "SELECT id FROM a ORDER BY v DESC;");
+        assert_eq!(rows, [["3"], ["1"], ["2"]]);
+
+        // IS NULL looks up NULL keys in the index
+        let rows = query(&mut system, "SELECT v FROM b WHERE v IS NULL;");
+        assert_eq!(system.plan, ["index b.annoy.v"]);
+        assert_eq!(rows, [["NULL"]]);
+        let rows = query(&mut system, "SELECT v FROM b WHERE v IS NOT NULL;");
+        assert_eq!(rows, [["5"]]);
+
+        // NULLs never join, with or without an index
+        let rows = query(&mut system, "SELECT a.id FROM a, b WHERE a.v = b.v;");
+        assert_eq!(rows, [["1"]]);
+        let rows = query(&mut system, "SELECT a.id FROM a, b WHERE b.v = a.v;");
+        assert_eq!(rows, [["1"]]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_cast_and_coercion() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_cast_and_coercion");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (a INT, f FLOAT, d DATE, dt DATETIME DEFAULT '2020-01-01');
+            INSERT INTO t VALUES (1, 2, '2020-1-5', '2020-01-05 10:00:00');
+            INSERT INTO t VALUES (CAST('7' AS INT), CAST(5 AS FLOAT), CAST('2021-02-03' AS DATE), NULL);",
+        );
+
+        // Ints are coerced into floats, and strings into dates, on insert and comparison
+        let rows = query(&mut system, "SELECT a, f, d FROM t WHERE f = 2;");
+        assert_eq!(rows, [["1", "2.00", "2020-01-05"]]);
+        let rows = query(&mut system, "SELECT a FROM t WHERE d > '2020-06-01';");
+        assert_eq!(rows, [["7"]]);
+        let rows = query(&mut system, "SELECT a FROM t WHERE dt > '2020-01-05';");
+        assert_eq!(rows, [["1"]]);
+
+        query(&mut system, "UPDATE t SET f = 9 WHERE a = 1;");
+        let rows = query(&mut system, "SELECT a FROM t WHERE f > 8.5;");
+        assert_eq!(rows, [["1"]]);
+
+        // Columns and constants are casted in selectors and where clauses
+        let sql = "SELECT CAST(a AS FLOAT), CAST('12' AS INT), CAST(d AS VARCHAR(10)) FROM t;";
+        let rows = query(&mut system, sql);
+        let expected = [["1.00", "12", "2020-01-05"], ["7.00", "12", "2021-02-03"]];
+        assert_eq!(rows, expected);
+        let rows = query(&mut system, "SELECT a FROM t WHERE f > CAST(a AS FLOAT);");
+        assert_eq!(rows, [["1"]]);
+        let sql = "SELECT a FROM t WHERE dt > CAST(d AS DATETIME);";
+        let rows = query(&mut system, sql);
+        assert_eq!(rows, [["1"]]);
+
+        // Lossy or invalid conversions are rejected
+        for sql in [
+            "INSERT INTO t VALUES (1, 'x', NULL, NULL);",
+            "INSERT INTO t VALUES (1.5, 1, NULL, NULL);",
+            "INSERT INTO t VALUES (CAST('abc' AS INT), 1, NULL, NULL);",
+            "UPDATE t SET d = 'abc' WHERE a = 1;",
+            "SELECT CAST('abc' AS INT) FROM t;",
+            "SELECT CAST(dt AS INT) FROM t;",
+        ] {
+            let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+            assert!(matches!(result, Err(Error::TypeMismatch(_, _))), "{sql}");
+        }
+
+        // Values equal across types fall into the same group
+        let date = NaiveDate::from_ymd_opt(2020, 1, 5).unwrap();
+        let values = [
+            Value::Varchar("2020-01-05".to_owned()),
+            Value::Date(date),
+            Value::DateTime(date.and_hms_opt(0, 0, 0).unwrap()),
+            Value::Int(1),
+            Value::Float(1.0),
+            Value::Bigint(1),
+            Value::Bigint((1 << 53) + 1),
+            Value::Float((1u64 << 53) as f64),
+        ];
+        let results = values
+            .into_iter()
+            .map(|value| (Record::new(vec![value]), 0, 0))
+            .collect();
+        let groups = system.group(results, 0, Collation::Binary);
+        let sizes: Vec<_> = groups.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [3, 3, 2]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_ttl_purge() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_ttl_purge");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (a INT, d DATE, s VARCHAR(5));
+            INSERT INTO t VALUES (1, '2000-01-01', 'x'), (2, '2999-01-01', 'y'), (3, NULL, 'z');",
+        );
+
+        let sql = "PURGE EXPIRED FROM t;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::NoTtl(_))));
+        let sql = "ALTER TABLE t SET TTL s INTERVAL 1 DAY;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::TtlColumnType(_, _))));
+
+        // TTL follows renamed columns and survives reopening
+        query(
+            &mut system,
+            "ALTER TABLE t SET TTL d INTERVAL 1 DAY;
+            ALTER TABLE t RENAME COLUMN d TO e;
+            USE db;",
+        );
+        let rows = query(&mut system, "PURGE EXPIRED FROM t;");
+        assert_eq!(rows, [["1"]]);
+        let rows = query(&mut system, "SELECT a FROM t;");
+        assert_eq!(rows, [["2"], ["3"]]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_unique_index() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_unique_index");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (a INT, b INT);
+            INSERT INTO t VALUES (1, 1), (2, 1), (NULL, 3), (NULL, 4);",
+        );
+
+        // Building on duplicate keys fails and leaves no index behind
+        let sql = "CREATE UNIQUE INDEX ub ON t (b);";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::DuplicateKey(name)) if name == "ub"));
+        assert!(!system.get_table_schema("t").unwrap().has_index("ub"));
+
+        query(&mut system, "CREATE UNIQUE INDEX ua ON t (a);");
+        for sql in [
+            "INSERT INTO t VALUES (1, 9);",
+            "UPDATE t SET a = 2 WHERE b = 3;",
+        ] {
+            let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+            assert!(matches!(result, Err(Error::DuplicateKey(_))), "{sql}");
+        }
+
+        // Nulls never collide
+        query(&mut system, "INSERT INTO t VALUES (NULL, 5);");
+        let rows = query(&mut system, "SELECT COUNT(*) FROM t;");
+        assert_eq!(rows, [["5"]]);
+
+        // A named unique constraint referenced by a foreign key can't be dropped
+        query(
+            &mut system,
+            "ALTER TABLE t ADD UNIQUE uc (a);
+            CREATE TABLE c (x INT, FOREIGN KEY fk (x) REFERENCES t (a));",
+        );
+        let sql = "ALTER TABLE t DROP INDEX uc;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::IndexReferencedByForeignKey(_))));
+
+        // Referenced keys are looked up through the index of the unique constraint
+        let schema = system.get_table_schema("t").unwrap();
+        assert_eq!(schema.get_indexes().len(), 2);
+        query(&mut system, "INSERT INTO c VALUES (1);");
+        let (_, result) = parser::parse(&mut system, "INSERT INTO c VALUES (7);")
+            .pop()
+            .unwrap();
+        assert!(matches!(result, Err(Error::ReferencedFieldsNotExist(_))));
+
+        query(
+            &mut system,
+            "ALTER TABLE c DROP FOREIGN KEY fk; ALTER TABLE t DROP INDEX uc;",
+        );
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::IndexNotFound(..))));
+
+        // Keys referenced in another order are looked up in the order of the key
+        query(
+            &mut system,
+            "CREATE TABLE p (a INT, b INT, PRIMARY KEY (a, b));
+            INSERT INTO p VALUES (1, 2);
+            CREATE TABLE q (x INT, y INT, FOREIGN KEY fq (y, x) REFERENCES p (b, a));
+            INSERT INTO q VALUES (1, 2);",
+        );
+        for (sql, referenced) in [
+            ("INSERT INTO q VALUES (2, 1);", false),
+            ("ALTER TABLE p DROP PRIMARY KEY;", true),
+        ] {
+            let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+            match result {
+                Err(Error::ReferencedFieldsNotExist(_)) => assert!(!referenced, "{sql}"),
+                Err(Error::IndexReferencedByForeignKey(_)) => assert!(referenced, "{sql}"),
+                _ => panic!("Unexpected result of {sql}"),
+            }
+        }
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_index_order() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_index_order");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        let values: Vec<_> = (0..2000)
+            .map(|i| format!("({}, {i})", i * 7 % 2000))
+            .collect();
+        query(
+            &mut system,
+            &format!(
+                "CREATE DATABASE db; USE db;
+                CREATE TABLE t (a INT, b INT);
+                INSERT INTO t VALUES {}, (NULL, 2000);
+                ALTER TABLE t ADD INDEX (a);
+                DELETE FROM t WHERE b < 1000;",
+                values.join(", "),
+            ),
+        );
+        let mut expected: Vec<_> = (1000..2000).map(|i| i * 7 % 2000).collect();
+        expected.sort();
+
+        // Descending order walks leaves backwards, with nulls last
+        let rows = query(&mut system, "SELECT a FROM t ORDER BY a DESC;");
+        assert_eq!(system.plan, ["index t.annoy.a desc"]);
+        let mut desc: Vec<_> = expected.iter().rev().map(|a| vec![a.to_string()]).collect();
+        desc.push(vec!["NULL".to_owned()]);
+        assert_eq!(rows, desc);
+
+        let rows = query(&mut system, "SELECT a FROM t WHERE b < 2000 ORDER BY a;");
+        assert_eq!(system.plan, ["index t.annoy.a asc"]);
+        let asc: Vec<_> = expected.iter().map(|a| vec![a.to_string()]).collect();
+        assert_eq!(rows, asc);
+
+        // Unindexed columns are still sorted
+        query(&mut system, "SELECT a FROM t ORDER BY b DESC;");
+        assert_eq!(system.plan, ["scan t"]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_soft_delete() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_soft_delete");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (a INT, s TEXT, PRIMARY KEY (a));
+            INSERT INTO t VALUES (1, 'one'), (2, 'two'), (3, 'three');
+            SET soft_delete = 1;
+            DELETE FROM t WHERE a < 3;",
+        );
+
+        let rows = query(&mut system, "SELECT * FROM t;");
+        assert_eq!(rows, [["3", "three"]]);
+        let rows = query(&mut system, "SELECT s FROM t WITH DELETED WHERE a > 1;");
+        assert_eq!(rows, [["three"], ["two"]]);
+
+        // Nothing is restored if any record violates constraints
+        query(&mut system, "INSERT INTO t VALUES (2, 'again');");
+        let sql = "UNDELETE FROM t;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::DuplicateValue(_))));
+        let rows = query(&mut system, "SELECT a FROM t WITH DELETED;");
+        assert_eq!(rows, [["3"], ["2"], ["1"], ["2"]]);
+
+        // Nor if records to be restored collide with each other
+        query(&mut system, "DELETE FROM t WHERE a = 2;");
+        let sql = "UNDELETE FROM t WHERE a = 2;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::DuplicateValue(_))));
+        let rows = query(&mut system, "SELECT a FROM t;");
+        assert_eq!(rows, [["3"]]);
+
+        let rows = query(&mut system, "UNDELETE FROM t WHERE a = 1;");
+        assert_eq!(rows, [["1"]]);
+        let rows = query(&mut system, "SELECT s FROM t WHERE a = 1;");
+        assert_eq!(rows, [["one"]]);
+
+        let rows = query(&mut system, "PURGE DELETED FROM t;");
+        assert_eq!(rows, [["2"]]);
+        let rows = query(&mut system, "SELECT a FROM t WITH DELETED;");
+        assert_eq!(rows, [["1"], ["3"]]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_bulk_load");
+        fs::create_dir(&base).unwrap();
+
+        let csv = base.join("t.csv");
+        let lines: Vec<_> = (0..5000)
+            .map(|i| format!("{},{}\n", i * 3 % 5000, i % 10))
+            .collect();
+        fs::write(&csv, lines.concat()).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            &format!(
+                "CREATE DATABASE db; USE db;
+                CREATE TABLE t (a INT NOT NULL, b INT, PRIMARY KEY (a));
+                ALTER TABLE t ADD INDEX (b);
+                LOAD DATA INFILE '{}' INTO TABLE t FIELDS TERMINATED BY ',';",
+                csv.display(),
+            ),
+        );
+
+        let rows = query(&mut system, "SELECT b FROM t WHERE a = 4321;");
+        assert_eq!(rows, [["7"]]);
+        let rows = query(
+            &mut system,
+            "SELECT COUNT(*) FROM t WHERE a >= 1000 AND a < 3000;",
+        );
+        assert_eq!(rows, [["2000"]]);
+        let rows = query(&mut system, "SELECT COUNT(*) FROM t WHERE b = 3;");
+        assert_eq!(rows, [["500"]]);
+
+        // The bulk built trees keep working with later changes
+        query(
+            &mut system,
+            "DELETE FROM t WHERE b < 5;
+            INSERT INTO t VALUES (5000, 3), (5001, 9);
+            ALTER TABLE t ADD INDEX (a, b);",
+        );
+        let rows = query(&mut system, "SELECT COUNT(*) FROM t WHERE b = 3;");
+        assert_eq!(rows, [["1"]]);
+        let rows = query(&mut system, "SELECT a FROM t ORDER BY a DESC LIMIT 3;");
+        assert_eq!(rows, [["5001"], ["5000"], ["4998"]]);
+        let rows = query(&mut system, "SELECT COUNT(*) FROM t WHERE a >= 0;");
+        assert_eq!(rows, [["2502"]]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_create_table_as() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_create_table_as");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (a INT NOT NULL, b VARCHAR(8), s TEXT, PRIMARY KEY (a));
+            INSERT INTO t VALUES (1, 'x', 'one'), (2, 'y', NULL), (3, 'x', 'three');
+            CREATE TABLE u AS SELECT b, s FROM t WHERE a > 1;
+            CREATE TABLE g AS SELECT b, COUNT(*), AVG(a) FROM t GROUP BY b;",
+        );
+
+        // Column types and nullability follow the query, without constraints
+        let schema = system.get_table_schema("u").unwrap();
+        let columns: Vec<_> = schema.get_columns().iter().map(|c| &c.typ).collect();
+        assert_eq!(columns, [&Type::Varchar(8), &Type::Text]);
+        assert!(schema.get_constraints().is_empty());
+        let rows = query(&mut system, "SELECT * FROM u;");
+        assert_eq!(rows, [["y", "NULL"], ["x", "three"]]);
+
+        let schema = system.get_table_schema("g").unwrap();
+        let names: Vec<_> = schema.get_columns().iter().map(|c| &c.name).collect();
+        assert_eq!(names, ["b", "count", "avg_a"]);
+        assert!(!schema.get_column("count").nullable);
+        let rows = query(&mut system, "SELECT b, avg_a FROM g WHERE count = 2;");
+        assert_eq!(rows, [["x", "2.00"]]);
+
+        let sql = "CREATE TABLE u AS SELECT a FROM t;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::TableExists(_))));
+        let sql = "CREATE TABLE w AS SELECT t.b, u.b FROM t, u WHERE t.b = u.b;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::DuplicateColumn(name)) if name == "b"));
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_analyze() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_analyze");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (a INT, b VARCHAR(8), f FLOAT);
+            INSERT INTO t VALUES (3, 'x', 1.5), (1, 'y', NULL), (NULL, 'x', 1.25), (3, 'z', 1.5);",
+        );
+        assert!(system.get_table_schema("t").unwrap().get_stats().is_none());
+
+        let rows = query(&mut system, "ANALYZE TABLE t;");
+        assert_eq!(
+            rows,
+            [
+                ["a", "2", "1", "1", "3"],
+                ["b", "3", "0", "x", "z"],
+                ["f", "2", "1", "1.25", "1.50"],
+            ]
+        );
+
+        // Statistics are persisted with the schema
+        system.close_all().unwrap();
+        let stats = system.get_table_schema("t").unwrap().get_stats().unwrap();
+        assert_eq!(stats.rows, 4);
+        assert_eq!(stats.columns[1].max, Value::Varchar("z".to_owned()));
+
+        // Modifying a column drops outdated statistics
+        query(&mut system, "ALTER TABLE t MODIFY b VARCHAR(16);");
+        assert!(system.get_table_schema("t").unwrap().get_stats().is_none());
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_clone_database() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_clone_database");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE a; USE a;
+            CREATE TABLE t (id INT NOT NULL, s TEXT, PRIMARY KEY (id));
+            INSERT INTO t VALUES (1, 'one'), (2, 'two');
+            CLONE DATABASE a TO b;
+            DELETE FROM t WHERE id = 1;",
+        );
+
+        let sql = "CLONE DATABASE a TO b;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::DatabaseExists(_))));
+
+        // The clone keeps data and indexes as they were when cloned
+        let rows = query(&mut system, "USE b; SELECT * FROM t WHERE id >= 1;");
+        assert_eq!(rows, [["1", "one"], ["2", "two"]]);
+        assert_eq!(query(&mut system, "DESC t;").len(), 2);
+
+        // The clone is a snapshot, rejecting modification
+        for sql in [
+            "INSERT INTO t VALUES (3, 'three');",
+            "DELETE FROM t;",
+            "CREATE TABLE u (id INT);",
+            "ANALYZE TABLE t;",
+            "ALTER TABLE t ADD INDEX (s);",
+            "ALTER DATABASE b SET quota = 1024;",
+        ] {
+            let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+            assert!(
+                matches!(result, Err(Error::ReadOnlyDatabase(ref name)) if name == "b"),
+                "{sql}"
+            );
+        }
+        let rows = query(&mut system, "SELECT * FROM t;");
+        assert_eq!(rows, [["1", "one"], ["2", "two"]]);
+
+        let rows = query(&mut system, "USE a; SELECT * FROM t;");
+        assert_eq!(rows, [["2", "two"]]);
+        query(&mut system, "INSERT INTO t VALUES (3, 'three');");
+        query(&mut system, "DROP DATABASE b;");
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_join_order() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_join_order");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        let values = |n: usize| {
+            let values: Vec<_> = (0..n).map(|i| format!("({i}, {i})")).collect();
+            values.join(", ")
+        };
+        query(
+            &mut system,
+            &format!(
+                "CREATE DATABASE db; USE db;
+                CREATE TABLE b (id INT NOT NULL, v INT, PRIMARY KEY (id));
+                CREATE TABLE s (id INT NOT NULL, v INT, PRIMARY KEY (id));
+                INSERT INTO b VALUES {};
+                INSERT INTO s VALUES {};
+                DELETE FROM s WHERE id > 2;",
+                values(2000),
+                values(4000),
+            ),
+        );
+
+        // Without statistics, the emptied pages make s look larger
+        let sql = "SELECT * FROM s, b WHERE s.id = b.id;";
+        assert_eq!(query(&mut system, sql).len(), 3);
+        assert_eq!(system.plan, ["index join b -> s.id"]);
+
+        query(&mut system, "ANALYZE TABLE s;");
+        assert_eq!(query(&mut system, sql).len(), 3);
+        assert_eq!(system.plan, ["index join s -> b.id"]);
+
+        // A selective filter makes b the better outer table
+        query(&mut system, "ANALYZE TABLE b;");
+        let sql = "SELECT * FROM s, b WHERE s.id = b.id AND b.v = 1;";
+        assert_eq!(query(&mut system, sql), [["1", "1", "1", "1"]]);
+        assert_eq!(system.plan, ["index join b -> s.id"]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_raw_filter() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_raw_filter");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (a INT, b BIGINT, f FLOAT, s VARCHAR(8));
+            INSERT INTO t VALUES (1, 10, 1.5, 'x'), (2, NULL, 2.5, 'y'), (NULL, 30, NULL, 'z');",
+        );
+
+        let cases = [
+            ("a = 1", vec!["x"]),
+            ("a <> 1", vec!["y"]),
+            ("a >= 1 AND f < 2.0", vec!["x"]),
+            ("b > 5", vec!["x", "z"]),
+            ("b < 100 AND a IS NOT NULL", vec!["x"]),
+            ("f <= 2.5", vec!["x", "y"]),
+            ("f IS NULL", vec!["z"]),
+            ("b IS NULL AND s = 'y'", vec!["y"]),
+        ];
+        for (cond, expected) in cases {
+            let rows = query(&mut system, &format!("SELECT s FROM t WHERE {cond};"));
+            let rows: Vec<_> = rows.into_iter().map(|mut row| row.remove(0)).collect();
+            assert_eq!(rows, expected, "{cond}");
+        }
+
+        // Filters also apply to updates and deletions
+        query(&mut system, "UPDATE t SET s = 'w' WHERE b >= 30;");
+        query(&mut system, "DELETE FROM t WHERE a < 2;");
+        let rows = query(&mut system, "SELECT s FROM t;");
+        assert_eq!(rows, [["y"], ["w"]]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_explain() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_explain");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (a INT NOT NULL, b INT, PRIMARY KEY (a));
+            CREATE TABLE u (a INT, c INT);
+            INSERT INTO t VALUES (1, 2), (3, 4);
+            INSERT INTO u VALUES (1, 5);",
+        );
+
+        let rows = query(
+            &mut system,
+            "EXPLAIN SELECT b, COUNT(*) FROM t WHERE b > 0 GROUP BY b ORDER BY b DESC LIMIT 1;",
+        );
+        assert_eq!(
+            rows,
+            [
+                ["limit 1"],
+                ["└── sort b DESC"],
+                ["    └── group b"],
+                ["        └── project b, COUNT(*)"],
+                ["            └── filter b > 0"],
+                ["                └── scan t"],
+            ]
+        );
+
+        // Walking an index in order needs no sorting
+        let rows = query(
+            &mut system,
+            "EXPLAIN FORMAT = TREE SELECT a FROM t ORDER BY a;",
+        );
+        assert_eq!(rows.len(), 2);
+        assert!(rows[1][0].ends_with(" asc"), "{rows:?}");
+
+        let rows = query(
+            &mut system,
+            "EXPLAIN FORMAT = JSON SELECT * FROM t, u WHERE t.a = u.a;",
+        );
+        let tree: serde_json::Value = serde_json::from_str(&rows[0][0]).unwrap();
+        let join = &tree["children"][0]["children"][0];
+        assert_eq!(join["operator"], "index join u -> t.a");
+        assert_eq!(join["children"][1]["operator"], "index lookup t.a");
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_auto_analyze() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_auto_analyze");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        let insert = |range: Range<usize>| {
+            let values: Vec<_> = range.map(|i| format!("({i})")).collect();
+            format!("INSERT INTO t VALUES {};", values.join(", "))
+        };
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (a INT);",
+        );
+        query(&mut system, &insert(0..10));
+        query(&mut system, "ANALYZE TABLE t;");
+
+        // Below the ratio, inserted records are only counted
+        query(&mut system, &insert(10..11));
+        let stats = system.get_table_schema("t").unwrap().get_stats().unwrap();
+        assert_eq!((stats.rows, stats.modified), (10, 1));
+
+        query(&mut system, &insert(11..13));
+        let stats = system.get_table_schema("t").unwrap().get_stats().unwrap();
+        assert_eq!((stats.rows, stats.modified), (13, 0));
+        assert_eq!(stats.columns[0].max, Value::Int(12));
+
+        query(&mut system, "SET auto_analyze_ratio = 0;");
+        query(&mut system, &insert(13..100));
+        let stats = system.get_table_schema("t").unwrap().get_stats().unwrap();
+        assert_eq!((stats.rows, stats.modified), (13, 87));
+
+        let sql = "SET auto_analyze_ratio = 'high';";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::TypeMismatch(..))));
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_page_size() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_page_size");
+        fs::create_dir(&base).unwrap();
+        let mut system = System::new(base.clone());
+
+        assert!(matches!(
+            system.set_page_size(1000),
+            Err(Error::InvalidPageSize(1000))
+        ));
+        assert!(matches!(
+            system.set_page_size(512),
+            Err(Error::InvalidPageSize(512))
+        ));
+
+        system.set_page_size(4096).unwrap();
+        system.create_database("db").unwrap();
+        let meta: DatabaseMeta =
+            serde_json::from_reader(File::open(base.join("db").join(DB_META)).unwrap()).unwrap();
+        assert_eq!(meta.page_size, 4096);
+
+        // Systems in the same process keep page sizes of their own databases
+        let mut other = System::new(base.clone());
+        query(&mut other, "CREATE DATABASE other; USE other;");
+        query(
+            &mut system,
+            "USE db; CREATE TABLE t (a INT); INSERT INTO t VALUES (1);",
+        );
+        query(
+            &mut other,
+            "CREATE TABLE t (a INT); INSERT INTO t VALUES (2);",
+        );
+        assert_eq!(system.fs.lock_arc().page_size(), 4096);
+        assert_eq!(other.fs.lock_arc().page_size(), DEFAULT_PAGE_SIZE);
+        let schema = system.get_table_schema("t").unwrap();
+        assert_eq!(schema.get_page_size(), 4096);
+        assert_eq!(query(&mut system, "SELECT * FROM t;"), [["1"]]);
+        assert_eq!(query(&mut other, "SELECT * FROM t;"), [["2"]]);
+
+        drop(system);
+        drop(other);
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_optimize() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_optimize");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        let values: Vec<_> = (0..2000).map(|i| format!("({i}, 'text {i}')")).collect();
+        query(
+            &mut system,
+            &format!(
+                "CREATE DATABASE db; USE db;
+                CREATE TABLE t (a INT, b TEXT);
+                ALTER TABLE t ADD INDEX (a);
+                INSERT INTO t VALUES {};",
+                values.join(", ")
+            ),
+        );
+        let pages = system.get_table_schema("t").unwrap().get_pages();
+
+        // Deleted through the index, leaving the tail of the files unused
+        query(&mut system, "DELETE FROM t WHERE a >= 100;");
+        let result = query(&mut system, "OPTIMIZE TABLE t;");
+        assert_eq!(result[0][0], "data");
+        let remaining: usize = result[0][1].parse().unwrap();
+        let released: usize = result[0][2].parse().unwrap();
+        assert_eq!(remaining + released, pages);
+        assert!(remaining < pages / 10);
+
+        let result = query(&mut system, "OPTIMIZE TABLE t;");
+        assert!(result.iter().all(|row| row[2] == "0"));
+
+        // Released pages are allocated again as usual
+        query(&mut system, "INSERT INTO t VALUES (5000, 'new');");
+        let result = query(&mut system, "SELECT b FROM t WHERE a >= 99;");
+        assert_eq!(result, vec![vec!["text 99"], vec!["new"]]);
+        let result = query(&mut system, "SELECT COUNT(*) FROM t;");
+        assert_eq!(result, vec![vec!["101"]]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_warm_cache() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_warm_cache");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+        query(
+            &mut system,
+            "CREATE DATABASE db; CREATE DATABASE other; USE db;
+            CREATE TABLE t (a INT); ALTER TABLE t ADD INDEX (a);
+            INSERT INTO t VALUES (1), (2), (3);
+            SELECT * FROM t WHERE a = 2;",
+        );
+
+        // Pages are saved when leaving the database
+        query(&mut system, "USE other;");
+        let db = base.join("db");
+        let pages: Vec<(PathBuf, usize)> =
+            serde_json::from_reader(File::open(db.join(HOT_PAGES)).unwrap()).unwrap();
+        assert!(pages.contains(&(PathBuf::from("t/data.bin"), 0)));
+
+        system.set_warm_cache(true);
+        query(&mut system, "USE db;");
+        assert!(system.tables.contains_key("t"));
+        assert!(!system.fs.lock().hot_pages(&db).is_empty());
+
+        let result = query(&mut system, "SELECT * FROM t WHERE a = 3;");
+        assert_eq!(result, vec![vec!["3"]]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_cancel_index_build() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_cancel_index_build");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        let values: Vec<_> = (0..1000).map(|i| format!("({i}, {i})")).collect();
+        query(
+            &mut system,
+            &format!(
+                "CREATE DATABASE db; USE db;
+                CREATE TABLE t (a INT, b INT);
+                INSERT INTO t VALUES {};",
+                values.join(", ")
+            ),
+        );
+
+        // The partially built index is dropped on cancellation
+        for sql in [
+            "ALTER TABLE t ADD INDEX (a);",
+            "ALTER TABLE t ADD PRIMARY KEY (a);",
+            "ALTER TABLE t ADD UNIQUE (a);",
+        ] {
+            system.cancel_token().cancel();
+            let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+            assert!(matches!(result, Err(Error::Cancelled)), "{sql}");
+            let schema = system.get_table_schema("t").unwrap();
+            assert!(schema.get_indexes().is_empty());
+            assert_eq!(fs::read_dir(base.join("db/t")).unwrap().count(), 2);
+        }
+
+        // Pending requests are cleared once the build stops
+        query(&mut system, "ALTER TABLE t ADD INDEX (a);");
+        let result = query(&mut system, "SELECT b FROM t WHERE a = 500;");
+        assert_eq!(result, vec![vec!["500"]]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_compression() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_compression");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        let values: Vec<_> = (0..1000)
+            .map(|i| format!("({i}, 'name {i}', 'description')"))
+            .collect();
+        query(
+            &mut system,
+            &format!(
+                "CREATE DATABASE db; USE db;
+                CREATE TABLE t (a INT, b VARCHAR(200), c VARCHAR(200));
+                INSERT INTO t VALUES {};
+                ALTER TABLE t SET COMPRESSION LZ4;",
+                values.join(", ")
+            ),
+        );
+
+        let data = base.join("db/t/data.bin");
+        let result = query(&mut system, "DESC EXTENDED t;");
+        assert!(result.contains(&vec!["Compression".to_owned(), "LZ4".to_owned()]));
+        query(
+            &mut system,
+            "INSERT INTO t VALUES (1000, 'new', 'description');
+            DELETE FROM t WHERE a < 500;",
+        );
+        drop(system);
+
+        // The flag is kept in the schema
+        let mut system = System::new(base.clone());
+        query(&mut system, "USE db;");
+        let result = query(&mut system, "SELECT COUNT(*), MIN(a), MAX(a) FROM t;");
+        assert_eq!(result, vec![vec!["501", "500", "1000"]]);
+        let result = query(&mut system, "SELECT b FROM t WHERE a = 1000;");
+        assert_eq!(result, vec![vec!["new"]]);
+
+        let compressed = fs::metadata(&data).unwrap().len();
+        query(&mut system, "ALTER TABLE t SET COMPRESSION NONE;");
+        assert!(fs::metadata(&data).unwrap().len() < compressed);
+        let result = query(&mut system, "SELECT b FROM t WHERE a = 999;");
+        assert_eq!(result, vec![vec!["name 999"]]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_online_index_build() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_online_index_build");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        let values: Vec<_> = (0..10000).map(|i| format!("({i}, {i}, 'x')")).collect();
+        query(
+            &mut system,
+            &format!(
+                "CREATE DATABASE db; USE db;
+                CREATE TABLE t (a INT, b INT, c VARCHAR(100));
+                INSERT INTO t VALUES {};",
+                values.join(", ")
+            ),
+        );
+        let pages = system.get_table_schema("t").unwrap().get_pages();
+        assert!(pages > 2 * INDEX_BUILD_BATCH);
+
+        // Not used until all pages are scanned
+        query(&mut system, "CREATE INDEX CONCURRENTLY i ON t (a);");
+        let build = system.index_build.as_ref().unwrap();
+        assert_eq!(build.next_page, INDEX_BUILD_BATCH);
+        let schema = system.get_table_schema("t").unwrap();
+        assert!(schema.get_indexes().is_empty());
+
+        // Changes on both scanned and unscanned pages
+        query(
+            &mut system,
+            "INSERT INTO t VALUES (100000, 100000, 'x');
+            DELETE FROM t WHERE b = 3;
+            UPDATE t SET a = 200000 WHERE b = 5;
+            UPDATE t SET a = 300000 WHERE b = 9999;",
+        );
+        while system.index_build.is_some() {
+            query(&mut system, "SELECT COUNT(*) FROM t;");
+        }
+        assert_eq!(system.get_table_schema("t").unwrap().get_indexes().len(), 1);
+
+        let expected = [
+            (100000, "100000"),
+            (200000, "5"),
+            (300000, "9999"),
+            (7, "7"),
+        ];
+        for (a, b) in expected {
+            let result = query(&mut system, &format!("SELECT b FROM t WHERE a = {a};"));
+            assert_eq!(result, vec![vec![b]]);
+        }
+        for a in [3, 5, 9999] {
+            let result = query(&mut system, &format!("SELECT b FROM t WHERE a = {a};"));
+            assert!(result.is_empty());
+        }
+
+        // A failed build is reported by the statement waiting for it, and
+        // leaves no index behind
+        query(
+            &mut system,
+            "INSERT INTO t VALUES (10007, 7, 'x');
+            CREATE UNIQUE INDEX CONCURRENTLY u ON t (b);",
+        );
+        let mut results = parser::parse(&mut system, "ALTER TABLE t ADD INDEX (a, b);");
+        assert_eq!(results.len(), 2);
+        assert!(results.pop().unwrap().1.is_ok());
+        let (_, result) = results.pop().unwrap();
+        assert!(matches!(result, Err(Error::IndexBuildFailed(name, _)) if name == "u"));
+        let schema = system.get_table_schema("t").unwrap();
+        assert_eq!(schema.get_indexes().len(), 2);
+        assert!(!base.join("db/t/u.index.json").exists());
+
+        // Or by the statement after which it is polled
+        let sql = "CREATE TABLE s (a INT);
+            INSERT INTO s VALUES (1), (1);
+            CREATE UNIQUE INDEX CONCURRENTLY u ON s (a);";
+        let mut results = parser::parse(&mut system, sql);
+        assert_eq!(results.len(), 4);
+        let (_, result) = results.pop().unwrap();
+        assert!(matches!(result, Err(Error::IndexBuildFailed(name, _)) if name == "u"));
+        assert!(results.pop().unwrap().1.is_ok());
+        assert!(!base.join("db/s/u.index.json").exists());
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_primary_keys() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_duplicate_primary_keys");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (a INT, b VARCHAR(8));
+            INSERT INTO t VALUES (1, 'x'), (2, 'y'), (1, 'x'), (3, 'z'), (2, 'y'), (1, 'x');",
+        );
+
+        // Every duplicate key is reported once, in the order found
+        let sql = "ALTER TABLE t ADD CONSTRAINT pk PRIMARY KEY (a, b);";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        let Err(Error::DuplicateValues(name, keys, more)) = result else {
+            panic!("Duplicate keys not reported");
+        };
+        assert_eq!(name, "pk");
+        assert_eq!(keys, ["(1, x)", "(2, y)"]);
+        assert_eq!(more, 0);
+        let schema = system.get_table_schema("t").unwrap();
+        assert!(schema.get_indexes().is_empty());
+
+        // Only the first duplicate keys are listed, followed by the count of the rest
+        let values: Vec<_> = (0..MAX_REPORTED_DUPLICATES + 4)
+            .map(|i| format!("({i}), ({i})"))
+            .collect();
+        query(
+            &mut system,
+            &format!(
+                "CREATE TABLE u (a INT); INSERT INTO u VALUES {};",
+                values.join(", ")
+            ),
+        );
+        let sql = "ALTER TABLE u ADD PRIMARY KEY (a);";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        let Err(err @ Error::DuplicateValues(_, keys, 4)) = &result else {
+            panic!("Duplicate keys not capped");
+        };
+        assert_eq!(keys.len(), MAX_REPORTED_DUPLICATES);
+        let last = format!("({}) ... and 4 more", MAX_REPORTED_DUPLICATES - 1);
+        assert!(err.to_string().ends_with(&last));
+
+        query(&mut system, "DELETE FROM t WHERE a < 3;");
+        query(&mut system, "ALTER TABLE t ADD PRIMARY KEY (a, b);");
+        let schema = system.get_table_schema("t").unwrap();
+        assert!(schema.get_primary_key().is_some());
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_check_foreign_key() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_check_foreign_key");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE p (id INT, PRIMARY KEY (id));
+            CREATE TABLE c (a INT, b VARCHAR(8), FOREIGN KEY fk (a) REFERENCES p (id));
+            INSERT INTO p VALUES (1), (2);
+            INSERT INTO c VALUES (1, 'one'), (NULL, 'none');
+            SET foreign_key_checks = 0;
+            INSERT INTO c VALUES (3, 'three'), (2, 'two'), (3, 'again'), (4, 'four');",
+        );
+
+        let mut rows = query(&mut system, "CHECK FOREIGN KEY fk ON c;");
+        rows.sort();
+        assert_eq!(rows, [["3", "again"], ["3", "three"], ["4", "four"]]);
+
+        let sql = "CHECK FOREIGN KEY nope ON c;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::ConstraintNotFound(_))));
+
+        // Orphans are gone once deleted, so checks can be enabled again
+        query(&mut system, "CHECK FOREIGN KEY fk ON c DELETE;");
+        let rows = query(&mut system, "SELECT b FROM c WHERE a >= 3;");
+        assert!(rows.is_empty());
+        query(&mut system, "SET foreign_key_checks = 1;");
+        let rows = query(&mut system, "SELECT COUNT(*) FROM c;");
+        assert_eq!(rows, [["3"]]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_skip_scan() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_skip_scan");
+        fs::create_dir(&base).unwrap();
+        let mut system = System::new(base.clone());
+
+        let values: Vec<_> = (0..200)
+            .map(|i| match i % 10 {
+                0 => format!("(NULL, {}, {i})", i % 20),
+                _ => format!("({}, {}, {i})", i % 3, i % 20),
+            })
+            .collect();
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (a INT, b INT, c INT);
+            ALTER TABLE t ADD INDEX (a, b);",
+        );
+        let insert = format!("INSERT INTO t VALUES {};", values.join(", "));
+        query(&mut system, &insert);
+
+        let select = |system: &mut System, sql: &str| {
+            let mut rows: Vec<i32> = query(system, sql)
+                .iter()
+                .map(|row| row[0].parse().unwrap())
+                .collect();
+            rows.sort();
+            rows
+        };
+        let expected = |b: fn(i32) -> bool| (0..200).filter(|i| b(i % 20)).collect::<Vec<_>>();
+
+        // Cardinality of the leading column is unknown before analysis
+        let rows = select(&mut system, "SELECT c FROM t WHERE b = 5;");
+        assert_eq!(rows, expected(|b| b == 5));
+        assert_eq!(system.get_plan(), ["scan t"]);
+
+        query(&mut system, "ANALYZE TABLE t;");
+
+        let rows = select(&mut system, "SELECT c FROM t WHERE b = 5;");
+        assert_eq!(rows, expected(|b| b == 5));
+        assert!(system.get_plan()[0].starts_with("skip scan t."));
+
+        let rows = select(&mut system, "SELECT c FROM t WHERE b >= 18 AND c < 100;");
+        let mut all = expected(|b| b >= 18);
+        all.retain(|&c| c < 100);
+        assert_eq!(rows, all);
+
+        let rows = select(&mut system, "SELECT c FROM t WHERE b < 0;");
+        assert!(rows.is_empty());
+
+        // Bounds on the leading column use the index as usual
+        query(&mut system, "SELECT c FROM t WHERE a = 1 AND b = 5;");
+        assert!(system.get_plan()[0].starts_with("index t."));
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_database_settings() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_database_settings");
+        fs::create_dir(&base).unwrap();
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE b (name VARCHAR(8));
+            ALTER DATABASE db SET COLLATION = 'nocase';
+            CREATE TABLE t (name VARCHAR(8), n INT);
+            INSERT INTO b VALUES ('Alice'), ('alice');
+            INSERT INTO t VALUES ('Alice', 1), ('bob', 2), ('alice', 3), ('Bob', 4), ('carol', 5);",
+        );
+
+        // Tables created before keep the binary collation
+        let rows = query(&mut system, "SELECT name FROM b WHERE name = 'ALICE';");
+        assert!(rows.is_empty());
+
+        let rows = query(&mut system, "SELECT n FROM t WHERE name = 'ALICE';");
+        assert_eq!(rows, [["1"], ["3"]]);
+        let rows = query(&mut system, "SELECT n FROM t WHERE name LIKE 'B%';");
+        assert_eq!(rows, [["2"], ["4"]]);
+        let rows = query(&mut system, "SELECT n FROM t WHERE name < 'BZ';");
+        assert_eq!(rows.len(), 4);
+        let rows = query(
+            &mut system,
+            "SELECT name, COUNT(*) FROM t GROUP BY name ORDER BY name DESC;",
+        );
+        assert_eq!(rows, [["carol", "1"], ["bob", "2"], ["Alice", "2"]]);
+
+        // Strings could not be unique keys when compared ignoring case
+        for sql in [
+            "CREATE TABLE k (name VARCHAR(8), PRIMARY KEY (name));",
+            "ALTER TABLE t ADD PRIMARY KEY (name);",
+            "ALTER TABLE t ADD UNIQUE (name);",
+            "CREATE UNIQUE INDEX un ON t (name);",
+        ] {
+            let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+            assert!(matches!(result, Err(Error::CaseInsensitiveKey(_))), "{sql}");
+        }
+        query(&mut system, "ALTER TABLE t ADD UNIQUE (n);");
+        let sql = "ALTER TABLE t CHANGE n n VARCHAR(8);";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::CaseInsensitiveKey(_))));
+        query(&mut system, "CREATE UNIQUE INDEX bn ON b (name);");
+
+        let sql = "ALTER DATABASE db SET COLLATION = 'unicode';";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::UnknownCollation(_))));
+        let sql = "ALTER DATABASE db SET TIME_ZONE = 'Mars';";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::InvalidTimeZone(_))));
+
+        query(&mut system, "ALTER DATABASE db SET TIME_ZONE = '+14:00';");
+        let offset = system.now() - Utc::now().naive_utc();
+        assert!((offset - TimeDelta::hours(14)).abs() < TimeDelta::minutes(1));
+
+        // Settings are kept in the catalog across sessions
+        system.close_all().unwrap();
+        drop(system);
+        let mut system = System::new(base.clone());
+        query(&mut system, "USE db; CREATE TABLE u (name VARCHAR(8));");
+        let schema = system.get_table_schema("u").unwrap();
+        assert_eq!(schema.get_collation(), Collation::NoCase);
+        assert_eq!(system.time_zone, "+14:00".parse().ok());
+
+        query(&mut system, "ALTER DATABASE db SET TIME_ZONE = SYSTEM;");
+        assert_eq!(system.time_zone, None);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_quota() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_quota");
+        fs::create_dir(&base).unwrap();
+
+        let csv = base.join("t.csv");
+        let lines: Vec<_> = (0..20000).map(|i| format!("{i},{}\n", i % 10)).collect();
+        fs::write(&csv, lines.concat()).unwrap();
+        let load = format!(
+            "LOAD DATA INFILE '{}' INTO TABLE t FIELDS TERMINATED BY ',';",
+            csv.display()
+        );
+
+        let mut system = System::new(base.clone());
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (a INT, b INT);
+            ALTER TABLE t ADD INDEX (b);",
+        );
+        let size = system.database_size().unwrap();
+        let quota = size + 4 * DEFAULT_PAGE_SIZE as u64;
+        let alter = format!("ALTER DATABASE db SET QUOTA = {quota};");
+        query(&mut system, &alter);
+
+        // A load beyond the quota inserts nothing
+        let (_, result) = parser::parse(&mut system, &load).pop().unwrap();
+        assert!(matches!(result, Err(Error::QuotaExceeded(..))));
+        let rows = query(&mut system, "SELECT COUNT(*) FROM t;");
+        assert_eq!(rows, [["0"]]);
+        assert!(system.database_size().unwrap() < size + DEFAULT_PAGE_SIZE as u64);
+
+        fs::write(&csv, lines[..200].concat()).unwrap();
+        query(&mut system, &load);
+        let rows = query(&mut system, "SELECT COUNT(*) FROM t WHERE b = 3;");
+        assert_eq!(rows, [["20"]]);
+
+        let values: Vec<_> = (0..5000).map(|i| format!("({i}, 0)")).collect();
+        let insert = format!("INSERT INTO t VALUES {};", values.join(", "));
+        let (_, result) = parser::parse(&mut system, &insert).pop().unwrap();
+        assert!(matches!(result, Err(Error::QuotaExceeded(..))));
+        let rows = query(&mut system, "SELECT COUNT(*) FROM t;");
+        assert_eq!(rows, [["200"]]);
+
+        // Overflow pages of text are counted
+        query(&mut system, "CREATE TABLE x (s TEXT);");
+        let text = "a".repeat(8 * DEFAULT_PAGE_SIZE);
+        let insert_text = format!("INSERT INTO x VALUES ('{text}');");
+        let (_, result) = parser::parse(&mut system, &insert_text).pop().unwrap();
+        assert!(matches!(result, Err(Error::QuotaExceeded(..))));
+        query(&mut system, "INSERT INTO x VALUES ('a');");
+
+        query(&mut system, "ALTER DATABASE db SET QUOTA = NULL;");
+        query(&mut system, &insert);
+        query(&mut system, &insert_text);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_cache_status() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_cache_status");
+        fs::create_dir(&base).unwrap();
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (a INT, b INT);
+            INSERT INTO t VALUES (1, 2), (3, 4);",
+        );
+        let stats = |system: &System| {
+            let files = system.get_cache_status().unwrap();
+            assert_eq!(files.len(), 1);
+            let path = Path::new("t").join("data.bin");
+            assert_eq!(files[0].0, path.display().to_string());
+            files[0].1.clone()
+        };
+        let before = stats(&system);
+        assert!(before.misses >= 1);
+        let page_size = system.fs.lock_arc().page_size();
+        assert_eq!(before.bytes_read, before.misses * page_size as u64);
+
+        query(&mut system, "SELECT * FROM t;");
+        let after = stats(&system);
+        assert_eq!(after.hits, before.hits + 1);
+        assert_eq!(after.misses, before.misses);
+
+        let rows = query(&mut system, "SHOW CACHE STATUS;");
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][1], after.hits.to_string());
+
+        drop(system);
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_insert_default() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_insert_default");
+        fs::create_dir(&base).unwrap();
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (a INT NOT NULL, b VARCHAR(8) DEFAULT 'none', c FLOAT DEFAULT NULL);
+            INSERT INTO t VALUES (1, DEFAULT, DEFAULT), (2, 'two', 2.5);",
+        );
+        let rows = query(&mut system, "SELECT * FROM t;");
+        assert_eq!(rows, [["1", "none", "NULL"], ["2", "two", "2.50"]]);
+
+        // A column without default takes NULL, which it may not allow
+        let (_, result) = parser::parse(&mut system, "INSERT INTO t VALUES (DEFAULT, 'x', 1.0);")
+            .pop()
+            .unwrap();
+        assert!(matches!(result, Err(Error::NotNullable(column)) if column == "a"));
+
+        drop(system);
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_field_count_mismatch() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_field_count_mismatch");
+        fs::create_dir(&base).unwrap();
+        let csv = base.join("t.csv");
+        fs::write(&csv, "1,2\n3\n").unwrap();
+        let mut system = System::new(base.clone());
+
+        query(&mut system, "CREATE DATABASE db; USE db;");
+        query(&mut system, "CREATE TABLE t (a INT, b INT);");
+        let (_, result) = parser::parse(&mut system, "INSERT INTO t VALUES (1, 2), (3, 4, 5);")
+            .pop()
+            .unwrap();
+        let error = result.err().unwrap();
+        assert!(matches!(error, Error::RowFieldCountMismatch(2, 3, 2, _)));
+        assert_eq!(
+            error.to_string(),
+            "Field count mismatch in row 2: 3 provided but 2 expected, for columns (a, b)"
+        );
+
+        let statement = format!(
+            "LOAD DATA INFILE '{}' INTO TABLE t FIELDS TERMINATED BY ',';",
+            csv.display()
+        );
+        let (_, result) = parser::parse(&mut system, &statement).pop().unwrap();
+        let error = result.err().unwrap();
+        assert!(matches!(error, Error::RowFieldCountMismatch(2, 1, 2, _)));
+
+        drop(system);
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_table_cache() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_table_cache");
+        fs::create_dir(&base).unwrap();
+        let mut system = System::new(base.clone());
+
+        query(&mut system, "CREATE DATABASE db; USE db;");
+        for i in 0..=TABLE_CACHE_SIZE {
+            query(&mut system, &format!("CREATE TABLE t{i} (a INT);"));
+        }
+        query(&mut system, "SHOW TABLES;");
+        // The least recently used table is closed
+        assert_eq!(system.tables.len(), TABLE_CACHE_SIZE);
+        assert!(!system.tables.contains_key("t0"));
+        query(&mut system, "INSERT INTO t0 VALUES (1);");
+        assert!(system.tables.contains_key("t0"));
+        drop(system);
+
+        // Changes by another system are seen on the next statement
+        let mut system = System::new(base.clone());
+        query(&mut system, "USE db;");
+        assert_eq!(query(&mut system, "SELECT * FROM t0;").len(), 1);
+        let mut other = System::new(base.clone());
+        query(&mut other, "USE db; INSERT INTO t0 VALUES (2);");
+        assert_eq!(query(&mut system, "SELECT * FROM t0;").len(), 2);
+        query(&mut system, "INSERT INTO t0 VALUES (3);");
+        assert_eq!(query(&mut other, "SELECT * FROM t0;").len(), 3);
+
+        // Changes saved by another process meanwhile are not overwritten
+        query(&mut other, "INSERT INTO t0 VALUES (4);");
+        system.get_table_mut("t0").unwrap();
+        assert!(matches!(system.save_tables(), Err(Error::TableConflict(_))));
+        drop(other);
+
+        drop(system);
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_ddl_recovery() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_ddl_recovery");
+        fs::create_dir(&base).unwrap();
+        let mut system = System::new(base.clone());
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db; CREATE TABLE u (a INT);",
+        );
+        drop(system);
+
+        // A crash left a table without its schema, and files of an index
+        let db = base.join("db");
+        fs::create_dir(db.join("t")).unwrap();
+        File::create(db.join("t").join("data.bin")).unwrap();
+        File::create(db.join("u").join("i.index.bin")).unwrap();
+        let journal = vec![
+            DdlOperation::CreateTable {
+                table: "t".to_owned(),
+            },
+            DdlOperation::CreateIndex {
+                table: "u".to_owned(),
+                index: "i".to_owned(),
+            },
+        ];
+        serde_json::to_writer(File::create(db.join(DDL_JOURNAL)).unwrap(), &journal).unwrap();
+
+        let mut system = System::new(base.clone());
+        query(&mut system, "USE db;");
+        assert!(!db.join(DDL_JOURNAL).exists());
+        assert!(!db.join("t").exists());
+        assert!(!db.join("u").join("i.index.bin").exists());
+        query(&mut system, "CREATE TABLE t (a INT, PRIMARY KEY (a));");
+        assert!(!db.join(DDL_JOURNAL).exists());
+        assert_eq!(query(&mut system, "SELECT * FROM u;").len(), 0);
+
+        // A failed creation leaves nothing behind, on the referenced table either
+        let sql = "CREATE TABLE c (x INT, s TEXT, FOREIGN KEY (x) REFERENCES t (a), UNIQUE (s));";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::TextIndex(_))));
+        assert!(!db.join(DDL_JOURNAL).exists());
+        assert!(!db.join("c").exists());
+        assert_eq!(fs::read_dir(db.join("t")).unwrap().count(), 4);
+        query(&mut system, "DROP TABLE t;");
+
+        drop(system);
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_modify_column() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_modify_column");
+        fs::create_dir(&base).unwrap();
+        let mut system = System::new(base.clone());
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (a INT, b VARCHAR(4), PRIMARY KEY (a), UNIQUE (b));
+            INSERT INTO t VALUES (1, '1'), (2, '01'), (3, NULL);",
+        );
+        let db = base.join("db");
+
+        // Columns of the primary key stay NOT NULL
+        query(&mut system, "ALTER TABLE t MODIFY a FLOAT;");
+        let rows = query(&mut system, "DESC t;");
+        assert_eq!(rows[0], ["a", "FLOAT", "NO", "NULL"]);
+        let rows = query(&mut system, "SELECT b FROM t WHERE a = 2;");
+        assert_eq!(rows, [["01"]]);
+
+        // A failed rewrite leaves the table intact, with nothing staged left
+        let sql = "ALTER TABLE t MODIFY b INT;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::DuplicateValue(_))));
+        assert!(!db.join(DDL_JOURNAL).exists());
+        assert!(!db.join("t.new").exists());
+        assert!(!db.join("t.old").exists());
+        let rows = query(&mut system, "SELECT b FROM t WHERE b = '01';");
+        assert_eq!(rows, [["01"]]);
+        let rows = query(&mut system, "DESC t;");
+        assert_eq!(rows[1], ["b", "VARCHAR(4)", "YES", "NULL"]);
+
+        // A crash while swapping puts the old table back
+        drop(system);
+        fs::rename(db.join("t"), db.join("t.old")).unwrap();
+        fs::create_dir(db.join("t.new")).unwrap();
+        let journal = vec![DdlOperation::ModifyColumn {
+            table: "t".to_owned(),
+        }];
+        serde_json::to_writer(File::create(db.join(DDL_JOURNAL)).unwrap(), &journal).unwrap();
+
+        let mut system = System::new(base.clone());
+        query(&mut system, "USE db;");
+        assert!(!db.join(DDL_JOURNAL).exists());
+        assert!(!db.join("t.new").exists());
+        assert!(!db.join("t.old").exists());
+        let rows = query(&mut system, "SELECT a, b FROM t WHERE a >= 1;");
+        assert_eq!(rows, [["1.00", "1"], ["2.00", "01"], ["3.00", "NULL"]]);
+
+        // A changed key column renames its implicit index along
+        query(&mut system, "ALTER TABLE t CHANGE a c INT;");
+        let rows = query(&mut system, "SELECT b FROM t WHERE c = 2;");
+        assert_eq!(rows, [["01"]]);
+        assert_eq!(system.get_plan(), ["index t.pk.annoy.c.implicit"]);
+
+        // A failed change keeps the old name of the column
+        query(
+            &mut system,
+            "CREATE TABLE u (a BIGINT, UNIQUE (a));
+            INSERT INTO u VALUES (9007199254740993), (9007199254740992);",
+        );
+        let sql = "ALTER TABLE u CHANGE a b FLOAT;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::DuplicateValue(_))));
+        let rows = query(&mut system, "DESC u;");
+        assert_eq!(rows[0], ["a", "BIGINT", "YES", "NULL"]);
+        let sql = "ALTER TABLE t CHANGE c b INT;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::DuplicateColumn(_))));
+
+        drop(system);
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_missing_index_files() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_missing_index_files");
+        fs::create_dir(&base).unwrap();
+        let mut system = System::new(base.clone());
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (a INT, b INT, PRIMARY KEY (a));
+            ALTER TABLE t ADD INDEX i (b);
+            INSERT INTO t VALUES (1, 10), (2, 20), (3, 20);",
+        );
+        drop(system);
+
+        // Remove the data of one index and the schema of the other
+        let table = base.join("db").join("t");
+        fs::remove_file(table.join("i.index.bin")).unwrap();
+        let pk = fs::read_dir(&table)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .find(|name| name.starts_with("pk.") && name.ends_with(".index.json"))
+            .unwrap();
+        fs::remove_file(table.join(pk)).unwrap();
+
+        // Queries skip unavailable indexes, while modification is refused
+        let mut system = System::new(base.clone());
+        query(&mut system, "USE db;");
+        let rows = query(&mut system, "SELECT a FROM t WHERE b = 20;");
+        assert_eq!(rows.len(), 2);
+        assert_eq!(system.get_plan(), ["scan t"]);
+        assert!(!table.join("i.index.bin").exists());
+
+        let sql = "INSERT INTO t VALUES (1, 30);";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::IndexUnavailable(..))));
+
+        query(&mut system, "ALTER TABLE t REBUILD INDEX i;");
+        let rows = query(&mut system, "SELECT a FROM t WHERE b = 20;");
+        assert_eq!(rows.len(), 2);
+        assert_eq!(system.get_plan(), ["index t.i"]);
+        assert!(table.join("i.index.bin").exists());
+
+        // Implicit indexes are rebuilt by the names reported
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        let Err(Error::IndexUnavailable(name, _)) = result else {
+            panic!("Expected an unavailable index");
+        };
+        query(&mut system, &format!("ALTER TABLE t REBUILD INDEX {name};"));
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::DuplicateValue(..))));
+
+        // Rebuilt on opening once asked to
+        system.close_all().unwrap();
+        fs::remove_file(table.join("i.index.bin")).unwrap();
+        query(&mut system, "SET rebuild_missing_indexes = 1;");
+        query(&mut system, "INSERT INTO t VALUES (4, 20);");
+        let rows = query(&mut system, "SELECT a FROM t WHERE b = 20;");
+        assert_eq!(rows.len(), 3);
+        assert_eq!(system.get_plan(), ["index t.i"]);
+
+        drop(system);
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_sum_overflow() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_sum_overflow");
+        fs::create_dir(&base).unwrap();
+        let mut system = System::new(base.clone());
+
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE x (a INT, b BIGINT);
+            INSERT INTO x VALUES (2147483647, 9223372036854775807), (2147483647, 1);",
+        );
+        // Integers are summed up as BIGINT
+        let rows = query(&mut system, "SELECT SUM(a), AVG(a) FROM x;");
+        assert_eq!(rows, [["4294967294", "2147483647.00"]]);
+
+        let (_, result) = parser::parse(&mut system, "SELECT SUM(b) FROM x;")
+            .pop()
+            .unwrap();
+        assert!(matches!(result, Err(Error::SumOverflow)));
+
+        // A single integer is widened as well
+        let sql = "SELECT SUM(a) FROM x WHERE b = 1;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        let (result, _) = result.unwrap();
+        assert!(matches!(result.rows[0][..], [Value::Bigint(2147483647)]));
+
+        drop(system);
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_replica_path() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_replica_path");
+        fs::create_dir_all(base.join("db")).unwrap();
+        // The data directory is given without a file name, as `--path .` is
+        let mut system = System::new(base.join("db").join(".."));
+
+        let replica = system.replica().unwrap();
+        let path = replica.base.clone();
+        let sibling = base
+            .canonicalize()
+            .unwrap()
+            .with_file_name("test_replica_path.replay");
+        assert_eq!(path, sibling);
+        assert!(path.join("db").exists());
+        assert!(!base.join(".replay").exists());
+
+        // The copy is removed once the replica is done with
+        drop(replica);
+        assert!(!path.exists());
+
+        drop(system);
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_load_where() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_load_where");
+        fs::create_dir(&base).unwrap();
+
+        let csv = base.join("t.csv");
+        let lines: Vec<_> = (0..100)
+            .map(|i| format!("{i},2024-{:02}-{:02},{}\n", i % 12 + 1, i % 28 + 1, i % 10))
+            .collect();
+        fs::write(&csv, lines.concat()).unwrap();
+
+        let mut system = System::new(base.clone());
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (a INT NOT NULL, d DATE, b INT, PRIMARY KEY (a));",
+        );
+
+        // Only rows in June are inserted
+        let sql = format!(
+            "LOAD DATA INFILE '{}' INTO TABLE t FIELDS TERMINATED BY ','
+            WHERE d >= '2024-06-01' AND d < '2024-07-01';",
+            csv.display(),
+        );
+        let rows = query(&mut system, &sql);
+        assert_eq!(rows, [["8"]]);
+        let rows = query(&mut system, "SELECT COUNT(*), MIN(a), MAX(a) FROM t;");
+        assert_eq!(rows, [["8", "5", "89"]]);
+
+        // Rows of other months are loaded later without colliding
+        let sql = format!(
+            "LOAD DATA INFILE '{}' INTO TABLE t FIELDS TERMINATED BY ',' WHERE d < '2024-06-01';",
+            csv.display(),
+        );
+        let rows = query(&mut system, &sql);
+        assert_eq!(rows, [["44"]]);
+        let rows = query(&mut system, "SELECT COUNT(*) FROM t WHERE b = 3;");
+        assert_eq!(rows, [["5"]]);
+
+        // Conditions are checked before any row is read
+        let sql = format!(
+            "LOAD DATA INFILE '{}' INTO TABLE t FIELDS TERMINATED BY ',' WHERE c = 1;",
+            csv.display(),
+        );
+        let (_, result) = parser::parse(&mut system, &sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::ColumnNotFound(_))));
+        let rows = query(&mut system, "SELECT COUNT(*) FROM t;");
+        assert_eq!(rows, [["52"]]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_order_by_directions() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_order_by_directions");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE e (name VARCHAR(8), dept INT, salary INT);
+            INSERT INTO e VALUES ('a', 2, 10), ('b', 1, 30), ('c', 2, 30),
+                ('d', 1, 20), ('e', 2, 20), ('f', 1, 30);",
+        );
+
+        let rows = query(
+            &mut system,
+            "SELECT name FROM e ORDER BY dept ASC, salary DESC, name DESC;",
+        );
+        assert_eq!(rows, [["f"], ["b"], ["d"], ["c"], ["e"], ["a"]]);
+        let rows = query(
+            &mut system,
+            "SELECT name FROM e ORDER BY dept DESC, salary, name;",
+        );
+        assert_eq!(rows, [["a"], ["e"], ["c"], ["d"], ["b"], ["f"]]);
+
+        // Keys after the first still order rows within a limit
+        let rows = query(
+            &mut system,
+            "SELECT name FROM e ORDER BY salary DESC, name ASC LIMIT 2;",
+        );
+        assert_eq!(rows, [["b"], ["c"]]);
+        let rows = query(
+            &mut system,
+            "SELECT name FROM e ORDER BY salary DESC, name ASC LIMIT 2 OFFSET 2;",
+        );
+        assert_eq!(rows, [["f"], ["d"]]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_rename() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_rename");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE p (id INT, v INT, PRIMARY KEY (id));
+            CREATE TABLE c (x INT, FOREIGN KEY (x) REFERENCES p (id));
+            ALTER TABLE p ADD INDEX (v);
+            INSERT INTO p VALUES (1, 10), (2, 20);
+            INSERT INTO c VALUES (1);
+            ALTER TABLE p RENAME TO q;",
+        );
+
+        let sql = "SELECT * FROM p;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::TableNotFound(_))));
+        let rows = query(&mut system, "SELECT id FROM q WHERE v = 20;");
+        assert_eq!(rows, [["2"]]);
+
+        // The foreign key follows the renamed table
+        let sql = "INSERT INTO c VALUES (3);";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::ReferencedFieldsNotExist(_))));
+        let sql = "DELETE FROM q WHERE id = 1;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::RowReferencedByForeignKey(_))));
+
+        // So do indexes and the foreign key after renaming the column
+        query(&mut system, "ALTER TABLE q RENAME COLUMN id TO pid;");
+        let rows = query(&mut system, "SELECT v FROM q WHERE pid = 1;");
+        assert_eq!(rows, [["10"]]);
+        assert_eq!(system.get_plan(), ["index q.pk.annoy.pid.implicit"]);
+        query(&mut system, "INSERT INTO c VALUES (2);");
+        let (_, result) = parser::parse(&mut system, "INSERT INTO c VALUES (3);")
+            .pop()
+            .unwrap();
+        assert!(matches!(result, Err(Error::ReferencedFieldsNotExist(_))));
+
+        let sql = "ALTER TABLE q RENAME COLUMN v TO pid;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::DuplicateColumn(_))));
+        let sql = "ALTER TABLE c RENAME TO q;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::TableExists(_))));
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_deferred_foreign_keys() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_deferred_foreign_keys");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE a (id INT, b INT, PRIMARY KEY (id));
+            CREATE TABLE b (id INT, a INT, PRIMARY KEY (id), FOREIGN KEY (a) REFERENCES a (id));
+            ALTER TABLE a ADD FOREIGN KEY (b) REFERENCES b (id);",
+        );
+
+        // Mutually referencing rows can't be inserted one by one
+        let sql = "INSERT INTO a VALUES (1, 1);";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::ReferencedFieldsNotExist(_))));
+
+        query(
+            &mut system,
+            "SET foreign_key_checks = 0;
+            INSERT INTO a VALUES (1, 1), (2, 2);
+            INSERT INTO b VALUES (1, 2), (2, 1), (3, 3);",
+        );
+
+        // Enabling checks validates all keys, and fails keeping checks off
+        let sql = "SET foreign_key_checks = 1;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::ReferencedFieldsNotExist(_))));
+        query(&mut system, "INSERT INTO b VALUES (4, 9);");
+
+        query(
+            &mut system,
+            "DELETE FROM b WHERE id >= 3;
+            SET foreign_key_checks = 1;",
+        );
+        let sql = "INSERT INTO b VALUES (3, 3);";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::ReferencedFieldsNotExist(_))));
+        let rows = query(&mut system, "SELECT COUNT(*) FROM b;");
+        assert_eq!(rows, [["2"]]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_foreign_key_batch_checks() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_foreign_key_batch_checks");
+        fs::create_dir(&base).unwrap();
+
+        // Enough keys to span many leaves of both indexes
+        let parents: Vec<_> = (0..3000).map(|i| format!("({})", i * 2)).collect();
+        let children: Vec<_> = (0..3000).map(|i| format!("({})", i * 2 % 2000)).collect();
+
+        let mut system = System::new(base.clone());
+        query(
+            &mut system,
+            &format!(
+                "CREATE DATABASE db; USE db;
+                CREATE TABLE p (id INT, PRIMARY KEY (id));
+                CREATE TABLE c (x INT, FOREIGN KEY (x) REFERENCES p (id));
+                INSERT INTO p VALUES {};
+                SET foreign_key_checks = 0;
+                INSERT INTO c VALUES {};",
+                parents.join(", "),
+                children.join(", "),
+            ),
+        );
+
+        // A single orphan among the keys fails the whole check
+        query(&mut system, "INSERT INTO c VALUES (1001);");
+        let sql = "SET foreign_key_checks = 1;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::ReferencedFieldsNotExist(_))));
+        query(
+            &mut system,
+            "DELETE FROM c WHERE x = 1001;
+            SET foreign_key_checks = 1;",
+        );
+
+        // Deleting a batch of parents fails if any of them is referenced
+        let sql = "DELETE FROM p WHERE id >= 1998;";
+        let (_, result) = parser::parse(&mut system, sql).pop().unwrap();
+        assert!(matches!(result, Err(Error::RowReferencedByForeignKey(_))));
+        let rows = query(&mut system, "SELECT COUNT(*) FROM p;");
+        assert_eq!(rows, [["3000"]]);
+        query(&mut system, "DELETE FROM p WHERE id >= 2000;");
+        let rows = query(&mut system, "SELECT COUNT(*) FROM p;");
+        assert_eq!(rows, [["1000"]]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_index_point_lookup() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_index_point_lookup");
+        fs::create_dir(&base).unwrap();
+
+        let parents: Vec<_> = (0..2000)
+            .map(|i| format!("({}, {})", i * 2 + 2, i % 3))
+            .collect();
+
+        let mut system = System::new(base.clone());
+        query(
+            &mut system,
+            &format!(
+                "CREATE DATABASE db; USE db;
+                CREATE TABLE p (a INT, b INT, PRIMARY KEY (a, b));
+                CREATE TABLE c (a INT, b INT, FOREIGN KEY (a, b) REFERENCES p (a, b));
+                INSERT INTO p VALUES {};",
+                parents.join(", "),
+            ),
+        );
+
+        // Keys in the gaps and beyond both ends are missing
+        for (a, b) in [(0, 0), (1, 0), (1999, 1), (4002, 1), (2, 1), (4000, 0)] {
+            let sql = format!("INSERT INTO c VALUES ({a}, {b});");
+            let (_, result) = parser::parse(&mut system, &sql).pop().unwrap();
+            assert!(
+                matches!(result, Err(Error::ReferencedFieldsNotExist(_))),
+                "{sql}"
+            );
+        }
+        // Only exact keys are found, not ones sharing a prefix
+        query(
+            &mut system,
+            "INSERT INTO c VALUES (2, 0), (1002, 2), (4000, 1), (2000, 0);",
+        );
+        let rows = query(&mut system, "SELECT COUNT(*) FROM c;");
+        assert_eq!(rows, [["4"]]);
+
+        for (a, b) in [(2, 0), (1002, 2), (4000, 1)] {
+            let sql = format!("INSERT INTO p VALUES ({a}, {b});");
+            let (_, result) = parser::parse(&mut system, &sql).pop().unwrap();
+            assert!(matches!(result, Err(Error::DuplicateValue(..))), "{sql}");
+        }
+        query(&mut system, "INSERT INTO p VALUES (2, 1), (4000, 0);");
+        let rows = query(&mut system, "SELECT COUNT(*) FROM p;");
+        assert_eq!(rows, [["2002"]]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_not_like() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_not_like");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (id INT, s VARCHAR(8));
+            INSERT INTO t VALUES (1, 'apple'), (2, 'apply'), (3, 'banana'), (4, NULL), (5, 'ape');",
+        );
+
+        let rows = query(&mut system, "SELECT id FROM t WHERE s NOT LIKE 'app%';");
+        assert_eq!(rows, [["3"], ["5"]]);
+        let rows = query(&mut system, "SELECT id FROM t WHERE s NOT LIKE 'appl_';");
+        assert_eq!(rows, [["3"], ["5"]]);
+        let rows = query(
+            &mut system,
+            "SELECT id FROM t WHERE s NOT LIKE '%a' AND s LIKE 'a%';",
+        );
+        assert_eq!(rows, [["1"], ["2"], ["5"]]);
+
+        // Each record is in exactly one of LIKE, NOT LIKE and NULL
+        let rows = query(&mut system, "SELECT COUNT(*) FROM t WHERE s LIKE '%p%';");
+        assert_eq!(rows, [["3"]]);
+        let rows = query(
+            &mut system,
+            "SELECT COUNT(*) FROM t WHERE s NOT LIKE '%p%';",
+        );
+        assert_eq!(rows, [["1"]]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_desc_extended() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_desc_extended");
+        fs::create_dir(&base).unwrap();
+
+        let mut system = System::new(base.clone());
+        query(
+            &mut system,
+            "CREATE DATABASE db; USE db;
+            CREATE TABLE t (a INT, b VARCHAR(100));",
+        );
+        let property = |rows: &[Vec<String>], name: &str| {
+            rows.iter()
+                .find(|row| row[0] == name)
+                .map(|row| row[1].clone())
+                .unwrap()
+        };
+
+        let rows = query(&mut system, "DESC EXTENDED t;");
+        assert_eq!(property(&rows, "Pages"), "0");
+        assert_eq!(property(&rows, "Free list head"), "NULL");
+        assert_eq!(property(&rows, "Full list head"), "NULL");
+        assert_eq!(property(&rows, "Compression"), "NONE");
+        assert_eq!(property(&rows, "TTL"), "NULL");
+        let max: usize = property(&rows, "Max records per page").parse().unwrap();
+
+        // Fill the first page and start another one
+        let values: Vec<_> = (0..=max).map(|i| format!("({i}, 'x')")).collect();
+        query(
+            &mut system,
+            &format!("INSERT INTO t VALUES {};", values.join(", ")),
+        );
+        let rows = query(&mut system, "DESC EXTENDED t;");
+        assert_eq!(property(&rows, "Pages"), "2");
+        assert_eq!(property(&rows, "Full list head"), "0");
+        assert_eq!(property(&rows, "Free list head"), "1");
+        assert_eq!(property(&rows, "Deleted records"), "0");
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_index_is_null() {
+        setup::init_logging();
+
+        let base = PathBuf::from("test_index_is_null");
+        fs::create_dir(&base).unwrap();
+
+        // NULLs among enough keys to span several leaves
+        let values: Vec<_> = (0..2000)
+            .map(|i| match i % 5 {
+                0 => format!("({i}, NULL, {})", i % 7),
+                _ => format!("({i}, {}, {})", i % 50, i % 7),
+            })
+            .collect();
+
+        let mut system = System::new(base.clone());
+        query(
+            &mut system,
+            &format!(
+                "CREATE DATABASE db; USE db;
+                CREATE TABLE t (id INT, a INT, b INT);
+                ALTER TABLE t ADD INDEX i (a, b);
+                INSERT INTO t VALUES {};",
+                values.join(", "),
+            ),
+        );
+
+        let rows = query(&mut system, "SELECT COUNT(*) FROM t WHERE a IS NULL;");
+        assert_eq!(rows, [["400"]]);
+        assert_eq!(system.get_plan(), ["index t.i"]);
+        let rows = query(
+            &mut system,
+            "SELECT COUNT(*) FROM t WHERE a IS NULL AND b = 3;",
+        );
+        assert_eq!(rows, [["57"]]);
+        assert_eq!(system.get_plan(), ["index t.i"]);
+
+        // NULL keys are ordered first, before the smallest values
+        let rows = query(&mut system, "SELECT COUNT(*) FROM t WHERE a < 1;");
+        assert_eq!(rows, [["0"]]);
+        let rows = query(&mut system, "SELECT COUNT(*) FROM t WHERE a IS NOT NULL;");
+        assert_eq!(rows, [["1600"]]);
+
+        query(&mut system, "UPDATE t SET a = 0 WHERE a IS NULL AND b = 3;");
+        let rows = query(&mut system, "SELECT COUNT(*) FROM t WHERE a IS NULL;");
+        assert_eq!(rows, [["343"]]);
+
+        system.close_all().unwrap();
+        fs::remove_dir_all(base).unwrap();
+    }
 }
@@ -8,32 +8,173 @@
 //! the previous and next pages in the linked list,
 //! 4 bytes each. 0 stands for nil, and the rest numbers
 //! are incremented by 1 to avoid confusion with nil.
+//!
+//! The links are followed by the free slot bitmap, the deleted slot
+//! bitmap of the same size, and then the slots. Each slot holds a
+//! record followed by the time it is deleted softly, if marked so in
+//! the deleted slot bitmap. Records deleted softly stay in their slots
+//! until purged, and are skipped when scanning the table.
+//!
+//! Text fields are stored in overflow pages, which are not
+//! in either list and have an empty free slot bitmap, so that
+//! they are skipped when scanning the table. The contents
+//! follow the bitmap, and the next link points to the page
+//! holding the rest of the text. Unused overflow pages are
+//! linked together for reuse.
+
+use std::collections::{BTreeSet, HashSet};
 
 use bit_set::BitSet;
+use chrono::{DateTime, NaiveDateTime};
 use uuid::Uuid;
 
-use crate::config::LINK_SIZE;
+use crate::config::{DELETED_TIME_SIZE, LINK_SIZE};
 use crate::error::Result;
 use crate::file::PageCache;
 use crate::index::IndexSchema;
-use crate::record::Record;
-use crate::schema::{Constraint, Selectors, SetPair, TableSchema, WhereClause};
+use crate::record::{Record, RecordSchema};
+use crate::schema::{
+    ColumnSelector, Constraint, Expression, Operator, Selectors, SetPair, TableSchema, TableStats,
+    Ttl, Type, Value, WhereClause,
+};
 
 /// Select result containing page and slot id.
 pub type SelectResult = (Record, usize, usize);
 
+/// A where clause evaluated directly on the bytes of a record, so that
+/// records not matching are skipped without being deserialized.
+///
+/// Only null tests and comparisons of numeric columns with constants of
+/// the same type are supported. Records passing the filters are still
+/// checked against the complete where clauses.
+pub enum RawFilter {
+    /// Whether the column is null.
+    IsNull(usize, bool),
+    /// Comparison of an int column at an offset in the record.
+    Int(usize, usize, Operator, i32),
+    /// Comparison of a bigint column at an offset in the record.
+    Bigint(usize, usize, Operator, i64),
+    /// Comparison of a float column at an offset in the record.
+    Float(usize, usize, Operator, f64),
+}
+
+impl RawFilter {
+    /// Build filters out of the supported where clauses.
+    pub fn from_clauses(where_clauses: &[WhereClause], schema: &TableSchema) -> Vec<Self> {
+        let mut filters = vec![];
+        for where_clause in where_clauses {
+            match where_clause {
+                WhereClause::IsNull(ColumnSelector(_, column), is_null)
+                    if schema.has_column(column) =>
+                {
+                    let index = schema.get_column_index(column);
+                    filters.push(RawFilter::IsNull(index, *is_null));
+                }
+                WhereClause::OperatorExpression(
+                    ColumnSelector(_, column),
+                    op,
+                    Expression::Value(value),
+                ) if schema.has_column(column) => {
+                    let index = schema.get_column_index(column);
+                    let offset = schema.get_null_bitmap_size() + schema.get_offset(column);
+                    let op = op.clone();
+                    let filter = match (&schema.get_column(column).typ, value) {
+                        (Type::Int, Value::Int(v)) => RawFilter::Int(index, offset, op, *v),
+                        (Type::Bigint, Value::Int(v)) => {
+                            RawFilter::Bigint(index, offset, op, *v as i64)
+                        }
+                        (Type::Bigint, Value::Bigint(v)) => {
+                            RawFilter::Bigint(index, offset, op, *v)
+                        }
+                        (Type::Float, Value::Float(v)) => RawFilter::Float(index, offset, op, *v),
+                        _ => continue,
+                    };
+                    filters.push(filter);
+                }
+                _ => (),
+            }
+        }
+        filters
+    }
+
+    /// Check whether a record starting at an offset in the buffer may match.
+    pub fn matches(&self, buf: &[u8], offset: usize) -> bool {
+        fn compare<T: PartialOrd>(op: &Operator, a: T, b: T) -> bool {
+            match op {
+                Operator::Eq => a == b,
+                Operator::Ne => a != b,
+                Operator::Lt => a < b,
+                Operator::Le => a <= b,
+                Operator::Gt => a > b,
+                Operator::Ge => a >= b,
+            }
+        }
+
+        let column = match self {
+            RawFilter::IsNull(column, _)
+            | RawFilter::Int(column, ..)
+            | RawFilter::Bigint(column, ..)
+            | RawFilter::Float(column, ..) => *column,
+        };
+        // Bits in the null bitmap are stored from the most significant one
+        let null = buf[offset + column / 8] & (0x80 >> (column % 8)) != 0;
+
+        match self {
+            RawFilter::IsNull(_, is_null) => null == *is_null,
+            // Comparing with null is never true
+            _ if null => false,
+            RawFilter::Int(_, field, op, value) => {
+                let field = &buf[offset + field..offset + field + 4];
+                compare(op, i32::from_le_bytes(field.try_into().unwrap()), *value)
+            }
+            RawFilter::Bigint(_, field, op, value) => {
+                let field = &buf[offset + field..offset + field + 8];
+                compare(op, i64::from_le_bytes(field.try_into().unwrap()), *value)
+            }
+            RawFilter::Float(_, field, op, value) => {
+                let field = &buf[offset + field..offset + field + 8];
+                compare(op, f64::from_le_bytes(field.try_into().unwrap()), *value)
+            }
+        }
+    }
+}
+
 /// A table.
 pub struct Table {
     /// The table's fd.
     fd: Uuid,
     /// The table's schema.
     schema: TableSchema,
+    /// Pages whose records changed since tracking started, as the side log
+    /// of an index built online.
+    changes: Option<BTreeSet<usize>>,
 }
 
 impl Table {
     /// Create a new table.
     pub fn new(fd: Uuid, schema: TableSchema) -> Self {
-        Self { fd, schema }
+        Self {
+            fd,
+            schema,
+            changes: None,
+        }
+    }
+
+    /// Start tracking pages whose records change.
+    pub fn track_changes(&mut self) {
+        self.changes = Some(BTreeSet::new());
+    }
+
+    /// Stop tracking changes, and take pages whose records changed.
+    pub fn take_changes(&mut self) -> BTreeSet<usize> {
+        self.changes.take().unwrap_or_default()
+    }
+
+    /// Record a change of records on a page, if tracked.
+    fn log_change(&mut self, page_id: usize) {
+        if let Some(changes) = &mut self.changes {
+            changes.insert(page_id);
+        }
     }
 
     /// Get the file descriptor of the table.
@@ -46,8 +187,18 @@ impl Table {
         &self.schema
     }
 
+    /// Save the schema of the table as a new version.
+    pub fn save_schema(&mut self) -> Result<()> {
+        self.schema.save()
+    }
+
+    /// Drop the table without saving its schema.
+    pub fn discard(&mut self) {
+        self.schema.discard();
+    }
+
     /// Allocate a new page.
-    pub fn new_page<'a>(&'a mut self, fs: &'a mut PageCache) -> Result<TablePageMut> {
+    pub fn new_page<'a>(&'a mut self, fs: &'a mut PageCache) -> Result<TablePageMut<'a>> {
         let page_id = self.schema.new_page();
         log::debug!("Allocating new page {page_id}");
 
@@ -156,6 +307,187 @@ impl Table {
         Ok(())
     }
 
+    /// Get the offset of text contents in an overflow page.
+    fn get_overflow_offset(&self) -> usize {
+        2 * LINK_SIZE + self.schema.get_free_bitmap_size()
+    }
+
+    /// Allocate an overflow page, reusing unused ones first.
+    fn new_overflow_page(&mut self, fs: &mut PageCache) -> Result<usize> {
+        if let Some(page_id) = self.schema.get_overflow() {
+            let page_buf = fs.get(self.fd, page_id)?;
+            let next = TablePage::new(self, page_buf).get_next();
+            self.schema.set_overflow(next);
+            Ok(page_id)
+        } else {
+            Ok(self.schema.new_page())
+        }
+    }
+
+    /// Return trailing empty pages and unused overflow pages to the file system.
+    /// Return the number of pages released.
+    pub fn compact(&mut self, fs: &mut PageCache) -> Result<usize> {
+        // Deletes through an index used to leave empty pages in the full list
+        let mut empty = HashSet::new();
+        for head in [self.schema.get_free(), self.schema.get_full()] {
+            let mut page_id = head;
+            while let Some(id) = page_id {
+                let page = TablePage::new(self, fs.get(self.fd, id)?);
+                if page.get_occupied().is_empty() {
+                    empty.insert(id);
+                }
+                page_id = page.get_next();
+            }
+        }
+
+        let mut overflow = BTreeSet::new();
+        let mut page_id = self.schema.get_overflow();
+        while let Some(id) = page_id {
+            overflow.insert(id);
+            page_id = TablePage::new(self, fs.get(self.fd, id)?).get_next();
+        }
+
+        let pages = self.schema.get_pages();
+        let overflow_pages = overflow.len();
+        while let Some(last) = self.schema.get_pages().checked_sub(1) {
+            if empty.remove(&last) {
+                let (prev, next) = self.take_page(fs, last)?;
+                if prev.is_none() && self.schema.get_free() == Some(last) {
+                    self.schema.set_free(next);
+                } else if prev.is_none() {
+                    self.schema.set_full(next);
+                }
+            } else if !overflow.remove(&last) {
+                break;
+            }
+            self.schema.pop_page();
+        }
+        let released = pages - self.schema.get_pages();
+        if released == 0 {
+            return Ok(0);
+        }
+        log::info!("Releasing {released} unused pages of the table");
+
+        // Relink the remaining unused overflow pages, lowest first
+        if overflow.len() < overflow_pages {
+            self.schema.set_overflow(None);
+            for &id in overflow.iter().rev() {
+                let page_buf = fs.get_mut(self.fd, id)?;
+                TablePageMut::new(self, page_buf).set_next(self.schema.get_overflow());
+                self.schema.set_overflow(Some(id));
+            }
+        }
+        fs.truncate(self.fd, self.schema.get_pages())?;
+
+        Ok(released)
+    }
+
+    /// Read text fields of a record from overflow pages.
+    fn read_overflow(&self, fs: &mut PageCache, record: &mut Record) -> Result<()> {
+        let offset = self.get_overflow_offset();
+        for &(column, page_id, len) in &record.overflow {
+            let mut bytes = Vec::with_capacity(len);
+            let mut page_id = Some(page_id);
+            while bytes.len() < len {
+                let page_buf = fs.get(self.fd, page_id.expect("Broken overflow pages"))?;
+                let size = (len - bytes.len()).min(page_buf.len() - offset);
+                bytes.extend_from_slice(&page_buf[offset..offset + size]);
+                page_id = TablePage::new(self, page_buf).get_next();
+            }
+            record.fields[column] = Value::Varchar(String::from_utf8_lossy(&bytes).into_owned());
+        }
+        Ok(())
+    }
+
+    /// Get the size of overflow pages taken by text fields of a record.
+    pub fn get_overflow_size(&self, record: &Record) -> usize {
+        let page_size = self.schema.get_page_size();
+        let capacity = page_size - self.get_overflow_offset();
+        self.schema
+            .get_columns()
+            .iter()
+            .zip(&record.fields)
+            .map(|(column, value)| match value {
+                Value::Varchar(text) if column.typ == Type::Text => {
+                    text.len().div_ceil(capacity) * page_size
+                }
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Write text fields of a record into newly allocated overflow pages.
+    fn write_overflow(&mut self, fs: &mut PageCache, record: &mut Record) -> Result<()> {
+        let offset = self.get_overflow_offset();
+        let columns: Vec<usize> = self
+            .schema
+            .get_columns()
+            .iter()
+            .enumerate()
+            .filter(|(_, column)| column.typ == Type::Text)
+            .map(|(i, _)| i)
+            .collect();
+
+        record.overflow.clear();
+        for i in columns {
+            let Value::Varchar(text) = &record.fields[i] else {
+                continue;
+            };
+            // Written backwards, so that each page links to the next one
+            let mut next = None;
+            for chunk in text.as_bytes().chunks(fs.page_size() - offset).rev() {
+                let page_id = self.new_overflow_page(fs)?;
+                let page_buf = fs.get_mut(self.fd, page_id)?;
+                page_buf[offset..offset + chunk.len()].copy_from_slice(chunk);
+                TablePageMut::new(self, page_buf).set_next(next);
+                next = Some(page_id);
+            }
+            record.overflow.push((i, next.unwrap_or(0), text.len()));
+        }
+        Ok(())
+    }
+
+    /// Release overflow pages of a record for reuse.
+    fn free_overflow(&mut self, fs: &mut PageCache, record: &Record) -> Result<()> {
+        for &(_, page_id, len) in &record.overflow {
+            let mut page_id = (len > 0).then_some(page_id);
+            while let Some(id) = page_id {
+                let page_buf = fs.get_mut(self.fd, id)?;
+                let mut page = TablePageMut::new(self, page_buf);
+                page_id = page.get_next();
+                page.set_next(self.schema.get_overflow());
+                self.schema.set_overflow(Some(id));
+            }
+        }
+        Ok(())
+    }
+
+    /// Read records in a page passing the raw filters, with text fields filled in.
+    /// Only records deleted softly are read if `deleted` is set, or only live ones otherwise.
+    fn read_page(
+        &self,
+        fs: &mut PageCache,
+        page_id: usize,
+        filters: &[RawFilter],
+        deleted: bool,
+    ) -> Result<Vec<(Record, usize, usize)>> {
+        let page_buf = fs.scan(self.fd, page_id, self.schema.get_pages())?;
+        let page = TablePage::new(self, page_buf);
+        let mut records: Vec<_> = PageIterator::new(&page, filters, deleted).collect();
+        for (record, _, _) in &mut records {
+            self.read_overflow(fs, record)?;
+        }
+        Ok(records)
+    }
+
+    /// Read a record in a page, with text fields filled in.
+    fn read_record(&self, fs: &mut PageCache, page_id: usize, slot: usize) -> Result<Record> {
+        let page_buf = fs.get(self.fd, page_id)?;
+        let mut record = TablePage::new(self, page_buf).get_record(slot);
+        self.read_overflow(fs, &mut record)?;
+        Ok(record)
+    }
+
     /// Select from table using selector.
     pub fn select(
         &self,
@@ -164,12 +496,10 @@ impl Table {
         where_clauses: &[WhereClause],
     ) -> Result<Vec<SelectResult>> {
         let mut records = Vec::new();
+        let filters = RawFilter::from_clauses(where_clauses, &self.schema);
 
         for page_id in 0..self.schema.get_pages() {
-            let page_buf = fs.get(self.fd, page_id)?;
-            let page = TablePage::new(self, page_buf);
-
-            for (record, slot, _) in &page {
+            for (record, slot, _) in self.read_page(fs, page_id, &filters, false)? {
                 if where_clauses
                     .iter()
                     .all(|clause| clause.matches(&record, &self.schema))
@@ -183,6 +513,41 @@ impl Table {
         Ok(records)
     }
 
+    /// Select records deleted softly from table using selector.
+    pub fn select_deleted(
+        &self,
+        fs: &mut PageCache,
+        selector: &Selectors,
+        where_clauses: &[WhereClause],
+    ) -> Result<Vec<SelectResult>> {
+        let mut records = Vec::new();
+        let filters = RawFilter::from_clauses(where_clauses, &self.schema);
+
+        for page_id in 0..self.schema.get_pages() {
+            for (record, slot, _) in self.read_page(fs, page_id, &filters, true)? {
+                if where_clauses
+                    .iter()
+                    .all(|clause| clause.matches(&record, &self.schema))
+                {
+                    records.push((record.select(selector, &self.schema), page_id, slot));
+                }
+            }
+        }
+
+        Ok(records)
+    }
+
+    /// Get the time a record is deleted softly, given page and slot.
+    pub fn get_deleted_time(
+        &self,
+        fs: &mut PageCache,
+        page_id: usize,
+        slot: usize,
+    ) -> Result<NaiveDateTime> {
+        let page_buf = fs.get(self.fd, page_id)?;
+        Ok(TablePage::new(self, page_buf).get_deleted_time(slot))
+    }
+
     /// Read a record from the table, given page and slot.
     pub fn select_page_slot(
         &self,
@@ -194,10 +559,7 @@ impl Table {
     ) -> Result<Option<Record>> {
         log::debug!("Fetching indexed record {page_id}, {slot}");
 
-        let page_buf = fs.get(self.fd, page_id)?;
-        let page = TablePage::new(self, page_buf);
-
-        let record = page.get_record(slot);
+        let record = self.read_record(fs, page_id, slot)?;
 
         if where_clauses
             .iter()
@@ -217,12 +579,10 @@ impl Table {
         selector: &Selectors,
         where_clauses: &[WhereClause],
     ) -> Result<Vec<SelectResult>> {
-        let page_buf = fs.get(self.fd, page_id)?;
-        let page = TablePage::new(self, page_buf);
-
         let mut ret = Vec::new();
+        let filters = RawFilter::from_clauses(where_clauses, &self.schema);
 
-        for (record, slot, _) in &page {
+        for (record, slot, _) in self.read_page(fs, page_id, &filters, false)? {
             if where_clauses
                 .iter()
                 .all(|clause| clause.matches(&record, &self.schema))
@@ -242,10 +602,12 @@ impl Table {
     pub fn insert<'a>(
         &'a mut self,
         fs: &'a mut PageCache,
-        record: Record,
+        mut record: Record,
     ) -> Result<(usize, usize)> {
         log::debug!("Inserting {record:?}");
 
+        self.write_overflow(fs, &mut record)?;
+
         if self.schema.get_free().is_none() {
             log::debug!("No free page, allocating a new page");
             self.new_page(fs)?;
@@ -260,6 +622,7 @@ impl Table {
             log::debug!("A page is filled");
             self.full_page(fs, page_id)?;
         }
+        self.log_change(page_id);
 
         Ok((page_id, slot))
     }
@@ -279,23 +642,29 @@ impl Table {
         log::debug!("Updating {set_pairs:?} where {where_clauses:?}");
 
         let mut updated = vec![];
+        let filters = RawFilter::from_clauses(where_clauses, &self.schema);
         for page_id in 0..self.schema.get_pages() {
-            let page_buf = fs.get_mut(self.fd, page_id)?;
-            let mut page = TablePageMut::new(self, page_buf);
-
             let mut to_update = vec![];
 
-            for (mut record, slot, offset) in &page {
+            for (mut record, slot, offset) in self.read_page(fs, page_id, &filters, false)? {
                 let record_before = record.clone();
                 if where_clauses
                     .iter()
                     .all(|clause| clause.matches(&record, &self.schema))
                     && record.update(set_pairs, &self.schema)
                 {
+                    self.free_overflow(fs, &record_before)?;
+                    self.write_overflow(fs, &mut record)?;
                     updated.push((record_before, record.clone(), page_id, slot));
                     to_update.push((record, offset));
                 }
             }
+            if !to_update.is_empty() {
+                self.log_change(page_id);
+            }
+
+            let page_buf = fs.get_mut(self.fd, page_id)?;
+            let mut page = TablePageMut::new(self, page_buf);
 
             for (record, offset) in to_update {
                 page.update(record, offset, &self.schema);
@@ -316,10 +685,7 @@ impl Table {
     ) -> Result<Option<(Record, Record)>> {
         log::info!("Updating indexed record {page_id}, {slot}");
 
-        let page_buf = fs.get_mut(self.fd, page_id)?;
-        let mut page = TablePageMut::new(self, page_buf);
-
-        let mut record = page.get_record(slot);
+        let mut record = self.read_record(fs, page_id, slot)?;
         let record_old = record.clone();
 
         if where_clauses
@@ -327,10 +693,15 @@ impl Table {
             .all(|clause| clause.matches(&record, &self.schema))
             && record.update(set_pairs, &self.schema)
         {
-            let offset = 2 * LINK_SIZE
-                + self.schema.get_free_bitmap_size()
-                + slot * self.schema.get_record_size();
+            self.free_overflow(fs, &record_old)?;
+            self.write_overflow(fs, &mut record)?;
+
+            let page_buf = fs.get_mut(self.fd, page_id)?;
+            let mut page = TablePageMut::new(self, page_buf);
+
+            let offset = page.get_offset(slot);
             page.update(record.clone(), offset, &self.schema);
+            self.log_change(page_id);
             Ok(Some((record_old, record)))
         } else {
             Ok(None)
@@ -339,6 +710,9 @@ impl Table {
 
     /// Delete records from the table.
     ///
+    /// With a time given, records are deleted softly at that time,
+    /// staying in their slots until purged.
+    ///
     /// # Returns
     ///
     /// Returns the deleted record and their page and slot ids.
@@ -346,30 +720,41 @@ impl Table {
         &'a mut self,
         fs: &'a mut PageCache,
         where_clauses: &[WhereClause],
+        time: Option<NaiveDateTime>,
     ) -> Result<Vec<(Record, usize, usize)>> {
         log::debug!("Deleting where {where_clauses:?}");
 
         let mut deleted = vec![];
+        let filters = RawFilter::from_clauses(where_clauses, &self.schema);
 
         let mut free_page_id = self.schema.get_free();
         while let Some(page_id) = free_page_id {
-            let page_buf = fs.get_mut(self.fd, page_id)?;
-            let mut page = TablePageMut::new(self, page_buf);
-
             let mut to_delete = vec![];
 
-            for (record, slot, _) in &page {
+            for (record, slot, _) in self.read_page(fs, page_id, &filters, false)? {
                 if where_clauses
                     .iter()
                     .all(|clause| clause.matches(&record, &self.schema))
                 {
+                    if time.is_none() {
+                        self.free_overflow(fs, &record)?;
+                    }
                     deleted.push((record, page_id, slot));
                     to_delete.push(slot);
                 }
             }
+            if !to_delete.is_empty() {
+                self.log_change(page_id);
+            }
+
+            let page_buf = fs.get_mut(self.fd, page_id)?;
+            let mut page = TablePageMut::new(self, page_buf);
 
             for slot in to_delete {
-                page.free(slot);
+                match time {
+                    Some(time) => page.delete(slot, time),
+                    None => page.free(slot),
+                }
             }
 
             free_page_id = page.get_next();
@@ -378,28 +763,37 @@ impl Table {
         let mut full_page_id = self.schema.get_full();
         let mut to_free = vec![];
         while let Some(page_id) = full_page_id {
-            let page_buf = fs.get_mut(self.fd, page_id)?;
-            let mut page = TablePageMut::new(self, page_buf);
-
             let mut to_delete = vec![];
 
-            for (record, slot, _) in &page {
+            for (record, slot, _) in self.read_page(fs, page_id, &filters, false)? {
                 if where_clauses
                     .iter()
                     .all(|clause| clause.matches(&record, &self.schema))
                 {
-                    deleted.push((record, page_id, slot));
                     // If the page is full, it will be marked
                     // as having free space due to this deletion.
-                    if to_delete.is_empty() {
-                        to_free.push(page_id);
+                    if time.is_none() {
+                        self.free_overflow(fs, &record)?;
+                        if to_delete.is_empty() {
+                            to_free.push(page_id);
+                        }
                     }
+                    deleted.push((record, page_id, slot));
                     to_delete.push(slot);
                 }
             }
+            if !to_delete.is_empty() {
+                self.log_change(page_id);
+            }
+
+            let page_buf = fs.get_mut(self.fd, page_id)?;
+            let mut page = TablePageMut::new(self, page_buf);
 
             for slot in to_delete {
-                page.free(slot);
+                match time {
+                    Some(time) => page.delete(slot, time),
+                    None => page.free(slot),
+                }
             }
 
             full_page_id = page.get_next();
@@ -409,39 +803,111 @@ impl Table {
             self.free_page(fs, page_id)?;
         }
 
+        if time.is_some() {
+            self.schema
+                .set_deleted(self.schema.get_deleted() + deleted.len());
+        }
+
         Ok(deleted)
     }
 
     /// Delete a record in the table, given page and slot.
+    ///
+    /// With a time given, the record is deleted softly at that time.
     pub fn delete_page_slot(
         &mut self,
         fs: &mut PageCache,
         page_id: usize,
         slot: usize,
         where_clauses: &[WhereClause],
+        time: Option<NaiveDateTime>,
     ) -> Result<Option<Record>> {
         log::info!("Deleting indexed record {page_id}, {slot}");
 
-        let page_buf = fs.get_mut(self.fd, page_id)?;
-        let mut page = TablePageMut::new(self, page_buf);
-
-        let record = page.get_record(slot);
+        let record = self.read_record(fs, page_id, slot)?;
 
         if where_clauses
             .iter()
             .all(|clause| clause.matches(&record, &self.schema))
         {
+            if let Some(time) = time {
+                let page_buf = fs.get_mut(self.fd, page_id)?;
+                TablePageMut::new(self, page_buf).delete(slot, time);
+                self.schema.set_deleted(self.schema.get_deleted() + 1);
+                self.log_change(page_id);
+                return Ok(Some(record));
+            }
+
+            self.free_overflow(fs, &record)?;
+
+            let page_buf = fs.get_mut(self.fd, page_id)?;
+            let mut page = TablePageMut::new(self, page_buf);
+
+            let full = page.is_full();
             page.free(slot);
             // Mark the page as free due to this deletion
-            if page.is_full() {
+            if full {
                 self.free_page(fs, page_id)?;
             }
+            self.log_change(page_id);
             Ok(Some(record))
         } else {
             Ok(None)
         }
     }
 
+    /// Restore a record deleted softly, given page and slot.
+    pub fn undelete_page_slot(
+        &mut self,
+        fs: &mut PageCache,
+        page_id: usize,
+        slot: usize,
+    ) -> Result<()> {
+        log::info!("Restoring deleted record {page_id}, {slot}");
+
+        let page_buf = fs.get_mut(self.fd, page_id)?;
+        TablePageMut::new(self, page_buf).undelete(slot);
+        self.schema.set_deleted(self.schema.get_deleted() - 1);
+        self.log_change(page_id);
+        Ok(())
+    }
+
+    /// Free slots of all records deleted softly.
+    ///
+    /// # Returns
+    ///
+    /// Returns the count of records purged.
+    pub fn purge_deleted(&mut self, fs: &mut PageCache) -> Result<usize> {
+        log::debug!("Purging deleted records");
+
+        let mut purged = 0;
+        for page_id in 0..self.schema.get_pages() {
+            let records = self.read_page(fs, page_id, &[], true)?;
+            if records.is_empty() {
+                continue;
+            }
+            for (record, _, _) in &records {
+                self.free_overflow(fs, record)?;
+            }
+
+            let page_buf = fs.get_mut(self.fd, page_id)?;
+            let mut page = TablePageMut::new(self, page_buf);
+
+            let full = page.is_full();
+            for (_, slot, _) in &records {
+                page.free(*slot);
+            }
+            if full {
+                self.free_page(fs, page_id)?;
+            }
+            self.log_change(page_id);
+            purged += records.len();
+        }
+
+        self.schema.set_deleted(0);
+        Ok(purged)
+    }
+
     /// Save an index schema into the table.
     pub fn add_index(&mut self, schema: IndexSchema) {
         self.schema.add_index(schema);
@@ -474,6 +940,22 @@ impl Table {
         self.schema.remove_primary_key();
     }
 
+    /// Set the time to live of records in the table.
+    pub fn set_ttl(&mut self, ttl: Option<Ttl>) {
+        self.schema.set_ttl(ttl);
+    }
+
+    /// Replace the data file with a rewritten one, compressed or not.
+    pub fn set_compressed(&mut self, fd: Uuid, compressed: bool) {
+        self.fd = fd;
+        self.schema.set_compressed(compressed);
+    }
+
+    /// Set statistics of the table.
+    pub fn set_stats(&mut self, stats: Option<TableStats>) {
+        self.schema.set_stats(stats);
+    }
+
     /// Remove a referred constraint.
     pub fn remove_referred_constraint(&mut self, table: &str, name: &str) {
         self.schema.remove_referred_constraint(table, name);
@@ -494,8 +976,8 @@ pub trait LinkedPage<'a> {
     /// Get the buffer.
     fn get_buf(&self) -> &[u8];
 
-    /// Get the size of a record.
-    fn get_record_size(&self) -> usize;
+    /// Get the size of a slot.
+    fn get_slot_size(&self) -> usize;
 
     /// Get the maximum number of records in the page.
     fn get_max_records(&self) -> usize;
@@ -506,18 +988,39 @@ pub trait LinkedPage<'a> {
     /// Get the occupied slots.
     fn get_occupied(&self) -> &BitSet;
 
-    /// Get an iterator over records in the page.
+    /// Get the slots of records deleted softly.
+    fn get_deleted(&self) -> &BitSet;
+
+    /// Get an iterator over live records in the page.
     fn iter(&'a self) -> PageIterator<'a, Self>
     where
         Self: Sized,
     {
-        PageIterator::new(self)
+        PageIterator::new(self, &[], false)
+    }
+
+    /// Get the offset of a record using a slot id.
+    fn get_offset(&self, slot: usize) -> usize {
+        2 * LINK_SIZE + 2 * self.get_free_bitmap_size() + slot * self.get_slot_size()
     }
 
     /// Get a record from the page using a slot id.
     fn get_record(&self, slot: usize) -> Record {
-        let offset = 2 * LINK_SIZE + self.get_free_bitmap_size() + slot * self.get_record_size();
-        Record::from(self.get_buf(), offset, &self.get_table().schema)
+        Record::from(
+            self.get_buf(),
+            self.get_offset(slot),
+            &self.get_table().schema,
+        )
+    }
+
+    /// Get the time a record is deleted softly using a slot id.
+    fn get_deleted_time(&self, slot: usize) -> NaiveDateTime {
+        let offset = self.get_offset(slot) + self.get_table().schema.get_record_size();
+        let time = &self.get_buf()[offset..offset + DELETED_TIME_SIZE];
+        let time = i64::from_le_bytes(time.try_into().unwrap());
+        DateTime::from_timestamp_micros(time)
+            .expect("Broken deletion time")
+            .naive_utc()
     }
 
     /// Get the previous page number.
@@ -547,6 +1050,11 @@ pub trait LinkedPage<'a> {
         !self.get_occupied().contains(i)
     }
 
+    /// Check if the record in the i-th slot is deleted softly.
+    fn is_deleted(&self, i: usize) -> bool {
+        self.get_deleted().contains(i)
+    }
+
     /// Check if this page is full.
     fn is_full(&self) -> bool {
         self.get_occupied().len() == self.get_max_records()
@@ -559,30 +1067,36 @@ pub struct TablePage<'a> {
     table: &'a Table,
     /// The buffer of the page.
     buf: &'a [u8],
-    /// The size of a record.
-    record_size: usize,
+    /// The size of a slot.
+    slot_size: usize,
     /// Maximum number of records in the page.
     max_records: usize,
     /// The size of the free slot bitmap.
     free_bitmap_size: usize,
     /// Free slot bitmap.
     occupied: BitSet,
+    /// Deleted slot bitmap.
+    deleted: BitSet,
 }
 
 impl<'a> TablePage<'a> {
     /// Create a new page object representing a page in a buffer.
     pub fn new(table: &'a Table, buf: &'a [u8]) -> Self {
-        let record_size = table.schema.get_record_size();
+        let slot_size = table.schema.get_slot_size();
         let max_records = table.schema.get_max_records();
         let free_bitmap_size = table.schema.get_free_bitmap_size();
         let occupied = BitSet::from_bytes(&buf[2 * LINK_SIZE..2 * LINK_SIZE + free_bitmap_size]);
+        let deleted = BitSet::from_bytes(
+            &buf[2 * LINK_SIZE + free_bitmap_size..2 * LINK_SIZE + 2 * free_bitmap_size],
+        );
         Self {
             table,
             buf,
-            record_size,
+            slot_size,
             max_records,
             free_bitmap_size,
             occupied,
+            deleted,
         }
     }
 }
@@ -596,8 +1110,8 @@ impl<'a> LinkedPage<'a> for TablePage<'a> {
         self.buf
     }
 
-    fn get_record_size(&self) -> usize {
-        self.record_size
+    fn get_slot_size(&self) -> usize {
+        self.slot_size
     }
 
     fn get_max_records(&self) -> usize {
@@ -611,6 +1125,10 @@ impl<'a> LinkedPage<'a> for TablePage<'a> {
     fn get_occupied(&self) -> &BitSet {
         &self.occupied
     }
+
+    fn get_deleted(&self) -> &BitSet {
+        &self.deleted
+    }
 }
 
 impl<'a> IntoIterator for &'a TablePage<'a> {
@@ -628,8 +1146,8 @@ pub struct TablePageMut<'a> {
     table: &'a Table,
     /// The buffer of the page.
     buf: &'a mut [u8],
-    /// The size of a record.
-    record_size: usize,
+    /// The size of a slot.
+    slot_size: usize,
     /// Maximum number of records in the page.
     max_records: usize,
     /// The size of the free slot bitmap.
@@ -638,24 +1156,30 @@ pub struct TablePageMut<'a> {
     free: Option<usize>,
     /// Free slot bitmap.
     occupied: BitSet,
+    /// Deleted slot bitmap.
+    deleted: BitSet,
 }
 
 impl<'a> TablePageMut<'a> {
     /// Create a new page object representing a page in a buffer.
     pub fn new(table: &'a Table, buf: &'a mut [u8]) -> Self {
-        let record_size = table.schema.get_record_size();
+        let slot_size = table.schema.get_slot_size();
         let max_records = table.schema.get_max_records();
         let free_bitmap_size = table.schema.get_free_bitmap_size();
         let occupied = BitSet::from_bytes(&buf[2 * LINK_SIZE..2 * LINK_SIZE + free_bitmap_size]);
+        let deleted = BitSet::from_bytes(
+            &buf[2 * LINK_SIZE + free_bitmap_size..2 * LINK_SIZE + 2 * free_bitmap_size],
+        );
         let free = (0..max_records).find(|i| !occupied.contains(*i)).or(None);
         Self {
             table,
             buf,
-            record_size,
+            slot_size,
             max_records,
             free_bitmap_size,
             free,
             occupied,
+            deleted,
         }
     }
 
@@ -682,6 +1206,22 @@ impl<'a> TablePageMut<'a> {
         if self.free.is_none() || self.free.unwrap() > i {
             self.free = Some(i);
         }
+        self.undelete(i);
+    }
+
+    /// Mark the record in the i-th slot as deleted softly at a time.
+    pub fn delete(&mut self, i: usize, time: NaiveDateTime) {
+        self.deleted.insert(i);
+        self.buf[2 * LINK_SIZE + self.free_bitmap_size + i / 8] |= 1 << (7 - i % 8);
+        let offset = self.get_offset(i) + self.table.schema.get_record_size();
+        let time = time.and_utc().timestamp_micros().to_le_bytes();
+        self.buf[offset..offset + DELETED_TIME_SIZE].copy_from_slice(&time);
+    }
+
+    /// Clear the deleted mark of the record in the i-th slot.
+    pub fn undelete(&mut self, i: usize) {
+        self.deleted.remove(i);
+        self.buf[2 * LINK_SIZE + self.free_bitmap_size + i / 8] &= !(1 << (7 - i % 8));
     }
 
     /// Set the i-th slot to be occupied.
@@ -704,8 +1244,7 @@ impl<'a> TablePageMut<'a> {
         let free = self.free.expect("Insert called on a full page");
         self.occupy(free);
 
-        let offset =
-            2 * LINK_SIZE + self.free_bitmap_size + free * self.table.schema.get_record_size();
+        let offset = self.get_offset(free);
         record.save_into(self.buf, offset, schema);
 
         (self.free.is_some(), free)
@@ -726,8 +1265,8 @@ impl<'a> LinkedPage<'a> for TablePageMut<'a> {
         self.buf
     }
 
-    fn get_record_size(&self) -> usize {
-        self.record_size
+    fn get_slot_size(&self) -> usize {
+        self.slot_size
     }
 
     fn get_max_records(&self) -> usize {
@@ -741,6 +1280,10 @@ impl<'a> LinkedPage<'a> for TablePageMut<'a> {
     fn get_occupied(&self) -> &BitSet {
         &self.occupied
     }
+
+    fn get_deleted(&self) -> &BitSet {
+        &self.deleted
+    }
 }
 
 impl<'a> IntoIterator for &'a TablePageMut<'a> {
@@ -765,22 +1308,29 @@ pub struct PageIterator<'a, T: LinkedPage<'a>> {
     page: &'a T,
     slot: usize,
     offset: usize,
+    /// Records not passing the filters are skipped.
+    filters: &'a [RawFilter],
+    /// Whether records deleted softly are iterated over instead of live ones.
+    deleted: bool,
 }
 
 impl<'a, T: LinkedPage<'a>> PageIterator<'a, T> {
-    /// Create a new iterator over records in a page.
-    pub fn new(page: &'a T) -> Self {
+    /// Create a new iterator over records in a page passing the raw filters,
+    /// either records deleted softly or live ones.
+    pub fn new(page: &'a T, filters: &'a [RawFilter], deleted: bool) -> Self {
         Self {
             page,
             slot: 0,
-            offset: 2 * LINK_SIZE + page.get_free_bitmap_size(),
+            offset: page.get_offset(0),
+            filters,
+            deleted,
         }
     }
 
     /// Increment the iterator.
     fn inc(&mut self) {
         self.slot += 1;
-        self.offset += self.page.get_record_size();
+        self.offset += self.page.get_slot_size();
     }
 }
 
@@ -789,7 +1339,13 @@ impl<'a, T: LinkedPage<'a>> Iterator for PageIterator<'a, T> {
 
     fn next(&mut self) -> Option<Self::Item> {
         while self.slot < self.page.get_max_records() {
-            if self.page.is_free(self.slot) {
+            if self.page.is_free(self.slot)
+                || self.page.is_deleted(self.slot) != self.deleted
+                || !self
+                    .filters
+                    .iter()
+                    .all(|filter| filter.matches(self.page.get_buf(), self.offset))
+            {
                 self.inc();
                 continue;
             }
@@ -0,0 +1,76 @@
+//! Tracing context of the statement being executed.
+//!
+//! The context is kept per thread and attached to every log record as
+//! structured fields, so that log records, e.g. page cache activity, can be
+//! correlated with the statement causing them.
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// Id of the next statement.
+static NEXT_STATEMENT: AtomicUsize = AtomicUsize::new(1);
+
+thread_local! {
+    static CONTEXT: RefCell<Context> = RefCell::new(Context::default());
+}
+
+/// Fields attached to log records.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Context {
+    /// Id of the statement being executed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub statement: Option<usize>,
+    /// Table last opened by the statement.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub table: Option<String>,
+    /// Page last accessed by the statement.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page: Option<usize>,
+    /// Duration of the statement in milliseconds, only set once it finishes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<f64>,
+}
+
+/// Start tracing a new statement with a fresh id.
+///
+/// Returns the context of the enclosing statement, e.g. `REPLAY`, which
+/// should be passed back to `end_statement`.
+pub fn begin_statement() -> Context {
+    let id = NEXT_STATEMENT.fetch_add(1, Ordering::Relaxed);
+    CONTEXT.with(|context| {
+        context.replace(Context {
+            statement: Some(id),
+            ..Default::default()
+        })
+    })
+}
+
+/// Log the duration of the current statement and restore the enclosing context.
+pub fn end_statement(outer: Context, elapsed: Duration) {
+    CONTEXT.with(|context| context.borrow_mut().duration_ms = Some(elapsed.as_secs_f64() * 1000.0));
+    log::info!("Statement finished");
+    CONTEXT.with(|context| context.replace(outer));
+}
+
+/// Record the table being accessed.
+pub fn set_table(table: &str) {
+    CONTEXT.with(|context| {
+        let mut context = context.borrow_mut();
+        if context.table.as_deref() != Some(table) {
+            context.table = Some(table.to_owned());
+        }
+    });
+}
+
+/// Record the page being accessed.
+pub fn set_page(page: usize) {
+    CONTEXT.with(|context| context.borrow_mut().page = Some(page));
+}
+
+/// Get a copy of the current context.
+pub fn get() -> Context {
+    CONTEXT.with(|context| context.borrow().clone())
+}